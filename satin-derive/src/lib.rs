@@ -0,0 +1,327 @@
+//! Derive macros for `satin`'s `IntoLua` and `FromLua` traits.
+//!
+//! Structs with named fields convert to and from tables keyed by the names of their fields,
+//! tuple structs to and from sequences, newtypes as the value they wrap, and unit structs to
+//! and from nil. Enums convert as their variants do, with a unit variant as its name and any
+//! other as a table with a single field, the variant's name, holding its contents.
+//!
+//! A field or variant is given another name in Lua with `#[lua(rename = "name")]`.
+
+use {
+    proc_macro::TokenStream,
+    proc_macro2::TokenStream as TokenStream2,
+    quote::{format_ident, quote},
+    syn::{
+        ext::IdentExt, parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Fields,
+        Generics, Ident, LitStr, Result,
+    },
+};
+
+#[proc_macro_derive(IntoLua, attributes(lua))]
+pub fn derive_into_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    into_lua(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+#[proc_macro_derive(FromLua, attributes(lua))]
+pub fn derive_from_lua(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_lua(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn into_lua(input: DeriveInput) -> Result<TokenStream2> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let (pattern, bindings) = destructure(quote!(Self), &data.fields);
+            let convert = encode(&data.fields, &bindings)?;
+            quote! {
+                let #pattern = self;
+                #convert
+            }
+        }
+
+        Data::Enum(data) => {
+            let arms = data
+                .variants
+                .iter()
+                .map(|variant| {
+                    let ident = &variant.ident;
+                    let name = lua_name(&variant.attrs, ident)?;
+                    let (pattern, bindings) = destructure(quote!(Self::#ident), &variant.fields);
+
+                    let convert = match variant.fields {
+                        Fields::Unit => quote!(::satin::IntoLua::into_lua(#name, lua)),
+
+                        _ => {
+                            let payload = encode(&variant.fields, &bindings)?;
+                            quote! {
+                                let table = lua.create_table();
+                                let payload = { #payload }?;
+                                table.raw_set(lua, #name, payload)?;
+                                ::satin::IntoLua::into_lua(table, lua)
+                            }
+                        }
+                    };
+
+                    Ok(quote!(#pattern => { #convert }))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+
+        Data::Union(_) => return Err(unsupported(&input.ident, "IntoLua")),
+    };
+
+    let ident = &input.ident;
+    let generics = bound(&input.generics, quote!(::satin::IntoLua));
+    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::satin::IntoLua for #ident #type_generics #where_clause {
+            fn into_lua(self, lua: &mut ::satin::Lua) -> ::satin::Result<::satin::Value> {
+                #body
+            }
+        }
+    })
+}
+
+fn from_lua(input: DeriveInput) -> Result<TokenStream2> {
+    let ident = &input.ident;
+    let type_name = ident.to_string();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let convert = decode(quote!(Self), &data.fields, &type_name)?;
+            quote!(::std::result::Result::Ok(#convert))
+        }
+
+        Data::Enum(data) => {
+            let mut units = Vec::new();
+            let mut others = Vec::new();
+
+            for variant in &data.variants {
+                let variant_ident = &variant.ident;
+                let name = lua_name(&variant.attrs, variant_ident)?;
+                match variant.fields {
+                    Fields::Unit => units.push(quote! {
+                        #name => return ::std::result::Result::Ok(Self::#variant_ident),
+                    }),
+
+                    _ => {
+                        let convert =
+                            decode(quote!(Self::#variant_ident), &variant.fields, &type_name)?;
+                        others.push(quote! {
+                            let value: ::satin::Value = table.raw_get(lua, #name)?;
+                            if !value.is_nil() {
+                                return ::std::result::Result::Ok(#convert);
+                            }
+                        });
+                    }
+                }
+            }
+
+            let units = (!units.is_empty()).then(|| {
+                quote! {
+                    if let ::satin::Value::String(_) = value {
+                        let name: ::std::string::String = ::satin::FromLua::from_lua(value, lua)?;
+                        match name.as_str() {
+                            #(#units)*
+                            _ => {}
+                        }
+                    }
+                }
+            });
+
+            let others = (!others.is_empty()).then(|| {
+                quote! {
+                    if let ::satin::Value::Table(_) = value {
+                        let table: ::satin::Table = ::satin::FromLua::from_lua(value, lua)?;
+                        #(#others)*
+                    }
+                }
+            });
+
+            quote! {
+                #units
+                #others
+
+                ::std::result::Result::Err(::satin::Error::FromLua {
+                    from: lua.type_name(value),
+                    to: #type_name,
+                    message: ::std::option::Option::Some("no variant matches".to_owned()),
+                })
+            }
+        }
+
+        Data::Union(_) => return Err(unsupported(ident, "FromLua")),
+    };
+
+    let generics = bound(&input.generics, quote!(::satin::FromLua));
+    let (impl_generics, type_generics, where_clause) = generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::satin::FromLua for #ident #type_generics #where_clause {
+            fn from_lua(
+                value: ::satin::Value,
+                lua: &mut ::satin::Lua,
+            ) -> ::satin::Result<Self> {
+                #body
+            }
+        }
+    })
+}
+
+/// A pattern that binds every field of a struct or variant, and the names it binds them to.
+fn destructure(path: TokenStream2, fields: &Fields) -> (TokenStream2, Vec<Ident>) {
+    match fields {
+        // The fields are bound to names of their own, so that they cannot shadow those of
+        // the generated code.
+        Fields::Named(named) => {
+            let idents: Vec<_> = named.named.iter().flat_map(|f| f.ident.as_ref()).collect();
+            let bindings: Vec<_> = idents
+                .iter()
+                .map(|ident| format_ident!("field_{}", ident.unraw()))
+                .collect();
+            (quote!(#path { #(#idents: #bindings),* }), bindings)
+        }
+
+        Fields::Unnamed(unnamed) => {
+            let bindings: Vec<_> = (0..unnamed.unnamed.len())
+                .map(|index| format_ident!("field{index}"))
+                .collect();
+            (quote!(#path(#(#bindings),*)), bindings)
+        }
+
+        Fields::Unit => (path, Vec::new()),
+    }
+}
+
+/// Converts the bound fields of a struct or variant to a Lua value.
+fn encode(fields: &Fields, bindings: &[Ident]) -> Result<TokenStream2> {
+    match fields {
+        Fields::Named(named) => {
+            let keys = named
+                .named
+                .iter()
+                .map(|field| lua_name(&field.attrs, field.ident.as_ref().unwrap()))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(quote! {
+                let table = lua.create_table();
+                #(table.raw_set(lua, #keys, #bindings)?;)*
+                ::satin::IntoLua::into_lua(table, lua)
+            })
+        }
+
+        Fields::Unnamed(_) if bindings.len() == 1 => {
+            let binding = &bindings[0];
+            Ok(quote!(::satin::IntoLua::into_lua(#binding, lua)))
+        }
+
+        Fields::Unnamed(_) => {
+            let keys = (1..=bindings.len() as i64).collect::<Vec<_>>();
+            Ok(quote! {
+                let table = lua.create_table();
+                #(table.raw_set(lua, #keys, #bindings)?;)*
+                ::satin::IntoLua::into_lua(table, lua)
+            })
+        }
+
+        Fields::Unit => Ok(quote!(::std::result::Result::Ok(::satin::Value::Nil))),
+    }
+}
+
+/// Builds a struct or variant from the Lua value in `value`, returning early with an error if
+/// it has the wrong shape.
+fn decode(path: TokenStream2, fields: &Fields, type_name: &str) -> Result<TokenStream2> {
+    let mismatch = quote! {
+        return ::std::result::Result::Err(::satin::Error::FromLua {
+            from: lua.type_name(value),
+            to: #type_name,
+            message: ::std::option::Option::None,
+        })
+    };
+
+    let table = quote! {
+        let table: ::satin::Table = match value {
+            ::satin::Value::Table(_) => ::satin::FromLua::from_lua(value, lua)?,
+            _ => #mismatch,
+        };
+    };
+
+    match fields {
+        Fields::Named(named) => {
+            let idents: Vec<_> = named.named.iter().flat_map(|f| f.ident.as_ref()).collect();
+            let keys = named
+                .named
+                .iter()
+                .map(|field| lua_name(&field.attrs, field.ident.as_ref().unwrap()))
+                .collect::<Result<Vec<_>>>()?;
+
+            Ok(quote! {{
+                #table
+                #path { #(#idents: table.raw_get(lua, #keys)?),* }
+            }})
+        }
+
+        Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+            Ok(quote!(#path(::satin::FromLua::from_lua(value, lua)?)))
+        }
+
+        Fields::Unnamed(unnamed) => {
+            let keys = (1..=unnamed.unnamed.len() as i64).collect::<Vec<_>>();
+            Ok(quote! {{
+                #table
+                #path(#(table.raw_get(lua, #keys)?),*)
+            }})
+        }
+
+        Fields::Unit => Ok(quote! {{
+            if !value.is_nil() {
+                #mismatch
+            }
+
+            #path
+        }}),
+    }
+}
+
+/// The name of a field or variant in Lua: its own, unless an attribute renames it.
+fn lua_name(attrs: &[Attribute], ident: &Ident) -> Result<LitStr> {
+    let mut name = LitStr::new(&ident.unraw().to_string(), ident.span());
+    for attr in attrs.iter().filter(|attr| attr.path().is_ident("lua")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = meta.value()?.parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported attribute"))
+            }
+        })?;
+    }
+
+    Ok(name)
+}
+
+/// Requires every type parameter to implement `bound`.
+fn bound(generics: &Generics, bound: TokenStream2) -> Generics {
+    let mut generics = generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(#bound));
+    }
+
+    generics
+}
+
+fn unsupported(ident: &Ident, derive: &str) -> syn::Error {
+    syn::Error::new(ident.span(), format!("cannot derive {derive} for a union"))
+}
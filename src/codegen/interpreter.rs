@@ -0,0 +1,422 @@
+//! Runs the code of a lowered function in its frame.
+
+use {
+    super::{Code, Comparison, Instruction, Operand, Results},
+    crate::runtime::{float_to_int, Error, Function, Result, State, Value},
+    std::{mem, rc::Rc},
+};
+
+/// A call of a compiled function that [`run`] is to return to once the function it called
+/// returns.
+struct Activation {
+    code: Rc<Code>,
+    pc: usize,
+    frame_top: usize,
+
+    /// Where the results of the call it made go.
+    results: Results,
+}
+
+/// Runs a closure of the function that `code` was lowered from, as the callback of its
+/// prototype.
+///
+/// The frame holds the parameters on entry, and is extended to hold every slot the code uses.
+/// Values of unknown number, such as the results of a call that are all passed on, are kept
+/// above it, until the next instruction that uses the stack takes them.
+///
+/// Calls of other compiled functions run here too, each in a frame of its own, so that however
+/// deeply they nest, they take no more of the native stack than the first function does.
+pub(super) fn run(state: &mut State, code: &Rc<Code>) -> Result<usize> {
+    let mut callers = Vec::new();
+    execute(state, code.clone(), &mut callers).map_err(|error| {
+        // The functions still running were called from the first one, whose frame the call
+        // that ran it unwinds.
+        callers
+            .iter()
+            .fold(error, |error, _| state.unwind_call(error))
+    })
+}
+
+fn execute(state: &mut State, mut code: Rc<Code>, callers: &mut Vec<Activation>) -> Result<usize> {
+    let mut frame_top = enter(state, &code);
+    let mut pc = 0;
+
+    loop {
+        let count = loop {
+            state.trace(pc as u32)?;
+            let instruction = &code.instructions[pc];
+            let operands = &code.operands[pc];
+            let name = |error: Error| error.name_operand(|i| operands[i].clone());
+            pc += 1;
+
+            match instruction {
+                &Instruction::Move(destination, operand) => {
+                    let value = get(state, &code, operand);
+                    state.set_local(destination, value);
+                }
+
+                &Instruction::GetUpvalue(destination, index) => {
+                    let value = state.upvalue(index + 1);
+                    state.set_local(destination, value);
+                }
+
+                &Instruction::SetUpvalue(index, operand) => {
+                    let value = get(state, &code, operand);
+                    state.set_upvalue(index + 1, value);
+                }
+
+                &Instruction::NewTable(destination) => {
+                    let table = state.heap.new_table();
+                    state.set_local(destination, Value::Table(table));
+                    state.check_gc();
+                }
+
+                &Instruction::Closure(destination, index) => {
+                    let function = state.new_closure(code.prototypes[index].clone());
+                    state.set_local(destination, Value::Function(function));
+                    state.check_gc();
+                }
+
+                Instruction::MarkToBeClosed(slot, name) => state.mark_to_be_closed(*slot, name)?,
+                &Instruction::Close(slot) => state.close(slot)?,
+                &Instruction::Jump(target) => pc = target,
+
+                &Instruction::JumpIf(condition, then, otherwise) => {
+                    pc = match get(state, &code, condition).is_truthy() {
+                        true => then,
+                        false => otherwise,
+                    };
+                }
+
+                &Instruction::Arith(op, destination, a, b) => {
+                    let (a, b) = (get(state, &code, a), get(state, &code, b));
+                    let value = state.arith(op, a, b).map_err(name)?;
+                    state.set_local(destination, value);
+                }
+
+                &Instruction::Len(destination, operand) => {
+                    let operand = get(state, &code, operand);
+                    let value = state.len(operand).map_err(name)?;
+                    state.set_local(destination, value);
+                }
+
+                &Instruction::Concat(destination, a, b) => {
+                    let (a, b) = (get(state, &code, a), get(state, &code, b));
+                    let value = state.concat(a, b).map_err(name)?;
+                    state.set_local(destination, value);
+                    state.check_gc();
+                }
+
+                &Instruction::Compare(comparison, destination, a, b) => {
+                    let (a, b) = (get(state, &code, a), get(state, &code, b));
+                    let result = match comparison {
+                        Comparison::Eq => state.equals(a, b)?,
+                        Comparison::Ne => !state.equals(a, b)?,
+                        Comparison::Lt => state.less_than(a, b)?,
+                        Comparison::Le => state.less_equal(a, b)?,
+                    };
+                    state.set_local(destination, Value::Bool(result));
+                }
+
+                &Instruction::Index(destination, object, key) => {
+                    let (object, key) = (get(state, &code, object), get(state, &code, key));
+                    let value = state.index(object, key).map_err(name)?;
+                    state.set_local(destination, value);
+                }
+
+                &Instruction::SetIndex(object, key, value) => {
+                    let (object, key) = (get(state, &code, object), get(state, &code, key));
+                    let value = get(state, &code, value);
+                    state.set_index(object, key, value).map_err(name)?;
+                }
+
+                Instruction::SetList(table, values, open) => {
+                    let Value::Table(table) = get(state, &code, *table) else {
+                        unreachable!("only table constructors set lists");
+                    };
+
+                    let mut values: Vec<_> = values
+                        .iter()
+                        .map(|&operand| get(state, &code, operand))
+                        .collect();
+                    if *open {
+                        values.extend_from_slice(state.values_from(frame_top));
+                        state.set_top(frame_top);
+                    }
+
+                    for (i, value) in values.into_iter().enumerate() {
+                        state.heap[table].set_int(i as i64 + 1, value);
+                    }
+                }
+
+                &Instruction::Varargs(results) => {
+                    state.push_varargs();
+                    if let Results::Slots(first, count) = results {
+                        store_results(state, frame_top, first, count);
+                    }
+                }
+
+                Instruction::Call(callee, args, open, results) => {
+                    let nargs = push_call(state, &code, frame_top, *callee, args, *open);
+                    let results = *results;
+
+                    if let Some(callee) = compiled_callee(state, nargs) {
+                        state.enter_call(nargs)?;
+                        let caller = mem::replace(&mut code, callee);
+                        callers.push(Activation {
+                            code: caller,
+                            pc,
+                            frame_top,
+                            results,
+                        });
+
+                        frame_top = enter(state, &code);
+                        pc = 0;
+                    } else if let Results::Slots(first, count) = results {
+                        state.call(nargs, Some(count))?;
+                        store_results(state, frame_top, first, count);
+                    } else {
+                        state.call(nargs, None)?;
+                    }
+                }
+
+                Instruction::TailCall(callee, args, open) => {
+                    let nargs = push_call(state, &code, frame_top, *callee, args, *open);
+
+                    // Only a compiled function takes the place of the frame that called it. A
+                    // native function is called the ordinary way, as is a value that cannot be
+                    // called, so that the frame is still there for errors to blame and for the
+                    // debug library to see. The first function's frame belongs to the call
+                    // that ran it, which replaces it.
+                    match compiled_callee(state, nargs) {
+                        Some(_) if callers.is_empty() => return state.tail_call(nargs),
+
+                        Some(callee) => {
+                            if let Err(error) = state.replace_call(nargs) {
+                                callers.pop();
+                                return Err(error);
+                            }
+
+                            code = callee;
+                            frame_top = enter(state, &code);
+                            pc = 0;
+                        }
+
+                        None => {
+                            state.call(nargs, None)?;
+                            break state.top() - frame_top;
+                        }
+                    }
+                }
+
+                Instruction::Return(values, open) => {
+                    let open_count = open_count(state, frame_top, *open);
+                    break push_below(state, &code, open_count, values);
+                }
+
+                &Instruction::ForPrep(destination, slot) => {
+                    let runs = for_prep(state, slot)?;
+                    state.set_local(destination, Value::Bool(runs));
+                }
+
+                &Instruction::ForStep(destination, slot) => {
+                    let again = for_step(state, slot);
+                    state.set_local(destination, Value::Bool(again));
+                }
+            }
+        };
+
+        let Some(caller) = callers.pop() else {
+            return Ok(count);
+        };
+
+        state.leave_call(count)?;
+        (code, pc, frame_top) = (caller.code, caller.pc, caller.frame_top);
+        if let Results::Slots(first, count) = caller.results {
+            store_results(state, frame_top, first, count);
+        }
+    }
+}
+
+/// Extends the frame of a call of the function that `code` was lowered from, which holds its
+/// parameters, to every slot the code uses, returning the top of the frame.
+fn enter(state: &mut State, code: &Code) -> usize {
+    let frame_top = state.top() - code.parameters + code.frame_size;
+    state.set_top(frame_top);
+    frame_top
+}
+
+/// The code of the function that a call of the value below the top `nargs` values on the
+/// stack runs, if it is a function compiled here.
+fn compiled_callee(state: &State, nargs: usize) -> Option<Rc<Code>> {
+    let callee = state.values_from(state.top() - nargs - 1)[0];
+    match &state.heap[state.function_called(callee)?] {
+        Function::Lua(closure) => closure.prototype.body.clone().downcast().ok(),
+        Function::Native(_) => None,
+    }
+}
+
+fn get(state: &State, code: &Code, operand: Operand) -> Value {
+    match operand {
+        Operand::Slot(slot) => state.local(slot),
+        Operand::Constant(index) => code.constants[index],
+        Operand::Not(slot) => Value::Bool(!state.local(slot).is_truthy()),
+        Operand::Truthy(slot) => Value::Bool(state.local(slot).is_truthy()),
+    }
+}
+
+/// How many values are above the frame, for an instruction that takes them if `open` says so.
+fn open_count(state: &State, frame_top: usize, open: bool) -> usize {
+    match open {
+        true => state.top() - frame_top,
+        false => 0,
+    }
+}
+
+/// Places `values` on top of the stack, below the top `open_count` values, returning how many
+/// values that makes.
+fn push_below(state: &mut State, code: &Code, open_count: usize, values: &[Operand]) -> usize {
+    for &operand in values {
+        let value = get(state, code, operand);
+        state.insert_below(open_count, value);
+    }
+
+    values.len() + open_count
+}
+
+/// Places the value being called and its arguments on top of the stack, returning the number
+/// of arguments.
+fn push_call(
+    state: &mut State,
+    code: &Code,
+    frame_top: usize,
+    callee: Operand,
+    args: &[Operand],
+    open: bool,
+) -> usize {
+    let open_count = open_count(state, frame_top, open);
+    let callee = get(state, code, callee);
+    state.insert_below(open_count, callee);
+    push_below(state, code, open_count, args)
+}
+
+/// Moves the values above the frame to `count` slots from `first`, padding with nils.
+fn store_results(state: &mut State, frame_top: usize, first: usize, count: usize) {
+    state.set_top(frame_top + count);
+    for i in (0..count).rev() {
+        let value = state.pop();
+        state.set_local(first + i, value);
+    }
+}
+
+/// Prepares the numeric `for` loop whose control value, limit and step are in the three slots
+/// from `slot`, returning whether it runs at all.
+///
+/// A loop over integers counts its iterations in advance, in place of its limit, so that it
+/// cannot overflow. Any other loop is over floats.
+fn for_prep(state: &mut State, slot: usize) -> Result<bool> {
+    let (init, limit, step) = (
+        state.local(slot),
+        state.local(slot + 1),
+        state.local(slot + 2),
+    );
+
+    if let (Value::Int(init), Value::Int(step)) = (init, step) {
+        if step == 0 {
+            return Err(Error::ForStepZero);
+        }
+
+        let limit = match limit {
+            Value::Int(limit) => limit,
+
+            // The limit is rounded towards the start of the loop, or clipped to the integers,
+            // which a loop starting in them cannot leave anyway.
+            Value::Float(limit) => {
+                let rounded = match step < 0 {
+                    true => limit.ceil(),
+                    false => limit.floor(),
+                };
+
+                match float_to_int(rounded) {
+                    Some(limit) => limit,
+                    None if limit.is_nan() => return Ok(false),
+                    None if limit > 0.0 => match step < 0 {
+                        true => return Ok(false),
+                        false => i64::MAX,
+                    },
+                    None => match step > 0 {
+                        true => return Ok(false),
+                        false => i64::MIN,
+                    },
+                }
+            }
+
+            _ => return Err(Error::ForNotNumber("limit")),
+        };
+
+        let count = match step > 0 {
+            true if init > limit => return Ok(false),
+            false if init < limit => return Ok(false),
+            true => (limit as u64).wrapping_sub(init as u64) / step as u64,
+            false => (init as u64).wrapping_sub(limit as u64) / ((-(step + 1)) as u64 + 1),
+        };
+
+        state.set_local(slot + 1, Value::Int(count as i64));
+        return Ok(true);
+    }
+
+    let float = |value, what| match value {
+        Value::Int(i) => Ok(i as f64),
+        Value::Float(f) => Ok(f),
+        _ => Err(Error::ForNotNumber(what)),
+    };
+
+    let limit = float(limit, "limit")?;
+    let step = float(step, "step")?;
+    let init = float(init, "initial value")?;
+    if step == 0.0 {
+        return Err(Error::ForStepZero);
+    }
+
+    state.set_local(slot, Value::Float(init));
+    state.set_local(slot + 1, Value::Float(limit));
+    state.set_local(slot + 2, Value::Float(step));
+    Ok(match step > 0.0 {
+        true => init <= limit,
+        false => limit <= init,
+    })
+}
+
+/// Advances a numeric `for` loop prepared by [`for_prep`], returning whether it runs again.
+fn for_step(state: &mut State, slot: usize) -> bool {
+    match (
+        state.local(slot),
+        state.local(slot + 1),
+        state.local(slot + 2),
+    ) {
+        (Value::Int(control), Value::Int(count), Value::Int(step)) => {
+            if count as u64 == 0 {
+                return false;
+            }
+
+            state.set_local(slot, Value::Int(control.wrapping_add(step)));
+            state.set_local(slot + 1, Value::Int((count as u64 - 1) as i64));
+            true
+        }
+
+        (Value::Float(control), Value::Float(limit), Value::Float(step)) => {
+            let control = control + step;
+            let again = match step > 0.0 {
+                true => control <= limit,
+                false => limit <= control,
+            };
+
+            if again {
+                state.set_local(slot, Value::Float(control));
+            }
+            again
+        }
+
+        _ => unreachable!("the loop was prepared"),
+    }
+}
@@ -0,0 +1,1360 @@
+//! Lowering of the IR of a chunk to the code of its prototypes.
+//!
+//! Each function becomes a sequence of register instructions over the slots of its frame,
+//! which [`interpreter`] runs. Locals live in the slot of their depth, as the IR numbers them,
+//! and the values of expressions in the slots above, which are shared among values whose
+//! lifetimes do not overlap.
+
+use {
+    crate::{
+        ir::{self, BlockRef, InstructionRef, Op, Origin, ValueRef},
+        parse,
+        runtime::{
+            short_source, Arith, Capture, Error, LocalVariable, Position, Prototype, Result, State,
+            Value, Variable,
+        },
+        string_pool::{StringPool, StringRef},
+    },
+    ahash::AHashMap,
+    cranelift_entity::{EntityList, EntityRef, SecondaryMap},
+    std::rc::Rc,
+};
+
+mod interpreter;
+
+/// Compiles the text of a chunk to the prototype of its main function, as a
+/// [`crate::runtime::Compiler`].
+pub fn compile(state: &mut State, chunk: &[u8], chunk_name: &str) -> Result<Rc<Prototype>> {
+    let strings = Rc::new(StringPool::new());
+    let function = parse::parse(chunk, strings.clone()).map_err(|error| {
+        Error::Syntax(format!(
+            "{}:{}: {}",
+            short_source(chunk_name),
+            error.line,
+            error.message
+        ))
+    })?;
+
+    let mut lowering = Lowering {
+        state,
+        strings: &strings,
+        source: chunk_name.into(),
+    };
+
+    Ok(lowering.lower(&function, &SecondaryMap::new()))
+}
+
+/// Where an instruction reads a value from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Operand {
+    Slot(usize),
+    Constant(usize),
+
+    /// Whether the value in the slot is false or nil, as `not` makes it.
+    Not(usize),
+
+    /// Whether the value in the slot is neither false nor nil.
+    Truthy(usize),
+}
+
+/// Where the results of a call go.
+#[derive(Clone, Copy, Debug)]
+enum Results {
+    /// To the given number of consecutive slots, starting at the given one.
+    Slots(usize, usize),
+
+    /// To the top of the stack, above the frame, for the call or return that uses them all,
+    /// which is the next instruction to use the stack.
+    Open,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+}
+
+#[derive(Clone, Debug)]
+enum Instruction {
+    Move(usize, Operand),
+    GetUpvalue(usize, usize),
+    SetUpvalue(usize, Operand),
+    NewTable(usize),
+    Closure(usize, usize),
+    MarkToBeClosed(usize, Rc<str>),
+    Close(usize),
+    Jump(usize),
+    JumpIf(Operand, usize, usize),
+    Arith(Arith, usize, Operand, Operand),
+    Len(usize, Operand),
+    Concat(usize, Operand, Operand),
+    Compare(Comparison, usize, Operand, Operand),
+    Index(usize, Operand, Operand),
+    SetIndex(Operand, Operand, Operand),
+
+    /// Stores values at consecutive integer keys of a table, starting at 1, followed by the
+    /// open results on top of the stack if the flag says so.
+    SetList(Operand, Box<[Operand]>, bool),
+
+    Varargs(Results),
+
+    /// Calls a value with arguments, followed by the open results on top of the stack if the
+    /// flag says so. The same goes for the values of the other instructions that end in a
+    /// flag.
+    Call(Operand, Box<[Operand]>, bool, Results),
+
+    TailCall(Operand, Box<[Operand]>, bool),
+    Return(Box<[Operand]>, bool),
+
+    /// Prepares and advances a numeric `for` loop whose state is in the three slots from the
+    /// second one, storing whether the loop goes on in the first.
+    ForPrep(usize, usize),
+    ForStep(usize, usize),
+}
+
+/// A lowered function, which every closure of its prototype runs.
+struct Code {
+    instructions: Box<[Instruction]>,
+    constants: Box<[Value]>,
+
+    /// The variables the first two operands of each instruction were read from, which errors
+    /// about them name.
+    operands: Box<[[Option<Variable>; 2]]>,
+    prototypes: Box<[Rc<Prototype>]>,
+    parameters: usize,
+    frame_size: usize,
+}
+
+struct Lowering<'a> {
+    state: &'a mut State,
+    strings: &'a StringPool,
+    source: Rc<str>,
+}
+
+/// Where a jump goes before the code is laid out.
+#[derive(Clone, Copy, Debug)]
+enum Target {
+    Block(BlockRef),
+
+    /// One of the moves of block arguments that a conditional branch makes on one of its
+    /// edges, which are laid out after the rest of the code.
+    Edge(usize),
+}
+
+/// How long a value needs a slot, over the positions of the instructions in the order they
+/// were built, and how many consecutive slots it needs.
+#[derive(Clone, Copy, Debug)]
+struct Interval {
+    start: usize,
+    end: usize,
+    size: usize,
+}
+
+impl<'a> Lowering<'a> {
+    /// Lowers a function, given the slots of the locals of the function enclosing it, which
+    /// its closures capture.
+    fn lower(
+        &mut self,
+        function: &ir::Function,
+        enclosing_slots: &SecondaryMap<ValueRef, usize>,
+    ) -> Rc<Prototype> {
+        let graph = &function.graph;
+
+        let mut local_slots = SecondaryMap::new();
+        let mut frame_size = function.parameters as usize;
+        for local in &function.locals {
+            local_slots[local.variable] = local.depth as usize;
+            frame_size = frame_size.max(local.depth as usize + 1);
+        }
+
+        let prototypes: Box<[_]> = function
+            .prototypes
+            .values()
+            .map(|prototype| self.lower(prototype, &local_slots))
+            .collect();
+
+        let (strings, source) = (self.strings, self.source.clone());
+        let text = |name| Rc::from(String::from_utf8_lossy(&strings[name]));
+
+        let slots = Slots::allocate(function, frame_size);
+        let mut builder = CodeBuilder {
+            lowering: self,
+            function,
+            local_slots: &local_slots,
+            slots: &slots,
+            instructions: Vec::new(),
+            positions: Vec::new(),
+            operands: Vec::new(),
+            constants: Vec::new(),
+            constant_indices: AHashMap::new(),
+            jumps: Vec::new(),
+            edges: Vec::new(),
+            edge_starts: Vec::new(),
+            line: 0,
+        };
+
+        let mut starts = Vec::new();
+        for instruction in graph.instructions() {
+            starts.push(builder.instructions.len());
+            builder.line = graph.line(instruction);
+            builder.lower(instruction);
+        }
+        let end = builder.instructions.len();
+        starts.push(end);
+
+        builder.lay_out_edges();
+        let block_start = |block| {
+            let first = graph.first_instruction(block).unwrap();
+            starts[first.index()]
+        };
+        let edge_starts = builder.edge_starts.clone();
+        for &(pc, which, target) in &builder.jumps {
+            let destination = match target {
+                Target::Block(block) => block_start(block),
+                Target::Edge(edge) => edge_starts[edge],
+            };
+
+            match (&mut builder.instructions[pc], which) {
+                (Instruction::Jump(to), _) => *to = destination,
+                (Instruction::JumpIf(_, then, _), 0) => *then = destination,
+                (Instruction::JumpIf(_, _, otherwise), _) => *otherwise = destination,
+                _ => unreachable!("only jumps have targets"),
+            }
+        }
+
+        let dead_slots = slots.dead_slots(&starts, builder.instructions.len());
+
+        let locals = function
+            .locals
+            .iter()
+            .map(|local| LocalVariable {
+                name: text(local.name),
+                slot: local.depth as usize,
+                scope: starts[local.scope.start as usize] as u32
+                    ..starts[local.scope.end as usize] as u32,
+            })
+            .collect();
+
+        let captures = function
+            .upvalues
+            .values()
+            .map(|upvalue| match upvalue.capture {
+                ir::Capture::Local(local) => Capture::Local(enclosing_slots[local]),
+                ir::Capture::Upvalue(upvalue) => Capture::Upvalue(upvalue.index()),
+                ir::Capture::Environment => Capture::Environment,
+            })
+            .collect();
+
+        let upvalue_names = function
+            .upvalues
+            .values()
+            .map(|upvalue| text(upvalue.name))
+            .collect();
+
+        let code = Rc::new(Code {
+            instructions: builder.instructions.into(),
+            constants: builder.constants.into(),
+            operands: builder.operands.into(),
+            prototypes: prototypes.clone(),
+            parameters: function.parameters as usize,
+            frame_size: slots.frame_size,
+        });
+
+        Rc::new(Prototype {
+            code: Rc::new({
+                let code = code.clone();
+                move |state: &mut State| interpreter::run(state, &code)
+            }),
+            body: code.clone(),
+            constants: code.constants.clone(),
+            source,
+            line_defined: function.line_defined,
+            last_line_defined: function.last_line_defined,
+            positions: builder.positions.into(),
+            dead_slots,
+            parameters: function.parameters as usize,
+            is_vararg: function.is_vararg,
+            captures,
+            upvalue_names,
+            locals,
+            prototypes,
+        })
+    }
+
+    fn text(&self, name: StringRef) -> String {
+        String::from_utf8_lossy(&self.strings[name]).into_owned()
+    }
+
+    fn variable(&self, origin: Origin) -> Variable {
+        match origin {
+            Origin::Global(name) => Variable::Global(self.text(name)),
+            Origin::Local(name) => Variable::Local(self.text(name)),
+            Origin::Upvalue(name) => Variable::Upvalue(self.text(name)),
+            Origin::Field(name) => Variable::Field(self.text(name)),
+            Origin::Method(name) => Variable::Method(self.text(name)),
+        }
+    }
+}
+
+/// The slots of the values of a function's expressions.
+struct Slots {
+    slots: SecondaryMap<ValueRef, Option<usize>>,
+
+    /// Where the results of each call or `...` go.
+    results: SecondaryMap<ValueRef, Option<Results>>,
+
+    /// The first of the slots of each value, with how long it needs them.
+    intervals: Vec<(usize, Interval)>,
+    locals: usize,
+    frame_size: usize,
+}
+
+impl Slots {
+    /// Assigns slots above the function's locals to the values of its expressions, sharing
+    /// them among values that are not needed at the same time.
+    fn allocate(function: &ir::Function, locals: usize) -> Self {
+        let graph = &function.graph;
+        let mut intervals: SecondaryMap<ValueRef, Option<Interval>> = SecondaryMap::new();
+        let mut results = SecondaryMap::new();
+
+        // A pack needs as many slots as are unpacked from it, unless all of it is used, in
+        // which case its values stay on top of the stack.
+        for (_, value) in graph.values() {
+            match value {
+                ir::Value::Unpack(pack, position) => {
+                    let start = defining_instruction(graph, pack).index();
+                    let interval = intervals[pack].get_or_insert(Interval {
+                        start,
+                        end: start,
+                        size: 0,
+                    });
+                    interval.size = interval.size.max(position as usize + 1);
+                }
+
+                ir::Value::Trailing(pack, _) => results[pack] = Some(Results::Open),
+                _ => (),
+            }
+        }
+
+        // Where a value is needed, which for a value that is part of another is where that
+        // one is.
+        let owner = |value: ValueRef| match graph.value(value) {
+            ir::Value::Not(inner) => inner,
+            ir::Value::Unpack(pack, _) => pack,
+            _ => value,
+        };
+
+        let mut back_edges = Vec::new();
+        for instruction in graph.instructions() {
+            let position = instruction.index();
+            let op = graph.op(instruction);
+
+            if produces_value(op) {
+                if let Some(result) = graph.result(instruction) {
+                    intervals[result] = Some(Interval {
+                        start: position,
+                        end: position,
+                        size: 1,
+                    });
+                }
+            }
+
+            let use_at = |intervals: &mut SecondaryMap<_, Option<Interval>>, value| {
+                if let Some(interval) = &mut intervals[owner(value)] {
+                    interval.end = interval.end.max(position);
+                }
+            };
+            for_each_operand(graph, op, |value| use_at(&mut intervals, value));
+
+            // The arguments of a block are moved into its parameters as it is branched to,
+            // so each parameter is needed from the first branch that sets it.
+            let mut branch_to = |target: ir::BranchTarget| {
+                for (i, &arg) in graph.list(target.args).iter().enumerate() {
+                    use_at(&mut intervals, arg);
+                    if let Some(parameter) = graph.block_argument(target.block, i as u32) {
+                        let interval = intervals[parameter].get_or_insert(Interval {
+                            start: position,
+                            end: position,
+                            size: 1,
+                        });
+                        interval.start = interval.start.min(position);
+                    }
+                }
+
+                let head = graph.first_instruction(target.block).unwrap().index();
+                if head <= position {
+                    back_edges.push(head..position);
+                }
+            };
+
+            match op {
+                Op::Branch(target) => branch_to(target),
+
+                Op::BranchIf(_, then, otherwise) => {
+                    branch_to(then);
+                    branch_to(otherwise);
+                }
+
+                _ => (),
+            }
+        }
+
+        // A value that is live as a loop goes around must keep its slot for the whole loop.
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for interval in intervals.values_mut().flatten() {
+                for back_edge in &back_edges {
+                    if interval.start < back_edge.start
+                        && interval.end >= back_edge.start
+                        && interval.end < back_edge.end
+                    {
+                        interval.end = back_edge.end;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        let mut sorted: Vec<_> = intervals
+            .iter()
+            .filter_map(|(value, interval)| Some((value, (*interval)?)))
+            .filter(|&(value, _)| results[value].is_none())
+            .collect();
+        sorted.sort_by_key(|&(_, interval)| interval.start);
+
+        // Each slot above the locals is free once the last value in it is no longer needed.
+        let mut busy_until: Vec<Option<usize>> = Vec::new();
+        let mut slots = SecondaryMap::new();
+        let mut assigned = Vec::new();
+        for (value, interval) in sorted {
+            let is_free = |slot: usize| match busy_until.get(slot) {
+                Some(&Some(end)) => end < interval.start,
+                _ => true,
+            };
+            let first = (0..=busy_until.len())
+                .find(|&first| (first..first + interval.size).all(is_free))
+                .unwrap();
+
+            if busy_until.len() < first + interval.size {
+                busy_until.resize(first + interval.size, None);
+            }
+            busy_until[first..first + interval.size].fill(Some(interval.end));
+            assigned.push((locals + first, interval));
+
+            match graph.is_pack(value) {
+                true => results[value] = Some(Results::Slots(locals + first, interval.size)),
+                false => slots[value] = Some(locals + first),
+            }
+        }
+
+        Self {
+            slots,
+            results,
+            intervals: assigned,
+            locals,
+            frame_size: locals + busy_until.len(),
+        }
+    }
+
+    /// For each instruction of the code, the slots above the locals that hold no value that is
+    /// still needed while it runs, given where the code of each IR instruction starts and
+    /// where the code of the last one ends. A value is needed from after the instruction that
+    /// produces it up to the one that last uses it.
+    ///
+    /// The moves laid out after the code have none, since they are not in the order the
+    /// intervals follow.
+    fn dead_slots(&self, starts: &[usize], len: usize) -> Box<[Box<[usize]>]> {
+        let end = starts[starts.len() - 1];
+
+        // How many values need each slot, as it changes from one instruction to the next.
+        let mut changes = vec![vec![0isize; end + 1]; self.frame_size - self.locals];
+        for &(first, interval) in &self.intervals {
+            let needed = starts[interval.start] + 1..starts[interval.end + 1];
+            if needed.is_empty() {
+                continue;
+            }
+
+            for changes in &mut changes[first - self.locals..][..interval.size] {
+                changes[needed.start] += 1;
+                changes[needed.end] -= 1;
+            }
+        }
+
+        let mut needed_by = vec![0; changes.len()];
+        (0..len)
+            .map(|pc| {
+                if pc >= end {
+                    return Box::default();
+                }
+
+                for (count, changes) in needed_by.iter_mut().zip(&changes) {
+                    *count += changes[pc];
+                }
+
+                needed_by
+                    .iter()
+                    .enumerate()
+                    .filter(|&(_, &count)| count == 0)
+                    .map(|(slot, _)| self.locals + slot)
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// The instruction whose result a value is.
+fn defining_instruction(graph: &ir::Graph, value: ValueRef) -> InstructionRef {
+    match graph.value(value) {
+        ir::Value::InstructionResult(instruction) => instruction,
+        _ => unreachable!("the value is not the result of an instruction"),
+    }
+}
+/// Whether an operation produces a value that is not a pack.
+fn produces_value(op: Op) -> bool {
+    matches!(
+        op,
+        Op::Table
+            | Op::LocalGet(_)
+            | Op::Closure(_)
+            | Op::UpvalueGet(_)
+            | Op::Bnot(_)
+            | Op::Len(_)
+            | Op::Unm(_)
+            | Op::Add(..)
+            | Op::Band(..)
+            | Op::Bor(..)
+            | Op::Bxor(..)
+            | Op::Concat(..)
+            | Op::Div(..)
+            | Op::Eq(..)
+            | Op::Ge(..)
+            | Op::Gt(..)
+            | Op::Idiv(..)
+            | Op::Index(..)
+            | Op::Le(..)
+            | Op::Lt(..)
+            | Op::Mod(..)
+            | Op::Mul(..)
+            | Op::Ne(..)
+            | Op::Pow(..)
+            | Op::Shl(..)
+            | Op::Shr(..)
+            | Op::Sub(..)
+            | Op::ForPrep(_)
+            | Op::ForStep(_)
+    )
+}
+
+/// Calls `f` with each value an operation reads, other than the locals it refers to and the
+/// arguments of the blocks it branches to.
+fn for_each_operand(graph: &ir::Graph, op: Op, mut f: impl FnMut(ValueRef)) {
+    let list = |list: EntityList<ValueRef>, f: &mut dyn FnMut(ValueRef)| {
+        graph.list(list).iter().for_each(|&value| f(value))
+    };
+
+    match op {
+        Op::Table
+        | Op::Local
+        | Op::LocalGet(_)
+        | Op::Closure(_)
+        | Op::UpvalueGet(_)
+        | Op::ToBeClosed(..)
+        | Op::Close(_)
+        | Op::Branch(_)
+        | Op::Vararg
+        | Op::ForPrep(_)
+        | Op::ForStep(_) => (),
+
+        Op::LocalSet(_, value)
+        | Op::UpvalueSet(_, value)
+        | Op::BranchIf(value, ..)
+        | Op::Bnot(value)
+        | Op::Len(value)
+        | Op::Unm(value) => f(value),
+
+        Op::Add(a, b)
+        | Op::Band(a, b)
+        | Op::Bor(a, b)
+        | Op::Bxor(a, b)
+        | Op::Concat(a, b)
+        | Op::Div(a, b)
+        | Op::Eq(a, b)
+        | Op::Ge(a, b)
+        | Op::Gt(a, b)
+        | Op::Idiv(a, b)
+        | Op::Index(a, b)
+        | Op::Le(a, b)
+        | Op::Lt(a, b)
+        | Op::Mod(a, b)
+        | Op::Mul(a, b)
+        | Op::Ne(a, b)
+        | Op::Pow(a, b)
+        | Op::Shl(a, b)
+        | Op::Shr(a, b)
+        | Op::Sub(a, b) => {
+            f(a);
+            f(b);
+        }
+
+        Op::Newindex(table, key, value) => {
+            f(table);
+            f(key);
+            f(value);
+        }
+
+        Op::SetList(table, values) => {
+            f(table);
+            list(values, &mut f);
+        }
+
+        Op::Call(callee, args) | Op::TailCall(callee, args) => {
+            f(callee);
+            list(args, &mut f);
+        }
+
+        Op::Return(values) => list(values, &mut f),
+    }
+}
+
+/// Lowers the instructions of one function, in the order they were built.
+struct CodeBuilder<'l, 'a> {
+    lowering: &'l mut Lowering<'a>,
+    function: &'l ir::Function,
+    local_slots: &'l SecondaryMap<ValueRef, usize>,
+    slots: &'l Slots,
+
+    instructions: Vec<Instruction>,
+    positions: Vec<Position>,
+    operands: Vec<[Option<Variable>; 2]>,
+    constants: Vec<Value>,
+    constant_indices: AHashMap<ir::Value, usize>,
+
+    /// The jumps whose targets are yet to be laid out, as the instruction, which of its
+    /// targets it is, and where it goes.
+    jumps: Vec<(usize, usize, Target)>,
+
+    /// The moves of the block arguments of the edges of conditional branches, with their
+    /// lines, and where each of them was laid out.
+    edges: Vec<(ir::BranchTarget, u32)>,
+    edge_starts: Vec<usize>,
+
+    /// The line of the instruction being lowered.
+    line: u32,
+}
+
+impl CodeBuilder<'_, '_> {
+    fn lower(&mut self, instruction: InstructionRef) {
+        let function = self.function;
+        let graph = &function.graph;
+        let op = graph.op(instruction);
+        let result = || graph.result(instruction).unwrap();
+
+        match op {
+            Op::Local => (),
+
+            Op::Table => {
+                let destination = self.slot(result());
+                self.emit(Instruction::NewTable(destination));
+            }
+
+            Op::LocalGet(local) => {
+                let destination = self.slot(result());
+                let local = self.local_slots[local];
+                self.emit(Instruction::Move(destination, Operand::Slot(local)));
+            }
+
+            Op::LocalSet(local, value) => {
+                let local = self.local_slots[local];
+                let value = self.operand(value);
+                if value != Operand::Slot(local) {
+                    self.emit(Instruction::Move(local, value));
+                }
+            }
+
+            Op::Closure(prototype) => {
+                let destination = self.slot(result());
+                self.emit(Instruction::Closure(destination, prototype.index()));
+            }
+
+            Op::UpvalueGet(upvalue) => {
+                let destination = self.slot(result());
+                self.emit(Instruction::GetUpvalue(destination, upvalue.index()));
+            }
+
+            Op::UpvalueSet(upvalue, value) => {
+                let value = self.operand(value);
+                self.emit(Instruction::SetUpvalue(upvalue.index(), value));
+            }
+
+            Op::ToBeClosed(local, name) => {
+                let local = self.local_slots[local];
+                let name = self.lowering.text(name).into();
+                self.emit(Instruction::MarkToBeClosed(local, name));
+            }
+
+            Op::Close(local) => {
+                let local = self.local_slots[local];
+                self.emit(Instruction::Close(local));
+            }
+
+            Op::Branch(target) => {
+                self.move_arguments(target);
+                let pc = self.emit(Instruction::Jump(0));
+                self.jumps.push((pc, 0, Target::Block(target.block)));
+            }
+
+            Op::BranchIf(condition, then, otherwise) => {
+                let condition = self.operand(condition);
+                let pc = self.emit(Instruction::JumpIf(condition, 0, 0));
+                for (which, target) in [then, otherwise].into_iter().enumerate() {
+                    let target = match target.args.is_empty() {
+                        true => Target::Block(target.block),
+
+                        false => {
+                            self.edges.push((target, self.line));
+                            Target::Edge(self.edges.len() - 1)
+                        }
+                    };
+                    self.jumps.push((pc, which, target));
+                }
+            }
+
+            Op::Bnot(value) | Op::Unm(value) => {
+                let arith = match op {
+                    Op::Bnot(_) => Arith::Bnot,
+                    _ => Arith::Unm,
+                };
+
+                let destination = self.slot(result());
+                let operand = self.operand(value);
+                let name = self.name(value);
+                self.emit_named(
+                    Instruction::Arith(arith, destination, operand, operand),
+                    [name.clone(), name],
+                );
+            }
+
+            Op::Len(value) => {
+                let destination = self.slot(result());
+                let operand = self.operand(value);
+                let name = self.name(value);
+                self.emit_named(Instruction::Len(destination, operand), [name, None]);
+            }
+
+            Op::Add(a, b)
+            | Op::Sub(a, b)
+            | Op::Mul(a, b)
+            | Op::Div(a, b)
+            | Op::Idiv(a, b)
+            | Op::Mod(a, b)
+            | Op::Pow(a, b)
+            | Op::Band(a, b)
+            | Op::Bor(a, b)
+            | Op::Bxor(a, b)
+            | Op::Shl(a, b)
+            | Op::Shr(a, b) => {
+                let arith = match op {
+                    Op::Add(..) => Arith::Add,
+                    Op::Sub(..) => Arith::Sub,
+                    Op::Mul(..) => Arith::Mul,
+                    Op::Div(..) => Arith::Div,
+                    Op::Idiv(..) => Arith::Idiv,
+                    Op::Mod(..) => Arith::Mod,
+                    Op::Pow(..) => Arith::Pow,
+                    Op::Band(..) => Arith::Band,
+                    Op::Bor(..) => Arith::Bor,
+                    Op::Bxor(..) => Arith::Bxor,
+                    Op::Shl(..) => Arith::Shl,
+                    _ => Arith::Shr,
+                };
+
+                let destination = self.slot(result());
+                let (x, y) = (self.operand(a), self.operand(b));
+                let names = [self.name(a), self.name(b)];
+                self.emit_named(Instruction::Arith(arith, destination, x, y), names);
+            }
+
+            Op::Concat(a, b) => {
+                let destination = self.slot(result());
+                let (x, y) = (self.operand(a), self.operand(b));
+                let names = [self.name(a), self.name(b)];
+                self.emit_named(Instruction::Concat(destination, x, y), names);
+            }
+
+            Op::Eq(a, b) | Op::Ne(a, b) | Op::Lt(a, b) | Op::Le(a, b) => {
+                let comparison = match op {
+                    Op::Eq(..) => Comparison::Eq,
+                    Op::Ne(..) => Comparison::Ne,
+                    Op::Lt(..) => Comparison::Lt,
+                    _ => Comparison::Le,
+                };
+
+                let destination = self.slot(result());
+                let (x, y) = (self.operand(a), self.operand(b));
+                self.emit(Instruction::Compare(comparison, destination, x, y));
+            }
+
+            // `a > b` is `b < a`, with the operands evaluated in their written order.
+            Op::Gt(a, b) | Op::Ge(a, b) => {
+                let comparison = match op {
+                    Op::Gt(..) => Comparison::Lt,
+                    _ => Comparison::Le,
+                };
+
+                let destination = self.slot(result());
+                let (x, y) = (self.operand(a), self.operand(b));
+                self.emit(Instruction::Compare(comparison, destination, y, x));
+            }
+
+            Op::Index(table, key) => {
+                let destination = self.slot(result());
+                let (x, y) = (self.operand(table), self.operand(key));
+                let name = self.name(table);
+                self.emit_named(Instruction::Index(destination, x, y), [name, None]);
+            }
+
+            Op::Newindex(table, key, value) => {
+                let (x, y, z) = (self.operand(table), self.operand(key), self.operand(value));
+                let name = self.name(table);
+                self.emit_named(Instruction::SetIndex(x, y, z), [name, None]);
+            }
+
+            Op::ForPrep(local) | Op::ForStep(local) => {
+                let destination = self.slot(result());
+                let state = self.local_slots[local];
+                self.emit(match op {
+                    Op::ForPrep(_) => Instruction::ForPrep(destination, state),
+                    _ => Instruction::ForStep(destination, state),
+                });
+            }
+
+            Op::SetList(table, values) => {
+                let table = self.operand(table);
+                let (values, open) = self.operand_list(values);
+                self.emit(Instruction::SetList(table, values, open));
+            }
+
+            Op::Vararg => {
+                let results = self.results(result());
+                self.emit(Instruction::Varargs(results));
+            }
+
+            Op::Call(callee, args) => {
+                let results = self.results(result());
+                let function = self.operand(callee);
+                let (args, open) = self.operand_list(args);
+                let callee = self.name(callee);
+                self.emit_call(Instruction::Call(function, args, open, results), callee);
+            }
+
+            Op::TailCall(callee, args) => {
+                let function = self.operand(callee);
+                let (args, open) = self.operand_list(args);
+                let callee = self.name(callee);
+                self.emit_call(Instruction::TailCall(function, args, open), callee);
+            }
+
+            Op::Return(values) => {
+                let (values, open) = self.operand_list(values);
+                self.emit(Instruction::Return(values, open));
+            }
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.emit_named(instruction, [None, None])
+    }
+
+    /// Emits an instruction, along with the variables its first two operands were read from.
+    fn emit_named(&mut self, instruction: Instruction, names: [Option<Variable>; 2]) -> usize {
+        self.instructions.push(instruction);
+        self.operands.push(names);
+        self.positions.push(Position {
+            line: self.line,
+            callee: None,
+        });
+        self.instructions.len() - 1
+    }
+
+    /// Emits a call, along with the variable the called value was read from.
+    fn emit_call(&mut self, instruction: Instruction, callee: Option<Variable>) {
+        let pc = self.emit(instruction);
+        self.positions[pc].callee = callee;
+    }
+
+    /// Lays out the moves of the block arguments of the edges of conditional branches, each
+    /// followed by a jump to the block.
+    fn lay_out_edges(&mut self) {
+        for (target, line) in std::mem::take(&mut self.edges) {
+            self.edge_starts.push(self.instructions.len());
+            self.line = line;
+            self.move_arguments(target);
+            let pc = self.emit(Instruction::Jump(0));
+            self.jumps.push((pc, 0, Target::Block(target.block)));
+        }
+    }
+
+    fn move_arguments(&mut self, target: ir::BranchTarget) {
+        let function = self.function;
+        let graph = &function.graph;
+        for (i, &arg) in graph.list(target.args).iter().enumerate() {
+            let Some(parameter) = graph.block_argument(target.block, i as u32) else {
+                continue;
+            };
+            let parameter = self.slot(parameter);
+            let value = self.operand(arg);
+            if value != Operand::Slot(parameter) {
+                self.emit(Instruction::Move(parameter, value));
+            }
+        }
+    }
+
+    fn slot(&self, value: ValueRef) -> usize {
+        self.slots.slots[value].expect("the value has a slot")
+    }
+
+    fn results(&self, pack: ValueRef) -> Results {
+        self.slots.results[pack].unwrap_or(Results::Slots(0, 0))
+    }
+
+    fn operand(&mut self, value: ValueRef) -> Operand {
+        let function = self.function;
+        let graph = &function.graph;
+        match graph.value(value) {
+            ir::Value::Nil => self.constant(graph.value(value), Value::Nil),
+            ir::Value::Bool(b) => self.constant(graph.value(value), Value::Bool(b)),
+            ir::Value::Int(i) => self.constant(graph.value(value), Value::Int(i)),
+
+            ir::Value::Float(bits) => {
+                self.constant(graph.value(value), Value::Float(f64::from_bits(bits)))
+            }
+
+            ir::Value::String(string) => {
+                let string = self
+                    .lowering
+                    .state
+                    .heap
+                    .intern(&self.lowering.strings[string]);
+                self.constant(graph.value(value), Value::String(string))
+            }
+
+            ir::Value::BlockArgument(block, index) if block == self.function.entry => {
+                Operand::Slot(index as usize)
+            }
+
+            ir::Value::InstructionResult(_) | ir::Value::BlockArgument(..) => {
+                Operand::Slot(self.slot(value))
+            }
+
+            ir::Value::Unpack(pack, position) => match self.results(pack) {
+                Results::Slots(first, _) => Operand::Slot(first + position as usize),
+                Results::Open => unreachable!("an open pack is not unpacked"),
+            },
+
+            ir::Value::Trailing(..) => unreachable!("a trailing pack is not an operand"),
+
+            ir::Value::Not(inner) => match self.operand(inner) {
+                Operand::Slot(slot) | Operand::Truthy(slot) => Operand::Not(slot),
+                Operand::Not(slot) => Operand::Truthy(slot),
+                Operand::Constant(_) => unreachable!("`not` of a constant is folded"),
+            },
+        }
+    }
+
+    /// The operands of a list of values, and whether the list ends with an open pack.
+    fn operand_list(&mut self, list: EntityList<ValueRef>) -> (Box<[Operand]>, bool) {
+        let function = self.function;
+        let graph = &function.graph;
+        let values = graph.list(list);
+        let open = matches!(
+            values.last().map(|&value| graph.value(value)),
+            Some(ir::Value::Trailing(..))
+        );
+
+        let fixed = &values[..values.len() - open as usize];
+        let operands = fixed.iter().map(|&value| self.operand(value)).collect();
+        (operands, open)
+    }
+
+    fn constant(&mut self, key: ir::Value, value: Value) -> Operand {
+        let index = *self.constant_indices.entry(key).or_insert_with(|| {
+            self.constants.push(value);
+            self.constants.len() - 1
+        });
+        Operand::Constant(index)
+    }
+
+    /// The variable a value was read from.
+    fn name(&self, value: ValueRef) -> Option<Variable> {
+        let origin = self.function.graph.origin(value)?;
+        Some(self.lowering.variable(origin))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{assert_fails, assert_runs};
+
+    #[test]
+    fn control_flow() {
+        assert_runs("local s = 0 for i = 1, 10 do s = s + i end return s", "55");
+        assert_runs(
+            "local s = 0 for i = 10, 1, -2 do s = s + i end return s",
+            "30",
+        );
+        assert_runs(
+            "local s = 0 for i = 1.0, 2.0, 0.5 do s = s + i end return s",
+            "4.5",
+        );
+        assert_runs("local s = 0 for i = 3, 1 do s = s + 1 end return s", "0");
+        assert_runs("local n = 0 while n < 5 do n = n + 1 end return n", "5");
+        assert_runs(
+            "local n = 0 repeat local m = n n = n + 1 until m >= 3 return n",
+            "4",
+        );
+        assert_runs(
+            "if false then return 'a' elseif nil then return 'b' else return 'c' end",
+            "c",
+        );
+        assert_runs(
+            "local s = '' for i = 1, 2 do for j = 1, 3 do if j == 2 then break end s = s .. i .. j end end return s",
+            "1121",
+        );
+        assert_runs(
+            "local x = 0 ::top:: x = x + 1 if x < 3 then goto top end return x",
+            "3",
+        );
+        assert_runs("do goto done local x = 1 end ::done:: return 'ok'", "ok");
+    }
+
+    #[test]
+    fn numeric_for_stays_within_the_integers() {
+        assert_runs(
+            "local n = 0 for i = math.maxinteger - 2, math.maxinteger do n = n + 1 end return n",
+            "3",
+        );
+        assert_runs(
+            "local n = 0 for i = math.mininteger, math.mininteger + 2 do n = n + 1 end return n",
+            "3",
+        );
+        assert_runs("local s = 0 for i = 1, 3.5 do s = s + i end return s", "6");
+    }
+
+    #[test]
+    fn expressions() {
+        assert_runs(
+            "return 7 // 2 .. ' ' .. 7 % 3 .. ' ' .. (1 << 4) .. ' ' .. ~0",
+            "3 1 16 -1",
+        );
+        assert_runs("return -2 ^ 2 .. ' ' .. 2 ^ 3 ^ 2", "-4.0 512.0");
+        assert_runs("return 10 - 2 - 3 .. ' ' .. 2 * 3 + 4", "5 10");
+        assert_runs("local x = nil return tostring(x and 1 or 2)", "2");
+        assert_runs("local n = 0 local function f() n = n + 1 return true end if f() and f() or f() then end return n", "2");
+        assert_runs("local a = 1 return tostring(not a == 2)", "false");
+        assert_runs(
+            "local mt = {__lt = function() return true end, __le = function() return false end} \
+             local a, b = setmetatable({}, mt), setmetatable({}, mt) \
+             return tostring(a < b) .. tostring(a <= b) .. tostring(a > b) .. tostring(a >= b)",
+            "truefalsetruefalse",
+        );
+    }
+
+    #[test]
+    fn assignment_evaluates_before_storing() {
+        assert_runs("local a, b = 1, 2 a, b = b, a return a .. b", "21");
+        assert_runs(
+            "local t = {1, 2} local i = 1 i, t[i] = i + 1, 20 return t[1] .. t[2] .. i",
+            "2022",
+        );
+    }
+
+    #[test]
+    fn tables() {
+        assert_runs("local t = {10, 20, x = 1, 30} return #t .. t.x", "31");
+        assert_runs(
+            "local t = {[1] = 'a', [2] = 'b', n = 2} return t[1] .. t[2] .. t.n",
+            "ab2",
+        );
+        assert_runs(
+            "local o = {n = 2} function o:f(x) return self.n * x end return o:f(21)",
+            "42",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__newindex = function(t, k, v) rawset(t, k, v * 2) end}) \
+             t.x = 2 return t.x",
+            "4",
+        );
+    }
+
+    #[test]
+    fn closures_share_captured_locals() {
+        assert_runs(
+            "local function counter() local n = 0 return function() n = n + 1 return n end end \
+             local c = counter() c() c() return c()",
+            "3",
+        );
+        assert_runs(
+            "local x = 1 local function f() return x end x = 2 return f()",
+            "2",
+        );
+        assert_runs(
+            "local x = 0 local function outer() local function inner() x = x + 1 end inner() inner() end \
+             outer() return x",
+            "2",
+        );
+        assert_runs(
+            "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end \
+             return fib(20)",
+            "6765",
+        );
+    }
+
+    #[test]
+    fn closures_reach_the_locals_of_suspended_coroutines() {
+        assert_runs(
+            "local get, set \
+             local co = coroutine.wrap(function() \
+               local x = 1 \
+               get = function() return x end \
+               set = function(v) x = v end \
+               coroutine.yield() \
+               return x \
+             end) \
+             co() set(5) local seen = get() \
+             return seen .. co()",
+            "55",
+        );
+    }
+
+    #[test]
+    fn loops_capture_a_fresh_local_each_iteration() {
+        assert_runs(
+            "local fs = {} for i = 1, 3 do local j = i * 2 fs[i] = function() j = j + 1 return j end end \
+             return fs[1]() .. fs[1]() .. fs[2]()",
+            "345",
+        );
+        assert_runs(
+            "local fs = {} local i = 0 repeat local k = i fs[#fs + 1] = function() return k end i = i + 1 \
+             until k >= 2 return fs[1]() .. fs[3]()",
+            "02",
+        );
+        assert_runs(
+            "local fs = {} for i = 1, 3 do if i == 2 then goto skip end \
+             fs[#fs + 1] = function() return i end ::skip:: end return fs[1]() .. fs[2]()",
+            "13",
+        );
+    }
+
+    #[test]
+    fn to_be_closed_variables() {
+        assert_runs(
+            "local log = {} do local x <close> = setmetatable({}, {__close = function() log[#log + 1] = 'c' end}) \
+             log[#log + 1] = 'b' end return table.concat(log)",
+            "bc",
+        );
+    }
+
+    #[test]
+    fn long_brackets() {
+        assert_runs("--[[ a\nb ]] return 1", "1");
+        assert_runs("--[==[ a ]] ]=] \n ]==] return 2", "2");
+        assert_runs("return [==[a]]b]=]c]==]", "a]]b]=]c");
+        assert_runs("return [[\nx\ny]]", "x\ny");
+        assert_runs("return '\\65\\x41\\u{41}'", "AAA");
+    }
+
+    #[test]
+    fn hooks_see_each_line() {
+        assert_runs(
+            "local lines = {}\n\
+             debug.sethook(function(_, line) lines[#lines + 1] = line end, 'l')\n\
+             local a = 1\n\
+             local b = 2\n\
+             debug.sethook()\n\
+             return table.concat(lines, ',')",
+            "3,4,5",
+        );
+    }
+
+    #[test]
+    fn varargs() {
+        assert_runs("return select('#', ...)", "0");
+        assert_runs(
+            "local function f(...) return select('#', ...) end return f(nil, nil, nil)",
+            "3",
+        );
+        assert_runs(
+            "local function f(...) local a, b = ... return tostring(a) .. tostring(b) end \
+             return f(1)",
+            "1nil",
+        );
+        assert_runs(
+            "local function f(...) return ... end return #{f(1, 2, 3)}",
+            "3",
+        );
+        assert_runs(
+            "local function f(...) return #{..., 'x'} end return f(1, 2, 3)",
+            "2",
+        );
+        assert_runs(
+            "local function f() return 1, 2, 3 end return select('#', f(), f())",
+            "4",
+        );
+        assert_runs(
+            "local function f(...) return select('#', (...)) end return f(1, 2, 3)",
+            "1",
+        );
+        assert_runs(
+            "local function f(a, ...) return a + select(2, ...) end return f(1, 2, 3)",
+            "4",
+        );
+    }
+
+    #[test]
+    fn error_messages_name_the_culprit() {
+        assert_fails(
+            "local t = nil; return t.x",
+            "test:1: attempt to index a nil value (local 't')",
+        );
+        assert_fails(
+            "undefined_fn()",
+            "test:1: attempt to call a nil value (global 'undefined_fn')",
+        );
+        assert_fails(
+            "local t = {} return t.a.b",
+            "test:1: attempt to index a nil value (field 'a')",
+        );
+        assert_fails(
+            "local s = {} return s .. 'x'",
+            "test:1: attempt to concatenate a table value (local 's')",
+        );
+        assert_fails(
+            "local x = {} local function f() return x + 1 end return f()",
+            "test:1: attempt to perform arithmetic on a table value (upvalue 'x')",
+        );
+        assert_fails(
+            "local t = {} t:nomethod()",
+            "test:1: attempt to call a nil value (method 'nomethod')",
+        );
+        assert_fails(
+            "local t = {}\nlocal y =\n  t.x.y",
+            "test:3: attempt to index a nil value (field 'x')",
+        );
+        assert_fails("\n\nerror('boom')", "test:3: boom");
+        assert_fails(
+            "for i = 1, 'x' do end",
+            "test:1: 'for' limit must be a number",
+        );
+        assert_fails("for i = 1, 2, 0 do end", "test:1: 'for' step is zero");
+    }
+
+    #[test]
+    fn compile_errors() {
+        assert_fails("return 1 +", "test:1: unexpected symbol near <eof>");
+        assert_fails("x = = 1", "test:1: unexpected symbol near '='");
+        assert_fails(
+            "goto nowhere",
+            "test:1: no visible label 'nowhere' for <goto> at line 1",
+        );
+        assert_fails(
+            "local x <const> = 1; x = 2",
+            "test:1: attempt to assign to const variable 'x'",
+        );
+    }
+
+    #[test]
+    fn calls_between_compiled_functions_nest_deeply() {
+        assert_runs(
+            "local function sum(n) if n == 0 then return 0 end return n + sum(n - 1) end \
+             return sum(100000)",
+            "5000050000",
+        );
+        assert_runs(
+            "local function depth(n) \
+               local ok, d = pcall(function() return depth(n + 1) end) \
+               if ok then return d end return n \
+             end \
+             local d = depth(0) return d > 50 and d < 200",
+            "true",
+        );
+        assert_runs(
+            "local function sum(n) if n == 0 then return 0 end return n + sum(n - 1) end \
+             return coroutine.wrap(function() return sum(10000) end)()",
+            "50005000",
+        );
+        assert_runs(
+            "local function f(t) t[#t + 1] = 1 return f(t) + 1 end \
+             local ok, e = pcall(f, {}) return e",
+            "test:1: stack overflow",
+        );
+    }
+
+    #[test]
+    fn tail_calls_run_in_constant_stack_space() {
+        assert_runs(
+            "local function loop(n) if n == 0 then return 'done' end return loop(n - 1) end \
+             return loop(100000)",
+            "done",
+        );
+        assert_runs(
+            "local even, odd \
+             function even(n) if n == 0 then return true end return odd(n - 1) end \
+             function odd(n) if n == 0 then return false end return even(n - 1) end \
+             return tostring(even(100001))",
+            "false",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__call = function(self, n) return n end}) \
+             local function f(n) return t(n) end return f(7)",
+            "7",
+        );
+    }
+
+    #[test]
+    fn tail_calls_of_native_functions_keep_the_calling_frame() {
+        assert_fails("\nreturn error('boom')", "test:2: boom");
+        assert_runs(
+            "local function check(x)\n\
+               return error('bad ' .. x, 2)\n\
+             end\n\
+             local ok, e = pcall(function()\n\
+               check('x')\n\
+             end)\n\
+             return e",
+            "test:5: bad x",
+        );
+        assert_runs(
+            "local function f()\n\
+               return debug.traceback('f')\n\
+             end\n\
+             return (f())",
+            "f\nstack traceback:\n\ttest:2: in local 'f'\n\ttest:4: in main chunk",
+        );
+        assert_runs(
+            "local function f(a, b) return debug.getlocal(1, 2) end \
+             return table.concat({f(1, 'two')}, ' ')",
+            "b two",
+        );
+    }
+
+    #[test]
+    fn tail_calls_keep_what_they_must() {
+        assert_fails(
+            "return undefined_fn()",
+            "test:1: attempt to call a nil value (global 'undefined_fn')",
+        );
+        assert_runs(
+            "local log = {} \
+             local function id(x) log[#log + 1] = x return x end \
+             local function f() \
+               local c <close> = setmetatable({}, {__close = function() id('closed') end}) \
+               return id('called') \
+             end \
+             f() return table.concat(log, ',')",
+            "called,closed",
+        );
+    }
+}
@@ -1,36 +1,128 @@
 use {
-    crate::{entity_ref_type, lex::Numeral, string_pool::StringRef, vec_cell::VecCell},
+    crate::{
+        entity_ref_type,
+        lex::Numeral,
+        parse::{Error, Result},
+        string_pool::{StringPool, StringRef},
+        vec_cell::VecCell,
+    },
     ahash::AHashMap,
-    cranelift_bforest::{Set, SetForest},
-    cranelift_entity::{packed_option::PackedOption, EntityList, ListPool, PrimaryMap},
+    cranelift_entity::{
+        packed_option::PackedOption, EntityList, ListPool, PrimaryMap, SecondaryMap,
+    },
     std::{
-        borrow::BorrowMut,
         cell::{Cell, RefCell},
+        ops::Range,
+        rc::Rc,
     },
 };
 
 entity_ref_type!(BlockRef);
 entity_ref_type!(InstructionRef);
+entity_ref_type!(PrototypeRef);
+entity_ref_type!(UpvalueRef);
 entity_ref_type!(ValueRef);
 
+/// A function body, together with the prototypes of the functions nested directly within it.
+pub struct Function {
+    pub graph: Graph,
+    pub entry: BlockRef,
+
+    /// The number of fixed parameters, which are the arguments of the entry block.
+    pub parameters: u32,
+
+    /// The lines the function's definition starts and ends on, both 0 for a main chunk.
+    pub line_defined: u32,
+    pub last_line_defined: u32,
+
+    pub is_vararg: bool,
+    pub upvalues: PrimaryMap<UpvalueRef, Upvalue>,
+    pub prototypes: PrimaryMap<PrototypeRef, Function>,
+
+    /// Every local the function declares, in the order their scopes start.
+    pub locals: Vec<Declaration>,
+}
+
+impl Function {
+    fn new() -> Self {
+        let mut graph = Graph::new();
+        let entry = graph.new_block();
+
+        Self {
+            graph,
+            entry,
+            parameters: 0,
+            line_defined: 0,
+            last_line_defined: 0,
+            is_vararg: false,
+            upvalues: PrimaryMap::new(),
+            prototypes: PrimaryMap::new(),
+            locals: Vec::new(),
+        }
+    }
+}
+
+/// A local variable, as the debug library sees it.
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub name: StringRef,
+
+    /// The result of the [`Op::Local`] that declared the local.
+    pub variable: ValueRef,
+
+    /// How many locals of the function were in scope as this one was declared. No two locals
+    /// in scope at the same time share a depth, which makes it the slot the local can live in.
+    pub depth: u32,
+
+    /// The instructions, in the order they were built, over which the local is in scope.
+    pub scope: Range<u32>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Upvalue {
+    pub name: StringRef,
+    pub capture: Capture,
+}
+
+/// Where a closure finds one of its upvalues as it is created by [`Op::Closure`].
+///
+/// Closures that capture the same local share a single variable, so a local that is captured
+/// must outlive the scope that declared it. Until that scope is left the upvalue refers to the
+/// local itself ("open"); leaving the scope executes an [`Op::Close`], which moves the current
+/// value into the upvalue ("closed") for the closures that remain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Capture {
+    /// A local of the enclosing function, identified by the result of the [`Op::Local`] that
+    /// declared it.
+    Local(ValueRef),
+
+    /// One of the enclosing function's own upvalues.
+    Upvalue(UpvalueRef),
+
+    /// The environment a chunk is loaded with, which is the only upvalue of a main chunk.
+    Environment,
+}
+
 pub struct Graph {
-    block_sets: SetForest<BlockRef>,
     blocks: PrimaryMap<BlockRef, Block>,
     instructions: PrimaryMap<InstructionRef, Instruction>,
     value_dedup: AHashMap<Value, ValueRef>,
     value_lists: ListPool<ValueRef>,
     values: PrimaryMap<ValueRef, Value>,
+
+    /// The variables that values were read from, for runtime error messages.
+    origins: SecondaryMap<ValueRef, Option<Origin>>,
 }
 
 impl Graph {
     pub fn new() -> Self {
         Self {
-            block_sets: SetForest::new(),
             blocks: PrimaryMap::new(),
             instructions: PrimaryMap::new(),
             value_dedup: AHashMap::new(),
             value_lists: ListPool::new(),
             values: PrimaryMap::new(),
+            origins: SecondaryMap::new(),
         }
     }
 
@@ -45,19 +137,30 @@ impl Graph {
         self.blocks.push(Block {
             head: None.into(),
             tail: None.into(),
-            predecessors: Set::new(),
         })
     }
 
     pub fn new_instruction(&mut self, op: Op) -> InstructionRef {
         self.instructions.push(Instruction {
             op,
+            line: 0,
             prior: None.into(),
             next: None.into(),
             block: None.into(),
         })
     }
 
+    /// The line of the source that an instruction was built from, or 0 if it is unknown.
+    pub fn line(&self, instruction: InstructionRef) -> u32 {
+        self.instructions[instruction].line
+    }
+
+    /// The variable a value was read from, which runtime errors about the value name, and
+    /// which names the function a call calls in tracebacks.
+    pub fn origin(&self, value: ValueRef) -> Option<Origin> {
+        self.origins[value]
+    }
+
     pub fn new_value_list(&mut self, contents: &[ValueRef]) -> EntityList<ValueRef> {
         EntityList::from_slice(contents, &mut self.value_lists)
     }
@@ -84,28 +187,918 @@ impl Graph {
         self.insert_at_end(instruction, block);
         instruction
     }
+
+    pub fn value(&self, value: ValueRef) -> Value {
+        self.values[value]
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = (ValueRef, Value)> + '_ {
+        self.values.iter().map(|(key, &value)| (key, value))
+    }
+
+    pub fn op(&self, instruction: InstructionRef) -> Op {
+        self.instructions[instruction].op
+    }
+
+    /// The result of an instruction, if anything refers to it.
+    pub fn result(&self, instruction: InstructionRef) -> Option<ValueRef> {
+        self.find_value(Value::InstructionResult(instruction))
+    }
+
+    /// An argument of a block, if anything refers to it.
+    pub fn block_argument(&self, block: BlockRef, index: u32) -> Option<ValueRef> {
+        self.find_value(Value::BlockArgument(block, index))
+    }
+
+    /// Whether a value is a value pack, the result of an [`Op::Call`] or [`Op::Vararg`].
+    pub fn is_pack(&self, value: ValueRef) -> bool {
+        match self.values[value] {
+            Value::InstructionResult(instruction) => {
+                matches!(self.instructions[instruction].op, Op::Call(..) | Op::Vararg)
+            }
+
+            _ => false,
+        }
+    }
+
+    fn find_value(&self, value: Value) -> Option<ValueRef> {
+        self.value_dedup.get(&value).copied()
+    }
+
+    /// Every instruction, in the order they were built. The builder fills one block at a time,
+    /// so each block's instructions are contiguous in this order.
+    pub fn instructions(&self) -> impl Iterator<Item = InstructionRef> {
+        self.instructions.keys()
+    }
+
+    pub fn first_instruction(&self, block: BlockRef) -> Option<InstructionRef> {
+        self.blocks[block].head.expand()
+    }
+
+    pub fn list(&self, list: EntityList<ValueRef>) -> &[ValueRef] {
+        list.as_slice(&self.value_lists)
+    }
 }
 
-pub struct Builder<'a> {
-    graph: RefCell<&'a mut Graph>,
+/// Builds the IR of a chunk as the parser reduces it, one nested function at a time.
+pub struct Builder {
+    strings: Rc<StringPool>,
+    function: RefCell<FunctionBuilder>,
+    enclosing: RefCell<Vec<Enclosing>>,
+    env_name: StringRef,
     current_block: Cell<PackedOption<BlockRef>>,
+
+    /// The line of the source being built, which each instruction records.
+    line: Cell<u32>,
     expression_stack: VecCell<ValueRef>,
     merge_block_stack: VecCell<BlockRef>,
+
+    /// How many positional fields of each open table constructor are still on the expression
+    /// stack, innermost last.
+    field_counts: VecCell<usize>,
+
+    /// How many statements other than labels and empty statements have been built, which
+    /// tells whether a label is the last statement of its block.
+    statements: Cell<u32>,
+}
+
+struct FunctionBuilder {
+    function: Function,
+
+    /// The locals in scope, innermost last.
+    locals: Vec<Local>,
+
+    /// How many locals were in scope as each open block was entered.
+    block_starts: Vec<usize>,
+
+    /// The loops being built, innermost last.
+    loops: Vec<Loop>,
+
+    /// The `if` statements being built, innermost last.
+    conditionals: Vec<Conditional>,
+
+    /// The labels visible from the statement being built.
+    labels: Vec<Label>,
+
+    /// The `goto` statements that jump to labels yet to be built.
+    gotos: Vec<Goto>,
+}
+
+impl FunctionBuilder {
+    fn new() -> Self {
+        Self {
+            function: Function::new(),
+            locals: Vec::new(),
+            block_starts: Vec::new(),
+            loops: Vec::new(),
+            conditionals: Vec::new(),
+            labels: Vec::new(),
+            gotos: Vec::new(),
+        }
+    }
+
+    /// The first local from the given depth onwards that must be closed as its scope ends.
+    fn first_to_close(&self, depth: usize) -> Option<ValueRef> {
+        self.locals[depth..]
+            .iter()
+            .find(|local| local.needs_close)
+            .map(|local| local.slot)
+    }
+}
+
+struct Local {
+    name: StringRef,
+    slot: ValueRef,
+    attribute: Option<Attribute>,
+
+    /// Whether a nested function has captured the local or it is a to-be-closed variable, so
+    /// that it must be closed when its scope ends.
+    needs_close: bool,
+
+    /// The index of the local among [`Function::locals`].
+    declaration: usize,
+}
+
+/// A function whose body is suspended while a function nested in it is built.
+struct Enclosing {
+    builder: FunctionBuilder,
+    current_block: PackedOption<BlockRef>,
 }
 
-impl<'a> Builder<'a> {
-    pub fn new(graph: &'a mut Graph) -> Self {
+struct Loop {
+    /// The block after the loop, which `break` jumps to.
+    exit: BlockRef,
+
+    /// How many locals were in scope as the loop's body was entered.
+    locals: usize,
+    kind: LoopKind,
+}
+
+/// How a loop goes around again once its body is done.
+enum LoopKind {
+    /// By going back to the block that tests its condition.
+    While { header: BlockRef },
+
+    /// By testing its condition at the end of the body.
+    Repeat { body: BlockRef },
+
+    /// By advancing the control variable in the given local.
+    Numeric { state: ValueRef, body: BlockRef },
+
+    /// By going back to the block that calls the iterator.
+    Generic { header: BlockRef },
+}
+
+struct Conditional {
+    /// The block after the whole statement.
+    merge: BlockRef,
+
+    /// The block that runs if the condition of the branch being built is false, until the
+    /// next branch starts there.
+    otherwise: PackedOption<BlockRef>,
+}
+
+struct Label {
+    name: StringRef,
+    block: BlockRef,
+    line: u32,
+
+    /// How many locals and open blocks there were at the label.
+    locals: usize,
+    depth: usize,
+
+    /// How many statements had been built by the label, and the error to raise if a jump to
+    /// it entered the scope of a local, which is only allowed if no statement follows the
+    /// label in its block.
+    statement: u32,
+    into_scope: Option<Error>,
+}
+
+struct Goto {
+    name: StringRef,
+    line: u32,
+
+    /// The block that jumps on to the label once it is found.
+    landing: BlockRef,
+
+    /// The locals in scope at the `goto`, which a jump out of their scope must close, and how
+    /// many of them are in scope of the blocks that are still open.
+    locals: Vec<(ValueRef, bool)>,
+    visible_locals: usize,
+    depth: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Variable {
+    Local(ValueRef),
+    Upvalue(UpvalueRef),
+    Global,
+}
+
+impl Builder {
+    /// Starts building a main chunk, whose names and string literals are interned in
+    /// `strings`.
+    pub fn new(strings: Rc<StringPool>) -> Self {
+        let env_name = strings.intern(b"_ENV");
+
+        let mut main = FunctionBuilder::new();
+        main.function.is_vararg = true;
+        main.function.upvalues.push(Upvalue {
+            name: env_name,
+            capture: Capture::Environment,
+        });
+        main.block_starts.push(0);
+
+        let entry = main.function.entry;
         Self {
-            graph: RefCell::new(graph),
-            current_block: Default::default(),
+            strings,
+            function: RefCell::new(main),
+            enclosing: Default::default(),
+            env_name,
+            current_block: Cell::new(entry.into()),
+            line: Cell::new(0),
             expression_stack: Default::default(),
             merge_block_stack: Default::default(),
+            field_counts: Default::default(),
+            statements: Cell::new(0),
+        }
+    }
+
+    /// Finishes the main chunk, returning from it if the source has not.
+    pub fn finish(self) -> Result<Function> {
+        debug_assert!(self.enclosing.borrow().is_empty());
+        self.finish_function()?;
+        debug_assert_eq!(self.expression_stack.len(), 0);
+        Ok(self.function.into_inner().function)
+    }
+
+    /// Sets the line of the source that the instructions built from now on come from.
+    pub fn set_line(&self, line: u32) {
+        self.line.set(line);
+    }
+
+    /// The text of an interned name, for error messages.
+    fn text(&self, name: StringRef) -> String {
+        String::from_utf8_lossy(&self.strings[name]).into_owned()
+    }
+
+    /// Counts a statement other than a label or an empty statement as built.
+    pub fn end_statement(&self) {
+        self.statements.set(self.statements.get() + 1);
+    }
+
+    pub fn enter_function(&self) {
+        let builder = self.function.replace(FunctionBuilder::new());
+        self.enclosing.borrow_mut().push(Enclosing {
+            builder,
+            current_block: self.current_block.get(),
+        });
+
+        let entry = {
+            let function = &mut self.function.borrow_mut().function;
+            function.line_defined = self.line.get();
+            function.entry
+        };
+        self.current_block.set(entry.into());
+        self.enter_block();
+    }
+
+    /// Declares the next fixed parameter of the function being built.
+    pub fn parameter(&self, name: StringRef) {
+        let argument = {
+            let builder = &mut *self.function.borrow_mut();
+            let function = &mut builder.function;
+            let index = function.parameters;
+            function.parameters += 1;
+            function
+                .graph
+                .add_value(Value::BlockArgument(function.entry, index))
+        };
+
+        self.expression_stack.push(argument);
+        self.declare_local(name);
+    }
+
+    /// Declares the implicit `self` parameter of a method.
+    pub fn self_parameter(&self) {
+        self.parameter(self.strings.intern(b"self"));
+    }
+
+    pub fn vararg_parameter(&self) {
+        self.function.borrow_mut().function.is_vararg = true;
+    }
+
+    /// Finishes the function being built, whose `end` is on the current line, and pushes a
+    /// closure of it.
+    pub fn leave_function(&self) -> Result<()> {
+        self.function.borrow_mut().function.last_line_defined = self.line.get();
+        self.finish_function()?;
+
+        let enclosing = self.enclosing.borrow_mut().pop().unwrap();
+        let finished = self.function.replace(enclosing.builder);
+        self.current_block.set(enclosing.current_block);
+
+        let prototype = self
+            .function
+            .borrow_mut()
+            .function
+            .prototypes
+            .push(finished.function);
+
+        let closure = self.emit(Op::Closure(prototype));
+        self.expression_stack.push(closure);
+        Ok(())
+    }
+
+    /// Leaves the outermost block of the function being built and returns from it, checking
+    /// that every `goto` in it has found its label.
+    fn finish_function(&self) -> Result<()> {
+        self.leave_block()?;
+
+        if let Some(goto) = self.function.borrow().gotos.first() {
+            return Err(Error::NoVisibleLabel(self.text(goto.name), goto.line));
+        }
+
+        let values = EntityList::new();
+        self.emit(Op::Return(values));
+        Ok(())
+    }
+
+    pub fn enter_block(&self) {
+        let builder = &mut *self.function.borrow_mut();
+        builder.block_starts.push(builder.locals.len());
+    }
+
+    /// Ends the scope of the locals declared since the matching [`Builder::enter_block`],
+    /// closing them if any of them has been captured or is to be closed.
+    pub fn leave_block(&self) -> Result<()> {
+        let first_to_close = {
+            let builder = &mut *self.function.borrow_mut();
+            let start = builder.block_starts.pop().unwrap();
+            let depth = builder.block_starts.len();
+
+            let end = builder.function.graph.instructions.len() as u32;
+            for local in &builder.locals[start..] {
+                builder.function.locals[local.declaration].scope.end = end;
+            }
+
+            while let Some(label) = builder.labels.last() {
+                if label.depth <= depth {
+                    break;
+                }
+
+                let label = builder.labels.pop().unwrap();
+                if let Some(error) = label.into_scope {
+                    if self.statements.get() > label.statement {
+                        return Err(error);
+                    }
+                }
+            }
+
+            // A pending `goto` can now only find a label of an enclosing block, in which the
+            // locals of this block are not in scope.
+            for goto in builder.gotos.iter_mut().filter(|goto| goto.depth > depth) {
+                goto.depth = depth;
+                goto.visible_locals = goto.visible_locals.min(start);
+            }
+
+            let first_to_close = builder.first_to_close(start);
+            builder.locals.truncate(start);
+            first_to_close
+        };
+
+        if let Some(slot) = first_to_close {
+            self.emit(Op::Close(slot));
+        }
+
+        Ok(())
+    }
+
+    /// Declares a local in the current block, initialized with the value on top of the
+    /// expression stack.
+    pub fn declare_local(&self, name: StringRef) {
+        self.declare(name, None);
+    }
+
+    /// Declares a `<close>` local in the current block, initialized with the value on top of
+    /// the expression stack, whose `__close` metamethod runs when its scope is left, whether
+    /// normally or by an error.
+    pub fn declare_close_local(&self, name: StringRef) {
+        self.declare(name, Some(Attribute::Close));
+    }
+
+    fn declare(&self, name: StringRef, attribute: Option<Attribute>) {
+        let value = self.pop_single();
+        let slot = self.emit(Op::Local);
+        self.emit(Op::LocalSet(slot, value));
+
+        let needs_close = attribute == Some(Attribute::Close);
+        if needs_close {
+            self.emit(Op::ToBeClosed(slot, name));
+        }
+
+        let builder = &mut *self.function.borrow_mut();
+        let start = builder.function.graph.instructions.len() as u32;
+        let declaration = builder.function.locals.len();
+        builder.function.locals.push(Declaration {
+            name,
+            variable: slot,
+            depth: builder.locals.len() as u32,
+            scope: start..start,
+        });
+
+        builder.locals.push(Local {
+            name,
+            slot,
+            attribute,
+            needs_close,
+            declaration,
+        });
+    }
+
+    /// Pops `count` values and declares the locals `names` with them, adjusted to one value
+    /// each, along with the attribute named after each.
+    pub fn build_local(
+        &self,
+        names: &[(StringRef, Option<StringRef>)],
+        count: usize,
+    ) -> Result<()> {
+        let mut attributes = Vec::with_capacity(names.len());
+        for &(_, attribute) in names {
+            attributes.push(match attribute.map(|attribute| &self.strings[attribute]) {
+                None => None,
+                Some(b"const") => Some(Attribute::Const),
+                Some(b"close") => Some(Attribute::Close),
+                Some(_) => return Err(Error::UnknownAttribute(self.text(attribute.unwrap()))),
+            });
+        }
+
+        if attributes
+            .iter()
+            .filter(|&&attribute| attribute == Some(Attribute::Close))
+            .count()
+            > 1
+        {
+            return Err(Error::MultipleToBeClosed);
+        }
+
+        self.build_adjust(count, names.len());
+        let values = self.pop_list(names.len());
+
+        for ((&(name, _), attribute), value) in names.iter().zip(attributes).zip(values) {
+            self.expression_stack.push(value);
+            self.declare(name, attribute);
+        }
+
+        Ok(())
+    }
+
+    /// Declares the local of a `local function` statement, whose scope includes the function
+    /// itself, so that it can call itself recursively.
+    pub fn build_local_function(&self, name: StringRef) {
+        self.build_constant(Value::Nil);
+        self.declare_local(name);
+    }
+
+    /// Pushes a constant.
+    pub fn build_constant(&self, value: impl Into<Value>) {
+        let value = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .add_value(value.into());
+        self.expression_stack.push(value);
+    }
+
+    /// Pushes the value of the variable `name`.
+    pub fn build_name(&self, name: StringRef) {
+        let (value, origin) = match self.resolve(name) {
+            Variable::Local(slot) => (self.emit(Op::LocalGet(slot)), Origin::Local(name)),
+
+            Variable::Upvalue(upvalue) => {
+                (self.emit(Op::UpvalueGet(upvalue)), Origin::Upvalue(name))
+            }
+
+            Variable::Global => {
+                let (env, key) = self.global_key(name);
+                (self.emit(Op::Index(env, key)), Origin::Global(name))
+            }
+        };
+
+        self.set_origin(value, origin);
+        self.expression_stack.push(value);
+    }
+
+    /// Pops a key and the value below it, and pushes the value indexed with the key.
+    pub fn build_index(&self) {
+        let key = self.pop_single();
+        let table = self.pop_single();
+        let value = self.emit(Op::Index(table, key));
+
+        let key = self.function.borrow().function.graph.values[key];
+        if let Value::String(name) = key {
+            self.set_origin(value, Origin::Field(name));
+        }
+
+        self.expression_stack.push(value);
+    }
+
+    /// Pushes the value of a place.
+    pub fn build_place(&self, place: Place) {
+        match place {
+            Place::Name(name) => self.build_name(name),
+            Place::Index => self.build_index(),
+        }
+    }
+
+    /// Pops `nargs` arguments and the receiver below them, and pushes the value pack of the
+    /// results of calling the receiver's method `name` with the receiver as an extra first
+    /// argument, as `receiver:name(...)` does.
+    pub fn build_method_call(&self, name: StringRef, nargs: usize) {
+        let args = self.pop_multiple(nargs);
+        let receiver = self.pop_single();
+        let key = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .add_value(Value::String(name));
+        let method = self.emit(Op::Index(receiver, key));
+        self.set_origin(method, Origin::Method(name));
+
+        let args = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .new_value_list(&[&[receiver], &args[..]].concat());
+        let results = self.emit(Op::Call(method, args));
+        self.expression_stack.push(results);
+    }
+
+    fn set_origin(&self, value: ValueRef, origin: Origin) {
+        self.function.borrow_mut().function.graph.origins[value] = Some(origin);
+    }
+
+    /// Assigns the value on top of the expression stack to the variable `name`.
+    pub fn build_assign_name(&self, name: StringRef) {
+        let value = self.pop_single();
+
+        match self.resolve(name) {
+            Variable::Local(slot) => self.emit(Op::LocalSet(slot, value)),
+            Variable::Upvalue(upvalue) => self.emit(Op::UpvalueSet(upvalue, value)),
+
+            Variable::Global => {
+                let (env, key) = self.global_key(name);
+                self.emit(Op::Newindex(env, key, value))
+            }
+        };
+    }
+
+    /// Pops `count` values, adjusted to one for each of `places`, and assigns them to the
+    /// places, whose tables and keys are below them.
+    pub fn build_assign(&self, places: &[Place], count: usize) -> Result<()> {
+        for &place in places {
+            if let Place::Name(name) = place {
+                if self.is_const(name) {
+                    return Err(Error::AssignToConst(self.text(name)));
+                }
+            }
+        }
+
+        self.build_adjust(count, places.len());
+        let values = self.pop_list(places.len());
+
+        for (&place, value) in places.iter().zip(values).rev() {
+            match place {
+                Place::Name(name) => {
+                    self.expression_stack.push(value);
+                    self.build_assign_name(name);
+                }
+
+                Place::Index => {
+                    let key = self.pop_single();
+                    let table = self.pop_single();
+                    self.emit(Op::Newindex(table, key, value));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the variable `name` refers to is a local declared `<const>` or `<close>`.
+    fn is_const(&self, name: StringRef) -> bool {
+        let function = self.function.borrow();
+        let enclosing = self.enclosing.borrow();
+        let scopes = std::iter::once(&*function)
+            .chain(enclosing.iter().rev().map(|enclosing| &enclosing.builder));
+
+        for scope in scopes {
+            if let Some(local) = scope.locals.iter().rev().find(|local| local.name == name) {
+                return local.attribute.is_some();
+            }
+        }
+
+        false
+    }
+
+    /// Pops the value on top of the expression stack, which a call statement does not use.
+    pub fn build_discard(&self) {
+        self.expression_stack.pop().unwrap();
+    }
+
+    /// Reads the environment a global lives in, returning it along with the key of the
+    /// global.
+    fn global_key(&self, name: StringRef) -> (ValueRef, ValueRef) {
+        let env = match self.resolve(self.env_name) {
+            Variable::Local(slot) => self.emit(Op::LocalGet(slot)),
+            Variable::Upvalue(upvalue) => self.emit(Op::UpvalueGet(upvalue)),
+            Variable::Global => unreachable!("the environment is always in scope"),
+        };
+
+        let key = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .add_value(Value::String(name));
+
+        (env, key)
+    }
+
+    /// Finds the variable `name` refers to, capturing it as an upvalue of every function
+    /// between the one being built and the one that declared it.
+    fn resolve(&self, name: StringRef) -> Variable {
+        resolve(
+            &mut self.function.borrow_mut(),
+            &mut self.enclosing.borrow_mut(),
+            name,
+        )
+    }
+
+    /// Appends an instruction to the current block, returning its result.
+    fn emit(&self, op: Op) -> ValueRef {
+        let graph = &mut self.function.borrow_mut().function.graph;
+        let instruction = graph.append_instruction(self.current_block.get().unwrap(), op);
+        graph.instructions[instruction].line = self.line.get();
+        graph.add_value(Value::InstructionResult(instruction))
+    }
+
+    fn new_block(&self) -> BlockRef {
+        self.function.borrow_mut().function.graph.new_block()
+    }
+
+    /// Ends the current block with a jump to `target`, and continues in `next`.
+    fn branch(&self, target: BlockRef, next: BlockRef) {
+        self.emit(Op::Branch(BranchTarget::new(target, EntityList::new())));
+        self.current_block.set(next.into());
+    }
+
+    /// Ends the current block with a jump to `then` if `condition` is true and to `otherwise`
+    /// if it is not, and continues in `next`.
+    fn branch_if(&self, condition: ValueRef, then: BlockRef, otherwise: BlockRef, next: BlockRef) {
+        self.emit(Op::BranchIf(
+            condition,
+            BranchTarget::new(then, EntityList::new()),
+            BranchTarget::new(otherwise, EntityList::new()),
+        ));
+        self.current_block.set(next.into());
+    }
+
+    /// Pushes the value pack of the extra arguments of the function being built.
+    pub fn build_vararg(&self) -> Result<()> {
+        if !self.function.borrow().function.is_vararg {
+            return Err(Error::VarargOutsideVarargFunction);
+        }
+
+        let pack = self.emit(Op::Vararg);
+        self.expression_stack.push(pack);
+        Ok(())
+    }
+
+    /// Truncates the expression on top of the stack to a single value, as parentheses do.
+    pub fn build_parenthesized(&self) {
+        let value = self.pop_single();
+        self.expression_stack.push(value);
+    }
+
+    /// Adjusts the top `count` expressions to `wanted` values, as the values of an
+    /// assignment or local declaration are: a value pack in the last position provides as
+    /// many values as are missing, missing values are nil, and extra values are discarded
+    /// after having been evaluated.
+    pub fn build_adjust(&self, count: usize, wanted: usize) {
+        let mut values = self.pop_list(count);
+
+        if let Some(&last) = values.last() {
+            if self.is_pack(last) && wanted >= count {
+                values.pop();
+                let graph = &mut self.function.borrow_mut().function.graph;
+                for position in 0..=(wanted - count) as u32 {
+                    values.push(graph.add_value(Value::Unpack(last, position)));
+                }
+            }
+        }
+
+        let nil = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .add_value(Value::Nil);
+        values.resize(wanted, nil);
+
+        for value in values {
+            let value = self.single(value);
+            self.expression_stack.push(value);
+        }
+    }
+
+    /// Pops `nargs` arguments and the value being called, and pushes the value pack of the
+    /// results of calling it.
+    pub fn build_call(&self, nargs: usize) {
+        let args = self.pop_multiple(nargs);
+        let callee = self.pop_single();
+        let args = self
+            .function
+            .borrow_mut()
+            .function
+            .graph
+            .new_value_list(&args);
+        let results = self.emit(Op::Call(callee, args));
+        self.expression_stack.push(results);
+    }
+
+    /// Pops `count` values and returns them from the function being built.
+    ///
+    /// Returning all the results of a call, as `return f(...)` does, makes it an
+    /// [`Op::TailCall`], which runs in constant stack space however long a chain of them gets.
+    /// Within the scope of a to-be-closed variable it stays an ordinary call, since the variable
+    /// is closed after the callee returns.
+    pub fn build_return(&self, count: usize) {
+        let values = self.pop_multiple(count);
+        if !self.build_tail_call(&values) {
+            let values = self
+                .function
+                .borrow_mut()
+                .function
+                .graph
+                .new_value_list(&values);
+            self.emit(Op::Return(values));
+        }
+
+        let unreachable = self.new_block();
+        self.current_block.set(unreachable.into());
+    }
+
+    /// Turns the call whose results are the only value returned into a tail call, if it is the
+    /// last instruction built and can be one.
+    fn build_tail_call(&self, values: &[ValueRef]) -> bool {
+        let builder = &mut *self.function.borrow_mut();
+        if builder
+            .locals
+            .iter()
+            .any(|local| local.attribute == Some(Attribute::Close))
+        {
+            return false;
+        }
+
+        let graph = &mut builder.function.graph;
+        let &[value] = values else {
+            return false;
+        };
+        let Value::Trailing(pack, 0) = graph.values[value] else {
+            return false;
+        };
+        let Value::InstructionResult(call) = graph.values[pack] else {
+            return false;
+        };
+        let Op::Call(callee, args) = graph.instructions[call].op else {
+            return false;
+        };
+
+        let block = self.current_block.get().unwrap();
+        if graph.blocks[block].tail.expand() != Some(call) {
+            return false;
+        }
+
+        graph.instructions[call].op = Op::TailCall(callee, args);
+        true
+    }
+
+    /// Pushes a new, empty table, to which the fields of a table constructor are added.
+    pub fn build_table(&self) {
+        let table = self.emit(Op::Table);
+        self.expression_stack.push(table);
+        self.field_counts.push(0);
+    }
+
+    /// Counts the value on top of the expression stack as the next positional field of the
+    /// table under construction. Positional fields are stored all at once, by
+    /// [`Builder::finish_table`].
+    pub fn build_positional_field(&self) {
+        let count = self.field_counts.pop().unwrap();
+        self.field_counts.push(count + 1);
+    }
+
+    /// Pops a key and a value and stores them in the table under construction below them and
+    /// its pending positional fields.
+    pub fn build_field(&self) {
+        let value = self.pop_single();
+        let key = self.pop_single();
+
+        // Only the last positional field can expand to several values, and this one is not.
+        let count = self.field_counts.pop().unwrap();
+        let positional = self.pop_list(count);
+        let table = self.expression_stack.pop().unwrap();
+        self.emit(Op::Newindex(table, key, value));
+
+        self.expression_stack.push(table);
+        for value in positional {
+            let value = self.single(value);
+            self.expression_stack.push(value);
+        }
+        self.field_counts.push(count);
+    }
+
+    /// Stores the positional fields of the table under construction, leaving the table on
+    /// top of the expression stack.
+    pub fn finish_table(&self) {
+        let count = self.field_counts.pop().unwrap();
+        let values = self.pop_multiple(count);
+        let table = self.expression_stack.pop().unwrap();
+
+        if !values.is_empty() {
+            let values = self
+                .function
+                .borrow_mut()
+                .function
+                .graph
+                .new_value_list(&values);
+            self.emit(Op::SetList(table, values));
+        }
+
+        self.expression_stack.push(table);
+    }
+
+    /// Whether `value` is a value pack.
+    fn is_pack(&self, value: ValueRef) -> bool {
+        self.function.borrow().function.graph.is_pack(value)
+    }
+
+    /// Reduces a value pack to its first value.
+    fn single(&self, value: ValueRef) -> ValueRef {
+        if self.is_pack(value) {
+            let graph = &mut self.function.borrow_mut().function.graph;
+            graph.add_value(Value::Unpack(value, 0))
+        } else {
+            value
+        }
+    }
+
+    fn pop_single(&self) -> ValueRef {
+        let value = self.expression_stack.pop().unwrap();
+        self.single(value)
+    }
+
+    fn pop_list(&self, count: usize) -> Vec<ValueRef> {
+        let mut values: Vec<_> = (0..count)
+            .map(|_| self.expression_stack.pop().unwrap())
+            .collect();
+        values.reverse();
+        values
+    }
+
+    /// Pops a list of `count` values in which only the last one can expand to several.
+    fn pop_multiple(&self, count: usize) -> Vec<ValueRef> {
+        let mut values = self.pop_list(count);
+        let last = values.len().checked_sub(1);
+
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = if Some(i) == last && self.is_pack(*value) {
+                let graph = &mut self.function.borrow_mut().function.graph;
+                graph.add_value(Value::Trailing(*value, 0))
+            } else {
+                self.single(*value)
+            };
+        }
+
+        values
+    }
+
+    pub fn build_unary(&self, op: Unary) {
+        match op {
+            Unary::Not => self.build_not(),
+            Unary::Len => self.build_len(),
+            Unary::Unm => self.build_unm(),
+            Unary::Bnot => self.build_bnot(),
         }
     }
 
     pub fn build_not(&self) {
-        let graph = &mut *self.graph.borrow_mut();
-        let operand = self.expression_stack.pop().unwrap();
+        let operand = self.pop_single();
+        let graph = &mut self.function.borrow_mut().function.graph;
         self.expression_stack
             .push(graph.add_value(match graph.values[operand] {
                 Value::Bool(true) | Value::Int(_) | Value::Float(_) | Value::String(_) => {
@@ -119,51 +1112,101 @@ impl<'a> Builder<'a> {
     }
 
     pub fn build_len(&self) {
-        todo!()
+        let operand = self.pop_single();
+        let value = self.emit(Op::Len(operand));
+        self.expression_stack.push(value);
     }
 
-    pub fn emit_unm(&self) {
-        todo!()
+    /// Negates the value on top of the expression stack, folding the negation of a numeric
+    /// constant into the constant, which is how negative numerals are written.
+    pub fn build_unm(&self) {
+        let operand = self.pop_single();
+        let folded = match self.function.borrow().function.graph.values[operand] {
+            Value::Int(i) => Some(Value::Int(i.wrapping_neg())),
+            Value::Float(bits) => Some(Value::from(-f64::from_bits(bits))),
+            _ => None,
+        };
+
+        let value = match folded {
+            Some(folded) => self.function.borrow_mut().function.graph.add_value(folded),
+            None => self.emit(Op::Unm(operand)),
+        };
+
+        self.expression_stack.push(value);
     }
 
-    pub fn build_bnot(self) {
-        todo!()
+    pub fn build_bnot(&self) {
+        let operand = self.pop_single();
+        let value = self.emit(Op::Bnot(operand));
+        self.expression_stack.push(value);
     }
 
-    pub fn build_add(&self) {
-        todo!()
+    /// Pops the two operands of a binary operator and pushes its result.
+    pub fn build_binary(&self, op: Binary) {
+        let rhs = self.pop_single();
+        let lhs = self.pop_single();
+        let value = self.emit(op.op(lhs, rhs));
+        self.expression_stack.push(value);
     }
 
+    /// Pops the left operand of `and`, skipping the right one if it is false.
     pub fn build_partial_and(&self) {
-        let graph = &mut *self.graph.borrow_mut();
-        let lhs = self.expression_stack.pop().unwrap();
+        self.build_short_circuit(false);
+    }
+
+    /// Pops the right operand of `and` and pushes the value of the whole expression.
+    pub fn build_and(&self) {
+        self.build_merge();
+    }
+
+    /// Pops the left operand of `or`, skipping the right one if it is true.
+    pub fn build_partial_or(&self) {
+        self.build_short_circuit(true);
+    }
+
+    /// Pops the right operand of `or` and pushes the value of the whole expression.
+    pub fn build_or(&self) {
+        self.build_merge();
+    }
+
+    /// Pops the left operand of `and` or `or`, which is the value of the whole expression if
+    /// it is as truthy as `short_circuit`, and starts the block that evaluates the right
+    /// operand otherwise.
+    fn build_short_circuit(&self, short_circuit: bool) {
+        let lhs = self.pop_single();
+        let graph = &mut self.function.borrow_mut().function.graph;
         let rhs_block = graph.new_block();
         let merge_block = graph.new_block();
         let merge_args = graph.new_value_list(&[lhs]);
 
-        graph.append_instruction(
+        let rhs = BranchTarget::new(rhs_block, EntityList::new());
+        let merge = BranchTarget::new(merge_block, merge_args);
+        let (then, otherwise) = match short_circuit {
+            true => (merge, rhs),
+            false => (rhs, merge),
+        };
+
+        let instruction = graph.append_instruction(
             self.current_block.get().unwrap(),
-            Op::BranchIf(
-                lhs,
-                BranchTarget::new(rhs_block, EntityList::new()),
-                BranchTarget::new(merge_block, merge_args),
-            ),
+            Op::BranchIf(lhs, then, otherwise),
         );
+        graph.instructions[instruction].line = self.line.get();
 
         self.current_block.set(rhs_block.into());
         self.merge_block_stack.push(merge_block);
     }
 
-    pub fn build_and(&self) {
-        let graph = &mut *self.graph.borrow_mut();
-        let rhs = self.expression_stack.pop().unwrap();
+    fn build_merge(&self) {
+        let rhs = self.pop_single();
+        let graph = &mut self.function.borrow_mut().function.graph;
         let merge_block = self.merge_block_stack.pop().unwrap();
         let merge_args = graph.new_value_list(&[rhs]);
 
-        graph.append_instruction(
+        let instruction = graph.append_instruction(
             self.current_block.get().unwrap(),
             Op::Branch(BranchTarget::new(merge_block, merge_args)),
         );
+        graph.instructions[instruction].line = self.line.get();
 
         self.current_block.set(merge_block.into());
 
@@ -171,21 +1214,432 @@ impl<'a> Builder<'a> {
         self.expression_stack.push(merged_value);
     }
 
-    pub fn build_or_incomplete(&self) {
-        todo!()
+    /// Starts an `if` statement, whose first condition comes next.
+    pub fn build_if(&self) {
+        let merge = self.new_block();
+        self.function.borrow_mut().conditionals.push(Conditional {
+            merge,
+            otherwise: None.into(),
+        });
     }
 
-    pub fn build_or(&self) {}
+    /// Pops the condition of a branch of an `if` statement and starts the block it guards.
+    pub fn build_then(&self) {
+        let condition = self.pop_single();
+        let then = self.new_block();
+        let otherwise = self.new_block();
+        self.branch_if(condition, then, otherwise, then);
+
+        let builder = &mut *self.function.borrow_mut();
+        builder.conditionals.last_mut().unwrap().otherwise = otherwise.into();
+        builder.block_starts.push(builder.locals.len());
+    }
+
+    /// Ends a branch of an `if` statement, continuing where its condition is false with the
+    /// condition of an `elseif`.
+    pub fn build_else_if(&self) -> Result<()> {
+        self.leave_block()?;
+
+        let (merge, otherwise) = {
+            let builder = &mut *self.function.borrow_mut();
+            let conditional = builder.conditionals.last_mut().unwrap();
+            (conditional.merge, conditional.otherwise.take().unwrap())
+        };
+
+        self.branch(merge, otherwise);
+        Ok(())
+    }
+
+    /// Ends a branch of an `if` statement, starting the `else` block where its condition is
+    /// false.
+    pub fn build_else(&self) -> Result<()> {
+        self.build_else_if()?;
+        self.enter_block();
+        Ok(())
+    }
+
+    /// Ends an `if` statement.
+    pub fn build_end_if(&self) -> Result<()> {
+        self.leave_block()?;
+
+        let conditional = self.function.borrow_mut().conditionals.pop().unwrap();
+        match conditional.otherwise.expand() {
+            Some(otherwise) => {
+                self.branch(conditional.merge, otherwise);
+                self.branch(conditional.merge, conditional.merge);
+            }
+
+            None => self.branch(conditional.merge, conditional.merge),
+        }
+
+        Ok(())
+    }
+
+    /// Starts a `while` loop, whose condition comes next.
+    pub fn build_while(&self) {
+        let header = self.new_block();
+        let exit = self.new_block();
+        self.branch(header, header);
+
+        let builder = &mut *self.function.borrow_mut();
+        builder.loops.push(Loop {
+            exit,
+            locals: builder.locals.len(),
+            kind: LoopKind::While { header },
+        });
+    }
+
+    /// Pops the condition of a `while` loop and starts its body.
+    pub fn build_while_body(&self) {
+        let condition = self.pop_single();
+        let body = self.new_block();
+        let exit = self.function.borrow().loops.last().unwrap().exit;
+        self.branch_if(condition, body, exit, body);
+        self.enter_block();
+    }
+
+    /// Starts a `repeat` loop, whose body comes next.
+    pub fn build_repeat(&self) {
+        let body = self.new_block();
+        let exit = self.new_block();
+        self.branch(body, body);
+
+        let builder = &mut *self.function.borrow_mut();
+        builder.loops.push(Loop {
+            exit,
+            locals: builder.locals.len(),
+            kind: LoopKind::Repeat { body },
+        });
+        builder.block_starts.push(builder.locals.len());
+    }
+
+    /// Pops the condition of a `repeat` loop, which is evaluated in the scope of its body, and
+    /// ends the loop.
+    pub fn build_until(&self) -> Result<()> {
+        let condition = self.pop_single();
+        self.leave_block()?;
+
+        let repeat = self.function.borrow_mut().loops.pop().unwrap();
+        let LoopKind::Repeat { body } = repeat.kind else {
+            unreachable!("the innermost loop is a repeat loop");
+        };
+        self.branch_if(condition, repeat.exit, body, repeat.exit);
+        Ok(())
+    }
+
+    /// Pops the initial value, limit and step of a numeric `for` loop and starts its body,
+    /// whose loop variable is `name`.
+    pub fn build_numeric_for(&self, name: StringRef) {
+        let values = self.pop_list(3);
+        let state_name = self.strings.intern(b"(for state)");
+
+        // The loop's state lives in three hidden locals, whose scope is the whole loop.
+        self.enter_block();
+        for value in values {
+            self.expression_stack.push(value);
+            self.declare_local(state_name);
+        }
+
+        let state = {
+            let builder = self.function.borrow();
+            builder.locals[builder.locals.len() - 3].slot
+        };
+
+        let runs = self.emit(Op::ForPrep(state));
+        let body = self.new_block();
+        let exit = self.new_block();
+        self.branch_if(runs, body, exit, body);
+
+        {
+            let builder = &mut *self.function.borrow_mut();
+            builder.loops.push(Loop {
+                exit,
+                locals: builder.locals.len(),
+                kind: LoopKind::Numeric { state, body },
+            });
+        }
+
+        self.enter_block();
+        let value = self.emit(Op::LocalGet(state));
+        self.expression_stack.push(value);
+        self.declare_local(name);
+    }
+
+    /// Pops `count` values, the iterator function, state, initial control value and closing
+    /// value of a generic `for` loop, and starts its body, whose loop variables are `names`.
+    pub fn build_generic_for(&self, names: &[StringRef], count: usize) {
+        self.build_adjust(count, 4);
+        let values = self.pop_list(4);
+        let state_name = self.strings.intern(b"(for state)");
+
+        // The loop's state lives in four hidden locals, whose scope is the whole loop, and the
+        // last of which is closed as the loop ends.
+        self.enter_block();
+        for (i, value) in values.into_iter().enumerate() {
+            self.expression_stack.push(value);
+            match i {
+                3 => self.declare_close_local(state_name),
+                _ => self.declare_local(state_name),
+            }
+        }
+
+        let state: Vec<_> = {
+            let builder = self.function.borrow();
+            builder.locals[builder.locals.len() - 4..]
+                .iter()
+                .map(|local| local.slot)
+                .collect()
+        };
+
+        let header = self.new_block();
+        let body = self.new_block();
+        let exit = self.new_block();
+        self.branch(header, header);
+
+        let function = self.emit(Op::LocalGet(state[0]));
+        let invariant = self.emit(Op::LocalGet(state[1]));
+        let control = self.emit(Op::LocalGet(state[2]));
+        let (results, nil) = {
+            let graph = &mut self.function.borrow_mut().function.graph;
+            (
+                graph.new_value_list(&[invariant, control]),
+                graph.add_value(Value::Nil),
+            )
+        };
+        let results = self.emit(Op::Call(function, results));
+
+        let values: Vec<_> = {
+            let graph = &mut self.function.borrow_mut().function.graph;
+            (0..names.len() as u32)
+                .map(|i| graph.add_value(Value::Unpack(results, i)))
+                .collect()
+        };
+
+        let done = self.emit(Op::Eq(values[0], nil));
+        self.branch_if(done, exit, body, body);
+        self.emit(Op::LocalSet(state[2], values[0]));
+
+        {
+            let builder = &mut *self.function.borrow_mut();
+            builder.loops.push(Loop {
+                exit,
+                locals: builder.locals.len(),
+                kind: LoopKind::Generic { header },
+            });
+        }
+
+        self.enter_block();
+        for (&name, value) in names.iter().zip(values) {
+            self.expression_stack.push(value);
+            self.declare_local(name);
+        }
+    }
+
+    /// Ends the body of a `while` or `for` loop, and the loop.
+    pub fn build_loop_end(&self) -> Result<()> {
+        self.leave_block()?;
+
+        let ended = self.function.borrow_mut().loops.pop().unwrap();
+        match ended.kind {
+            LoopKind::While { header } => self.branch(header, ended.exit),
+
+            LoopKind::Numeric { state, body } => {
+                let again = self.emit(Op::ForStep(state));
+                self.branch_if(again, body, ended.exit, ended.exit);
+                self.leave_block()?;
+            }
+
+            LoopKind::Generic { header } => {
+                self.branch(header, ended.exit);
+                self.leave_block()?;
+            }
+
+            LoopKind::Repeat { .. } => unreachable!("a repeat loop ends with build_until"),
+        }
+
+        Ok(())
+    }
+
+    /// Leaves the innermost loop, closing the locals of its body.
+    pub fn build_break(&self) -> Result<()> {
+        let (exit, first_to_close) = {
+            let builder = self.function.borrow();
+            let Some(innermost) = builder.loops.last() else {
+                return Err(Error::BreakOutsideLoop(self.line.get()));
+            };
+            (innermost.exit, builder.first_to_close(innermost.locals))
+        };
+
+        if let Some(slot) = first_to_close {
+            self.emit(Op::Close(slot));
+        }
+
+        let unreachable = self.new_block();
+        self.branch(exit, unreachable);
+        Ok(())
+    }
+
+    /// Jumps to the label `name`, which is either visible already or is to be found later in
+    /// the current block or one enclosing it.
+    pub fn build_goto(&self, name: StringRef) -> Result<()> {
+        let label = {
+            let builder = self.function.borrow();
+            builder
+                .labels
+                .iter()
+                .rev()
+                .find(|label| label.name == name)
+                .map(|label| (label.block, builder.first_to_close(label.locals)))
+        };
+
+        if let Some((block, first_to_close)) = label {
+            if let Some(slot) = first_to_close {
+                self.emit(Op::Close(slot));
+            }
+
+            let unreachable = self.new_block();
+            self.branch(block, unreachable);
+            return Ok(());
+        }
+
+        let landing = self.new_block();
+        let unreachable = self.new_block();
+        self.branch(landing, unreachable);
+
+        let builder = &mut *self.function.borrow_mut();
+        let goto = Goto {
+            name,
+            line: self.line.get(),
+            landing,
+            locals: builder
+                .locals
+                .iter()
+                .map(|local| (local.slot, local.needs_close))
+                .collect(),
+            visible_locals: builder.locals.len(),
+            depth: builder.block_starts.len(),
+        };
+        builder.gotos.push(goto);
+        Ok(())
+    }
+
+    /// Places the label `name`, to which the pending `goto` statements that can see it jump.
+    pub fn build_label(&self, name: StringRef) -> Result<()> {
+        if let Some(label) = self
+            .function
+            .borrow()
+            .labels
+            .iter()
+            .find(|label| label.name == name)
+        {
+            return Err(Error::DuplicateLabel(self.text(name), label.line));
+        }
+
+        let block = self.new_block();
+        self.branch(block, block);
+
+        let (locals, depth, gotos) = {
+            let builder = &mut *self.function.borrow_mut();
+            let depth = builder.block_starts.len();
+            let (gotos, pending) = builder
+                .gotos
+                .drain(..)
+                .partition(|goto| goto.name == name && goto.depth == depth);
+            builder.gotos = pending;
+            (builder.locals.len(), depth, gotos)
+        };
+
+        let mut into_scope = None;
+        for goto in gotos {
+            if goto.visible_locals < locals && into_scope.is_none() {
+                let local = self.function.borrow().locals[goto.visible_locals].name;
+                into_scope = Some(Error::JumpIntoScope(
+                    self.text(name),
+                    goto.line,
+                    self.text(local),
+                ));
+            }
+
+            self.current_block.set(goto.landing.into());
+            // The locals that are in scope at the `goto` but not at the label are closed.
+            let first_to_close = goto.locals[goto.visible_locals..]
+                .iter()
+                .find(|(_, needs_close)| *needs_close);
+            if let Some(&(slot, _)) = first_to_close {
+                self.emit(Op::Close(slot));
+            }
+            self.branch(block, block);
+        }
+
+        self.function.borrow_mut().labels.push(Label {
+            name,
+            block,
+            line: self.line.get(),
+            locals,
+            depth,
+            statement: self.statements.get(),
+            into_scope,
+        });
+
+        Ok(())
+    }
+}
+
+fn resolve(
+    function: &mut FunctionBuilder,
+    enclosing: &mut [Enclosing],
+    name: StringRef,
+) -> Variable {
+    if let Some(local) = function
+        .locals
+        .iter()
+        .rev()
+        .find(|local| local.name == name)
+    {
+        return Variable::Local(local.slot);
+    }
+
+    if let Some((upvalue, _)) = function
+        .function
+        .upvalues
+        .iter()
+        .find(|(_, upvalue)| upvalue.name == name)
+    {
+        return Variable::Upvalue(upvalue);
+    }
+
+    let Some((parent, rest)) = enclosing.split_last_mut() else {
+        return Variable::Global;
+    };
+
+    let capture = match resolve(&mut parent.builder, rest, name) {
+        Variable::Local(slot) => {
+            let local = parent
+                .builder
+                .locals
+                .iter_mut()
+                .rev()
+                .find(|local| local.slot == slot)
+                .unwrap();
+            local.needs_close = true;
+            Capture::Local(slot)
+        }
+
+        Variable::Upvalue(upvalue) => Capture::Upvalue(upvalue),
+        Variable::Global => return Variable::Global,
+    };
+
+    Variable::Upvalue(function.function.upvalues.push(Upvalue { name, capture }))
 }
 
 pub struct Block {
     head: PackedOption<InstructionRef>,
     tail: PackedOption<InstructionRef>,
-    predecessors: Set<BlockRef>,
 }
 
 pub struct Instruction {
     op: Op,
+    line: u32,
     prior: PackedOption<InstructionRef>,
     next: PackedOption<InstructionRef>,
     block: PackedOption<BlockRef>,
@@ -200,10 +1654,25 @@ pub enum Value {
     String(StringRef),
     InstructionResult(InstructionRef),
     BlockArgument(BlockRef, u32),
+
+    /// The value at the given position, counting from 0, of a value pack, or nil if the pack
+    /// is shorter than that.
+    ///
+    /// A value pack is the result of an [`Op::Call`] or [`Op::Vararg`], which can be any
+    /// number of values. Wherever a single value is expected, a pack stands for its first
+    /// value, `Unpack(pack, 0)`.
     Unpack(ValueRef, u32),
+
+    /// Every value of a value pack from the given position onwards, which may be none.
+    ///
+    /// This can only be the last element of the argument list of a call, the value list of a
+    /// return or the values of an [`Op::SetList`], which are the only places an expression
+    /// can expand to several values. Being of unknown length, these are the only lists that
+    /// need their length determined at runtime; every other list has a length known at
+    /// compile time, so calls that do not end in `Trailing` pass exactly as many arguments as
+    /// they list and ask for exactly as many results as they use.
     Trailing(ValueRef, u32),
     Not(ValueRef),
-    CoerceBool(ValueRef),
 }
 
 impl From<bool> for Value {
@@ -245,10 +1714,20 @@ impl From<Numeral> for Value {
     }
 }
 
+/// Where a value was read from, as name resolution and indexing with a constant key know it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Origin {
+    Global(StringRef),
+    Local(StringRef),
+    Upvalue(StringRef),
+    Field(StringRef),
+    Method(StringRef),
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct BranchTarget {
-    block: BlockRef,
-    args: EntityList<ValueRef>,
+    pub block: BlockRef,
+    pub args: EntityList<ValueRef>,
 }
 
 impl BranchTarget {
@@ -257,6 +1736,83 @@ impl BranchTarget {
     }
 }
 
+/// The operators of binary expressions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Binary {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Idiv,
+    Mod,
+    Pow,
+    Band,
+    Bor,
+    Bxor,
+    Shl,
+    Shr,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Binary {
+    fn op(self, lhs: ValueRef, rhs: ValueRef) -> Op {
+        match self {
+            Self::Add => Op::Add(lhs, rhs),
+            Self::Sub => Op::Sub(lhs, rhs),
+            Self::Mul => Op::Mul(lhs, rhs),
+            Self::Div => Op::Div(lhs, rhs),
+            Self::Idiv => Op::Idiv(lhs, rhs),
+            Self::Mod => Op::Mod(lhs, rhs),
+            Self::Pow => Op::Pow(lhs, rhs),
+            Self::Band => Op::Band(lhs, rhs),
+            Self::Bor => Op::Bor(lhs, rhs),
+            Self::Bxor => Op::Bxor(lhs, rhs),
+            Self::Shl => Op::Shl(lhs, rhs),
+            Self::Shr => Op::Shr(lhs, rhs),
+            Self::Concat => Op::Concat(lhs, rhs),
+            Self::Eq => Op::Eq(lhs, rhs),
+            Self::Ne => Op::Ne(lhs, rhs),
+            Self::Lt => Op::Lt(lhs, rhs),
+            Self::Le => Op::Le(lhs, rhs),
+            Self::Gt => Op::Gt(lhs, rhs),
+            Self::Ge => Op::Ge(lhs, rhs),
+        }
+    }
+}
+
+/// The operators of unary expressions.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unary {
+    Not,
+    Len,
+    Unm,
+    Bnot,
+}
+
+/// Something that can be assigned to, whose parts are on the expression stack: nothing for a
+/// name, and a table and a key for an index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Place {
+    Name(StringRef),
+    Index,
+}
+
+/// The attributes a local can be declared with, as `local name <attribute>`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Attribute {
+    /// The local cannot be assigned to.
+    Const,
+
+    /// The local is constant, and is closed as its scope ends, as [`Op::ToBeClosed`] says.
+    Close,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Op {
     Table,
@@ -265,6 +1821,22 @@ pub enum Op {
     LocalGet(ValueRef),
     LocalSet(ValueRef, ValueRef),
 
+    /// Creates a closure of one of the current function's prototypes, capturing the upvalues
+    /// the prototype lists.
+    Closure(PrototypeRef),
+
+    UpvalueGet(UpvalueRef),
+    UpvalueSet(UpvalueRef, ValueRef),
+
+    /// Marks a local as a to-be-closed variable, checking that its value has a `__close`
+    /// metamethod (or is nil or false). The name is for the error message.
+    ToBeClosed(ValueRef, StringRef),
+
+    /// Closes the upvalues of the given local and of every local declared after it that is
+    /// still in scope, as their scope ends, and runs the `__close` metamethods of the
+    /// to-be-closed variables among them in reverse order.
+    Close(ValueRef),
+
     Branch(BranchTarget),
     BranchIf(ValueRef, BranchTarget, BranchTarget),
 
@@ -295,7 +1867,29 @@ pub enum Op {
 
     Newindex(ValueRef, ValueRef, ValueRef),
 
+    /// Prepares a numeric `for` loop whose control variable, limit and step are the given
+    /// local and the two declared after it, producing whether the loop runs at all. The
+    /// control variable then holds the first value of the loop variable.
+    ForPrep(ValueRef),
+
+    /// Advances a numeric `for` loop prepared by [`Op::ForPrep`] on the same local, producing
+    /// whether it runs again.
+    ForStep(ValueRef),
+
+    /// Stores values at consecutive integer keys of a table, starting at 1, for the
+    /// positional fields of a table constructor.
+    SetList(ValueRef, EntityList<ValueRef>),
+
+    /// Produces a value pack of the arguments passed to a vararg function beyond its fixed
+    /// parameters.
+    Vararg,
+
+    /// Calls a value, producing a value pack of its results.
     Call(ValueRef, EntityList<ValueRef>),
+
+    /// Calls a value and returns its results, terminating the block like [`Op::Return`]. The
+    /// calling function's frame is gone by the time the callee runs, so its upvalues are
+    /// closed first.
     TailCall(ValueRef, EntityList<ValueRef>),
 
     Return(EntityList<ValueRef>),
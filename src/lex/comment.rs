@@ -0,0 +1,56 @@
+use {
+    super::{Error, Token},
+    logos::{FilterResult, Lexer, Logos},
+};
+
+#[derive(Debug, Logos)]
+#[logos(error = Error)]
+enum SubToken {
+    #[regex(br"[^\]]+")]
+    LiteralSegment,
+
+    /// The start of what may be a closing long bracket, as in a long literal.
+    #[regex(br"\]=*")]
+    ClosingLongBracket,
+}
+
+/// Skips a comment, which is long if `--` is followed by an opening long bracket, and runs to
+/// the end of the line otherwise.
+pub fn callback<T>(lexer: &mut Lexer<Token>) -> FilterResult<T, Error> {
+    let remainder = lexer.remainder();
+    let level = remainder
+        .iter()
+        .skip(1)
+        .take_while(|&&byte| byte == b'=')
+        .count();
+
+    if remainder.first() != Some(&b'[') || remainder.get(level + 1) != Some(&b'[') {
+        let length = remainder
+            .iter()
+            .position(|&byte| byte == b'\r' || byte == b'\n')
+            .unwrap_or(remainder.len());
+        lexer.bump(length);
+        return FilterResult::Skip;
+    }
+
+    lexer.bump(level + 2);
+    let mut sub_lexer = SubToken::lexer(lexer.remainder());
+    loop {
+        match sub_lexer.next() {
+            None => return FilterResult::Error(Error::UnclosedLongComment),
+
+            Some(Err(e)) => return FilterResult::Error(e),
+
+            Some(Ok(SubToken::LiteralSegment)) => (),
+
+            Some(Ok(SubToken::ClosingLongBracket)) => {
+                if sub_lexer.span().len() == level + 1
+                    && sub_lexer.remainder().first() == Some(&b']')
+                {
+                    lexer.bump(sub_lexer.span().end + 1);
+                    return FilterResult::Skip;
+                }
+            }
+        }
+    }
+}
@@ -8,7 +8,7 @@ use {
 #[derive(Debug, Logos)]
 #[logos(error = Error)]
 enum SubToken {
-    #[regex(b".+")]
+    #[regex(br"[^\]\r\n]+")]
     LiteralSegment,
 
     #[token(b"\r")]
@@ -17,16 +17,22 @@ enum SubToken {
     #[token(b"\n\r")]
     LineEnding,
 
-    #[regex(br"\]=*\]")]
+    /// The start of what may be a closing long bracket, which it is if it is followed by
+    /// another `]` and has as many `=` as the opening one.
+    #[regex(br"\]=*")]
     ClosingLongBracket,
 }
 
 pub fn callback(lexer: &mut Lexer<Token>) -> Result<StringRef> {
-    let open_len = lexer.span().len();
+    let level = lexer.span().len() - 2;
 
-    if matches!(lexer.slice(), &[b'\n', ..]) {
-        lexer.bump(1);
-    }
+    // A line ending right after the opening bracket is not part of the string.
+    let skipped = match lexer.remainder() {
+        [b'\r', b'\n', ..] | [b'\n', b'\r', ..] => 2,
+        [b'\r' | b'\n', ..] => 1,
+        _ => 0,
+    };
+    lexer.bump(skipped);
 
     lexer.extras.string_buffer.clear();
     let mut sub_lexer = SubToken::lexer(lexer.remainder());
@@ -40,7 +46,10 @@ pub fn callback(lexer: &mut Lexer<Token>) -> Result<StringRef> {
             SubToken::LineEnding => lexer.extras.string_buffer.push(b'\n'),
 
             SubToken::ClosingLongBracket => {
-                if sub_lexer.span().len() == open_len {
+                if sub_lexer.span().len() == level + 1
+                    && sub_lexer.remainder().first() == Some(&b']')
+                {
+                    sub_lexer.bump(1);
                     break;
                 } else {
                     lexer
@@ -7,8 +7,8 @@ use {
 };
 pub use {error::Error, numeral::Numeral};
 
+mod comment;
 mod error;
-mod long_comment;
 mod long_literal;
 mod numeral;
 mod short_string;
@@ -20,10 +20,20 @@ pub struct Extras {
     string_buffer: Vec<u8>,
 }
 
+impl Extras {
+    /// The state of a lexer that interns names and string literals into `strings`.
+    pub fn new(strings: Rc<StringPool>) -> Self {
+        Self {
+            strings,
+            string_buffer: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Logos)]
-#[logos(error = Error, extras = Extras, skip br"[ \f\n\r\t\v]", skip br"--[^\r\n]*")]
+#[logos(error = Error, extras = Extras, skip br"[ \f\n\r\t\v]")]
 pub enum Token {
-    #[regex(br"--\[=*\[", long_comment::callback)]
+    #[token(b"--", comment::callback)]
     #[regex(b"[_a-zA-Z][_0-9a-zA-Z]*", |lex| lex.extras.strings.intern(lex.slice()))]
     Name(StringRef),
 
@@ -32,15 +42,10 @@ pub enum Token {
     #[regex(br"\[=*\[", long_literal::callback)]
     String(StringRef),
 
-    #[regex(b"[0-9]+", numeral::dec_int_callback)]
-    #[regex(b"0[xX][0-9a-fA-F]+", numeral::hex_int_callback)]
-    #[regex(
-        br"([0-9]+\.[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?",
-        numeral::dec_float_callback
-    )]
+    #[regex(br"([0-9]+\.?[0-9]*|\.[0-9]+)([eE][+\-]?[0-9]+)?", numeral::callback)]
     #[regex(
-        br"0[xX]([0-9a-fA-F]+\.[0-9a-fA-F]*|\.[0-9a-fA-F]+)([pP][+\-]?[0-9]+)?",
-        numeral::hex_float_callback
+        br"0[xX]([0-9a-fA-F]+\.?[0-9a-fA-F]*|\.[0-9a-fA-F]+)([pP][+\-]?[0-9]+)?",
+        numeral::callback
     )]
     Numeral(Numeral),
 
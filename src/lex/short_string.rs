@@ -1,12 +1,12 @@
 use {
     super::{Error, Result, Token},
-    crate::string_pool::StringRef,
+    crate::{string_pool::StringRef, utf8},
     lexical::{parse_integer_options, NumberFormatBuilder},
     logos::{Lexer, Logos},
 };
 
 #[derive(Debug, Logos)]
-#[logos(error = Error, skip br"\\z[ \f\n\r\t\v]+")]
+#[logos(error = Error, skip br"\\z[ \f\n\r\t\v]*")]
 enum SubToken {
     #[regex(br#"[^\\'"\r\n]+"#)]
     LiteralSegment,
@@ -122,7 +122,7 @@ fn callback(lexer: &mut Lexer<Token>, kind: Kind) -> Result<StringRef> {
 
             SubToken::HexEscape => lexer.extras.string_buffer.push(
                 lexical::parse_with_options::<_, _, HEX_ESCAPE_FORMAT>(
-                    &sub_lexer.slice()[3..5],
+                    &sub_lexer.slice()[2..4],
                     &parse_integer_options::STANDARD,
                 )
                 .unwrap(),
@@ -139,46 +139,7 @@ fn callback(lexer: &mut Lexer<Token>, kind: Kind) -> Result<StringRef> {
                 )
                 .map_err(|_| Error::InvalidUnicodeEscape)? as u32;
 
-                match scalar {
-                    0..=0x7f => lexer.extras.string_buffer.push(scalar as _),
-
-                    0x80..=0x7ff => lexer.extras.string_buffer.extend_from_slice(&[
-                        (0xc0 | (scalar >> 6)) as _,
-                        (0x80 | (scalar & 0x3f)) as _,
-                    ]),
-
-                    0x800..=0xffff => lexer.extras.string_buffer.extend_from_slice(&[
-                        (0xe0 | (scalar >> 12)) as _,
-                        (0x80 | ((scalar >> 6) & 0x3f)) as _,
-                        (0x80 | (scalar & 0x3f)) as _,
-                    ]),
-
-                    0x10000..=0x1fffff => lexer.extras.string_buffer.extend_from_slice(&[
-                        (0xf0 | (scalar >> 18)) as _,
-                        (0x80 | ((scalar >> 12) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 6) & 0x3f)) as _,
-                        (0x80 | (scalar & 0x3f)) as _,
-                    ]),
-
-                    0x200000..=0x3ffffff => lexer.extras.string_buffer.extend_from_slice(&[
-                        (0xf8 | (scalar >> 24)) as _,
-                        (0x80 | ((scalar >> 18) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 12) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 6) & 0x3f)) as _,
-                        (0x80 | (scalar & 0x3f)) as _,
-                    ]),
-
-                    0x4000000..=0x7fffffff => lexer.extras.string_buffer.extend_from_slice(&[
-                        (0xfc | (scalar >> 30)) as _,
-                        (0x80 | ((scalar >> 24) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 18) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 12) & 0x3f)) as _,
-                        (0x80 | ((scalar >> 6) & 0x3f)) as _,
-                        (0x80 | (scalar & 0x3f)) as _,
-                    ]),
-
-                    _ => unreachable!(),
-                }
+                utf8::encode(&mut lexer.extras.string_buffer, scalar);
             }
         }
     };
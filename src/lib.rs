@@ -0,0 +1,31 @@
+//! A Lua implementation, and an API for embedding it.
+
+pub use {
+    codegen::compile,
+    lua::{
+        AnyUserData, FromLua, FromLuaMulti, Function, IntoLua, IntoLuaMulti, Lua, Table, UserData,
+        UserDataRegistry, Variadic,
+    },
+    runtime::{Error, Metamethod, Result, Value},
+    satin_derive::{FromLua, IntoLua},
+};
+
+#[cfg(feature = "serde")]
+pub use lua::{DeserializeOptions, Holes, Serde, SerializeOptions};
+
+// The derive macros name this crate by its path, which it must also have within itself.
+extern crate self as satin;
+
+mod codegen;
+mod entity;
+mod ir;
+mod lex;
+mod lua;
+mod number;
+mod parse;
+pub mod runtime;
+mod string_pool;
+#[cfg(test)]
+mod testing;
+mod utf8;
+mod vec_cell;
@@ -0,0 +1,688 @@
+//! Conversions between Rust and Lua values.
+//!
+//! Conversions from Lua follow the coercions of the reference implementation's `lua_to*`
+//! functions: strings holding numerals convert to numbers and numbers to strings, and every
+//! value converts to a boolean by its truthiness.
+//!
+//! Tuples convert to and from multiple values, the last element taking all the values that
+//! remain, so that a [`Variadic`] can end one. `Option` converts to and from nil when it is
+//! `None`, sequences to and from tables with keys from 1 up, and maps to and from tables.
+
+use {
+    super::{Function, Lua, Table},
+    crate::runtime::{float_to_int, Error, Result, TableRef, Value},
+    std::{
+        collections::{BTreeMap, HashMap},
+        hash::{BuildHasher, Hash},
+        ops::{Deref, DerefMut},
+    },
+};
+
+pub trait IntoLua {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value>;
+}
+
+pub trait FromLua: Sized {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self>;
+}
+
+/// Any number of values, as passed to and returned from functions.
+pub trait IntoLuaMulti {
+    /// Pushes the values onto the stack, each as soon as it is converted, so that the
+    /// collector sees those converted so far while the rest are. Returns how many there are.
+    ///
+    /// If a conversion fails, the values already pushed are left for the caller to remove.
+    fn push_into_lua(self, lua: &mut Lua) -> Result<usize>;
+}
+
+/// Any number of values, as passed to and returned from functions. Missing values are nil,
+/// and extra ones are ignored unless the type takes them.
+pub trait FromLuaMulti: Sized {
+    fn from_lua_multi(values: Vec<Value>, lua: &mut Lua) -> Result<Self>;
+}
+
+impl<T: IntoLua> IntoLuaMulti for T {
+    fn push_into_lua(self, lua: &mut Lua) -> Result<usize> {
+        let value = self.into_lua(lua)?;
+        lua.push(value);
+        Ok(1)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for T {
+    fn from_lua_multi(values: Vec<Value>, lua: &mut Lua) -> Result<Self> {
+        T::from_lua(values.first().copied().unwrap_or_default(), lua)
+    }
+}
+
+impl IntoLuaMulti for () {
+    fn push_into_lua(self, _: &mut Lua) -> Result<usize> {
+        Ok(0)
+    }
+}
+
+impl FromLuaMulti for () {
+    fn from_lua_multi(_: Vec<Value>, _: &mut Lua) -> Result<Self> {
+        Ok(())
+    }
+}
+
+/// Any number of values of one type, which as the last element of a tuple takes all the
+/// values that the elements before it leave.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> FromIterator<T> for Variadic<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<T: IntoLua> IntoLuaMulti for Variadic<T> {
+    fn push_into_lua(self, lua: &mut Lua) -> Result<usize> {
+        let count = self.0.len();
+        for item in self.0 {
+            let value = item.into_lua(lua)?;
+            lua.push(value);
+        }
+
+        Ok(count)
+    }
+}
+
+impl<T: FromLua> FromLuaMulti for Variadic<T> {
+    fn from_lua_multi(values: Vec<Value>, lua: &mut Lua) -> Result<Self> {
+        values
+            .into_iter()
+            .map(|value| T::from_lua(value, lua))
+            .collect()
+    }
+}
+
+macro_rules! tuple {
+    ($($name:ident)* ; $last:ident) => {
+        #[allow(non_snake_case, unused_mut)]
+        impl<$($name: IntoLua,)* $last: IntoLuaMulti> IntoLuaMulti for ($($name,)* $last,) {
+            fn push_into_lua(self, lua: &mut Lua) -> Result<usize> {
+                let ($($name,)* $last,) = self;
+                let mut count = 0;
+                $(
+                    let value = $name.into_lua(lua)?;
+                    lua.push(value);
+                    count += 1;
+                )*
+                Ok(count + $last.push_into_lua(lua)?)
+            }
+        }
+
+        #[allow(non_snake_case, unused_mut)]
+        impl<$($name: FromLua,)* $last: FromLuaMulti> FromLuaMulti for ($($name,)* $last,) {
+            fn from_lua_multi(values: Vec<Value>, lua: &mut Lua) -> Result<Self> {
+                let mut values = values.into_iter();
+                $(let $name = $name::from_lua(values.next().unwrap_or_default(), lua)?;)*
+                let $last = $last::from_lua_multi(values.collect(), lua)?;
+                Ok(($($name,)* $last,))
+            }
+        }
+    };
+}
+
+tuple!(; A);
+tuple!(A; B);
+tuple!(A B; C);
+tuple!(A B C; D);
+tuple!(A B C D; E);
+tuple!(A B C D E; F);
+tuple!(A B C D E F; G);
+tuple!(A B C D E F G; H);
+tuple!(A B C D E F G H; I);
+tuple!(A B C D E F G H I; J);
+tuple!(A B C D E F G H I J; K);
+tuple!(A B C D E F G H I J K; L);
+
+/// The error for a value that cannot be converted to `to`.
+pub(super) fn conversion_error(
+    lua: &Lua,
+    value: Value,
+    to: &'static str,
+    message: Option<&str>,
+) -> Error {
+    Error::FromLua {
+        from: lua.type_name(value),
+        to,
+        message: message.map(str::to_owned),
+    }
+}
+
+impl IntoLua for Value {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self)
+    }
+}
+
+impl FromLua for Value {
+    fn from_lua(value: Value, _: &mut Lua) -> Result<Self> {
+        Ok(value)
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(Value::Bool(self))
+    }
+}
+
+impl FromLua for bool {
+    fn from_lua(value: Value, _: &mut Lua) -> Result<Self> {
+        Ok(value.is_truthy())
+    }
+}
+
+macro_rules! integer {
+    ($($t:ty),*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _: &mut Lua) -> Result<Value> {
+                // Integers too large for a Lua integer are approximated by a float.
+                Ok(match i64::try_from(self) {
+                    Ok(int) => Value::Int(int),
+                    Err(_) => Value::Float(self as f64),
+                })
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+                let int = match lua.to_number(value) {
+                    Some(Value::Int(int)) => Some(int),
+                    Some(Value::Float(float)) => float_to_int(float),
+                    _ => return Err(conversion_error(lua, value, stringify!($t), None)),
+                };
+
+                match int {
+                    Some(int) => <$t>::try_from(int).map_err(|_| {
+                        conversion_error(lua, value, stringify!($t), Some("out of range"))
+                    }),
+
+                    None => Err(conversion_error(
+                        lua,
+                        value,
+                        stringify!($t),
+                        Some("no integer representation"),
+                    )),
+                }
+            }
+        }
+    )*};
+}
+
+integer!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, u128, usize);
+
+macro_rules! float {
+    ($($t:ty),*) => {$(
+        impl IntoLua for $t {
+            fn into_lua(self, _: &mut Lua) -> Result<Value> {
+                Ok(Value::Float(self.into()))
+            }
+        }
+
+        impl FromLua for $t {
+            fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+                match lua.to_number(value) {
+                    Some(Value::Int(int)) => Ok(int as $t),
+                    Some(Value::Float(float)) => Ok(float as $t),
+                    _ => Err(conversion_error(lua, value, stringify!($t), None)),
+                }
+            }
+        }
+    )*};
+}
+
+float!(f32, f64);
+
+impl IntoLua for &str {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        Ok(Value::String(lua.heap.intern(self.as_bytes())))
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        self.as_str().into_lua(lua)
+    }
+}
+
+impl FromLua for String {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        let string = match value {
+            Value::String(string) => string,
+            Value::Int(_) | Value::Float(_) => lua.tostring(value)?,
+            _ => return Err(conversion_error(lua, value, "String", None)),
+        };
+
+        String::from_utf8(lua.heap[string].to_vec())
+            .map_err(|_| conversion_error(lua, value, "String", Some("invalid UTF-8")))
+    }
+}
+
+impl IntoLua for Function {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl IntoLua for &Function {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl FromLua for Function {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        match value {
+            Value::Function(_) => Ok(Function(lua.create_ref(value))),
+            _ => Err(conversion_error(lua, value, "Function", None)),
+        }
+    }
+}
+
+impl IntoLua for Table {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl IntoLua for &Table {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl FromLua for Table {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        match value {
+            Value::Table(_) => Ok(Table(lua.create_ref(value))),
+            _ => Err(conversion_error(lua, value, "Table", None)),
+        }
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        match self {
+            Some(value) => value.into_lua(lua),
+            None => Ok(Value::Nil),
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        match value {
+            Value::Nil => Ok(None),
+            value => T::from_lua(value, lua).map(Some),
+        }
+    }
+}
+
+/// Builds a table from `fields`, which convert their keys and values with the table on the
+/// stack, where the collector sees it.
+fn new_table<F>(lua: &mut Lua, fields: F) -> Result<Value>
+where
+    F: FnOnce(&mut Lua, TableRef) -> Result<()>,
+{
+    let table = lua.heap.new_table();
+    let top = lua.top();
+    lua.push(Value::Table(table));
+    let result = fields(lua, table);
+    lua.set_top(top);
+    result.map(|()| Value::Table(table))
+}
+
+/// Reads a table with `fields`, keeping the table on the stack meanwhile, as [`new_table`]
+/// does.
+fn read_table<T, F>(lua: &mut Lua, value: Value, to: &'static str, fields: F) -> Result<T>
+where
+    F: FnOnce(&mut Lua, TableRef) -> Result<T>,
+{
+    let Value::Table(table) = value else {
+        return Err(conversion_error(lua, value, to, None));
+    };
+
+    let top = lua.top();
+    lua.push(value);
+    let result = fields(lua, table);
+    lua.set_top(top);
+    result
+}
+
+fn set_field(lua: &mut Lua, table: TableRef, key: impl IntoLua, value: impl IntoLua) -> Result<()> {
+    let key = key.into_lua(lua)?;
+    let top = lua.top();
+    lua.push(key);
+    let value = value.into_lua(lua);
+    lua.set_top(top);
+    lua.heap[table].set(key, value?)
+}
+
+/// Calls `f` with every field of a table, in traversal order.
+fn for_each_field(
+    lua: &mut Lua,
+    table: TableRef,
+    mut f: impl FnMut(&mut Lua, Value, Value) -> Result<()>,
+) -> Result<()> {
+    let mut key = Value::Nil;
+    while let Some((next, value)) = lua.heap[table].next(key)? {
+        f(lua, next, value)?;
+        key = next;
+    }
+
+    Ok(())
+}
+
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        new_table(lua, |lua, table| {
+            for (index, item) in (1..).zip(self) {
+                let value = item.into_lua(lua)?;
+                lua.heap[table].set_int(index, value);
+            }
+
+            Ok(())
+        })
+    }
+}
+
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        read_table(lua, value, "Vec", |lua, table| {
+            let len = lua.heap[table].border();
+            (1..=len)
+                .map(|index| {
+                    let value = lua.heap[table].get_int(index);
+                    T::from_lua(value, lua)
+                })
+                .collect()
+        })
+    }
+}
+
+impl<K: IntoLua, V: IntoLua, S> IntoLua for HashMap<K, V, S> {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        new_table(lua, |lua, table| {
+            self.into_iter()
+                .try_for_each(|(key, value)| set_field(lua, table, key, value))
+        })
+    }
+}
+
+impl<K, V, S> FromLua for HashMap<K, V, S>
+where
+    K: FromLua + Eq + Hash,
+    V: FromLua,
+    S: BuildHasher + Default,
+{
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        read_table(lua, value, "HashMap", |lua, table| {
+            let mut map = HashMap::default();
+            for_each_field(lua, table, |lua, key, value| {
+                map.insert(K::from_lua(key, lua)?, V::from_lua(value, lua)?);
+                Ok(())
+            })?;
+
+            Ok(map)
+        })
+    }
+}
+
+impl<K: IntoLua, V: IntoLua> IntoLua for BTreeMap<K, V> {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        new_table(lua, |lua, table| {
+            self.into_iter()
+                .try_for_each(|(key, value)| set_field(lua, table, key, value))
+        })
+    }
+}
+
+impl<K: FromLua + Ord, V: FromLua> FromLua for BTreeMap<K, V> {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        read_table(lua, value, "BTreeMap", |lua, table| {
+            let mut map = BTreeMap::new();
+            for_each_field(lua, table, |lua, key, value| {
+                map.insert(K::from_lua(key, lua)?, V::from_lua(value, lua)?);
+                Ok(())
+            })?;
+
+            Ok(map)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{FromLua, IntoLua},
+    };
+
+    /// Sets `value` as the global `v`, runs `source` and converts its result.
+    fn through_lua<T: IntoLua, R: FromLuaMulti>(value: T, source: &str) -> Result<R> {
+        let mut lua = Lua::new();
+        lua.set_global("v", value)?;
+        let function = lua.load(source, "=test")?;
+        function.call(&mut lua, ())
+    }
+
+    fn eval<R: FromLuaMulti>(source: &str) -> Result<R> {
+        let mut lua = Lua::new();
+        let function = lua.load(source, "=test")?;
+        function.call(&mut lua, ())
+    }
+
+    fn round_trip<T: IntoLua + FromLua>(value: T) -> Result<T> {
+        through_lua(value, "return v")
+    }
+
+    #[test]
+    fn numbers_convert_by_value() {
+        assert_eq!(round_trip(-5i8).unwrap(), -5);
+        assert_eq!(round_trip(u64::MAX >> 1).unwrap(), u64::MAX >> 1);
+        assert_eq!(round_trip(0.25f32).unwrap(), 0.25);
+        assert_eq!(
+            through_lua::<_, String>(u64::MAX, "return math.type(v)").unwrap(),
+            "float"
+        );
+        assert_eq!(eval::<i32>("return '10'").unwrap(), 10);
+        assert_eq!(eval::<u8>("return 2.0").unwrap(), 2);
+        assert_eq!(eval::<f64>("return ' 0x10 '").unwrap(), 16.0);
+
+        let error = eval::<i32>("return 1.5").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a number value to i32 (no integer representation)"
+        );
+        let error = eval::<u8>("return 256").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a number value to u8 (out of range)"
+        );
+        let error = eval::<i64>("return {}").unwrap_err();
+        assert_eq!(error.to_string(), "cannot convert a table value to i64");
+    }
+
+    #[test]
+    fn strings_booleans_and_options() {
+        assert_eq!(round_trip("héllo".to_owned()).unwrap(), "héllo");
+        assert_eq!(eval::<String>("return 1.5").unwrap(), "1.5");
+        let error = eval::<String>("return '\\xff'").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a string value to String (invalid UTF-8)"
+        );
+
+        assert!(!eval::<bool>("return nil").unwrap());
+        assert!(eval::<bool>("return 0").unwrap());
+        assert_eq!(round_trip(None::<i64>).unwrap(), None);
+        assert_eq!(round_trip(Some(3)).unwrap(), Some(3));
+    }
+
+    #[test]
+    fn collections_convert_to_tables() {
+        assert_eq!(round_trip(vec![1, 2, 3]).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            through_lua::<_, String>(vec!["a", "b"], "return table.concat(v, ',') .. #v").unwrap(),
+            "a,b2"
+        );
+        assert_eq!(
+            eval::<Vec<i64>>("local t = {1, 2} t[4] = 4 return t").unwrap(),
+            [1, 2]
+        );
+
+        let map = HashMap::from([("a".to_owned(), 1), ("b".to_owned(), 2)]);
+        assert_eq!(round_trip(map.clone()).unwrap(), map);
+        let map = BTreeMap::from([(1, vec![true]), (5, vec![false, true])]);
+        assert_eq!(round_trip(map.clone()).unwrap(), map);
+
+        let error = eval::<Vec<i64>>("return 'x'").unwrap_err();
+        assert_eq!(error.to_string(), "cannot convert a string value to Vec");
+    }
+
+    #[test]
+    fn tuples_and_variadics_are_multiple_values() {
+        let (a, b, c): (i64, String, Option<bool>) = eval("return 1, 'two'").unwrap();
+        assert_eq!((a, b, c), (1, "two".to_owned(), None));
+
+        let (first, rest): (i64, Variadic<i64>) = eval("return 1, 2, 3").unwrap();
+        assert_eq!((first, rest.0), (1, vec![2, 3]));
+
+        let mut lua = Lua::new();
+        let sum = lua.create_function(|_, (scale, numbers): (i64, Variadic<i64>)| {
+            Ok((scale * numbers.iter().sum::<i64>(), numbers.len()))
+        });
+        lua.set_global("sum", sum).unwrap();
+        let function = lua.load("return sum(10, 1, 2, 3)", "=test").unwrap();
+        let result: (i64, usize) = function.call(&mut lua, ()).unwrap();
+        assert_eq!(result, (60, 3));
+    }
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    struct Point {
+        x: i64,
+        #[lua(rename = "why")]
+        y: f64,
+    }
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    struct Pair(String, bool);
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    struct Meters(f64);
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    struct Nothing;
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    enum Shape {
+        Empty,
+        #[lua(rename = "dot")]
+        Dot(Point),
+        Circle {
+            radius: f64,
+        },
+        Line(i64, i64),
+    }
+
+    #[derive(Debug, PartialEq, IntoLua, FromLua)]
+    struct Bag<T> {
+        items: Vec<T>,
+        r#extra: Option<T>,
+    }
+
+    #[test]
+    fn derived_structs_convert_by_shape() {
+        let point = Point { x: 1, y: 2.5 };
+        assert_eq!(
+            through_lua::<_, String>(point, "return v.x .. ' ' .. v.why").unwrap(),
+            "1 2.5"
+        );
+        assert_eq!(
+            eval::<Point>("return {x = '3', why = 4}").unwrap(),
+            Point { x: 3, y: 4.0 }
+        );
+        assert_eq!(
+            through_lua::<_, String>(Pair("a".into(), true), "return v[1] .. tostring(v[2])")
+                .unwrap(),
+            "atrue"
+        );
+        assert_eq!(
+            through_lua::<_, String>(Meters(1.5), "return math.type(v)").unwrap(),
+            "float"
+        );
+        assert_eq!(round_trip(Nothing).unwrap(), Nothing);
+
+        let bag = Bag {
+            items: vec![1, 2],
+            r#extra: None,
+        };
+        assert_eq!(round_trip(bag).unwrap().items, [1, 2]);
+        assert_eq!(
+            eval::<Bag<String>>("return {items = {'a'}, extra = 'b'}").unwrap(),
+            Bag {
+                items: vec!["a".into()],
+                r#extra: Some("b".into()),
+            }
+        );
+    }
+
+    #[test]
+    fn derived_enums_convert_by_variant() {
+        assert_eq!(
+            through_lua::<_, String>(Shape::Empty, "return v").unwrap(),
+            "Empty"
+        );
+        assert_eq!(
+            through_lua::<_, String>(Shape::Dot(Point { x: 1, y: 2.0 }), "return v.dot.x").unwrap(),
+            "1"
+        );
+        assert_eq!(
+            through_lua::<_, String>(Shape::Line(3, 4), "return v.Line[1] + v.Line[2]").unwrap(),
+            "7"
+        );
+
+        for shape in [
+            Shape::Empty,
+            Shape::Dot(Point { x: -1, y: 0.5 }),
+            Shape::Circle { radius: 2.0 },
+            Shape::Line(1, 2),
+        ] {
+            let debug = format!("{shape:?}");
+            assert_eq!(format!("{:?}", round_trip(shape).unwrap()), debug);
+        }
+
+        let error = eval::<Shape>("return 'Square'").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a string value to Shape (no variant matches)"
+        );
+        let error = eval::<Point>("return 1").unwrap_err();
+        assert_eq!(error.to_string(), "cannot convert a number value to Point");
+        let error = eval::<Nothing>("return false").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a boolean value to Nothing"
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! The embedding API: a [`Lua`] state that Rust code loads and runs chunks in, exchanging
+//! values with it through conversions rather than through the stack.
+
+use {
+    crate::{
+        codegen::compile,
+        runtime::{
+            self, load_file, open_base, open_coroutine, open_debug, open_io, open_math, open_os,
+            open_package, open_string, open_table, open_utf8, Error, Ref, Result, State, Value,
+        },
+    },
+    std::ops::{Deref, DerefMut},
+};
+
+pub use {
+    convert::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Variadic},
+    userdata::{AnyUserData, UserData, UserDataRegistry},
+};
+
+#[cfg(feature = "serde")]
+pub use self::serde::{DeserializeOptions, Holes, Serde, SerializeOptions};
+
+mod convert;
+#[cfg(feature = "serde")]
+mod serde;
+mod userdata;
+
+/// A Lua state with the standard libraries open, which compiles chunks with [`compile`].
+///
+/// It dereferences to its [`State`], for whatever the embedding API does not cover, such as
+/// replacing the compiler with [`State::set_compiler`].
+///
+/// Errors raised in Lua reach Rust code as [`Error::Runtime`], holding their message, since
+/// the values themselves are not kept alive once they leave the state.
+#[repr(transparent)]
+pub struct Lua {
+    state: State,
+}
+
+impl Lua {
+    /// Creates a state, in a box of its own.
+    ///
+    /// A coroutine that has yielded from within native calls keeps pointers to the state on
+    /// its native stack, so the state must stay where it is for as long as any coroutine is
+    /// suspended. The box lets the `Lua` be moved around by moving the box instead.
+    pub fn new() -> Box<Self> {
+        let mut state = State::new();
+        state.set_compiler(compile);
+        open_base(&mut state);
+        open_package(&mut state);
+        open_coroutine(&mut state);
+        open_table(&mut state);
+        open_io(&mut state);
+        open_os(&mut state);
+        open_string(&mut state);
+        open_math(&mut state);
+        open_utf8(&mut state);
+        open_debug(&mut state);
+        Box::new(Self { state })
+    }
+
+    /// The state a native function is given, as a `Lua`, so that the function can use the
+    /// embedding API.
+    pub fn from_state(state: &mut State) -> &mut Self {
+        // SAFETY: `Lua` is a transparent wrapper of `State`.
+        unsafe { &mut *(state as *mut State).cast::<Self>() }
+    }
+
+    pub fn into_state(self: Box<Self>) -> Box<State> {
+        // SAFETY: `Lua` is a transparent wrapper of `State`.
+        unsafe { Box::from_raw(Box::into_raw(self).cast::<State>()) }
+    }
+
+    /// Loads a chunk, of source text or binary, without running it. `chunk_name` is what
+    /// error messages and tracebacks give as its source.
+    pub fn load(&mut self, chunk: impl AsRef<[u8]>, chunk_name: &str) -> Result<Function> {
+        let function = self.state.load(chunk.as_ref(), chunk_name, "bt", None)?;
+        Ok(Function(self.create_ref(Value::Function(function))))
+    }
+
+    /// Loads the named file, as `loadfile` does, without running it.
+    pub fn load_file(&mut self, path: &str) -> Result<Function> {
+        let function = load_file(&mut self.state, Some(path), "bt", None)?;
+        Ok(Function(self.create_ref(Value::Function(function))))
+    }
+
+    pub fn globals(&mut self) -> Table {
+        let globals = self.state.globals();
+        Table(self.create_ref(Value::Table(globals)))
+    }
+
+    pub fn global<T: FromLua>(&mut self, name: &str) -> Result<T> {
+        self.globals().get(self, name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl IntoLua) -> Result<()> {
+        self.globals().set(self, name, value)
+    }
+
+    pub fn create_table(&mut self) -> Table {
+        let table = self.heap.new_table();
+        Table(self.create_ref(Value::Table(table)))
+    }
+
+    /// Makes a Lua function of a Rust one, which receives the arguments it is called with
+    /// converted to `A` and returns its results converted from `R`.
+    pub fn create_function<A, R>(
+        &mut self,
+        function: impl Fn(&mut Lua, A) -> Result<R> + 'static,
+    ) -> Function
+    where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        let function = runtime::Function::native(move |state| {
+            let lua = Lua::from_state(state);
+            let args = (1..=lua.arg_count()).map(|n| lua.arg(n)).collect();
+            let args = A::from_lua_multi(args, lua)?;
+            function(lua, args)?.push_into_lua(lua)
+        });
+
+        let function = self.heap.new_function(function);
+        Function(self.create_ref(Value::Function(function)))
+    }
+
+    /// Converts an error that is leaving the state, replacing a raised value with its
+    /// message.
+    fn take_error(&mut self, error: Error) -> Error {
+        match error {
+            Error::Value(value) => match self.error_message(value) {
+                Ok(message) => Error::Runtime(message),
+                Err(error) => self.take_error(error),
+            },
+
+            error => error,
+        }
+    }
+
+    /// Converts the values on the stack from `top` up, then removes them.
+    fn pop_values<T: FromLuaMulti>(&mut self, top: usize) -> Result<T> {
+        // The values stay on the stack while they are converted, where the collector sees them.
+        let values = self.values_from(top).to_vec();
+        let result = T::from_lua_multi(values, self);
+        self.set_top(top);
+        result
+    }
+}
+
+impl Deref for Lua {
+    type Target = State;
+
+    fn deref(&self) -> &State {
+        &self.state
+    }
+}
+
+impl DerefMut for Lua {
+    fn deref_mut(&mut self) -> &mut State {
+        &mut self.state
+    }
+}
+
+/// A Lua function, kept alive for as long as this handle or a clone of it exists.
+#[derive(Clone, Debug)]
+pub struct Function(Ref);
+
+impl Function {
+    pub fn call<R: FromLuaMulti>(&self, lua: &mut Lua, args: impl IntoLuaMulti) -> Result<R> {
+        let top = lua.top();
+        lua.push(self.0.value());
+        let nargs = match args.push_into_lua(lua) {
+            Ok(nargs) => nargs,
+            Err(error) => {
+                lua.set_top(top);
+                return Err(error);
+            }
+        };
+
+        if let Err(error) = lua.state.call(nargs, None) {
+            return Err(lua.take_error(error));
+        }
+
+        lua.pop_values(top)
+    }
+
+    pub fn value(&self) -> Value {
+        self.0.value()
+    }
+}
+
+/// A Lua table, kept alive for as long as this handle or a clone of it exists.
+#[derive(Clone, Debug)]
+pub struct Table(Ref);
+
+impl Table {
+    /// Reads `table[key]`, honouring the `__index` metamethod.
+    pub fn get<V: FromLua>(&self, lua: &mut Lua, key: impl IntoLua) -> Result<V> {
+        let key = key.into_lua(lua)?;
+        let value = lua.index(self.0.value(), key);
+        let value = value.map_err(|error| lua.take_error(error))?;
+        V::from_lua(value, lua)
+    }
+
+    /// Assigns `table[key] = value`, honouring the `__newindex` metamethod.
+    pub fn set(&self, lua: &mut Lua, key: impl IntoLua, value: impl IntoLua) -> Result<()> {
+        let top = lua.top();
+        let key = key.into_lua(lua)?;
+        lua.push(key);
+        let value = value.into_lua(lua);
+        let result = value.and_then(|value| lua.set_index(self.0.value(), key, value));
+        lua.set_top(top);
+        result.map_err(|error| lua.take_error(error))
+    }
+
+    pub fn raw_get<V: FromLua>(&self, lua: &mut Lua, key: impl IntoLua) -> Result<V> {
+        let key = key.into_lua(lua)?;
+        let value = lua.heap[self.table()].get(key);
+        V::from_lua(value, lua)
+    }
+
+    pub fn raw_set(&self, lua: &mut Lua, key: impl IntoLua, value: impl IntoLua) -> Result<()> {
+        let top = lua.top();
+        let key = key.into_lua(lua)?;
+        lua.push(key);
+        let value = value.into_lua(lua);
+        lua.set_top(top);
+        lua.heap[self.table()].set(key, value?)
+    }
+
+    /// The length of the table as `#` gives it when there is no `__len` metamethod.
+    pub fn raw_len(&self, lua: &Lua) -> i64 {
+        lua.heap[self.table()].border()
+    }
+
+    pub fn value(&self) -> Value {
+        self.0.value()
+    }
+
+    fn table(&self) -> runtime::TableRef {
+        match self.0.value() {
+            Value::Table(table) => table,
+            _ => unreachable!("a table handle refers to a table"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::fs};
+
+    #[test]
+    fn load_compiles_chunks() {
+        let mut lua = Lua::new();
+        let add = lua.load("local a, b = ... return a + b", "=add").unwrap();
+        assert_eq!(add.call::<i64>(&mut lua, (1, 2)).unwrap(), 3);
+        assert_eq!(add.call::<f64>(&mut lua, (1, 0.5)).unwrap(), 1.5);
+
+        let error = lua.load("return +", "=bad").unwrap_err();
+        assert!(matches!(error, Error::Syntax(_)), "{error}");
+    }
+
+    #[test]
+    fn chunks_share_globals_with_rust() {
+        let mut lua = Lua::new();
+        lua.set_global("x", 20).unwrap();
+        let double = lua.create_function(|_, n: i64| Ok(n * 2));
+        lua.set_global("double", double).unwrap();
+
+        let chunk = lua.load("y = double(x) + 2", "=chunk").unwrap();
+        chunk.call::<()>(&mut lua, ()).unwrap();
+        assert_eq!(lua.global::<i64>("y").unwrap(), 42);
+    }
+
+    #[test]
+    fn errors_leave_the_state_as_messages() {
+        let mut lua = Lua::new();
+        let chunk = lua.load("error('boom')", "=chunk").unwrap();
+        let error = chunk.call::<()>(&mut lua, ()).unwrap_err();
+        assert!(matches!(&error, Error::Runtime(message) if message == "chunk:1: boom"));
+    }
+
+    #[test]
+    fn states_move_while_coroutines_are_suspended() {
+        let mut lua = Lua::new();
+        let chunk = lua
+            .load(
+                "local co = coroutine.wrap(function() \
+                   for i = 1, 3 do coroutine.yield(i) end \
+                 end) \
+                 return function() return co() end",
+                "=chunk",
+            )
+            .unwrap();
+        let next: Function = chunk.call(&mut lua, ()).unwrap();
+        assert_eq!(next.call::<i64>(&mut lua, ()).unwrap(), 1);
+
+        let mut moved = Box::new(lua);
+        assert_eq!(next.call::<i64>(&mut moved, ()).unwrap(), 2);
+
+        let mut states = [*moved];
+        assert_eq!(next.call::<i64>(&mut states[0], ()).unwrap(), 3);
+    }
+
+    #[test]
+    fn load_file_compiles_files() {
+        let path = std::env::temp_dir().join(format!("satin-load-file-{}.lua", std::process::id()));
+        fs::write(&path, "#!/usr/bin/env lua\nreturn ...").unwrap();
+
+        let mut lua = Lua::new();
+        let function = lua.load_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let value: String = function.unwrap().call(&mut lua, "arg").unwrap();
+        assert_eq!(value, "arg");
+    }
+}
@@ -0,0 +1,1184 @@
+//! Conversions between Lua values and any type that implements serde's `Serialize` or
+//! `Deserialize`.
+//!
+//! Structs and maps convert to and from tables keyed by their field names and keys, and
+//! sequences and tuples to and from tables with keys from 1 up. Enums convert as the derived
+//! `IntoLua` and `FromLua` convert them: a unit variant as its name, and any other as a table
+//! with a single field, the variant's name, holding its contents. `None` and unit are nil.
+//!
+//! Unlike the [`FromLua`] conversions, deserialization does not coerce strings and numbers
+//! into one another, nor other values into booleans, since the types being deserialized say
+//! what they expect. A float with an integral value does deserialize as an integer, as Lua
+//! compares them equal.
+
+use {
+    super::{FromLua, IntoLua, Lua},
+    crate::runtime::{float_to_int, Error, Result, TableRef, Value},
+    serde::{
+        de::{self, DeserializeOwned, DeserializeSeed, Expected, Unexpected, Visitor},
+        ser::{self, Serialize},
+    },
+    std::{fmt::Display, str},
+};
+
+/// How values are serialized to Lua.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SerializeOptions {
+    /// Whether floats with integral values become integers, as for values that come from a
+    /// format that does not tell them apart, such as JSON.
+    pub integral_floats: bool,
+}
+
+/// How Lua values are deserialized, where a table or number could be read more than one way.
+#[derive(Clone, Copy, Debug)]
+pub struct DeserializeOptions {
+    /// Whether tables whose keys are all positive integers are read as sequences by types
+    /// that accept anything, such as a JSON value, rather than as maps with integer keys.
+    /// Empty tables are always maps.
+    pub sequences: bool,
+
+    /// What nils in the middle of a sequence mean.
+    pub holes: Holes,
+
+    /// Whether floats with integral values, keys and values alike, are read as integers by
+    /// types that accept anything.
+    pub integral_floats: bool,
+}
+
+impl Default for DeserializeOptions {
+    fn default() -> Self {
+        Self {
+            sequences: true,
+            holes: Holes::default(),
+            integral_floats: false,
+        }
+    }
+}
+
+/// What nils in the middle of a sequence mean. Lua does not tell a nil element apart from a
+/// missing one, so that `{1, nil, 3}` may be a sequence of three elements or one of a single
+/// element followed by an unrelated field.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Holes {
+    /// A hole is an error, and a table with one is not array-like.
+    #[default]
+    Error,
+
+    /// The sequence runs up to its greatest key, with a nil element in every hole. A table is
+    /// array-like if at least half of the keys up to its greatest one are present, as it would
+    /// be for Lua to keep them in its array part.
+    Nil,
+
+    /// The sequence ends at its first hole, as `ipairs` would, ignoring any later elements.
+    Stop,
+}
+
+/// A value converted to and from Lua through serde, with the default options.
+///
+/// It lets serializable types be passed to and returned from functions, and read from
+/// globals and tables, as [`IntoLua`] and [`FromLua`] types are.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T: Serialize> IntoLua for Serde<T> {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        lua.to_value(&self.0)
+    }
+}
+
+impl<T: DeserializeOwned> FromLua for Serde<T> {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        lua.from_value(value).map(Serde)
+    }
+}
+
+impl Lua {
+    pub fn to_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<Value> {
+        self.to_value_with(value, SerializeOptions::default())
+    }
+
+    pub fn to_value_with<T>(&mut self, value: &T, options: SerializeOptions) -> Result<Value>
+    where
+        T: Serialize + ?Sized,
+    {
+        // The tables under construction are kept on the stack, where the collector sees them.
+        let top = self.top();
+        let result = value.serialize(Serializer { lua: self, options });
+        self.set_top(top);
+        result
+    }
+
+    pub fn from_value<T: DeserializeOwned>(&mut self, value: Value) -> Result<T> {
+        self.from_value_with(value, DeserializeOptions::default())
+    }
+
+    pub fn from_value_with<T>(&mut self, value: Value, options: DeserializeOptions) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let top = self.top();
+        self.push(value);
+        let result = T::deserialize(Deserializer {
+            lua: self,
+            value,
+            options,
+            tables: &mut Vec::new(),
+        });
+        self.set_top(top);
+        result
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(message: T) -> Self {
+        Error::Serde(message.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(message: T) -> Self {
+        Error::Serde(message.to_string())
+    }
+}
+
+struct Serializer<'a> {
+    lua: &'a mut Lua,
+    options: SerializeOptions,
+}
+
+impl<'a> Serializer<'a> {
+    fn table(self, variant: Option<&'static str>) -> SerializeTable<'a> {
+        let top = self.lua.top();
+        let table = self.lua.heap.new_table();
+        self.lua.push(Value::Table(table));
+        SerializeTable {
+            lua: self.lua,
+            options: self.options,
+            table,
+            top,
+            index: 1,
+            variant,
+        }
+    }
+}
+
+/// Wraps the contents of an enum variant in a table with a single field, the variant's name.
+fn variant(lua: &mut Lua, variant: &'static str, value: Value) -> Value {
+    let key = lua.heap.intern(variant.as_bytes());
+    let table = lua.heap.new_table();
+    lua.heap[table].set_str(key, value);
+    Value::Table(table)
+}
+
+impl<'a> ser::Serializer for Serializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeTable<'a>;
+    type SerializeTuple = SerializeTable<'a>;
+    type SerializeTupleStruct = SerializeTable<'a>;
+    type SerializeTupleVariant = SerializeTable<'a>;
+    type SerializeMap = SerializeTable<'a>;
+    type SerializeStruct = SerializeTable<'a>;
+    type SerializeStructVariant = SerializeTable<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value> {
+        Ok(Value::Int(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value> {
+        v.into_lua(self.lua)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value> {
+        v.into_lua(self.lua)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value> {
+        v.into_lua(self.lua)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value> {
+        Ok(match float_to_int(v) {
+            Some(int) if self.options.integral_floats => Value::Int(int),
+            _ => Value::Float(v),
+        })
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value> {
+        Ok(Value::String(self.lua.heap.intern(v)))
+    }
+
+    fn serialize_none(self) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Value> {
+        Ok(Value::Nil)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T>(self, _: &'static str, value: &T) -> Result<Value>
+    where
+        T: Serialize + ?Sized,
+    {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T>(
+        self,
+        _: &'static str,
+        _: u32,
+        name: &'static str,
+        value: &T,
+    ) -> Result<Value>
+    where
+        T: Serialize + ?Sized,
+    {
+        let top = self.lua.top();
+        let value = value.serialize(Serializer {
+            lua: &mut *self.lua,
+            options: self.options,
+        })?;
+
+        self.lua.push(value);
+        let table = variant(self.lua, name, value);
+        self.lua.set_top(top);
+        Ok(table)
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<SerializeTable<'a>> {
+        Ok(self.table(None))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(self.table(None))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(self.table(None))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SerializeTable<'a>> {
+        Ok(self.table(Some(variant)))
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<SerializeTable<'a>> {
+        Ok(self.table(None))
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<SerializeTable<'a>> {
+        Ok(self.table(None))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SerializeTable<'a>> {
+        Ok(self.table(Some(variant)))
+    }
+}
+
+/// A table under construction, which is on the stack from `top` up, followed by the key of the
+/// field being serialized if it is a map's.
+struct SerializeTable<'a> {
+    lua: &'a mut Lua,
+    options: SerializeOptions,
+    table: TableRef,
+    top: usize,
+
+    /// The index of the next element, if the table is a sequence.
+    index: i64,
+
+    /// The variant the table holds the contents of, if it does.
+    variant: Option<&'static str>,
+}
+
+impl SerializeTable<'_> {
+    fn serialize<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<Value> {
+        value.serialize(Serializer {
+            lua: &mut *self.lua,
+            options: self.options,
+        })
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let value = self.serialize(value)?;
+        self.lua.heap[self.table].set_int(self.index, value);
+        self.index += 1;
+        Ok(())
+    }
+
+    fn set_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T) -> Result<()> {
+        let value = self.serialize(value)?;
+        let key = self.lua.heap.intern(key.as_bytes());
+        self.lua.heap[self.table].set_str(key, value);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value> {
+        let table = Value::Table(self.table);
+        let value = match self.variant {
+            Some(name) => variant(self.lua, name, table),
+            None => table,
+        };
+
+        self.lua.set_top(self.top);
+        Ok(value)
+    }
+}
+
+impl ser::SerializeSeq for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeMap for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let key = self.serialize(key)?;
+        self.lua.push(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.lua.values_from(self.top + 1)[0];
+        let value = self.serialize(value)?;
+        self.lua.set_top(self.top + 1);
+        self.lua.heap[self.table].set(key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeTable<'_> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T>(&mut self, key: &'static str, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.set_field(key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        self.finish()
+    }
+}
+
+struct Deserializer<'a> {
+    lua: &'a mut Lua,
+    value: Value,
+    options: DeserializeOptions,
+
+    /// The tables being deserialized, from the outermost in, none of which the value can be
+    /// read as contents of itself.
+    tables: &'a mut Vec<TableRef>,
+}
+
+impl<'a> Deserializer<'a> {
+    fn with(&mut self, value: Value) -> Deserializer<'_> {
+        Deserializer {
+            lua: &mut *self.lua,
+            value,
+            options: self.options,
+            tables: &mut *self.tables,
+        }
+    }
+
+    /// Notes that the contents of `table` are being read, failing if the table is already
+    /// being read, as it would be forever.
+    fn enter(&mut self, table: TableRef) -> Result<()> {
+        if self.tables.contains(&table) {
+            return Err(Error::Serde(
+                "cannot deserialize a table that contains itself".to_owned(),
+            ));
+        }
+
+        self.tables.push(table);
+        Ok(())
+    }
+
+    /// The error for a value that is not of a type `expected` accepts.
+    fn invalid_type(&self, expected: &dyn Expected) -> Error {
+        let type_name = self.lua.type_name(self.value);
+        de::Error::invalid_type(Unexpected::Other(&type_name), expected)
+    }
+
+    /// How many positive integer keys a table has, the greatest of them, and whether it has
+    /// any other keys.
+    fn integer_keys(&self, table: TableRef) -> Result<(i64, i64, bool)> {
+        let table = &self.lua.heap[table];
+        let (mut count, mut max, mut others) = (0, 0, false);
+        let mut key = Value::Nil;
+        while let Some((next, _)) = table.next(key)? {
+            match next {
+                Value::Int(index) if index > 0 => {
+                    count += 1;
+                    max = max.max(index);
+                }
+
+                _ => others = true,
+            }
+
+            key = next;
+        }
+
+        Ok((count, max, others))
+    }
+
+    /// Whether a type that accepts anything reads a table as a sequence rather than a map.
+    fn is_sequence(&self, table: TableRef) -> Result<bool> {
+        let (count, max, others) = self.integer_keys(table)?;
+        Ok(self.options.sequences
+            && !others
+            && max > 0
+            && (count == max || self.options.holes == Holes::Nil && count >= max - count))
+    }
+
+    /// Reads a table as a sequence, as a type asks for one.
+    fn sequence<'de, V: Visitor<'de>>(mut self, table: TableRef, visitor: V) -> Result<V::Value> {
+        let first_hole = || (1..).find(|&i| self.lua.heap[table].get_int(i).is_nil());
+        let len = match self.options.holes {
+            Holes::Error => {
+                let (count, max, _) = self.integer_keys(table)?;
+                if count != max {
+                    let hole = first_hole().unwrap_or_default();
+                    let message = format!("hole at index {hole} of a sequence");
+                    return Err(Error::Serde(message));
+                }
+
+                max
+            }
+
+            Holes::Nil => self.integer_keys(table)?.1,
+            Holes::Stop => first_hole().unwrap_or_default() - 1,
+        };
+
+        self.enter(table)?;
+        let mut access = SeqAccess {
+            deserializer: self,
+            table,
+            index: 1,
+            len,
+        };
+
+        let result = visitor.visit_seq(&mut access);
+        access.deserializer.tables.pop();
+        result
+    }
+
+    fn map<'de, V: Visitor<'de>>(mut self, table: TableRef, visitor: V) -> Result<V::Value> {
+        self.enter(table)?;
+        let mut access = MapAccess {
+            deserializer: self,
+            table,
+            key: Value::Nil,
+            value: Value::Nil,
+        };
+
+        let result = visitor.visit_map(&mut access);
+        access.deserializer.tables.pop();
+        result
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Nil => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Int(int) => visitor.visit_i64(int),
+
+            Value::Float(float) => match float_to_int(float) {
+                Some(int) if self.options.integral_floats => visitor.visit_i64(int),
+                _ => visitor.visit_f64(float),
+            },
+
+            Value::String(string) => {
+                let bytes = &self.lua.heap[string];
+                match str::from_utf8(bytes) {
+                    Ok(string) => visitor.visit_str(string),
+                    Err(_) => visitor.visit_bytes(bytes),
+                }
+            }
+
+            Value::Table(table) if self.is_sequence(table)? => self.sequence(table, visitor),
+            Value::Table(table) => self.map(table, visitor),
+
+            Value::Function(_) | Value::Thread(_) | Value::UserData(_) => {
+                Err(self.invalid_type(&visitor))
+            }
+        }
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Float(float) => match float_to_int(float) {
+                Some(int) => visitor.visit_i64(int),
+                None => visitor.visit_f64(float),
+            },
+
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_i128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_u128<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V>(self, _: &'static str, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Table(table) => self.sequence(table, visitor),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V>(self, _: &'static str, _: usize, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.value {
+            Value::Table(table) => self.map(table, visitor),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_enum<V>(
+        mut self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let (variant, contents) = match self.value {
+            Value::String(_) => (self.value, Value::Nil),
+
+            Value::Table(table) => match self.lua.heap[table].next(Value::Nil)? {
+                Some((key @ Value::String(_), value))
+                    if self.lua.heap[table].next(key)?.is_none() =>
+                {
+                    (key, value)
+                }
+
+                _ => {
+                    return Err(Error::Serde(
+                        "expected a table with a single field, the variant".to_owned(),
+                    ))
+                }
+            },
+
+            _ => return Err(self.invalid_type(&visitor)),
+        };
+
+        visitor.visit_enum(EnumAccess {
+            deserializer: self.with(contents),
+            variant,
+        })
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool f32 f64 char str string bytes byte_buf unit unit_struct identifier
+    }
+}
+
+struct SeqAccess<'a> {
+    deserializer: Deserializer<'a>,
+    table: TableRef,
+    index: i64,
+    len: i64,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        if self.index > self.len {
+            return Ok(None);
+        }
+
+        let value = self.deserializer.lua.heap[self.table].get_int(self.index);
+        self.index += 1;
+        seed.deserialize(self.deserializer.with(value)).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        usize::try_from(self.len - self.index + 1).ok()
+    }
+}
+
+struct MapAccess<'a> {
+    deserializer: Deserializer<'a>,
+    table: TableRef,
+
+    /// The field being deserialized.
+    key: Value,
+    value: Value,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        match self.deserializer.lua.heap[self.table].next(self.key)? {
+            Some((key, value)) => {
+                (self.key, self.value) = (key, value);
+                seed.deserialize(self.deserializer.with(key)).map(Some)
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self.deserializer.with(self.value))
+    }
+}
+
+struct EnumAccess<'a> {
+    /// The deserializer of the variant's contents.
+    deserializer: Deserializer<'a>,
+    variant: Value,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = Deserializer<'a>;
+
+    fn variant_seed<V>(mut self, seed: V) -> Result<(V::Value, Deserializer<'a>)>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let variant = seed.deserialize(self.deserializer.with(self.variant))?;
+        Ok((variant, self.deserializer))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value>
+    where
+        T: DeserializeSeed<'de>,
+    {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V>(self, _: &'static [&'static str], visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        serde::{Deserialize, Serialize},
+        serde_json::{json, Value as Json},
+        std::collections::BTreeMap,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Config {
+        name: String,
+        sizes: Vec<u32>,
+        ratio: Option<f64>,
+        mode: Mode,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Off,
+        Fixed(i64),
+        Range { from: i64, to: i64 },
+    }
+
+    fn eval(lua: &mut Lua, source: &str) -> Value {
+        let function = lua.load(source, "=test").unwrap();
+        function.call(lua, ()).unwrap()
+    }
+
+    fn from_lua<T: DeserializeOwned>(source: &str, options: DeserializeOptions) -> Result<T> {
+        let mut lua = Lua::new();
+        let value = eval(&mut lua, source);
+        lua.from_value_with(value, options)
+    }
+
+    fn holes(holes: Holes) -> DeserializeOptions {
+        DeserializeOptions {
+            holes,
+            ..DeserializeOptions::default()
+        }
+    }
+
+    /// Serializes `value` as the global `v` and runs `source` on it.
+    fn through_lua<T: Serialize>(value: &T, options: SerializeOptions, source: &str) -> String {
+        let mut lua = Lua::new();
+        let value = lua.to_value_with(value, options).unwrap();
+        lua.set_global("v", value).unwrap();
+        let function = lua.load(source, "=test").unwrap();
+        function.call(&mut lua, ()).unwrap()
+    }
+
+    #[test]
+    fn structs_and_enums_round_trip() {
+        let mut lua = Lua::new();
+        for mode in [Mode::Off, Mode::Fixed(3), Mode::Range { from: 1, to: 9 }] {
+            let config = Config {
+                name: "wide".to_owned(),
+                sizes: vec![1, 2, 3],
+                ratio: None,
+                mode,
+            };
+            let value = lua.to_value(&config).unwrap();
+            assert_eq!(lua.from_value::<Config>(value).unwrap(), config);
+        }
+
+        let config: Config = from_lua(
+            "return {name = 'x', sizes = {}, ratio = 0.5, mode = {Range = {from = 2, to = 4.0}}}",
+            DeserializeOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(config.mode, Mode::Range { from: 2, to: 4 });
+        assert_eq!(config.ratio, Some(0.5));
+
+        let layout = through_lua(
+            &Mode::Fixed(3),
+            SerializeOptions::default(),
+            "return math.type(v.Fixed)",
+        );
+        assert_eq!(layout, "integer");
+        let layout = through_lua(&Mode::Off, SerializeOptions::default(), "return v");
+        assert_eq!(layout, "Off");
+
+        let error = from_lua::<Mode>("return {Off = 1, Fixed = 2}", Default::default());
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "expected a table with a single field, the variant"
+        );
+        let error = from_lua::<Config>("return {name = 1}", Default::default());
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "invalid type: integer `1`, expected a string"
+        );
+        let error = from_lua::<u8>("return print", Default::default());
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "invalid type: function, expected u8"
+        );
+    }
+
+    #[test]
+    fn holes_in_sequences() {
+        let source = "return {1, nil, 3}";
+        let error = from_lua::<Vec<Option<i64>>>(source, holes(Holes::Error)).unwrap_err();
+        assert_eq!(error.to_string(), "hole at index 2 of a sequence");
+        assert_eq!(
+            from_lua::<Vec<Option<i64>>>(source, holes(Holes::Nil)).unwrap(),
+            [Some(1), None, Some(3)]
+        );
+        assert_eq!(
+            from_lua::<Vec<i64>>(source, holes(Holes::Stop)).unwrap(),
+            [1]
+        );
+        let error = from_lua::<Vec<i64>>(source, holes(Holes::Nil)).unwrap_err();
+        assert_eq!(error.to_string(), "invalid type: unit value, expected i64");
+
+        let through = through_lua(
+            &[Some(1), None, Some(3)],
+            SerializeOptions::default(),
+            "return v[1] .. tostring(v[2]) .. v[3]",
+        );
+        assert_eq!(through, "1nil3");
+    }
+
+    /// A table as a type that accepts anything reads it.
+    #[derive(Debug, PartialEq, Deserialize)]
+    #[serde(untagged)]
+    enum Table {
+        Sequence(Vec<Json>),
+        Indexed(BTreeMap<i64, Json>),
+        Record(BTreeMap<String, Json>),
+    }
+
+    #[test]
+    fn tables_that_look_like_arrays() {
+        use Table::*;
+
+        let table = |source, options| from_lua::<Table>(source, options).unwrap();
+        let indexed = |entries: &[(i64, Json)]| Indexed(entries.iter().cloned().collect());
+        let defaults = DeserializeOptions::default();
+        assert_eq!(
+            table("return {'a', 'b'}", defaults),
+            Sequence(vec![json!("a"), json!("b")])
+        );
+        assert_eq!(from_lua::<Json>("return {}", defaults).unwrap(), json!({}));
+        assert_eq!(
+            table("return {[2] = 'b'}", defaults),
+            indexed(&[(2, json!("b"))])
+        );
+        assert_eq!(
+            table("return {1, nil, 3}", holes(Holes::Error)),
+            indexed(&[(1, json!(1)), (3, json!(3))])
+        );
+        assert_eq!(
+            table("return {1, nil, 3}", holes(Holes::Nil)),
+            Sequence(vec![json!(1), Json::Null, json!(3)])
+        );
+        assert_eq!(
+            table("return {[1] = 1, [4] = 4}", holes(Holes::Nil)),
+            Sequence(vec![json!(1), Json::Null, Json::Null, json!(4)])
+        );
+        assert_eq!(
+            table("return {[1] = 1, [5] = 5}", holes(Holes::Nil)),
+            indexed(&[(1, json!(1)), (5, json!(5))])
+        );
+        assert_eq!(
+            from_lua::<Vec<String>>("return {'a', x = 1}", defaults).unwrap(),
+            ["a"]
+        );
+
+        let maps = DeserializeOptions {
+            sequences: false,
+            ..defaults
+        };
+        assert_eq!(
+            table("return {'a', 'b'}", maps),
+            indexed(&[(1, json!("a")), (2, json!("b"))])
+        );
+        assert_eq!(
+            from_lua::<Vec<String>>("return {'a', 'b'}", maps).unwrap(),
+            ["a", "b"]
+        );
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        let defaults = DeserializeOptions::default();
+        let integral = DeserializeOptions {
+            integral_floats: true,
+            ..defaults
+        };
+        assert_eq!(
+            from_lua::<Json>("return 2.0", defaults).unwrap(),
+            json!(2.0)
+        );
+        assert_eq!(from_lua::<Json>("return 2.0", integral).unwrap(), json!(2));
+        assert_eq!(
+            from_lua::<Json>("return 2.5", integral).unwrap(),
+            json!(2.5)
+        );
+        assert_eq!(from_lua::<i32>("return 2.0", defaults).unwrap(), 2);
+        let error = from_lua::<i32>("return 2.5", defaults).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid type: floating point `2.5`, expected i32"
+        );
+        let error = from_lua::<i32>("return '2'", defaults).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid type: string \"2\", expected i32"
+        );
+
+        let keys = from_lua::<BTreeMap<i64, String>>("return {[2.0] = 'b', [-1] = 'a'}", defaults);
+        assert_eq!(
+            keys.unwrap(),
+            [(-1, "a".to_owned()), (2, "b".to_owned())].into()
+        );
+        let error = from_lua::<BTreeMap<i64, String>>("return {[1.5] = 'x'}", defaults);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "invalid type: floating point `1.5`, expected i64"
+        );
+
+        let source = "return math.type(v[1]) .. ' ' .. math.type(v[2])";
+        let floats = [2.0, 2.5];
+        let kept = through_lua(&floats, SerializeOptions::default(), source);
+        assert_eq!(kept, "float float");
+        let options = SerializeOptions {
+            integral_floats: true,
+        };
+        assert_eq!(through_lua(&floats, options, source), "integer float");
+
+        let value = json!({"n": 1, "x": 1.0, "list": [true, null, "s"]});
+        let mut lua = Lua::new();
+        let lua_value = lua.to_value(&value).unwrap();
+        assert_eq!(
+            lua.from_value_with::<Json>(lua_value, holes(Holes::Nil))
+                .unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn tables_that_contain_themselves() {
+        let defaults = DeserializeOptions::default();
+        let message = "cannot deserialize a table that contains itself";
+        let error = from_lua::<Json>("local t = {} t.t = t return t", defaults).unwrap_err();
+        assert_eq!(error.to_string(), message);
+        let error = from_lua::<Json>("local t = {1} t[2] = {t} return t", defaults).unwrap_err();
+        assert_eq!(error.to_string(), message);
+        let error = from_lua::<Vec<Json>>("local t = {} t[1] = t return t", defaults).unwrap_err();
+        assert_eq!(error.to_string(), message);
+
+        let shared = from_lua::<Json>("local t = {1} return {a = t, b = {t, t}}", defaults);
+        assert_eq!(shared.unwrap(), json!({"a": [1], "b": [[1], [1]]}));
+    }
+
+    #[test]
+    fn the_serde_wrapper() {
+        let mut lua = Lua::new();
+        let span = lua.create_function(|_, Serde(mode): Serde<Mode>| {
+            Ok(Serde(match mode {
+                Mode::Off => vec![],
+                Mode::Fixed(n) => vec![n],
+                Mode::Range { from, to } => (from..=to).collect(),
+            }))
+        });
+        lua.set_global("span", span).unwrap();
+        lua.set_global("v", Serde(Mode::Range { from: 2, to: 4 }))
+            .unwrap();
+        let function = lua
+            .load(
+                "return #span(v), span('Off')[1], span({Fixed = 5})[1]",
+                "=test",
+            )
+            .unwrap();
+        let results: (i64, Option<i64>, i64) = function.call(&mut lua, ()).unwrap();
+        assert_eq!(results, (3, None, 5));
+
+        let Serde(mode) = lua.global::<Serde<Mode>>("v").unwrap();
+        assert_eq!(mode, Mode::Range { from: 2, to: 4 });
+        let error = lua.global::<Serde<Mode>>("span").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "invalid type: function, expected enum Mode"
+        );
+    }
+}
@@ -0,0 +1,682 @@
+//! Rust types exposed to Lua as userdata, with the fields, methods and metamethods they
+//! register.
+//!
+//! Each type gets a metatable, made the first time a userdata of the type is created and kept
+//! in the registry under the type's name. Methods are found through `__index`, after the
+//! fields that have getters, and before whatever `__index` the type registers itself.
+
+use {
+    super::{FromLua, FromLuaMulti, IntoLua, IntoLuaMulti, Lua},
+    crate::runtime::{
+        self, Callback, Error, Metamethod, NativeFunction, Ref, Result, State, TableRef, Value,
+    },
+    std::{any, cell::RefCell, marker::PhantomData, rc::Rc},
+};
+
+/// A Rust type that Lua code can hold as userdata.
+///
+/// The data is kept in a `RefCell`, so that methods taking it by reference and by mutable
+/// reference are checked at runtime not to overlap, as they could when a method calls back
+/// into Lua code that calls another method of the same userdata.
+pub trait UserData: Sized + 'static {
+    /// The number of user values that each userdata of this type has, all nil at first.
+    const USER_VALUES: usize = 1;
+
+    /// Registers the fields, methods and metamethods of the type.
+    fn register(_registry: &mut UserDataRegistry<Self>) {}
+}
+
+/// The fields, methods and metamethods of a [`UserData`] type.
+///
+/// Methods and metamethods receive the userdata they are called on as their first argument,
+/// which must be a userdata of this type, and take the rest as `A`. Functions take all of
+/// their arguments as `A`.
+pub struct UserDataRegistry<T> {
+    getters: Vec<(String, Rc<Callback>)>,
+    setters: Vec<(String, Rc<Callback>)>,
+    methods: Vec<(String, Rc<Callback>)>,
+    metamethods: Vec<(Metamethod, Rc<Callback>)>,
+    marker: PhantomData<T>,
+}
+
+impl<T: UserData> UserDataRegistry<T> {
+    /// Adds a field that reads as what `get` returns.
+    pub fn add_field_getter<R>(
+        &mut self,
+        name: &str,
+        get: impl Fn(&mut Lua, &T) -> Result<R> + 'static,
+    ) where
+        R: IntoLua,
+    {
+        let callback = method(move |lua, this: &RefCell<T>, ()| {
+            let this = this.try_borrow().map_err(|_| Error::UserDataBorrowedMut)?;
+            get(lua, &this)
+        });
+        self.getters.push((name.to_owned(), callback));
+    }
+
+    /// Adds a field that assignments go to `set` for.
+    pub fn add_field_setter<A>(
+        &mut self,
+        name: &str,
+        set: impl Fn(&mut Lua, &mut T, A) -> Result<()> + 'static,
+    ) where
+        A: FromLua,
+    {
+        let callback = method(move |lua, this: &RefCell<T>, value| {
+            let mut this = this.try_borrow_mut().map_err(|_| Error::UserDataBorrowed)?;
+            set(lua, &mut this, value)
+        });
+        self.setters.push((name.to_owned(), callback));
+    }
+
+    pub fn add_method<A, R>(
+        &mut self,
+        name: &str,
+        method: impl Fn(&mut Lua, &T, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.methods.push((name.to_owned(), shared_method(method)));
+    }
+
+    pub fn add_method_mut<A, R>(
+        &mut self,
+        name: &str,
+        method: impl Fn(&mut Lua, &mut T, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.methods.push((name.to_owned(), mutable_method(method)));
+    }
+
+    /// Adds a function that is reached like a method, but is not given the userdata.
+    pub fn add_function<A, R>(
+        &mut self,
+        name: &str,
+        function: impl Fn(&mut Lua, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.methods
+            .push((name.to_owned(), self::function(function)));
+    }
+
+    /// Adds a metamethod. A binary operator's metamethod added this way fails when the
+    /// userdata is its second operand; one added with
+    /// [`add_meta_function`](Self::add_meta_function) handles both.
+    pub fn add_meta_method<A, R>(
+        &mut self,
+        event: Metamethod,
+        method: impl Fn(&mut Lua, &T, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.metamethods.push((event, shared_method(method)));
+    }
+
+    pub fn add_meta_method_mut<A, R>(
+        &mut self,
+        event: Metamethod,
+        method: impl Fn(&mut Lua, &mut T, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.metamethods.push((event, mutable_method(method)));
+    }
+
+    pub fn add_meta_function<A, R>(
+        &mut self,
+        event: Metamethod,
+        function: impl Fn(&mut Lua, A) -> Result<R> + 'static,
+    ) where
+        A: FromLuaMulti,
+        R: IntoLuaMulti,
+    {
+        self.metamethods.push((event, self::function(function)));
+    }
+}
+
+/// A native function that borrows the userdata of type `T` it is called on.
+fn method<T, A, R>(f: impl Fn(&mut Lua, &RefCell<T>, A) -> Result<R> + 'static) -> Rc<Callback>
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    Rc::new(move |state: &mut State| {
+        let lua = Lua::from_state(state);
+        let this = this::<T>(lua)?;
+        let args = (2..=lua.arg_count()).map(|n| lua.arg(n)).collect();
+        let args = A::from_lua_multi(args, lua)?;
+        f(lua, &this, args)?.push_into_lua(lua)
+    })
+}
+
+fn shared_method<T, A, R>(f: impl Fn(&mut Lua, &T, A) -> Result<R> + 'static) -> Rc<Callback>
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    method(move |lua, this: &RefCell<T>, args| {
+        let this = this.try_borrow().map_err(|_| Error::UserDataBorrowedMut)?;
+        f(lua, &this, args)
+    })
+}
+
+fn mutable_method<T, A, R>(f: impl Fn(&mut Lua, &mut T, A) -> Result<R> + 'static) -> Rc<Callback>
+where
+    T: UserData,
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    method(move |lua, this: &RefCell<T>, args| {
+        let mut this = this.try_borrow_mut().map_err(|_| Error::UserDataBorrowed)?;
+        f(lua, &mut this, args)
+    })
+}
+
+fn function<A, R>(f: impl Fn(&mut Lua, A) -> Result<R> + 'static) -> Rc<Callback>
+where
+    A: FromLuaMulti,
+    R: IntoLuaMulti,
+{
+    Rc::new(move |state: &mut State| {
+        let lua = Lua::from_state(state);
+        let args = (1..=lua.arg_count()).map(|n| lua.arg(n)).collect();
+        let args = A::from_lua_multi(args, lua)?;
+        f(lua, args)?.push_into_lua(lua)
+    })
+}
+
+/// The data of the userdata that the running method was called on. The data is shared with
+/// the userdata, so it outlives the call even if the userdata does not.
+fn this<T: UserData>(lua: &Lua) -> Result<Rc<RefCell<T>>> {
+    let value = lua.arg(1);
+    data(lua, value).ok_or_else(|| Error::UserDataMismatch {
+        expected: short_name::<T>(),
+        got: lua.type_name(value),
+    })
+}
+
+fn data<T: UserData>(lua: &Lua, value: Value) -> Option<Rc<RefCell<T>>> {
+    match value {
+        Value::UserData(userdata) => lua.heap[userdata].data().clone().downcast().ok(),
+        _ => None,
+    }
+}
+
+/// The name of `T` without its path or generic arguments, which Lua sees as the `__name` of
+/// its metatable.
+fn short_name<T>() -> &'static str {
+    let name = any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name)
+}
+
+impl Lua {
+    pub fn create_userdata<T: UserData>(&mut self, data: T) -> AnyUserData {
+        let metatable = self.userdata_metatable::<T>();
+        let data = Rc::new(RefCell::new(data));
+        let userdata = self
+            .heap
+            .new_userdata(runtime::UserData::new(data, T::USER_VALUES));
+        self.heap
+            .set_metatable(Value::UserData(userdata), Some(metatable));
+        AnyUserData(self.create_ref(Value::UserData(userdata)))
+    }
+
+    /// The metatable of userdata of type `T`, which is made from what the type registers the
+    /// first time it is needed.
+    fn userdata_metatable<T: UserData>(&mut self) -> TableRef {
+        let registry = self.heap.registry();
+        let key = self.heap.intern(any::type_name::<T>().as_bytes());
+        if let Value::Table(metatable) = self.heap[registry].get_str(key) {
+            return metatable;
+        }
+
+        let mut fields = UserDataRegistry {
+            getters: Vec::new(),
+            setters: Vec::new(),
+            methods: Vec::new(),
+            metamethods: Vec::new(),
+            marker: PhantomData,
+        };
+        T::register(&mut fields);
+
+        // Everything made here is reachable from the metatable as soon as it is made, and the
+        // metatable from the registry.
+        let metatable = self.heap.new_table();
+        self.heap[registry].set_str(key, Value::Table(metatable));
+
+        let name = self.heap.intern(short_name::<T>().as_bytes());
+        self.set_metafield(metatable, Metamethod::Name, Value::String(name));
+
+        let mut index = None;
+        let mut newindex = None;
+        for (event, callback) in fields.metamethods {
+            let function = self.native(callback, Vec::new());
+            match event {
+                Metamethod::Index => index = Some(function),
+                Metamethod::Newindex => newindex = Some(function),
+                event => self.set_metafield(metatable, event, function),
+            }
+        }
+
+        let methods = self.function_table(fields.methods);
+        self.set_metafield(metatable, Metamethod::Index, methods);
+
+        if !fields.getters.is_empty() || index.is_some() {
+            let getters = self.function_table(fields.getters);
+            let upvalues = vec![getters, methods, index.unwrap_or_default()];
+            let index = self.native(Rc::new(userdata_index), upvalues);
+            self.set_metafield(metatable, Metamethod::Index, index);
+        }
+
+        if !fields.setters.is_empty() || newindex.is_some() {
+            let setters = self.function_table(fields.setters);
+            let upvalues = vec![setters, newindex.unwrap_or_default()];
+            let newindex = self.native(Rc::new(userdata_newindex), upvalues);
+            self.set_metafield(metatable, Metamethod::Newindex, newindex);
+        }
+
+        metatable
+    }
+
+    fn native(&mut self, callback: Rc<Callback>, upvalues: Vec<Value>) -> Value {
+        let function = runtime::Function::Native(NativeFunction {
+            callback,
+            upvalues: upvalues.into_boxed_slice(),
+        });
+        Value::Function(self.heap.new_function(function))
+    }
+
+    /// A table of native functions by name.
+    fn function_table(&mut self, functions: Vec<(String, Rc<Callback>)>) -> Value {
+        let table = self.heap.new_table();
+        for (name, callback) in functions {
+            let name = self.heap.intern(name.as_bytes());
+            let function = self.native(callback, Vec::new());
+            self.heap[table].set_str(name, function);
+        }
+
+        Value::Table(table)
+    }
+
+    fn set_metafield(&mut self, metatable: TableRef, event: Metamethod, value: Value) {
+        let name = self.heap.metamethod_name(event);
+        self.heap[metatable].set_str(name, value);
+    }
+}
+
+/// The `__index` metamethod of a userdata type with fields: reads a field through its getter,
+/// and otherwise finds a method or defers to the type's own `__index`. Its upvalues are the
+/// getters, the methods and that `__index`.
+fn userdata_index(state: &mut State) -> Result<usize> {
+    let (Value::Table(getters), Value::Table(methods)) = (state.upvalue(1), state.upvalue(2))
+    else {
+        unreachable!("a userdata `__index` has lost its tables");
+    };
+
+    let (this, key) = (state.arg(1), state.arg(2));
+    let getter = state.heap[getters].get(key);
+    if !getter.is_nil() {
+        state.push(getter);
+        state.push(this);
+        return state.call(1, Some(1));
+    }
+
+    let method = state.heap[methods].get(key);
+    let fallback = state.upvalue(3);
+    if !method.is_nil() || fallback.is_nil() {
+        state.push(method);
+        return Ok(1);
+    }
+
+    state.push(fallback);
+    state.push(this);
+    state.push(key);
+    state.call(2, Some(1))
+}
+
+/// The `__newindex` metamethod of a userdata type with fields: assigns a field through its
+/// setter, and otherwise defers to the type's own `__newindex`. Its upvalues are the setters
+/// and that `__newindex`.
+fn userdata_newindex(state: &mut State) -> Result<usize> {
+    let Value::Table(setters) = state.upvalue(1) else {
+        unreachable!("a userdata `__newindex` has lost its setters");
+    };
+
+    let (this, key, value) = (state.arg(1), state.arg(2), state.arg(3));
+    let setter = state.heap[setters].get(key);
+    let args = match setter {
+        Value::Nil => match state.upvalue(2) {
+            Value::Nil => {
+                let key = state.tostring(key)?;
+                let key = String::from_utf8_lossy(&state.heap[key]).into_owned();
+                return Err(Error::UnknownField(key));
+            }
+
+            fallback => vec![fallback, this, key, value],
+        },
+
+        setter => vec![setter, this, value],
+    };
+
+    let nargs = args.len() - 1;
+    args.into_iter().for_each(|arg| state.push(arg));
+    state.call(nargs, Some(0))
+}
+
+/// A userdata, kept alive for as long as this handle or a clone of it exists.
+#[derive(Clone, Debug)]
+pub struct AnyUserData(Ref);
+
+impl AnyUserData {
+    /// Whether the userdata holds a `T`.
+    pub fn is<T: UserData>(&self, lua: &Lua) -> bool {
+        data::<T>(lua, self.value()).is_some()
+    }
+
+    /// Calls `f` with a reference to the userdata's `T`.
+    pub fn with_borrow<T: UserData, R>(&self, lua: &Lua, f: impl FnOnce(&T) -> R) -> Result<R> {
+        let data = self.data::<T>(lua)?;
+        let data = data.try_borrow().map_err(|_| Error::UserDataBorrowedMut)?;
+        Ok(f(&data))
+    }
+
+    /// Calls `f` with a mutable reference to the userdata's `T`.
+    pub fn with_borrow_mut<T: UserData, R>(
+        &self,
+        lua: &Lua,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> Result<R> {
+        let data = self.data::<T>(lua)?;
+        let mut data = data.try_borrow_mut().map_err(|_| Error::UserDataBorrowed)?;
+        Ok(f(&mut data))
+    }
+
+    /// The `n`th user value, counting from 1, which is nil if there is no such user value.
+    pub fn user_value<V: FromLua>(&self, lua: &mut Lua, n: usize) -> Result<V> {
+        let value = lua.heap[self.userdata()].user_value(n);
+        V::from_lua(value.unwrap_or_default(), lua)
+    }
+
+    /// Sets the `n`th user value, counting from 1, returning false if there is no such user
+    /// value.
+    pub fn set_user_value(&self, lua: &mut Lua, n: usize, value: impl IntoLua) -> Result<bool> {
+        let value = value.into_lua(lua)?;
+        Ok(lua.heap[self.userdata()].set_user_value(n, value))
+    }
+
+    pub fn value(&self) -> Value {
+        self.0.value()
+    }
+
+    fn data<T: UserData>(&self, lua: &Lua) -> Result<Rc<RefCell<T>>> {
+        let value = self.value();
+        data(lua, value).ok_or_else(|| Error::UserDataMismatch {
+            expected: short_name::<T>(),
+            got: lua.type_name(value),
+        })
+    }
+
+    fn userdata(&self) -> runtime::UserDataRef {
+        match self.value() {
+            Value::UserData(userdata) => userdata,
+            _ => unreachable!("a userdata handle refers to a userdata"),
+        }
+    }
+}
+
+impl IntoLua for AnyUserData {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl IntoLua for &AnyUserData {
+    fn into_lua(self, _: &mut Lua) -> Result<Value> {
+        Ok(self.value())
+    }
+}
+
+impl FromLua for AnyUserData {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        match value {
+            Value::UserData(_) => Ok(Self(lua.create_ref(value))),
+            _ => Err(Error::FromLua {
+                from: lua.type_name(value),
+                to: "AnyUserData",
+                message: None,
+            }),
+        }
+    }
+}
+
+impl<T: UserData> IntoLua for T {
+    fn into_lua(self, lua: &mut Lua) -> Result<Value> {
+        Ok(lua.create_userdata(self).value())
+    }
+}
+
+/// A userdata converts to its type by cloning its data.
+impl<T: UserData + Clone> FromLua for T {
+    fn from_lua(value: Value, lua: &mut Lua) -> Result<Self> {
+        let data = data::<T>(lua, value).ok_or_else(|| Error::FromLua {
+            from: lua.type_name(value),
+            to: short_name::<T>(),
+            message: None,
+        })?;
+
+        let data = data.try_borrow().map_err(|_| Error::UserDataBorrowedMut)?;
+        Ok(data.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::cell::Cell};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Counter {
+        count: i64,
+        step: i64,
+    }
+
+    impl UserData for Counter {
+        const USER_VALUES: usize = 2;
+
+        fn register(registry: &mut UserDataRegistry<Self>) {
+            registry.add_field_getter("count", |_, this| Ok(this.count));
+            registry.add_field_setter("step", |_, this, step| {
+                this.step = step;
+                Ok(())
+            });
+            registry.add_method("peek", |_, this, ()| Ok(this.count));
+            registry.add_method_mut("bump", |_, this, times: Option<i64>| {
+                this.count += this.step * times.unwrap_or(1);
+                Ok(this.count)
+            });
+            registry.add_method("visit", |lua, this, f: crate::Function| {
+                f.call::<()>(lua, this.count)
+            });
+            registry.add_function("new", |_, count| Ok(Counter { count, step: 1 }));
+            registry.add_meta_function(Metamethod::Add, |lua, (a, b): (Value, Value)| {
+                let mut count = |value| match Counter::from_lua(value, lua) {
+                    Ok(counter) => Ok(counter.count),
+                    Err(_) => i64::from_lua(value, lua),
+                };
+                Ok(count(a)? + count(b)?)
+            });
+            registry.add_meta_method(Metamethod::Tostring, |_, this, ()| {
+                Ok(format!("Counter({})", this.count))
+            });
+            registry.add_meta_method(Metamethod::Index, |_, this, key: String| {
+                Ok(format!("{key}{}", this.count))
+            });
+        }
+    }
+
+    fn lua_with_counter() -> (Box<Lua>, AnyUserData) {
+        let mut lua = Lua::new();
+        let counter = lua.create_userdata(Counter { count: 0, step: 1 });
+        lua.set_global("c", &counter).unwrap();
+        (lua, counter)
+    }
+
+    fn eval<R: FromLuaMulti>(lua: &mut Lua, source: &str) -> Result<R> {
+        let function = lua.load(source, "=test")?;
+        function.call(lua, ())
+    }
+
+    #[test]
+    fn fields_and_methods() {
+        let (mut lua, counter) = lua_with_counter();
+        let bumped: (i64, i64, i64) =
+            eval(&mut lua, "c.step = 5 return c:bump(), c:bump(2), c.count").unwrap();
+        assert_eq!(bumped, (5, 15, 15));
+        assert_eq!(eval::<i64>(&mut lua, "return c:peek()").unwrap(), 15);
+        assert_eq!(eval::<i64>(&mut lua, "return c.new(7):bump()").unwrap(), 8);
+        assert_eq!(
+            counter
+                .with_borrow(&lua, |this: &Counter| this.clone())
+                .unwrap(),
+            Counter { count: 15, step: 5 }
+        );
+
+        let error = eval::<()>(&mut lua, "c.count = 1").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "test:1: attempt to set an unknown field 'count'"
+        );
+        let error = eval::<()>(&mut lua, "c.peek(1)").unwrap_err();
+        assert_eq!(error.to_string(), "test:1: Counter expected, got number");
+        let error = eval::<()>(&mut lua, "c.step = 'x'").unwrap_err();
+        assert_eq!(error.to_string(), "cannot convert a string value to i64");
+    }
+
+    #[test]
+    fn metamethods_and_the_type_index() {
+        let (mut lua, _) = lua_with_counter();
+        let results: (String, i64, i64, String, String) = eval(
+            &mut lua,
+            "c:bump() return tostring(c), c + 2, 3 + c + c, c.other, \
+             getmetatable(c).__name",
+        )
+        .unwrap();
+        assert_eq!(
+            results,
+            (
+                "Counter(1)".to_owned(),
+                3,
+                5,
+                "other1".to_owned(),
+                "Counter".to_owned()
+            )
+        );
+    }
+
+    struct Guard(Rc<Cell<&'static str>>);
+
+    impl UserData for Guard {
+        fn register(registry: &mut UserDataRegistry<Self>) {
+            registry.add_meta_method(Metamethod::Close, |_, this, ()| {
+                this.0.set("closed");
+                Ok(())
+            });
+            registry.add_meta_method(Metamethod::Gc, |_, this, ()| {
+                this.0.set("collected");
+                Ok(())
+            });
+        }
+    }
+
+    #[test]
+    fn closing_and_collecting() {
+        let mut lua = Lua::new();
+        let state = Rc::new(Cell::new("open"));
+        let guard = lua.create_userdata(Guard(state.clone()));
+        lua.set_global("g", guard).unwrap();
+        eval::<()>(&mut lua, "do local g <close> = g end").unwrap();
+        assert_eq!(state.get(), "closed");
+        eval::<()>(&mut lua, "g = nil collectgarbage()").unwrap();
+        assert_eq!(state.get(), "collected");
+    }
+
+    #[test]
+    fn borrows_are_checked() {
+        let (mut lua, counter) = lua_with_counter();
+        let error = eval::<()>(&mut lua, "c:visit(function() c:bump() end)").unwrap_err();
+        assert_eq!(error.to_string(), "test:1: userdata already borrowed");
+        let error = eval::<()>(&mut lua, "c:visit(function() c.step = 2 end)").unwrap_err();
+        assert_eq!(error.to_string(), "userdata already borrowed");
+        assert_eq!(
+            eval::<i64>(
+                &mut lua,
+                "local n c:visit(function(x) n = x + c:peek() end) return n"
+            )
+            .unwrap(),
+            0
+        );
+
+        let error = counter
+            .with_borrow_mut(&lua, |_: &mut Counter| {
+                counter.with_borrow(&lua, |_: &Counter| ()).unwrap_err()
+            })
+            .unwrap();
+        assert!(matches!(error, Error::UserDataBorrowedMut));
+        let error = counter
+            .with_borrow(&lua, |_: &Counter| {
+                counter
+                    .with_borrow_mut(&lua, |_: &mut Counter| ())
+                    .unwrap_err()
+            })
+            .unwrap();
+        assert!(matches!(error, Error::UserDataBorrowed));
+    }
+
+    #[test]
+    fn handles_and_user_values() {
+        let (mut lua, counter) = lua_with_counter();
+        assert!(counter.is::<Counter>(&lua));
+        assert!(!counter.is::<Guard>(&lua));
+        let error = counter.with_borrow(&lua, |_: &Guard| ()).unwrap_err();
+        assert_eq!(error.to_string(), "Guard expected, got Counter");
+
+        assert!(counter.set_user_value(&mut lua, 2, "second").unwrap());
+        assert!(!counter.set_user_value(&mut lua, 3, "third").unwrap());
+        assert_eq!(
+            counter.user_value::<Option<String>>(&mut lua, 1).unwrap(),
+            None
+        );
+        assert_eq!(counter.user_value::<String>(&mut lua, 2).unwrap(), "second");
+        assert_eq!(
+            counter.user_value::<Option<String>>(&mut lua, 3).unwrap(),
+            None
+        );
+        assert_eq!(
+            eval::<String>(&mut lua, "return debug.getuservalue(c, 2)").unwrap(),
+            "second"
+        );
+
+        let copy: Counter = lua.global("c").unwrap();
+        assert_eq!(copy, Counter { count: 0, step: 1 });
+        let error = lua.global::<Counter>("print").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a function value to Counter"
+        );
+        let error = lua.global::<AnyUserData>("print").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "cannot convert a function value to AnyUserData"
+        );
+    }
+}
@@ -1,10 +1,285 @@
-mod entity;
-mod ir;
-mod lex;
-mod parse;
-mod string_pool;
-mod vec_cell;
-
-fn main() {
-    println!("Hello, world!");
+//! The standalone interpreter, which runs scripts and statements given on the command line,
+//! or reads them interactively, much as the reference `lua` program does.
+
+use {
+    clap::{ArgAction, CommandFactory, FromArgMatches, Parser},
+    satin::{Error, Function, IntoLuaMulti, Lua, Value, Variadic},
+    std::{
+        env,
+        io::{self, BufRead, IsTerminal, Read, Write},
+        process::ExitCode,
+    },
+};
+
+const PROGRAM: &str = env!("CARGO_PKG_NAME");
+
+#[derive(Debug, Parser)]
+#[command(version, disable_version_flag = true)]
+struct Args {
+    /// Execute the string `stat`.
+    #[arg(short, value_name = "stat", action = ArgAction::Append)]
+    execute: Vec<String>,
+
+    /// Require the module `mod` into the global of the same name.
+    #[arg(short, value_name = "mod", action = ArgAction::Append)]
+    library: Vec<String>,
+
+    /// Enter interactive mode after running the script.
+    #[arg(short)]
+    interactive: bool,
+
+    /// Show version information.
+    #[arg(short)]
+    version: bool,
+
+    /// Ignore the LUA_INIT environment variables.
+    #[arg(short = 'E')]
+    ignore_environment: bool,
+
+    /// The script to run, or `-` to run standard input.
+    script: Option<String>,
+
+    /// The arguments of the script, which it receives as `...` and in the table `arg`.
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+/// Something to do before the script, in the order given on the command line.
+enum Action<'a> {
+    Execute(&'a str),
+    Require(&'a str),
+}
+
+fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+    let indices = |id| matches.indices_of(id).into_iter().flatten();
+    let executes = indices("execute").zip(&args.execute);
+    let requires = indices("library").zip(&args.library);
+
+    let mut actions: Vec<_> = executes
+        .map(|(index, statement)| (index, Action::Execute(statement)))
+        .chain(requires.map(|(index, name)| (index, Action::Require(name))))
+        .collect();
+    actions.sort_by_key(|&(index, _)| index);
+
+    let mut lua = Lua::new();
+    match run(
+        &mut lua,
+        &args,
+        actions.into_iter().map(|(_, action)| action),
+    ) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(message) => {
+            report(&message);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run<'a>(
+    lua: &mut Lua,
+    args: &'a Args,
+    actions: impl Iterator<Item = Action<'a>>,
+) -> Result<(), String> {
+    // With nothing else to do, standard input is read interactively if it is a terminal, and
+    // run as a script otherwise.
+    let nothing_to_do = args.script.is_none() && args.execute.is_empty() && !args.version;
+    let interactive = args.interactive || nothing_to_do && io::stdin().is_terminal();
+
+    if args.version || interactive {
+        print_version(lua);
+    }
+
+    create_arg_table(lua, args).map_err(|error| error.to_string())?;
+    if !args.ignore_environment {
+        run_init(lua)?;
+    }
+
+    for action in actions {
+        match action {
+            Action::Execute(statement) => {
+                let function = lua.load(statement, "=(command line)");
+                call_and_discard(lua, function.map(|function| function.value()), ())?;
+            }
+
+            Action::Require(name) => {
+                let require = lua.global::<Value>("require");
+                let top = lua.top();
+                call(lua, require, name)?;
+                let module = lua.values_from(top).first().copied().unwrap_or(Value::Nil);
+                let result = lua.set_global(name, module);
+                lua.set_top(top);
+                result.map_err(|error| error.to_string())?;
+            }
+        }
+    }
+
+    let script = match &args.script {
+        Some(script) => Some(script.as_str()),
+        None if nothing_to_do && !interactive => Some("-"),
+        None => None,
+    };
+
+    if let Some(script) = script {
+        let function = match script {
+            "-" => load_stdin(lua),
+            script => lua.load_file(script),
+        };
+
+        let args: Variadic<_> = args.args.iter().map(String::as_str).collect();
+        call_and_discard(lua, function.map(|function| function.value()), args)?;
+    }
+
+    if interactive {
+        run_interactively(lua);
+    }
+
+    Ok(())
+}
+
+fn print_version(lua: &mut Lua) {
+    let language = lua.global::<String>("_VERSION").unwrap_or_default();
+    println!("{PROGRAM} {} ({language})", env!("CARGO_PKG_VERSION"));
+}
+
+/// Sets the global `arg` to a table of the command line, with the script at index 0, its
+/// arguments after it, and the interpreter and its options before it.
+fn create_arg_table(lua: &mut Lua, args: &Args) -> satin::Result<()> {
+    let command_line: Vec<_> = env::args().collect();
+    let script_index = match args.script {
+        Some(_) => command_line.len() - args.args.len() - 1,
+        None => 0,
+    };
+
+    let table = lua.create_table();
+    for (index, arg) in command_line.iter().enumerate() {
+        table.raw_set(lua, index as i64 - script_index as i64, arg.as_str())?;
+    }
+
+    lua.set_global("arg", table.value())
+}
+
+/// Runs the code that `LUA_INIT_5_4` or `LUA_INIT` gives, or the file it names after an `@`.
+fn run_init(lua: &mut Lua) -> Result<(), String> {
+    let Some((name, init)) = ["LUA_INIT_5_4", "LUA_INIT"]
+        .into_iter()
+        .find_map(|name| Some((name, env::var(name).ok()?)))
+    else {
+        return Ok(());
+    };
+
+    let function = match init.strip_prefix('@') {
+        Some(file) => lua.load_file(file),
+        None => lua.load(init, &format!("={name}")),
+    };
+
+    call_and_discard(lua, function.map(|function| function.value()), ())
+}
+
+fn load_stdin(lua: &mut Lua) -> satin::Result<Function> {
+    let mut chunk = Vec::new();
+    if let Err(error) = io::stdin().read_to_end(&mut chunk) {
+        return Err(Error::Runtime(format!("cannot read stdin: {error}")));
+    }
+
+    // A first line starting with '#' is skipped, as `loadfile` skips it, keeping its newline
+    // so that line numbers are unchanged.
+    if chunk.first() == Some(&b'#') {
+        let end = chunk.iter().position(|&c| c == b'\n');
+        chunk.drain(..end.unwrap_or(chunk.len()));
+    }
+
+    lua.load(chunk, "=stdin")
+}
+
+/// Calls a function that was loaded, leaving its results on the stack and returning how
+/// many there are, or returns a report of the error that it raised with a traceback.
+fn call(
+    lua: &mut Lua,
+    function: satin::Result<Value>,
+    args: impl IntoLuaMulti,
+) -> Result<usize, String> {
+    let function = function.map_err(|error| error.to_string())?;
+
+    let top = lua.top();
+    lua.push(function);
+    let nargs = args.push_into_lua(lua).map_err(|error| {
+        lua.set_top(top);
+        error.to_string()
+    })?;
+
+    lua.call_with_traceback(nargs, None)
+}
+
+fn call_and_discard(
+    lua: &mut Lua,
+    function: satin::Result<Value>,
+    args: impl IntoLuaMulti,
+) -> Result<(), String> {
+    let top = lua.top();
+    let result = call(lua, function, args);
+    lua.set_top(top);
+    result.map(|_| ())
+}
+
+/// Reads statements and expressions from standard input, running each and printing the
+/// values of expressions, until the input ends.
+fn run_interactively(lua: &mut Lua) {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    while let Some(line) = read_line(&mut lines, "> ") {
+        // A line is first tried as an expression, and otherwise read as a statement,
+        // continued by the lines after it for as long as it is incomplete.
+        let mut source = line;
+        let mut function = lua.load(format!("return {source}"), "=stdin");
+        if function.is_err() {
+            loop {
+                function = lua.load(&source, "=stdin");
+                if !matches!(&function, Err(Error::Syntax(message)) if message.ends_with("<eof>")) {
+                    break;
+                }
+
+                let Some(line) = read_line(&mut lines, ">> ") else {
+                    break;
+                };
+                source.push('\n');
+                source.push_str(&line);
+            }
+        }
+
+        let top = lua.top();
+        let result =
+            call(lua, function.map(|function| function.value()), ()).and_then(
+                |count| match count {
+                    0 => Ok(0),
+                    count => {
+                        let print = lua
+                            .global::<Value>("print")
+                            .map_err(|error| error.to_string())?;
+                        lua.insert_below(count, print);
+                        lua.call_with_traceback(count, Some(0))
+                    }
+                },
+            );
+        lua.set_top(top);
+
+        if let Err(message) = result {
+            report(&message);
+        }
+    }
+
+    println!();
+}
+
+fn read_line(lines: &mut impl Iterator<Item = io::Result<String>>, prompt: &str) -> Option<String> {
+    print!("{prompt}");
+    let _ = io::stdout().flush();
+    lines.next()?.ok()
+}
+
+fn report(message: &str) {
+    eprintln!("{PROGRAM}: {message}");
 }
@@ -0,0 +1,384 @@
+//! Conversions between numbers and strings.
+
+//!
+//! The lexer reads numerals and the runtime converts strings and formats numbers with the same
+//! routines, so that a number reads and prints the same way in source code and at runtime.
+
+use {
+    lexical::{parse_float_options, NumberFormatBuilder},
+    std::io::Write,
+};
+
+const DEC_FLOAT_FORMAT: u128 = NumberFormatBuilder::new().no_special(true).build();
+
+/// A number read from a numeral.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+/// Appends the textual form `tostring` gives an integer.
+pub fn write_int(buffer: &mut Vec<u8>, value: i64) {
+    write!(buffer, "{value}").unwrap();
+}
+
+/// Appends the textual form `tostring` gives a float: the shortest of fixed and scientific
+/// notation with 14 significant digits, as C's `%.14g` would produce, with `.0` appended when
+/// the result would otherwise read as an integer.
+pub fn write_float(buffer: &mut Vec<u8>, value: f64) {
+    let start = buffer.len();
+    write_general(buffer, value, 14);
+
+    if buffer[start..]
+        .iter()
+        .all(|&c| c == b'-' || c.is_ascii_digit())
+    {
+        buffer.extend_from_slice(b".0");
+    }
+}
+
+/// Appends `value` formatted as C's `%.{precision}g` would.
+pub fn write_general(buffer: &mut Vec<u8>, value: f64, precision: usize) {
+    if !value.is_finite() {
+        let sign = if value.is_sign_negative() { "-" } else { "" };
+        let body = if value.is_nan() { "nan" } else { "inf" };
+        write!(buffer, "{sign}{body}").unwrap();
+        return;
+    }
+
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, value);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if exponent < -4 || exponent >= precision as i32 {
+        let mantissa = strip_fraction_zeros(mantissa);
+        let sign = if exponent < 0 { '-' } else { '+' };
+        write!(buffer, "{mantissa}e{sign}{:02}", exponent.abs()).unwrap();
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        let fixed = format!("{value:.decimals$}");
+        buffer.extend_from_slice(strip_fraction_zeros(&fixed).as_bytes());
+    }
+}
+
+fn strip_fraction_zeros(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+/// Converts a string to a number as `tonumber` does without a base: to an integer if it is a
+/// decimal integer numeral in range or any hexadecimal integer numeral, which wraps around,
+/// and otherwise to a float. Whitespace around the numeral is allowed.
+pub fn parse_number(text: &[u8]) -> Option<Number> {
+    let text = trim_space(text);
+    parse_int(text)
+        .map(Number::Int)
+        .or_else(|| parse_float(text).map(Number::Float))
+}
+
+/// Converts a string of digits in the given base, from 2 to 36, to an integer as `tonumber`
+/// does with a base, wrapping around on overflow. Whitespace around the digits and a leading
+/// minus sign are allowed.
+pub fn parse_int_in_base(text: &[u8], base: u32) -> Option<i64> {
+    let (negative, digits) = split_sign(trim_space(text));
+    if digits.is_empty() || !digits.iter().all(|&c| digit_value(c) < base) {
+        return None;
+    }
+
+    let total = digits.iter().fold(0u64, |total, &c| {
+        total
+            .wrapping_mul(base as u64)
+            .wrapping_add(digit_value(c) as u64)
+    });
+
+    Some(if negative {
+        total.wrapping_neg()
+    } else {
+        total
+    } as i64)
+}
+
+/// Whether `c` is whitespace as C's `isspace` sees it, which unlike
+/// [`u8::is_ascii_whitespace`] includes the vertical tab.
+pub fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r')
+}
+
+fn trim_space(text: &[u8]) -> &[u8] {
+    let start = text
+        .iter()
+        .position(|&c| !is_space(c))
+        .unwrap_or(text.len());
+    let end = text
+        .iter()
+        .rposition(|&c| !is_space(c))
+        .map_or(start, |end| end + 1);
+    &text[start..end]
+}
+
+fn split_sign(text: &[u8]) -> (bool, &[u8]) {
+    match text.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, text),
+    }
+}
+
+fn strip_hex_prefix(text: &[u8]) -> Option<&[u8]> {
+    text.strip_prefix(b"0x")
+        .or_else(|| text.strip_prefix(b"0X"))
+}
+
+/// The value of an alphanumeric digit, or `u32::MAX` if `c` is not one.
+fn digit_value(c: u8) -> u32 {
+    (c as char).to_digit(36).unwrap_or(u32::MAX)
+}
+
+fn parse_int(text: &[u8]) -> Option<i64> {
+    let (negative, digits) = split_sign(text);
+
+    let total = match strip_hex_prefix(digits) {
+        Some(digits) => {
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+
+            digits.iter().fold(0u64, |total, &c| {
+                total.wrapping_mul(16).wrapping_add(digit_value(c) as u64)
+            })
+        }
+
+        None => {
+            if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                return None;
+            }
+
+            // A decimal numeral that does not fit is read as a float instead.
+            let total = digits.iter().try_fold(0u64, |total, &c| {
+                total.checked_mul(10)?.checked_add(digit_value(c) as u64)
+            })?;
+
+            if total > i64::MAX as u64 + negative as u64 {
+                return None;
+            }
+
+            total
+        }
+    };
+
+    Some(if negative {
+        total.wrapping_neg()
+    } else {
+        total
+    } as i64)
+}
+
+fn parse_float(text: &[u8]) -> Option<f64> {
+    // Neither `inf` nor `nan` is a numeral.
+    if text.iter().any(|&c| c == b'n' || c == b'N') {
+        return None;
+    }
+
+    let (negative, digits) = split_sign(text);
+    match strip_hex_prefix(digits) {
+        Some(digits) => parse_hex_float(digits).map(|value| if negative { -value } else { value }),
+
+        None => lexical::parse_with_options::<f64, _, DEC_FLOAT_FORMAT>(
+            text,
+            &parse_float_options::STANDARD,
+        )
+        .ok(),
+    }
+}
+
+/// The most significant hexadecimal digits of a float that are read, which is more than
+/// enough for any `f64`. Further digits only scale the result.
+const MAX_SIGNIFICANT_DIGITS: usize = 30;
+
+/// Parses the digits of a hexadecimal float after `0x`: a mantissa with an optional point,
+/// followed by an optional binary exponent introduced by `p`.
+fn parse_hex_float(text: &[u8]) -> Option<f64> {
+    let mut mantissa = 0.0;
+    let mut exponent = 0i32;
+    let mut significant = 0;
+    let mut any_digits = false;
+    let mut seen_point = false;
+    let mut rest = text;
+
+    while let Some((&c, tail)) = rest.split_first() {
+        if c == b'.' && !seen_point {
+            seen_point = true;
+        } else if c.is_ascii_hexdigit() {
+            any_digits = true;
+            if significant == 0 && c == b'0' {
+                // Leading zeros are not significant.
+            } else if significant < MAX_SIGNIFICANT_DIGITS {
+                significant += 1;
+                mantissa = mantissa * 16.0 + digit_value(c) as f64;
+            } else {
+                exponent = exponent.saturating_add(4);
+            }
+
+            if seen_point {
+                exponent = exponent.saturating_sub(4);
+            }
+        } else {
+            break;
+        }
+
+        rest = tail;
+    }
+
+    if !any_digits {
+        return None;
+    }
+
+    if let Some(tail) = rest.strip_prefix(b"p").or_else(|| rest.strip_prefix(b"P")) {
+        let (negative, digits) = split_sign(tail);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+
+        let power = digits.iter().fold(0i32, |power, &c| {
+            power.saturating_mul(10).saturating_add((c - b'0') as i32)
+        });
+
+        exponent = exponent.saturating_add(if negative { -power } else { power });
+        rest = &[];
+    }
+
+    rest.is_empty()
+        .then(|| scale_by_power_of_two(mantissa, exponent))
+}
+
+/// Computes `value * 2^exponent` in steps that neither overflow nor underflow early.
+fn scale_by_power_of_two(mut value: f64, mut exponent: i32) -> f64 {
+    while exponent > 1023 && value.is_finite() && value != 0.0 {
+        value *= 2f64.powi(1023);
+        exponent -= 1023;
+    }
+
+    while exponent < -1022 && value != 0.0 {
+        value *= 2f64.powi(-1022);
+        exponent += 1022;
+    }
+
+    value * 2f64.powi(exponent.clamp(-1022, 1023))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn float(value: f64) -> String {
+        let mut buffer = Vec::new();
+        write_float(&mut buffer, value);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn parse(text: &str) -> Option<Number> {
+        parse_number(text.as_bytes())
+    }
+
+    #[test]
+    fn floats_print_with_14_digits() {
+        assert_eq!(float(1.0), "1.0");
+        assert_eq!(float(-0.0), "-0.0");
+        assert_eq!(float(0.1), "0.1");
+        assert_eq!(float(1.0 / 3.0), "0.33333333333333");
+        assert_eq!(float(1e15), "1e+15");
+        assert_eq!(float(123456789012345.0), "1.2345678901234e+14");
+        assert_eq!(float(2f64.powi(53)), "9.007199254741e+15");
+        assert_eq!(float(1e-5), "1e-05");
+        assert_eq!(float(0.0001), "0.0001");
+        assert_eq!(float(f64::INFINITY), "inf");
+        assert_eq!(float(f64::NEG_INFINITY), "-inf");
+        assert_eq!(float(f64::NAN).trim_start_matches('-'), "nan");
+    }
+
+    #[test]
+    fn general_precision() {
+        let mut buffer = Vec::new();
+        write_general(&mut buffer, 100.0, 0);
+        buffer.push(b' ');
+        write_general(&mut buffer, 0.5, 3);
+        buffer.push(b' ');
+        write_general(&mut buffer, 999.9, 3);
+        assert_eq!(buffer, b"1e+02 0.5 1e+03");
+    }
+
+    #[test]
+    fn integers_and_floats() {
+        assert_eq!(parse("42"), Some(Number::Int(42)));
+        assert_eq!(parse("-42"), Some(Number::Int(-42)));
+        assert_eq!(parse(" \t\x0b12\n"), Some(Number::Int(12)));
+        assert_eq!(parse("1.5"), Some(Number::Float(1.5)));
+        assert_eq!(parse(".5"), Some(Number::Float(0.5)));
+        assert_eq!(parse("5."), Some(Number::Float(5.0)));
+        assert_eq!(parse("1e2"), Some(Number::Float(100.0)));
+        assert_eq!(parse("-1E-2"), Some(Number::Float(-0.01)));
+    }
+
+    #[test]
+    fn decimal_overflow_reads_as_a_float() {
+        assert_eq!(parse("9223372036854775807"), Some(Number::Int(i64::MAX)));
+        assert_eq!(parse("-9223372036854775808"), Some(Number::Int(i64::MIN)));
+        assert_eq!(
+            parse("9223372036854775808"),
+            Some(Number::Float(9223372036854775808.0))
+        );
+        assert_eq!(parse("1e400"), Some(Number::Float(f64::INFINITY)));
+    }
+
+    #[test]
+    fn hexadecimal_integers_wrap_around() {
+        assert_eq!(parse("0xff"), Some(Number::Int(255)));
+        assert_eq!(parse("-0X10"), Some(Number::Int(-16)));
+        assert_eq!(parse("0xffffffffffffffff"), Some(Number::Int(-1)));
+        assert_eq!(parse("0x10000000000000000"), Some(Number::Int(0)));
+    }
+
+    #[test]
+    fn hexadecimal_floats() {
+        assert_eq!(parse("0x1p4"), Some(Number::Float(16.0)));
+        assert_eq!(parse("0x.8"), Some(Number::Float(0.5)));
+        assert_eq!(parse("0xA.8P-1"), Some(Number::Float(5.25)));
+        assert_eq!(parse("-0x1P+1"), Some(Number::Float(-2.0)));
+        assert_eq!(parse("0x1p-1074"), Some(Number::Float(f64::from_bits(1))));
+        assert_eq!(parse("0x1p99999"), Some(Number::Float(f64::INFINITY)));
+        assert_eq!(
+            parse("0x123456789abcdef0123456789abcdef.0"),
+            Some(Number::Float(0x123456789abcdef0123456789abcdefu128 as f64))
+        );
+    }
+
+    #[test]
+    fn malformed_numerals() {
+        for text in [
+            "", " ", "-", "0x", "0x.", "1e", "1e+", "0x1p", "1.2.3", "inf", "nan", "-inf", "1 2",
+            "0x1g", "12a", "--1", "0x1p1.5",
+        ] {
+            assert_eq!(parse(text), None, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn integers_in_other_bases() {
+        assert_eq!(parse_int_in_base(b"ff", 16), Some(255));
+        assert_eq!(parse_int_in_base(b" -Zz ", 36), Some(-1295));
+        assert_eq!(parse_int_in_base(b"1111", 2), Some(15));
+        assert_eq!(parse_int_in_base(b"12", 2), None);
+        assert_eq!(parse_int_in_base(b"0x10", 16), None);
+        assert_eq!(parse_int_in_base(b"", 10), None);
+        assert_eq!(
+            parse_int_in_base(b"10000000000000000", 16),
+            Some(0),
+            "wraps around"
+        );
+    }
+}
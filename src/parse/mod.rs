@@ -1,11 +1,326 @@
-use {crate::lex, lalrpop_util::lalrpop_mod, std::result, thiserror::Error};
+//! Parsing of Lua source code, which builds the IR of a chunk as it goes.
 
-// lalrpop_mod!(grammar, "/parse/parser.rs");
+use {
+    crate::{
+        ir::{Builder, Function, Place},
+        lex::{self, Extras, Token},
+        string_pool::StringPool,
+    },
+    lalrpop_util::{lalrpop_mod, ParseError},
+    logos::Logos,
+    std::{ops::Range, rc::Rc, result},
+    thiserror::Error,
+};
+
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    grammar,
+    "/parse/parser.rs"
+);
 
 pub type Result<T> = result::Result<T, Error>;
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("lexical error: {0}")]
-    LexicalError(#[from] lex::Error),
+    #[error("{0}")]
+    Lexical(#[from] lex::Error),
+
+    #[error("cannot use '...' outside a vararg function")]
+    VarargOutsideVarargFunction,
+
+    /// A statement is a prefix expression that is neither a call nor assigned to.
+    #[error("syntax error")]
+    NotAStatement,
+
+    #[error("unknown attribute '{0}'")]
+    UnknownAttribute(String),
+
+    #[error("multiple to-be-closed variables in local list")]
+    MultipleToBeClosed,
+
+    #[error("attempt to assign to const variable '{0}'")]
+    AssignToConst(String),
+
+    #[error("break outside a loop at line {0}")]
+    BreakOutsideLoop(u32),
+
+    #[error("no visible label '{0}' for <goto> at line {1}")]
+    NoVisibleLabel(String, u32),
+
+    #[error("label '{0}' already defined on line {1}")]
+    DuplicateLabel(String, u32),
+
+    #[error("<goto {0}> at line {1} jumps into the scope of local '{2}'")]
+    JumpIntoScope(String, u32, String),
+
+    /// An error about a token that the parser has since read past, which the message names.
+    #[error("{error}")]
+    AtToken {
+        error: Box<Error>,
+        start: Location,
+        end: Location,
+    },
+}
+
+impl Error {
+    /// Whether the error is about the token the parser has reached, which the message then
+    /// names, rather than about what the source means.
+    fn is_about_token(&self) -> bool {
+        matches!(self, Self::Lexical(_) | Self::NotAStatement)
+    }
+
+    /// Ties the error to the token between `start` and `end`.
+    pub fn at_token(self, start: Location, end: Location) -> Self {
+        Self::AtToken {
+            error: Box::new(self),
+            start,
+            end,
+        }
+    }
+}
+
+/// Where a token is in the source.
+#[derive(Clone, Copy, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Location {
+    pub line: u32,
+    pub offset: usize,
+}
+
+/// A chunk that could not be parsed, with the line the problem was found on.
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub line: u32,
+    pub message: String,
+}
+
+/// What a prefix expression turned out to be, which decides what else it can be used as.
+#[derive(Clone, Copy, Debug)]
+pub enum Prefix {
+    /// A variable, which has not been read, so that it can be assigned to instead.
+    Place(Place),
+
+    /// A call, whose value pack is on the expression stack.
+    Call,
+
+    /// Any other value, which is on the expression stack.
+    Value,
+}
+
+/// Pushes the value of a prefix expression that is used as a value.
+fn build_value(builder: &Builder, prefix: Prefix) {
+    if let Prefix::Place(place) = prefix {
+        builder.build_place(place);
+    }
+}
+
+/// Parses a chunk, interning its names and string literals in `strings`, and returns the IR of
+/// its main function.
+pub fn parse(source: &[u8], strings: Rc<StringPool>) -> result::Result<Function, SyntaxError> {
+    let builder = Builder::new(strings.clone());
+    let mut lexer = Token::lexer_with_extras(source, Extras::new(strings));
+
+    // Lines are counted between tokens, which keeps track of the newlines in comments and
+    // long strings without the lexer having to.
+    let mut line = 1;
+    let mut offset = 0;
+    let mut last = (1, 0..0);
+
+    let tokens = std::iter::from_fn(|| {
+        let Some(token) = lexer.next() else {
+            line += count_lines(&source[offset..]);
+            offset = source.len();
+            last = (line, offset..offset);
+            return None;
+        };
+
+        let span = lexer.span();
+        line += count_lines(&source[offset..span.start]);
+        let start = Location {
+            line,
+            offset: span.start,
+        };
+
+        line += count_lines(&source[span.clone()]);
+        offset = span.end;
+        last = (start.line, span.clone());
+
+        let end = Location {
+            line,
+            offset: span.end,
+        };
+        Some(token.map(|token| (start, token, end)).map_err(Error::from))
+    });
+
+    let result = grammar::ChunkParser::new()
+        .parse(&builder, tokens)
+        .and_then(|()| builder.finish().map_err(ParseError::from));
+
+    let eof_line = 1 + count_lines(source);
+    let near = |span: Range<usize>| format!("'{}'", String::from_utf8_lossy(&source[span]));
+
+    result.map_err(|error| match error {
+        ParseError::InvalidToken { location } => SyntaxError {
+            line: location.line,
+            message: format!(
+                "unexpected symbol near {}",
+                near(location.offset..location.offset + 1)
+            ),
+        },
+
+        ParseError::UnrecognizedEof { expected, .. } => SyntaxError {
+            line: eof_line,
+            message: format!("{} near <eof>", expectation(&expected)),
+        },
+
+        ParseError::UnrecognizedToken {
+            token: (start, _, end),
+            expected,
+        } => SyntaxError {
+            line: start.line,
+            message: format!(
+                "{} near {}",
+                expectation(&expected),
+                near(start.offset..end.offset)
+            ),
+        },
+
+        ParseError::ExtraToken {
+            token: (start, _, end),
+        } => SyntaxError {
+            line: start.line,
+            message: format!("'<eof>' expected near {}", near(start.offset..end.offset)),
+        },
+
+        ParseError::User {
+            error: Error::AtToken { error, start, end },
+        } => SyntaxError {
+            line: start.line,
+            message: format!("{error} near {}", near(start.offset..end.offset)),
+        },
+
+        ParseError::User { error } if error.is_about_token() => {
+            let (line, span) = last.clone();
+            let near = match span.is_empty() {
+                true => "<eof>".to_owned(),
+                false => near(span),
+            };
+
+            SyntaxError {
+                line,
+                message: format!("{error} near {near}"),
+            }
+        }
+
+        ParseError::User { error } => SyntaxError {
+            line: last.0,
+            message: error.to_string(),
+        },
+    })
+}
+
+fn count_lines(text: &[u8]) -> u32 {
+    text.iter().filter(|&&byte| byte == b'\n').count() as u32
+}
+
+/// Describes what the parser expected instead of the token it found: a name if nothing else
+/// would do, the token that closes or separates a construct if that is the only one it could
+/// have been, the end of the chunk after its final return statement, and otherwise nothing in
+/// particular.
+fn expectation(expected: &[String]) -> String {
+    const DELIMITERS: [&str; 10] = [
+        "\"end\"",
+        "\"until\"",
+        "\"then\"",
+        "\"do\"",
+        "\"in\"",
+        "\"=\"",
+        "\")\"",
+        "\"]\"",
+        "\"}\"",
+        "\"::\"",
+    ];
+
+    if expected == ["Name"] {
+        return "<name> expected".to_owned();
+    }
+
+    // Where a statement could begin, some of the delimiters could begin it instead, and only
+    // the keywords that end a block close anything.
+    let statement_may_follow = expected.iter().any(|expected| expected == "Name");
+    let mut delimiters = expected.iter().filter(|expected| {
+        DELIMITERS.contains(&expected.as_str())
+            && (!statement_may_follow || ["\"end\"", "\"until\""].contains(&expected.as_str()))
+    });
+
+    // Only a return statement can be followed by a semicolon but not by another statement,
+    // and one that ends the chunk is not closed by any token.
+    let ends_chunk =
+        || !statement_may_follow && expected.iter().any(|expected| expected == "\";\"");
+
+    match (delimiters.next(), delimiters.next()) {
+        (Some(token), None) => format!("'{}' expected", token.trim_matches('"')),
+        (None, _) if ends_chunk() => "'<eof>' expected".to_owned(),
+        _ => "unexpected symbol".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> String {
+        let error = parse(source.as_bytes(), Rc::new(StringPool::new()))
+            .map(|_| ())
+            .unwrap_err();
+        format!("{}: {}", error.line, error.message)
+    }
+
+    #[test]
+    fn tokens_the_parser_expected() {
+        assert_eq!(error("return 1 2"), "1: '<eof>' expected near '2'");
+        assert_eq!(
+            error("function f() return 1 2 end"),
+            "1: 'end' expected near '2'"
+        );
+        assert_eq!(error("x = 1 1"), "1: unexpected symbol near '1'");
+        assert_eq!(error("if x then"), "1: 'end' expected near <eof>");
+        assert_eq!(error("while x\ny()"), "2: 'do' expected near 'y'");
+        assert_eq!(error("f(1, 2"), "1: ')' expected near <eof>");
+        assert_eq!(error("t = {1, 2"), "1: '}' expected near <eof>");
+        assert_eq!(error("goto 1"), "1: <name> expected near '1'");
+        assert_eq!(error("x = = 1"), "1: unexpected symbol near '='");
+        assert_eq!(error("return 1 +"), "1: unexpected symbol near <eof>");
+        assert_eq!(error("return a > > b"), "1: unexpected symbol near '>'");
+        assert_eq!(error("x"), "1: syntax error near <eof>");
+        assert_eq!(
+            error("return \"abc"),
+            "1: unclosed string literal near '\"abc'"
+        );
+    }
+
+    #[test]
+    fn errors_about_what_the_source_means() {
+        assert_eq!(
+            error("local function f() return ... end"),
+            "1: cannot use '...' outside a vararg function near '...'"
+        );
+        assert_eq!(
+            error("function f()\nlocal t = {...}\nend"),
+            "2: cannot use '...' outside a vararg function near '...'"
+        );
+        assert_eq!(error("break"), "1: break outside a loop at line 1");
+        assert_eq!(
+            error("goto done"),
+            "1: no visible label 'done' for <goto> at line 1"
+        );
+        assert_eq!(
+            error("::a:: ::a::"),
+            "1: label 'a' already defined on line 1"
+        );
+        assert_eq!(
+            error("local x <close>, y <close> = 1, 2"),
+            "1: multiple to-be-closed variables in local list"
+        );
+        assert_eq!(error("local x <big> = 1"), "1: unknown attribute 'big'");
+    }
 }
@@ -0,0 +1,497 @@
+//! Introspection of functions and of the call stacks of threads, and hooks, which are what the
+//! debug library is built on.
+//!
+//! Frames are identified by level, counting from the top of a thread's call stack: level 0 is
+//! the function running in it, level 1 the function that called that one, and so on.
+
+use {
+    super::{
+        state::{short_source, Frame},
+        Function, FunctionRef, Result, State, ThreadRef, UpvalueRef, Value, Variable,
+    },
+    std::rc::Rc,
+};
+
+/// What a hook is called for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HookEvent {
+    /// A function has just been called, and is at level 0 with its arguments in place.
+    Call,
+
+    /// Like [`HookEvent::Call`], for a function called by a tail call.
+    TailCall,
+
+    /// A function is about to return, with its results on top of the stack.
+    Return,
+
+    /// A Lua function is about to run an instruction of the given line, having just started,
+    /// come from another line or jumped back.
+    Line(u32),
+
+    /// A Lua function is about to run an instruction, and as many have run since the last
+    /// count event as the hook's count says.
+    Count,
+}
+
+impl HookEvent {
+    /// The name the debug library passes to hook functions.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Call => "call",
+            Self::TailCall => "tail call",
+            Self::Return => "return",
+            Self::Line(_) => "line",
+            Self::Count => "count",
+        }
+    }
+}
+
+/// The events a hook is called for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HookMask {
+    /// Calls, including tail calls.
+    pub call: bool,
+
+    pub returns: bool,
+    pub line: bool,
+
+    /// After how many instructions a count event comes, or 0 for no count events.
+    pub count: u32,
+}
+
+pub type HookFn = dyn Fn(&mut State, HookEvent) -> Result<()>;
+
+/// What is called as a thread runs, with the events it is called for.
+///
+/// While a hook runs, no hook is called for what it does itself. An error it returns is
+/// raised by the function it was called for.
+#[derive(Clone)]
+pub struct Hook {
+    pub mask: HookMask,
+    pub callback: Rc<HookFn>,
+}
+
+impl Hook {
+    pub fn new(
+        mask: HookMask,
+        callback: impl Fn(&mut State, HookEvent) -> Result<()> + 'static,
+    ) -> Self {
+        Self {
+            mask,
+            callback: Rc::new(callback),
+        }
+    }
+}
+
+/// What is known about a function, and about the call of it in a frame, when it is found
+/// through one.
+#[derive(Clone, Debug)]
+pub struct FunctionInfo {
+    pub function: FunctionRef,
+
+    /// The name of the chunk the function was defined in, or `=[C]` for a native function.
+    pub source: Rc<str>,
+
+    /// The source as error messages show it.
+    pub short_source: String,
+
+    /// `Lua` for a Lua function, `main` for the main function of a chunk and `C` for a native
+    /// function.
+    pub what: &'static str,
+
+    /// The lines the definition starts and ends on, which a native function has not.
+    pub line_defined: Option<u32>,
+    pub last_line_defined: Option<u32>,
+
+    /// The lines that have code in them, in order. This is empty for a native function.
+    pub lines: Vec<u32>,
+
+    pub upvalues: usize,
+    pub parameters: usize,
+    pub is_vararg: bool,
+
+    /// The line the call has reached, if it is of a Lua function that has reported one.
+    pub current_line: Option<u32>,
+
+    /// The variable the caller read the function from, if it is known.
+    pub name: Option<Variable>,
+
+    /// Whether the function was entered by a tail call.
+    pub tail_call: bool,
+
+    /// While a call or return hook runs for the call, the values passed in or out, as the
+    /// position in the frame of the first, counting from 1, and their number.
+    pub transfer: Option<(usize, usize)>,
+}
+
+impl State {
+    /// The frames of `thread`, from the bottom of its call stack up.
+    pub(super) fn frames_of(&self, thread: ThreadRef) -> &[Frame] {
+        if thread == self.thread {
+            &self.frames
+        } else {
+            &self.heap[thread].frames
+        }
+    }
+
+    fn stack_of(&self, thread: ThreadRef) -> &[Value] {
+        if thread == self.thread {
+            &self.stack
+        } else {
+            &self.heap[thread].stack
+        }
+    }
+
+    /// The index in the frames of `thread` of the frame at `level`.
+    fn frame_index(&self, thread: ThreadRef, level: usize) -> Option<usize> {
+        self.frames_of(thread).len().checked_sub(level + 1)
+    }
+
+    /// The number of functions running in `thread`, which is one more than the deepest level.
+    pub fn call_depth(&self, thread: ThreadRef) -> usize {
+        self.frames_of(thread).len()
+    }
+
+    /// The function running at `level` in `thread`.
+    pub fn frame_function(&self, thread: ThreadRef, level: usize) -> Option<FunctionRef> {
+        let index = self.frame_index(thread, level)?;
+        Some(self.frames_of(thread)[index].function)
+    }
+
+    /// What is known about `function` itself.
+    pub fn function_info(&self, function: FunctionRef) -> FunctionInfo {
+        let mut info = FunctionInfo {
+            function,
+            source: Rc::from("=[C]"),
+            short_source: "[C]".to_owned(),
+            what: "C",
+            line_defined: None,
+            last_line_defined: None,
+            lines: Vec::new(),
+            upvalues: 0,
+            parameters: 0,
+            is_vararg: true,
+            current_line: None,
+            name: None,
+            tail_call: false,
+            transfer: None,
+        };
+
+        match &self.heap[function] {
+            Function::Native(native) => info.upvalues = native.upvalues.len(),
+
+            Function::Lua(closure) => {
+                let prototype = &closure.prototype;
+                info.short_source = short_source(&prototype.source);
+                info.source = prototype.source.clone();
+                info.what = match prototype.line_defined {
+                    0 => "main",
+                    _ => "Lua",
+                };
+                info.line_defined = Some(prototype.line_defined);
+                info.last_line_defined = Some(prototype.last_line_defined);
+
+                info.lines = prototype
+                    .positions
+                    .iter()
+                    .map(|position| position.line)
+                    .collect();
+                info.lines.sort_unstable();
+                info.lines.dedup();
+
+                info.upvalues = closure.upvalues.len();
+                info.parameters = prototype.parameters;
+                info.is_vararg = prototype.is_vararg;
+            }
+        }
+
+        info
+    }
+
+    /// What is known about the call running at `level` in `thread`, or nothing if there is no
+    /// such level.
+    pub fn frame_info(&self, thread: ThreadRef, level: usize) -> Option<FunctionInfo> {
+        let index = self.frame_index(thread, level)?;
+        let frames = self.frames_of(thread);
+        let frame = &frames[index];
+
+        let mut info = self.function_info(frame.function);
+        info.current_line = self.current_line(frame);
+        info.tail_call = frame.tail_call;
+        info.transfer = frame.transfer;
+        info.name = self.callee_name(frames, index);
+
+        Some(info)
+    }
+
+    /// The name and value of the `n`th local of the call at `level` in `thread`.
+    ///
+    /// Locals are counted from 1 in the order their scopes started, among those in scope where
+    /// the call has reached. Beyond them, the rest of the frame is seen as temporaries, and
+    /// negative numbers count the extra arguments of a vararg function.
+    pub fn frame_local(&self, thread: ThreadRef, level: usize, n: i64) -> Option<(String, Value)> {
+        let (name, index) = self.find_local(thread, level, n)?;
+        Some((name, self.stack_of(thread)[index]))
+    }
+
+    /// Sets the local that [`State::frame_local`] finds, returning its name, or nothing if
+    /// there is no such local.
+    pub fn set_frame_local(
+        &mut self,
+        thread: ThreadRef,
+        level: usize,
+        n: i64,
+        value: Value,
+    ) -> Option<String> {
+        let (name, index) = self.find_local(thread, level, n)?;
+        if thread == self.thread {
+            self.stack[index] = value;
+        } else {
+            self.heap[thread].stack[index] = value;
+        }
+        Some(name)
+    }
+
+    /// The name of the `n`th local of the call at `level` in `thread`, with its index in the
+    /// thread's stack.
+    fn find_local(&self, thread: ThreadRef, level: usize, n: i64) -> Option<(String, usize)> {
+        let index = self.frame_index(thread, level)?;
+        let frames = self.frames_of(thread);
+        let frame = &frames[index];
+
+        if n < 0 {
+            let n = n.unsigned_abs() as usize;
+            return (n <= frame.varargs.len())
+                .then(|| ("(vararg)".to_owned(), frame.varargs.start + n - 1));
+        }
+
+        let n = usize::try_from(n).ok().filter(|&n| n > 0)?;
+        let name = match &self.heap[frame.function] {
+            Function::Lua(closure) => {
+                let position = frame.position.unwrap_or(0);
+                let local = closure
+                    .prototype
+                    .locals
+                    .iter()
+                    .filter(|local| local.scope.contains(&position))
+                    .nth(n - 1);
+
+                if let Some(local) = local {
+                    return Some((local.name.to_string(), frame.base + local.slot));
+                }
+
+                "(temporary)"
+            }
+
+            Function::Native(_) => "(C temporary)",
+        };
+
+        // The frame ends where the function it called, if any, starts.
+        let limit = match frames.get(index + 1) {
+            Some(next) => next.function_index,
+            None => self.stack_of(thread).len(),
+        };
+
+        let index = frame.base + n - 1;
+        (index < limit).then(|| (name.to_owned(), index))
+    }
+
+    /// The name of the `n`th parameter of `function`, counting from 1, if it is a Lua
+    /// function whose compiler recorded it.
+    pub fn parameter_name(&self, function: FunctionRef, n: usize) -> Option<Rc<str>> {
+        match &self.heap[function] {
+            Function::Lua(closure) if (1..=closure.prototype.parameters).contains(&n) => {
+                let local = closure.prototype.locals.get(n - 1)?;
+                Some(local.name.clone())
+            }
+
+            _ => None,
+        }
+    }
+
+    /// The name and value of the `n`th upvalue of `function`, counting from 1. The upvalues of
+    /// native functions have empty names, and those of Lua functions whose compiler did not
+    /// record their names are `(no name)`.
+    pub fn function_upvalue(&self, function: FunctionRef, n: usize) -> Option<(Rc<str>, Value)> {
+        let index = n.checked_sub(1)?;
+        match &self.heap[function] {
+            Function::Native(native) => Some((Rc::from(""), *native.upvalues.get(index)?)),
+
+            Function::Lua(closure) => {
+                let value = self.upvalue_value(*closure.upvalues.get(index)?);
+                Some((
+                    upvalue_name(closure.prototype.upvalue_names.get(index)),
+                    value,
+                ))
+            }
+        }
+    }
+
+    /// Sets the `n`th upvalue of `function`, returning its name as
+    /// [`State::function_upvalue`] gives it, or nothing if there is no such upvalue.
+    pub fn set_function_upvalue(
+        &mut self,
+        function: FunctionRef,
+        n: usize,
+        value: Value,
+    ) -> Option<Rc<str>> {
+        let index = n.checked_sub(1)?;
+        match &mut self.heap[function] {
+            Function::Native(native) => {
+                *native.upvalues.get_mut(index)? = value;
+                Some(Rc::from(""))
+            }
+
+            Function::Lua(closure) => {
+                let key = *closure.upvalues.get(index)?;
+                let name = upvalue_name(closure.prototype.upvalue_names.get(index));
+                self.set_upvalue_value(key, value);
+                Some(name)
+            }
+        }
+    }
+
+    /// The variable that the `n`th upvalue of `function` refers to, which is shared by every
+    /// closure that captured it. Only Lua functions share their upvalues.
+    pub fn upvalue_id(&self, function: FunctionRef, n: usize) -> Option<UpvalueRef> {
+        match &self.heap[function] {
+            Function::Lua(closure) => closure.upvalues.get(n.checked_sub(1)?).copied(),
+            Function::Native(_) => None,
+        }
+    }
+
+    /// Makes the `n`th upvalue of the Lua function `function` refer to `upvalue`, as found with
+    /// [`State::upvalue_id`]. Returns whether there is such an upvalue to replace.
+    pub fn join_upvalue(&mut self, function: FunctionRef, n: usize, upvalue: UpvalueRef) -> bool {
+        let Function::Lua(closure) = &mut self.heap[function] else {
+            return false;
+        };
+
+        match n
+            .checked_sub(1)
+            .and_then(|index| closure.upvalues.get_mut(index))
+        {
+            Some(slot) => {
+                *slot = upvalue;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Sets or removes the hook of `thread`. Coroutines start with the hook of the thread that
+    /// created them.
+    pub fn set_hook(&mut self, thread: ThreadRef, hook: Option<Hook>) {
+        let count = hook.as_ref().map_or(0, |hook| hook.mask.count);
+        if thread == self.thread {
+            self.hook = hook;
+            self.hook_count = count;
+        } else {
+            let thread = &mut self.heap[thread];
+            thread.hook = hook;
+            thread.hook_count = count;
+        }
+    }
+
+    pub fn hook(&self, thread: ThreadRef) -> Option<Hook> {
+        if thread == self.thread {
+            self.hook.clone()
+        } else {
+            self.heap[thread].hook.clone()
+        }
+    }
+
+    /// Like [`State::set_position`], but also runs the hook for line and count events. Code
+    /// compiled for debugging calls this before every instruction, which is what makes those
+    /// events possible, at the cost of speed.
+    pub fn trace(&mut self, position: u32) -> Result<()> {
+        let frame = self.frames.last_mut().expect("no function is running");
+        let previous = frame.position.replace(position);
+
+        let Some(hook) = &self.hook else {
+            return Ok(());
+        };
+        let mask = hook.mask;
+        if self.in_hook {
+            return Ok(());
+        }
+
+        if mask.count > 0 {
+            self.hook_count = self.hook_count.saturating_sub(1);
+            if self.hook_count == 0 {
+                self.hook_count = mask.count;
+                self.run_hook(HookEvent::Count, (0, 0))?;
+            }
+        }
+
+        if mask.line {
+            let frame = self.frames.last().expect("no function is running");
+            if let Function::Lua(closure) = &self.heap[frame.function] {
+                let positions = &closure.prototype.positions;
+                let line = positions[position as usize].line;
+                let new_line = match previous {
+                    Some(previous) => {
+                        position <= previous || positions[previous as usize].line != line
+                    }
+                    None => true,
+                };
+
+                if new_line {
+                    self.run_hook(HookEvent::Line(line), (0, 0))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs the running thread's hook for `event` in the running function's frame, if it has
+    /// one that wants the event and is not already running. `transfer` is what a call or
+    /// return hook reports as the values passed in or out.
+    pub(super) fn run_hook(&mut self, event: HookEvent, transfer: (usize, usize)) -> Result<()> {
+        let Some(hook) = &self.hook else {
+            return Ok(());
+        };
+
+        let mask = hook.mask;
+        let wanted = match event {
+            HookEvent::Call | HookEvent::TailCall => mask.call,
+            HookEvent::Return => mask.returns,
+            HookEvent::Line(_) => mask.line,
+            HookEvent::Count => mask.count > 0,
+        };
+
+        if !wanted || self.in_hook {
+            return Ok(());
+        }
+
+        let callback = hook.callback.clone();
+        let transfer = match event {
+            HookEvent::Call | HookEvent::TailCall | HookEvent::Return => Some(transfer),
+            HookEvent::Line(_) | HookEvent::Count => None,
+        };
+        self.set_transfer(transfer);
+
+        // Whatever the hook leaves on the stack is discarded, so that the results of a
+        // returning function are still on top.
+        let top = self.stack.len();
+        self.in_hook = true;
+        let result = callback(self, event);
+        self.in_hook = false;
+        self.stack.truncate(top);
+
+        self.set_transfer(None);
+        result
+    }
+
+    fn set_transfer(&mut self, transfer: Option<(usize, usize)>) {
+        self.frames
+            .last_mut()
+            .expect("no function is running")
+            .transfer = transfer;
+    }
+}
+
+fn upvalue_name(name: Option<&Rc<str>>) -> Rc<str> {
+    name.cloned().unwrap_or_else(|| Rc::from("(no name)"))
+}
@@ -0,0 +1,346 @@
+use {
+    super::{pattern, Value},
+    std::fmt::{self, Display, Formatter},
+    thiserror::Error,
+};
+
+#[derive(Clone, Debug, Error)]
+pub enum Error {
+    /// A value raised by `error`, or the result of a message handler. Converting the other
+    /// variants to a value gives their message as a string.
+    #[error("(error object is a {} value)", .0.type_name())]
+    Value(Value),
+
+    /// An error raised at a known point in the source, which `location` gives as `chunk:line: `.
+    /// The location is empty if the error was raised where there is no source, so that no
+    /// enclosing frame's location is given instead.
+    #[error("{location}{error}")]
+    Located { location: String, error: Box<Error> },
+
+    #[error("bad argument #{position} to '{function}' ({message})")]
+    Argument {
+        position: usize,
+        function: &'static str,
+        message: String,
+    },
+
+    #[error("index is nil")]
+    NilIndex,
+
+    #[error("index is NaN")]
+    NanIndex,
+
+    #[error("invalid key to 'next'")]
+    InvalidNextKey,
+
+    /// An operation was applied to a value that neither supports it nor has a metamethod for
+    /// it. `type_name` honours the `__name` field of the value's metatable.
+    ///
+    /// `operand` is the position of the value among the operation's operands, if it is one of
+    /// them rather than a value reached through a metamethod. Compiled code uses it to name
+    /// the variable the value came from, with [`Error::name_operand`].
+    #[error("attempt to {operation} a {type_name} value{}", describe(variable))]
+    Operand {
+        operation: &'static str,
+        type_name: String,
+        operand: Option<usize>,
+        variable: Option<Variable>,
+    },
+
+    #[error("attempt to compare two {0} values")]
+    CompareSame(String),
+
+    #[error("attempt to compare {0} with {1}")]
+    Compare(String, String),
+
+    /// A numeric `for` loop was given something other than a number as its initial value,
+    /// limit or step, as named.
+    #[error("'for' {0} must be a number")]
+    ForNotNumber(&'static str),
+
+    #[error("'for' step is zero")]
+    ForStepZero,
+
+    #[error("number has no integer representation")]
+    NoIntegerRepresentation,
+
+    #[error("attempt to perform 'n//0'")]
+    DivideByZero,
+
+    #[error("attempt to perform 'n%0'")]
+    ModuloByZero,
+
+    #[error("'{0}' chain too long; possible loop")]
+    ChainTooLong(&'static str),
+
+    #[error("stack overflow")]
+    StackOverflow,
+
+    #[error("cannot change a protected metatable")]
+    ProtectedMetatable,
+
+    #[error("'__tostring' must return a string")]
+    TostringNotString,
+
+    #[error("variable '{0}' got a non-closable value")]
+    NonClosable(String),
+
+    #[error("attempt to yield from outside a coroutine")]
+    YieldOutsideCoroutine,
+
+    #[error("attempt to yield across a C-call boundary")]
+    YieldAcrossBoundary,
+
+    #[error("cannot close a {0} coroutine")]
+    CloseActive(&'static str),
+
+    #[error("assertion failed!")]
+    AssertionFailed,
+
+    /// A chunk was loaded in a mode that does not allow its kind, binary or text.
+    #[error("attempt to load a {0} chunk (mode is '{1}')")]
+    ChunkMode(&'static str, String),
+
+    /// Source text could not be compiled. The message starts with the location of the
+    /// problem, as `chunk:line: `.
+    #[error("{0}")]
+    Syntax(String),
+
+    /// Source text was loaded into a state that has not been given a compiler.
+    #[error("no compiler to load source text with")]
+    NoCompiler,
+
+    #[error("reader function must return a string")]
+    ReaderNotString,
+
+    #[error("cannot {action} {file}: {message}")]
+    File {
+        action: &'static str,
+        file: String,
+        message: String,
+    },
+
+    #[error(transparent)]
+    Pattern(#[from] pattern::Error),
+
+    #[error("invalid use of '%' in replacement string")]
+    InvalidReplacementEscape,
+
+    /// A `gsub` replacement table or function produced a value that is neither a string, a
+    /// number nor false or nil, whose type is given.
+    #[error("invalid replacement value (a {0})")]
+    InvalidReplacement(&'static str),
+
+    #[error("resulting string too large")]
+    StringTooLarge,
+
+    #[error("invalid format string to 'format'")]
+    FormatTooLong,
+
+    /// A `string.format` conversion, given with its `%`, is not one of those supported.
+    #[error("invalid conversion '{0}' to 'format'")]
+    InvalidConversion(String),
+
+    /// A `string.format` conversion has flags, a width or a precision it does not take.
+    #[error("invalid conversion specification: '{0}'")]
+    InvalidConversionSpecification(String),
+
+    #[error("specifier '%q' cannot have modifiers")]
+    QuoteModifiers,
+
+    #[error("invalid format option '{0}'")]
+    InvalidFormatOption(char),
+
+    #[error("integral size ({0}) out of limits [1,16]")]
+    IntegralSize(usize),
+
+    #[error("missing size for format option 'c'")]
+    MissingCharSize,
+
+    #[error("{0}-byte integer does not fit into Lua Integer")]
+    IntegerTooLarge(usize),
+
+    #[error("object length is not an integer")]
+    LengthNotInteger,
+
+    #[error("wrong number of arguments")]
+    WrongArgumentCount,
+
+    #[error("wrong number of arguments to '{0}'")]
+    WrongArgumentCountTo(&'static str),
+
+    /// `table.concat` met an element that is neither a string nor a number, at the given
+    /// index.
+    #[error("invalid value (at index {0}) in table for 'concat'")]
+    InvalidConcatValue(i64),
+
+    #[error("too many results to unpack")]
+    TooManyResults,
+
+    /// The order function given to `table.sort` is not consistent.
+    #[error("invalid order function for sorting")]
+    InvalidOrder,
+
+    #[error("invalid UTF-8 code")]
+    InvalidUtf8,
+
+    #[error("initial position is a continuation byte")]
+    ContinuationByte,
+
+    #[error("string slice too long")]
+    StringSliceTooLong,
+
+    #[error("attempt to use a closed file")]
+    ClosedFile,
+
+    /// The default input or output file, as named, was used after being closed.
+    #[error("default {0} file is closed")]
+    ClosedDefaultFile(&'static str),
+
+    /// The iterator returned by `lines` was called after its file was closed.
+    #[error("file is already closed")]
+    FileAlreadyClosed,
+
+    #[error("cannot open file '{file}' ({message})")]
+    OpenFile { file: String, message: String },
+
+    /// Reading for the iterator returned by `lines` failed, with the given message.
+    #[error("{0}")]
+    ReadFailed(String),
+
+    #[error("unable to generate a unique filename")]
+    UniqueFilename,
+
+    #[error("field '{0}' missing in date table")]
+    MissingDateField(&'static str),
+
+    #[error("field '{0}' is not an integer")]
+    DateFieldNotInteger(&'static str),
+
+    #[error("field '{0}' is out-of-bound")]
+    DateFieldOutOfBounds(&'static str),
+
+    /// The result of `os.time` or `os.date`, as named, falls in a year that cannot be
+    /// represented.
+    #[error("{0} result cannot be represented in this installation")]
+    TimeOutOfRange(&'static str),
+
+    /// The host refused to end the process, for the given reason.
+    #[error("cannot exit: {0}")]
+    Exit(String),
+
+    /// A field of the `package` table that `require` relies on, as named, is not of the type
+    /// it must be.
+    #[error("'package.{0}' must be a {1}")]
+    PackageField(&'static str, &'static str),
+
+    /// No searcher found the module. The message holds what each of them said, each on a line
+    /// of its own.
+    #[error("module '{name}' not found:{message}")]
+    ModuleNotFound { name: String, message: String },
+
+    #[error("error loading module '{name}' from file '{file}':\n\t{message}")]
+    LoadModule {
+        name: String,
+        file: String,
+        message: String,
+    },
+
+    /// A value raised in Lua that has been taken out of the state, as its message.
+    #[error("{0}")]
+    Runtime(String),
+
+    /// A Lua value could not be converted to the named Rust type, for the given reason if
+    /// there is more to say than the type of the value.
+    #[error("cannot convert a {from} value to {to}{}", detail(message))]
+    FromLua {
+        from: String,
+        to: &'static str,
+        message: Option<String>,
+    },
+
+    /// A method of a userdata was called on a value that is not a userdata of the type it
+    /// belongs to, as named.
+    #[error("{expected} expected, got {got}")]
+    UserDataMismatch { expected: &'static str, got: String },
+
+    #[error("userdata already borrowed")]
+    UserDataBorrowed,
+
+    #[error("userdata already mutably borrowed")]
+    UserDataBorrowedMut,
+
+    /// A userdata was assigned a field that has no setter, as named.
+    #[error("attempt to set an unknown field '{0}'")]
+    UnknownField(String),
+
+    /// A value could not be serialized to or deserialized from Lua, as the conversion or the
+    /// type being converted says.
+    #[cfg(feature = "serde")]
+    #[error("{0}")]
+    Serde(String),
+
+    /// The message handler of an `xpcall` failed itself.
+    #[error("error in error handling")]
+    ErrorHandling,
+}
+
+impl Error {
+    /// Names the variable that the offending operand of an [`Error::Operand`] came from,
+    /// given the position of the operand. Other errors are returned as they are.
+    pub fn name_operand(self, name: impl FnOnce(usize) -> Option<Variable>) -> Self {
+        match self {
+            Self::Operand {
+                operation,
+                type_name,
+                operand: Some(operand),
+                variable: None,
+            } => Self::Operand {
+                operation,
+                type_name,
+                operand: Some(operand),
+                variable: name(operand),
+            },
+
+            error => error,
+        }
+    }
+}
+
+/// A variable that a value was read from, as error messages describe it.
+#[derive(Clone, Debug)]
+pub enum Variable {
+    Global(String),
+    Local(String),
+    Upvalue(String),
+    Field(String),
+    Method(String),
+}
+
+impl Display for Variable {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (kind, name) = match self {
+            Self::Global(name) => ("global", name),
+            Self::Local(name) => ("local", name),
+            Self::Upvalue(name) => ("upvalue", name),
+            Self::Field(name) => ("field", name),
+            Self::Method(name) => ("method", name),
+        };
+
+        write!(f, "{kind} '{name}'")
+    }
+}
+
+fn describe(variable: &Option<Variable>) -> String {
+    match variable {
+        Some(variable) => format!(" ({variable})"),
+        None => String::new(),
+    }
+}
+
+fn detail(message: &Option<String>) -> String {
+    match message {
+        Some(message) => format!(" ({message})"),
+        None => String::new(),
+    }
+}
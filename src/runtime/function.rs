@@ -0,0 +1,165 @@
+use {
+    super::{Result, State, ThreadRef, UpvalueRef, Value, Variable},
+    std::{any::Any, mem, ops::Range, rc::Rc},
+};
+
+/// The body of a function implemented in Rust, or compiled from Lua.
+///
+/// It runs in a new stack frame holding its arguments, which it reads with [`State::arg`], and
+/// returns how many values from the top of the stack are its results.
+pub type Callback = dyn Fn(&mut State) -> Result<usize>;
+
+/// Compiles the text of a chunk, given the name it is loaded under, to the prototype of its
+/// main function, which captures nothing but the environment.
+pub type Compiler = dyn Fn(&mut State, &[u8], &str) -> Result<Rc<Prototype>>;
+
+pub enum Function {
+    Native(NativeFunction),
+    Lua(Closure),
+}
+
+impl Function {
+    pub fn native(callback: impl Fn(&mut State) -> Result<usize> + 'static) -> Self {
+        Self::Native(NativeFunction {
+            callback: Rc::new(callback),
+            upvalues: Box::new([]),
+        })
+    }
+
+    /// Estimates the memory used by the function, in bytes.
+    pub(super) fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + match self {
+                Self::Native(native) => native.upvalues.len() * mem::size_of::<Value>(),
+                Self::Lua(closure) => closure.upvalues.len() * mem::size_of::<UpvalueRef>(),
+            }
+    }
+}
+
+pub struct NativeFunction {
+    pub callback: Rc<Callback>,
+
+    /// Values the function can read with [`State::upvalue`]. Unlike values captured by the
+    /// callback itself, these are visible to the collector.
+    pub upvalues: Box<[Value]>,
+}
+
+/// A Lua function, sharing its captured variables with every other closure that captured the
+/// same locals.
+pub struct Closure {
+    pub prototype: Rc<Prototype>,
+    pub upvalues: Box<[UpvalueRef]>,
+}
+
+/// What all the closures created from one function expression have in common.
+pub struct Prototype {
+    pub code: Rc<Callback>,
+
+    /// What the compiler lowered the function to, which `code` runs. Compiled code finds the
+    /// functions it calls here, to run them itself rather than through [`State::call`].
+    pub body: Rc<dyn Any>,
+
+    /// The values on the heap that the code refers to, such as the strings among its
+    /// constants, which the collector keeps alive for as long as a closure of the prototype or
+    /// of one enclosing it is.
+    pub constants: Box<[Value]>,
+
+    /// The name of the chunk the function was defined in, as it was loaded: `@` followed by a
+    /// file name, `=` followed by a description, or otherwise the source text itself.
+    pub source: Rc<str>,
+
+    /// The lines the function's definition starts and ends on, both 0 for a main chunk.
+    pub line_defined: u32,
+    pub last_line_defined: u32,
+
+    /// What is known about each of the function's instructions, which compiled code reports
+    /// its progress through with [`State::set_position`].
+    pub positions: Box<[Position]>,
+
+    /// For each instruction, the slots of the frame that hold nothing the function reads
+    /// again. They are cleared before the collector looks at the stack, so that the values
+    /// left in them can be collected. A compiler may leave this empty, or shorter than
+    /// [`Prototype::positions`].
+    pub dead_slots: Box<[Box<[usize]>]>,
+
+    /// The number of fixed parameters, which occupy the first slots of the frame.
+    pub parameters: usize,
+
+    /// Whether arguments beyond the fixed parameters are kept for [`State::push_varargs`]
+    /// rather than discarded.
+    pub is_vararg: bool,
+
+    /// Where a new closure finds each of its upvalues, in order.
+    pub captures: Box<[Capture]>,
+
+    /// The names of the upvalues, in the same order, for the debug library. A compiler may
+    /// leave this empty, or shorter than [`Prototype::captures`].
+    pub upvalue_names: Box<[Rc<str>]>,
+
+    /// The named locals, parameters first and then in the order their scopes start, for the
+    /// debug library. A compiler may leave this empty, in which case locals are only seen as
+    /// temporaries.
+    pub locals: Box<[LocalVariable]>,
+
+    /// The prototypes of the functions nested directly within this one.
+    pub prototypes: Box<[Rc<Prototype>]>,
+}
+
+impl Prototype {
+    /// Calls `mark` with the constants of the prototype and of every prototype nested within
+    /// it, which closures of it may yet create closures of.
+    pub(super) fn trace(&self, mark: &mut impl FnMut(Value)) {
+        self.constants.iter().for_each(|&value| mark(value));
+        for prototype in self.prototypes.iter() {
+            prototype.trace(mark);
+        }
+    }
+}
+
+/// The source of an instruction of a compiled function.
+#[derive(Clone, Debug)]
+pub struct Position {
+    pub line: u32,
+
+    /// For a call, the variable that the called value was read from, which names the
+    /// callee's frame in tracebacks.
+    pub callee: Option<Variable>,
+}
+
+/// A local variable of a compiled function, as the debug library sees it.
+#[derive(Clone, Debug)]
+pub struct LocalVariable {
+    pub name: Rc<str>,
+
+    /// The slot of the frame the local lives in.
+    pub slot: usize,
+
+    /// The instructions, indexing [`Prototype::positions`], over which the local is in scope:
+    /// from the first, which follows its declaration, up to but not including the end. The
+    /// scope of a parameter starts at 0.
+    pub scope: Range<u32>,
+}
+
+/// The runtime counterpart of [`crate::ir::Capture`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Capture {
+    /// The local in the given slot of the frame creating the closure.
+    Local(usize),
+
+    /// The upvalue with the given index, counting from 0, of the closure creating the closure.
+    Upvalue(usize),
+
+    /// The table of globals.
+    Environment,
+}
+
+/// A variable captured by a closure.
+#[derive(Clone, Copy, Debug)]
+pub enum Upvalue {
+    /// The variable is a local that is still in scope, at the given index in the stack of the
+    /// given thread.
+    Open(ThreadRef, usize),
+
+    /// The variable has outlived its scope and now lives in the upvalue itself.
+    Closed(Value),
+}
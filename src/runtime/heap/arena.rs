@@ -0,0 +1,46 @@
+use cranelift_entity::{EntityRef, PrimaryMap};
+
+/// Slot storage for one kind of heap object, reusing the slots of freed objects.
+pub struct Arena<K: EntityRef, T> {
+    pub objects: PrimaryMap<K, Option<T>>,
+    free: Vec<K>,
+}
+
+impl<K: EntityRef, T> Arena<K, T> {
+    pub fn new() -> Self {
+        Self {
+            objects: PrimaryMap::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, object: T) -> K {
+        match self.free.pop() {
+            Some(key) => {
+                self.objects[key] = Some(object);
+                key
+            }
+
+            None => self.objects.push(Some(object)),
+        }
+    }
+
+    pub fn free(&mut self, key: K) -> T {
+        let object = self.objects[key].take().unwrap();
+        self.free.push(key);
+        object
+    }
+
+    pub fn get(&self, key: K) -> Option<&T> {
+        self.objects.get(key)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: K) -> Option<&mut T> {
+        self.objects.get_mut(key)?.as_mut()
+    }
+
+    /// Returns one past the highest key that has ever been allocated.
+    pub fn end(&self) -> usize {
+        self.objects.len()
+    }
+}
@@ -0,0 +1,1332 @@
+//! An incremental and generational tri-color mark-and-sweep collector, modelled on the one in
+//! the reference implementation.
+//!
+//! In incremental mode a cycle is interleaved with the mutator: marking propagates from a gray
+//! list a little at a time, then an atomic phase re-scans the roots and every object that was
+//! written to while black, and finally the heap is swept in small slices. Two whites are used so
+//! that objects allocated during the sweep are never mistaken for garbage.
+//!
+//! In generational mode every collection is atomic. A minor collection only marks and sweeps
+//! young objects, treating old objects as alive; old objects that were written to since the
+//! last minor collection ("touched"), and those that were promoted by it, are traversed as well
+//! so that the young objects they point to survive.
+//!
+//! Weak tables and finalizers follow the reference implementation's semantics: entries whose
+//! values are only weakly reachable are cleared before finalizers are considered, objects
+//! with pending finalizers are then resurrected along with everything they refer to, and only
+//! after that are entries with dead keys cleared. A resurrected object is therefore removed
+//! from weak values right away but stays as a weak key until the collection after its
+//! finalizer has run. Finalizers run in the reverse order in which their objects were
+//! registered.
+
+use {
+    super::{arena::Arena, Headers, Heap, ObjectRef, StrRef, TableRef},
+    crate::runtime::{Function, Metamethod, Table, Upvalue, Value},
+    cranelift_entity::{EntityRef, PrimaryMap, SecondaryMap},
+    std::{cmp, collections::VecDeque, fmt, mem, ops::Range},
+};
+
+/// The number of slots visited by a single sweep step.
+const SWEEP_SLOTS: usize = 100;
+
+/// The arenas, in the order in which they are swept.
+const STRINGS: usize = 0;
+const TABLES: usize = 1;
+const FUNCTIONS: usize = 2;
+const UPVALUES: usize = 3;
+const THREADS: usize = 4;
+const USERDATA: usize = 5;
+const ARENA_COUNT: usize = 6;
+
+/// Indices into [`Collector::weak`].
+const WEAK_VALUES: usize = 0;
+const EPHEMERONS: usize = 1;
+const ALL_WEAK: usize = 2;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Header {
+    pub color: Color,
+    pub age: Age,
+    pub size: usize,
+
+    /// Whether the object is in the list of objects with finalizers.
+    pub finalizable: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Color {
+    #[default]
+    White0,
+    White1,
+    Gray,
+    Black,
+}
+
+impl Color {
+    fn is_white(self) -> bool {
+        matches!(self, Self::White0 | Self::White1)
+    }
+
+    fn other_white(self) -> Self {
+        match self {
+            Self::White0 => Self::White1,
+            _ => Self::White0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Age {
+    #[default]
+    New,
+    Survival,
+    Old1,
+    Old,
+    Touched1,
+    Touched2,
+}
+
+impl Age {
+    fn is_young(self) -> bool {
+        matches!(self, Self::New | Self::Survival)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    Incremental,
+    Generational,
+}
+
+impl Mode {
+    /// The name `collectgarbage` uses for the mode.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Incremental => "incremental",
+            Self::Generational => "generational",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Phase {
+    Pause,
+    Propagate,
+
+    /// Sweeping the arena with the given index, from the given slot onwards.
+    Sweep(usize, usize),
+}
+
+pub struct Collector {
+    mode: Mode,
+    phase: Phase,
+    running: bool,
+    white: Color,
+
+    /// Set while a minor collection is marking, during which old objects count as marked.
+    minor: bool,
+
+    gray: Vec<ObjectRef>,
+    gray_again: Vec<ObjectRef>,
+    young: Vec<ObjectRef>,
+    old1: Vec<ObjectRef>,
+    touched: Vec<ObjectRef>,
+
+    /// Weak tables found while marking, split into those with weak values only, those with weak
+    /// keys only (ephemerons) and those with both.
+    weak: [Vec<TableRef>; 3],
+
+    /// Objects registered for finalization, oldest first.
+    finalizable: Vec<ObjectRef>,
+
+    /// Unreachable objects whose finalizers have yet to run.
+    to_finalize: VecDeque<ObjectRef>,
+
+    /// Estimated size in bytes of every allocated object.
+    total: usize,
+
+    /// Bytes allocated beyond the point where the next step is due. Collection is due once
+    /// this becomes positive.
+    debt: isize,
+
+    /// The value of `total` after the last major collection.
+    major_base: usize,
+
+    pause: u32,
+    step_multiplier: u32,
+    step_size: u32,
+    minor_multiplier: u32,
+    major_multiplier: u32,
+}
+
+impl Collector {
+    pub fn new() -> Self {
+        Self {
+            mode: Mode::Incremental,
+            phase: Phase::Pause,
+            running: true,
+            white: Color::White0,
+            minor: false,
+            gray: Vec::new(),
+            gray_again: Vec::new(),
+            young: Vec::new(),
+            old1: Vec::new(),
+            touched: Vec::new(),
+            weak: Default::default(),
+            finalizable: Vec::new(),
+            to_finalize: VecDeque::new(),
+            total: 0,
+            debt: 0,
+            major_base: 0,
+            pause: 200,
+            step_multiplier: 100,
+            step_size: 13,
+            minor_multiplier: 20,
+            major_multiplier: 100,
+        }
+    }
+
+    /// Accounts for a newly allocated object and returns its header.
+    pub fn new_header(&mut self, size: usize) -> Header {
+        self.total += size;
+        self.debt += size as isize;
+        Header {
+            color: self.white,
+            age: Age::New,
+            size,
+            finalizable: false,
+        }
+    }
+
+    pub fn track(&mut self, object: ObjectRef) {
+        if self.mode == Mode::Generational {
+            self.young.push(object);
+        }
+    }
+
+    /// The write barrier, invoked before an object is mutated.
+    ///
+    /// This is a "backward" barrier: rather than marking whatever is being stored, the object
+    /// being written to is queued to be traversed again.
+    pub fn barrier(&mut self, object: ObjectRef, header: &mut Header) {
+        match self.mode {
+            Mode::Incremental => {
+                if self.phase == Phase::Propagate && header.color == Color::Black {
+                    header.color = Color::Gray;
+                    self.gray_again.push(object);
+                }
+            }
+
+            Mode::Generational => match header.age {
+                Age::Old1 | Age::Old => {
+                    header.age = Age::Touched1;
+                    self.touched.push(object);
+                }
+
+                Age::Touched2 => header.age = Age::Touched1,
+
+                _ => (),
+            },
+        }
+    }
+
+    pub fn register_finalizer(&mut self, object: ObjectRef, header: &mut Header) {
+        if !header.finalizable {
+            header.finalizable = true;
+            self.finalizable.push(object);
+        }
+    }
+
+    /// Revives an object that is about to be swept because it has just become reachable again,
+    /// which can only happen to interned strings.
+    pub fn resurrect(&mut self, header: &mut Header) {
+        if matches!(self.phase, Phase::Sweep(STRINGS, _))
+            && header.color == self.white.other_white()
+        {
+            header.color = self.white;
+        }
+    }
+
+    fn set_pause(&mut self) {
+        let threshold = self.total / 100 * self.pause as usize;
+        self.debt = self.total as isize - cmp::max(threshold, self.total) as isize;
+    }
+
+    fn set_minor_debt(&mut self) {
+        self.debt = -((self.total / 100 * self.minor_multiplier as usize) as isize);
+    }
+}
+
+impl Heap {
+    pub fn gc_mode(&self) -> Mode {
+        self.gc.mode
+    }
+
+    pub fn gc_is_running(&self) -> bool {
+        self.gc.running
+    }
+
+    pub fn gc_stop(&mut self) {
+        self.gc.running = false;
+    }
+
+    pub fn gc_restart(&mut self) {
+        self.gc.running = true;
+        self.gc.debt = 0;
+    }
+
+    /// The total memory in use, in kilobytes.
+    pub fn gc_count(&self) -> f64 {
+        self.gc.total as f64 / 1024.0
+    }
+
+    /// Removes the next object whose finalizer is due from the queue. The caller must keep the
+    /// object reachable until its `__gc` metamethod has been called.
+    pub fn take_finalizer(&mut self) -> Option<Value> {
+        self.gc.to_finalize.pop_front().map(Value::from)
+    }
+
+    /// Queues the finalizers of every object registered for finalization, whether it is
+    /// reachable or not, as is done when a state is closed.
+    pub fn finalize_all(&mut self) {
+        self.separate_finalizable(true);
+    }
+
+    /// Whether enough has been allocated since the last step for [`Heap::gc_step`] to do work.
+    pub fn gc_is_due(&self) -> bool {
+        self.gc.running && self.gc.debt > 0
+    }
+
+    /// Performs a step of collection if one is due, returning whether a cycle completed.
+    pub fn gc_step(&mut self, roots: &[Value]) -> bool {
+        self.gc_is_due() && self.perform_step(roots)
+    }
+
+    /// Performs collection work as if `kilobytes` more had been allocated, or a single basic
+    /// step if that is zero, regardless of whether the collector is stopped. Returns whether a
+    /// cycle completed.
+    pub fn gc_step_by(&mut self, roots: &[Value], kilobytes: i64) -> bool {
+        let due = if kilobytes <= 0 {
+            true
+        } else {
+            self.gc.debt = self
+                .gc
+                .debt
+                .saturating_add(kilobytes.saturating_mul(1024) as isize);
+            self.gc.debt > 0
+        };
+
+        due && self.perform_step(roots)
+    }
+
+    /// Performs a full collection.
+    pub fn gc_collect(&mut self, roots: &[Value]) {
+        match self.gc.mode {
+            Mode::Incremental => self.full_incremental(roots),
+            Mode::Generational => self.major_collection(roots),
+        }
+    }
+
+    /// Switches to incremental mode, changing each parameter that is not zero. Returns the
+    /// previous mode.
+    pub fn gc_set_incremental(&mut self, pause: u32, step_multiplier: u32, step_size: u32) -> Mode {
+        if pause != 0 {
+            self.gc.pause = pause;
+        }
+
+        if step_multiplier != 0 {
+            self.gc.step_multiplier = step_multiplier;
+        }
+
+        if step_size != 0 {
+            self.gc.step_size = step_size.min(usize::BITS - 2);
+        }
+
+        let previous = self.gc.mode;
+        if previous == Mode::Generational {
+            self.gc.mode = Mode::Incremental;
+            self.gc.phase = Phase::Pause;
+            self.gc.young.clear();
+            self.gc.old1.clear();
+            self.gc.touched.clear();
+            self.gc.set_pause();
+        }
+
+        previous
+    }
+
+    /// Switches to generational mode, changing each parameter that is not zero. Returns the
+    /// previous mode.
+    pub fn gc_set_generational(
+        &mut self,
+        roots: &[Value],
+        minor_multiplier: u32,
+        major_multiplier: u32,
+    ) -> Mode {
+        if minor_multiplier != 0 {
+            self.gc.minor_multiplier = minor_multiplier;
+        }
+
+        if major_multiplier != 0 {
+            self.gc.major_multiplier = major_multiplier;
+        }
+
+        let previous = self.gc.mode;
+        if previous == Mode::Incremental {
+            while self.gc.phase != Phase::Pause {
+                self.single_step(roots);
+            }
+
+            self.gc.mode = Mode::Generational;
+            self.major_collection(roots);
+        }
+
+        previous
+    }
+
+    fn perform_step(&mut self, roots: &[Value]) -> bool {
+        match self.gc.mode {
+            Mode::Incremental => self.incremental_step(roots),
+
+            Mode::Generational => {
+                let major_threshold = self.gc.major_base
+                    + self.gc.major_base / 100 * self.gc.major_multiplier as usize;
+
+                if self.gc.total > major_threshold {
+                    self.major_collection(roots);
+                } else {
+                    self.minor_collection(roots);
+                }
+
+                true
+            }
+        }
+    }
+
+    fn incremental_step(&mut self, roots: &[Value]) -> bool {
+        let step_size = 1usize << self.gc.step_size;
+        let mut work = (step_size / 100 * self.gc.step_multiplier.max(1) as usize) as isize;
+
+        loop {
+            work -= self.single_step(roots) as isize;
+
+            if self.gc.phase == Phase::Pause {
+                self.gc.set_pause();
+                return true;
+            }
+
+            if work <= 0 {
+                self.gc.debt = -(step_size as isize);
+                return false;
+            }
+        }
+    }
+
+    fn full_incremental(&mut self, roots: &[Value]) {
+        // Finish whatever cycle is in progress, since objects it has already marked might have
+        // become garbage since, and then run a complete one.
+        while self.gc.phase != Phase::Pause {
+            self.single_step(roots);
+        }
+
+        self.single_step(roots);
+
+        while self.gc.phase != Phase::Pause {
+            self.single_step(roots);
+        }
+
+        self.gc.set_pause();
+    }
+
+    /// Advances the incremental state machine, returning an estimate of the work done.
+    fn single_step(&mut self, roots: &[Value]) -> usize {
+        match self.gc.phase {
+            Phase::Pause => {
+                self.gc.gray.clear();
+                self.gc.gray_again.clear();
+                self.gc.weak.iter_mut().for_each(Vec::clear);
+                self.mark_roots(roots);
+                self.gc.phase = Phase::Propagate;
+                roots.len() + 1
+            }
+
+            Phase::Propagate => match self.gc.gray.pop() {
+                Some(object) => self.traverse(object),
+
+                None => {
+                    self.atomic(roots);
+                    self.gc.phase = Phase::Sweep(STRINGS, 0);
+                    0
+                }
+            },
+
+            Phase::Sweep(arena, start) => {
+                let arena_end = match arena {
+                    STRINGS => self.strings.end(),
+                    TABLES => self.tables.end(),
+                    FUNCTIONS => self.functions.end(),
+                    UPVALUES => self.upvalues.end(),
+                    THREADS => self.threads.end(),
+                    USERDATA => self.userdata.end(),
+                    _ => unreachable!(),
+                };
+
+                let end = cmp::min(start + SWEEP_SLOTS, arena_end);
+                self.sweep(arena, start..end);
+                self.gc.phase = if end < arena_end {
+                    Phase::Sweep(arena, end)
+                } else if arena + 1 < ARENA_COUNT {
+                    Phase::Sweep(arena + 1, 0)
+                } else {
+                    Phase::Pause
+                };
+                SWEEP_SLOTS
+            }
+        }
+    }
+
+    fn atomic(&mut self, roots: &[Value]) {
+        self.mark_roots(roots);
+        self.propagate_all();
+
+        let gray_again = mem::take(&mut self.gc.gray_again);
+        self.gc.gray.extend(gray_again);
+        self.finish_marking();
+
+        self.gc.white = self.gc.white.other_white();
+    }
+
+    /// Completes marking once the roots and every object that needs traversing have been
+    /// grayed, dealing with weak tables and finalizers.
+    fn finish_marking(&mut self) {
+        self.propagate_all();
+        self.converge_ephemerons();
+
+        // Every strongly reachable object is now marked.
+        self.clear_weak(WEAK_VALUES, 0, false);
+        self.clear_weak(ALL_WEAK, 0, false);
+        let weak_values = self.gc.weak[WEAK_VALUES].len();
+        let all_weak = self.gc.weak[ALL_WEAK].len();
+
+        self.separate_finalizable(false);
+        for i in 0..self.gc.to_finalize.len() {
+            let object = self.gc.to_finalize[i];
+            self.marker().mark_object(object);
+        }
+        self.propagate_all();
+        self.converge_ephemerons();
+
+        // Every resurrected object is now marked too.
+        self.clear_weak(EPHEMERONS, 0, true);
+        self.clear_weak(ALL_WEAK, 0, true);
+        self.clear_weak(WEAK_VALUES, weak_values, false);
+        self.clear_weak(ALL_WEAK, all_weak, false);
+    }
+
+    /// Marks the values of ephemeron entries whose keys are marked until no more are found.
+    fn converge_ephemerons(&mut self) {
+        loop {
+            let mut changed = false;
+
+            let Self {
+                tables,
+                headers,
+                gc,
+                ..
+            } = self;
+
+            let mut marker = Marker {
+                headers,
+                gray: &mut gc.gray,
+                minor: gc.minor,
+            };
+
+            for &key in &gc.weak[EPHEMERONS] {
+                if let Some(table) = &tables.objects[key] {
+                    table.for_each_entry(|key, value| {
+                        if !marker.is_cleared(key) && marker.is_cleared(value) {
+                            marker.mark(value);
+                            changed = true;
+                        }
+                    });
+                }
+            }
+
+            if !changed {
+                break;
+            }
+
+            self.propagate_all();
+        }
+    }
+
+    /// Clears the entries with dead keys (or dead values) from the weak tables in
+    /// `self.gc.weak[list][from..]`.
+    fn clear_weak(&mut self, list: usize, from: usize, by_keys: bool) {
+        let weak = mem::take(&mut self.gc.weak[list]);
+
+        let Self {
+            tables,
+            headers,
+            gc,
+            ..
+        } = self;
+
+        let mut marker = Marker {
+            headers,
+            gray: &mut gc.gray,
+            minor: gc.minor,
+        };
+
+        for &key in &weak[from..] {
+            if let Some(table) = &mut tables.objects[key] {
+                table.clear_entries(|key, value| {
+                    marker.is_cleared(if by_keys { key } else { value })
+                });
+            }
+        }
+
+        self.gc.weak[list] = weak;
+    }
+
+    /// Moves unmarked objects (or all of them) from the list of objects with finalizers to the
+    /// queue of finalizers to run, newest first.
+    fn separate_finalizable(&mut self, all: bool) {
+        let finalizable = mem::take(&mut self.gc.finalizable);
+        let mut remaining = Vec::with_capacity(finalizable.len());
+        let minor = self.gc.minor;
+
+        for &object in finalizable.iter().rev() {
+            let header = &mut self.headers[object];
+            if all || is_unmarked(header, minor) {
+                header.finalizable = false;
+                self.gc.to_finalize.push_back(object);
+            } else {
+                remaining.push(object);
+            }
+        }
+
+        remaining.reverse();
+        self.gc.finalizable = remaining;
+    }
+
+    fn minor_collection(&mut self, roots: &[Value]) {
+        self.gc.minor = true;
+        self.gc.weak.iter_mut().for_each(Vec::clear);
+        self.mark_roots(roots);
+
+        for i in 0..self.gc.old1.len() {
+            let object = self.gc.old1[i];
+            self.gc.gray.push(object);
+        }
+
+        for i in 0..self.gc.touched.len() {
+            let object = self.gc.touched[i];
+            self.gc.gray.push(object);
+        }
+
+        self.finish_marking();
+        self.gc.minor = false;
+
+        let white = self.gc.white;
+        let mut young = Vec::new();
+        let mut old1 = Vec::new();
+
+        for object in mem::take(&mut self.gc.young) {
+            let header = &mut self.headers[object];
+            if header.color.is_white() {
+                self.free(object);
+                continue;
+            }
+
+            header.color = white;
+            match header.age {
+                Age::New => {
+                    header.age = Age::Survival;
+                    young.push(object);
+                }
+
+                _ => {
+                    header.age = Age::Old1;
+                    old1.push(object);
+                }
+            }
+        }
+
+        for object in mem::replace(&mut self.gc.old1, old1) {
+            let header = &mut self.headers[object];
+            header.color = white;
+            if header.age == Age::Old1 {
+                header.age = Age::Old;
+            }
+        }
+
+        let mut touched = mem::take(&mut self.gc.touched);
+        touched.retain(|&object| {
+            let header = &mut self.headers[object];
+            header.color = white;
+            match header.age {
+                Age::Touched1 => {
+                    header.age = Age::Touched2;
+                    true
+                }
+
+                Age::Touched2 => {
+                    header.age = Age::Old;
+                    false
+                }
+
+                _ => false,
+            }
+        });
+
+        self.gc.touched = touched;
+        self.gc.young = young;
+        self.gc.set_minor_debt();
+    }
+
+    fn major_collection(&mut self, roots: &[Value]) {
+        self.gc.phase = Phase::Pause;
+
+        loop {
+            self.single_step(roots);
+            if self.gc.phase == Phase::Pause {
+                break;
+            }
+        }
+
+        // Slots of freed objects are aged as well, which is harmless since allocation gives
+        // them a new header.
+        let Headers {
+            strings,
+            tables,
+            functions,
+            upvalues,
+            threads,
+            userdata,
+        } = &mut self.headers;
+
+        strings
+            .values_mut()
+            .chain(tables.values_mut())
+            .chain(functions.values_mut())
+            .chain(upvalues.values_mut())
+            .chain(threads.values_mut())
+            .chain(userdata.values_mut())
+            .for_each(|header| header.age = Age::Old);
+
+        self.gc.young.clear();
+        self.gc.old1.clear();
+        self.gc.touched.clear();
+        self.gc.major_base = self.gc.total;
+        self.gc.set_minor_debt();
+    }
+
+    fn mark_roots(&mut self, roots: &[Value]) {
+        let Self {
+            headers,
+            open_upvalues,
+            registry,
+            metamethod_names,
+            type_metatables,
+            gc,
+            ..
+        } = self;
+
+        let mut marker = Marker {
+            headers,
+            gray: &mut gc.gray,
+            minor: gc.minor,
+        };
+
+        marker.mark(Value::Table(*registry));
+
+        for &name in metamethod_names.iter() {
+            marker.mark(Value::String(name));
+        }
+
+        for &metatable in type_metatables.iter().flatten() {
+            marker.mark(Value::Table(metatable));
+        }
+
+        for &object in &gc.to_finalize {
+            marker.mark_object(object);
+        }
+
+        for &key in open_upvalues.iter() {
+            marker.mark_object(ObjectRef::Upvalue(key));
+        }
+
+        for &root in roots {
+            marker.mark(root);
+        }
+    }
+
+    fn propagate_all(&mut self) {
+        while let Some(object) = self.gc.gray.pop() {
+            self.traverse(object);
+        }
+    }
+
+    /// Blackens a gray object by marking everything it refers to, returning its size as a
+    /// measure of the work done.
+    fn traverse(&mut self, object: ObjectRef) -> usize {
+        let mode_name = self.metamethod_name(Metamethod::Mode);
+
+        let Self {
+            strings,
+            tables,
+            functions,
+            upvalues,
+            threads,
+            userdata,
+            headers,
+            gc,
+            ..
+        } = self;
+
+        headers[object].color = Color::Black;
+        let mut marker = Marker {
+            headers,
+            gray: &mut gc.gray,
+            minor: gc.minor,
+        };
+
+        match object {
+            ObjectRef::String(_) => (),
+
+            ObjectRef::Table(key) => {
+                if let Some(table) = &tables.objects[key] {
+                    let (weak_keys, weak_values) =
+                        weakness(table, &tables.objects, &strings.objects, mode_name);
+
+                    if !weak_keys && !weak_values {
+                        table.trace(|value| marker.mark(value));
+                    } else {
+                        if let Some(metatable) = table.metatable() {
+                            marker.mark(Value::Table(metatable));
+                        }
+
+                        if !weak_keys {
+                            table.for_each_entry(|key, _| marker.mark(key));
+                            gc.weak[WEAK_VALUES].push(key);
+                        } else if !weak_values {
+                            table.for_each_entry(|key, value| {
+                                if !marker.is_cleared(key) {
+                                    marker.mark(value);
+                                }
+                            });
+                            gc.weak[EPHEMERONS].push(key);
+                        } else {
+                            gc.weak[ALL_WEAK].push(key);
+                        }
+                    }
+                }
+            }
+
+            ObjectRef::Function(key) => match &functions.objects[key] {
+                Some(Function::Native(native)) => {
+                    native.upvalues.iter().for_each(|&value| marker.mark(value))
+                }
+
+                Some(Function::Lua(closure)) => {
+                    closure.prototype.trace(&mut |value| marker.mark(value));
+                    for &upvalue in closure.upvalues.iter() {
+                        marker.mark_object(ObjectRef::Upvalue(upvalue));
+                    }
+                }
+
+                None => (),
+            },
+
+            ObjectRef::Upvalue(key) => match upvalues.objects[key] {
+                // An open upvalue's variable lives as long as the stack it is on.
+                Some(Upvalue::Open(thread, _)) => marker.mark_object(ObjectRef::Thread(thread)),
+                Some(Upvalue::Closed(value)) => marker.mark(value),
+                None => (),
+            },
+
+            // The stack of the running thread is a root instead, and is only put back into the
+            // thread through the write barrier.
+            ObjectRef::Thread(key) => {
+                if let Some(thread) = &threads.objects[key] {
+                    thread.trace(|value| marker.mark(value));
+                    for &upvalue in &thread.open_upvalues {
+                        marker.mark_object(ObjectRef::Upvalue(upvalue));
+                    }
+                }
+            }
+
+            ObjectRef::UserData(key) => {
+                if let Some(userdata) = &userdata.objects[key] {
+                    userdata.trace(|value| marker.mark(value));
+                }
+            }
+        }
+
+        marker.headers[object].size
+    }
+
+    fn marker(&mut self) -> Marker<'_> {
+        Marker {
+            headers: &mut self.headers,
+            gray: &mut self.gc.gray,
+            minor: self.gc.minor,
+        }
+    }
+
+    fn sweep(&mut self, arena: usize, range: Range<usize>) {
+        let Self {
+            strings,
+            string_dedup,
+            tables,
+            functions,
+            upvalues,
+            threads,
+            userdata,
+            headers,
+            gc,
+            ..
+        } = self;
+
+        let white = gc.white;
+        let total = &mut gc.total;
+
+        match arena {
+            STRINGS => sweep_arena(
+                strings,
+                &mut headers.strings,
+                range,
+                white,
+                |string, size| {
+                    string_dedup.remove(&*string);
+                    *total -= size;
+                },
+            ),
+
+            TABLES => {
+                // Tables grow after they are allocated, so take the chance to bring their sizes
+                // up to date.
+                for i in range.clone() {
+                    let key = TableRef::new(i);
+                    if let Some(table) = &tables.objects[key] {
+                        let header = &mut headers.tables[key];
+                        *total = *total - header.size + table.size();
+                        header.size = table.size();
+                    }
+                }
+
+                sweep_arena(tables, &mut headers.tables, range, white, |_, size| {
+                    *total -= size
+                });
+            }
+
+            FUNCTIONS => sweep_arena(
+                functions,
+                &mut headers.functions,
+                range,
+                white,
+                |_, size| *total -= size,
+            ),
+
+            UPVALUES => sweep_arena(upvalues, &mut headers.upvalues, range, white, |_, size| {
+                *total -= size
+            }),
+
+            THREADS => sweep_arena(threads, &mut headers.threads, range, white, |_, size| {
+                *total -= size
+            }),
+
+            USERDATA => sweep_arena(userdata, &mut headers.userdata, range, white, |_, size| {
+                *total -= size
+            }),
+
+            _ => unreachable!(),
+        }
+    }
+
+    fn free(&mut self, object: ObjectRef) {
+        self.gc.total -= self.headers[object].size;
+
+        match object {
+            ObjectRef::String(key) => {
+                let string = self.strings.free(key);
+                self.string_dedup.remove(&*string);
+            }
+
+            ObjectRef::Table(key) => {
+                self.tables.free(key);
+            }
+
+            ObjectRef::Function(key) => {
+                self.functions.free(key);
+            }
+
+            ObjectRef::Upvalue(key) => {
+                self.upvalues.free(key);
+            }
+
+            ObjectRef::Thread(key) => {
+                self.threads.free(key);
+            }
+
+            ObjectRef::UserData(key) => {
+                self.userdata.free(key);
+            }
+        }
+    }
+}
+
+struct Marker<'a> {
+    headers: &'a mut Headers,
+    gray: &'a mut Vec<ObjectRef>,
+    minor: bool,
+}
+
+impl Marker<'_> {
+    fn mark(&mut self, value: Value) {
+        if let Some(object) = ObjectRef::of(value) {
+            self.mark_object(object);
+        }
+    }
+
+    fn mark_object(&mut self, object: ObjectRef) {
+        let header = &mut self.headers[object];
+        if is_unmarked(header, self.minor) {
+            // Strings refer to nothing, so there is no need to traverse them.
+            if let ObjectRef::String(_) = object {
+                header.color = Color::Black;
+            } else {
+                header.color = Color::Gray;
+                self.gray.push(object);
+            }
+        }
+    }
+
+    /// Whether a weak reference to `value` must be cleared because nothing else keeps it
+    /// alive. Strings are values rather than objects as far as weak tables are concerned, so
+    /// they are never cleared, and are marked instead.
+    fn is_cleared(&mut self, value: Value) -> bool {
+        match ObjectRef::of(value) {
+            Some(ObjectRef::String(_)) => {
+                self.mark(value);
+                false
+            }
+
+            Some(object) => is_unmarked(&self.headers[object], self.minor),
+            None => false,
+        }
+    }
+}
+
+/// Determines whether a table has weak keys and weak values, from the `__mode` field of its
+/// metatable.
+fn weakness(
+    table: &Table,
+    tables: &PrimaryMap<TableRef, Option<Table>>,
+    strings: &PrimaryMap<StrRef, Option<Box<[u8]>>>,
+    mode_name: StrRef,
+) -> (bool, bool) {
+    let Some(Some(metatable)) = table.metatable().map(|key| &tables[key]) else {
+        return (false, false);
+    };
+
+    match metatable.get_str(mode_name) {
+        Value::String(mode) => match &strings[mode] {
+            Some(mode) => (mode.contains(&b'k'), mode.contains(&b'v')),
+            None => (false, false),
+        },
+
+        _ => (false, false),
+    }
+}
+
+fn is_unmarked(header: &Header, minor: bool) -> bool {
+    header.color.is_white() && (!minor || header.age.is_young())
+}
+
+/// Frees the objects in `range` of `arena` that were left unmarked by the last cycle, passing
+/// each one and its size to `on_free`, and turns the survivors back to `white`.
+fn sweep_arena<K: EntityRef, T>(
+    arena: &mut Arena<K, T>,
+    headers: &mut SecondaryMap<K, Header>,
+    range: Range<usize>,
+    white: Color,
+    mut on_free: impl FnMut(T, usize),
+) {
+    let dead = white.other_white();
+
+    for i in range {
+        let key = K::new(i);
+        if arena.objects[key].is_none() {
+            continue;
+        }
+
+        let header = &mut headers[key];
+        if header.color == dead {
+            let size = header.size;
+            on_free(arena.free(key), size);
+        } else {
+            header.color = white;
+        }
+    }
+}
+
+pub fn size_of_string(string: &[u8]) -> usize {
+    mem::size_of::<Box<[u8]>>() + string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_live(heap: &Heap, table: TableRef) -> bool {
+        heap.tables.get(table).is_some()
+    }
+
+    /// A table whose `field` refers to `value`.
+    fn new_table_with(heap: &mut Heap, field: i64, value: Value) -> TableRef {
+        let table = heap.new_table();
+        heap[table].set_int(field, value);
+        table
+    }
+
+    #[test]
+    fn collection_frees_unreachable_objects() {
+        let mut heap = Heap::new();
+        let garbage = heap.new_table();
+        let string = heap.intern(b"garbage");
+        let child = heap.new_table();
+        let root = new_table_with(&mut heap, 1, Value::Table(child));
+
+        heap.gc_collect(&[Value::Table(root)]);
+        assert!(is_live(&heap, root));
+        assert!(is_live(&heap, child));
+        assert!(!is_live(&heap, garbage));
+        assert!(heap.strings.get(string).is_none());
+
+        // Interning the string again makes a new one rather than finding the freed one.
+        let string = heap.intern(b"garbage");
+        assert_eq!(&heap[string], b"garbage");
+    }
+
+    #[test]
+    fn cycles_are_collected() {
+        let mut heap = Heap::new();
+        let a = heap.new_table();
+        let b = new_table_with(&mut heap, 1, Value::Table(a));
+        heap[a].set_int(1, Value::Table(b));
+
+        heap.gc_collect(&[]);
+        assert!(!is_live(&heap, a));
+        assert!(!is_live(&heap, b));
+    }
+
+    #[test]
+    fn the_registry_is_a_root() {
+        let mut heap = Heap::new();
+        let table = heap.new_table();
+        let registry = heap.registry();
+        heap[registry].set_int(1, Value::Table(table));
+
+        heap.gc_collect(&[]);
+        assert!(is_live(&heap, table));
+    }
+
+    #[test]
+    fn incremental_steps_complete_a_cycle() {
+        let mut heap = Heap::new();
+        let root = heap.new_table();
+        let garbage: Vec<_> = (0..1000).map(|_| heap.new_table()).collect();
+        let count = heap.gc_count();
+
+        let roots = [Value::Table(root)];
+        let mut steps = 0;
+        loop {
+            heap.single_step(&roots);
+            if heap.gc.phase == Phase::Pause {
+                break;
+            }
+
+            // Writes while marking is in progress go through the barrier.
+            if heap.gc.phase == Phase::Propagate {
+                let table = heap.new_table();
+                heap[root].set_int(steps + 1, Value::Table(table));
+                steps += 1;
+            }
+        }
+
+        assert!(steps > 0);
+        assert!(garbage.iter().all(|&table| !is_live(&heap, table)));
+        assert!(heap.gc_count() < count);
+        for i in 1..=steps {
+            let Value::Table(table) = heap[root].get_int(i) else {
+                panic!("field {i} was lost");
+            };
+            assert!(is_live(&heap, table));
+        }
+    }
+
+    #[test]
+    fn stopping_the_collector_defers_steps() {
+        let mut heap = Heap::new();
+        heap.gc_stop();
+        let garbage: Vec<_> = (0..10_000).map(|_| heap.new_table()).collect();
+        assert!(!heap.gc_is_due());
+        assert!(!heap.gc_step(&[]));
+        assert!(garbage.iter().all(|&table| is_live(&heap, table)));
+
+        heap.gc_restart();
+        assert!(heap.gc_is_running());
+    }
+
+    #[test]
+    fn minor_collections_keep_young_objects_stored_in_old_ones() {
+        let mut heap = Heap::new();
+        let old = heap.new_table();
+        let roots = [Value::Table(old)];
+        assert_eq!(heap.gc_set_generational(&roots, 0, 0), Mode::Incremental);
+        assert_eq!(heap.gc_mode(), Mode::Generational);
+
+        // Two minor collections age the root into the old generation.
+        heap.minor_collection(&roots);
+        heap.minor_collection(&roots);
+
+        let young = heap.new_table();
+        let garbage = heap.new_table();
+        heap[old].set_int(1, Value::Table(young));
+        heap.minor_collection(&roots);
+        assert!(is_live(&heap, young));
+        assert!(!is_live(&heap, garbage));
+
+        heap[old].set_int(1, Value::Nil);
+        heap.gc_collect(&roots);
+        assert!(!is_live(&heap, young));
+        assert_eq!(heap.gc_set_incremental(0, 0, 0), Mode::Generational);
+    }
+
+    /// A table whose metatable has `__mode` set to `mode`.
+    fn new_weak_table(heap: &mut Heap, mode: &[u8]) -> TableRef {
+        let table = heap.new_table();
+        let metatable = heap.new_table();
+        let (name, mode) = (heap.metamethod_name(Metamethod::Mode), heap.intern(mode));
+        heap[metatable].set_str(name, Value::String(mode));
+        heap.set_metatable(Value::Table(table), Some(metatable));
+        table
+    }
+
+    /// A table registered for finalization.
+    fn new_finalizable_table(heap: &mut Heap) -> TableRef {
+        let table = heap.new_table();
+        let metatable = heap.new_table();
+        let name = heap.metamethod_name(Metamethod::Gc);
+        heap[metatable].set_str(name, Value::Bool(true));
+        heap.set_metatable(Value::Table(table), Some(metatable));
+        table
+    }
+
+    #[test]
+    fn weak_values_are_cleared() {
+        let mut heap = Heap::new();
+        let weak = new_weak_table(&mut heap, b"v");
+        let (dead, alive) = (heap.new_table(), heap.new_table());
+        let string = heap.intern(b"strings are values, not objects");
+        heap[weak].set_int(1, Value::Table(dead));
+        heap[weak].set_int(2, Value::Table(alive));
+        heap[weak].set_int(3, Value::String(string));
+
+        heap.gc_collect(&[Value::Table(weak), Value::Table(alive)]);
+        assert!(heap[weak].get_int(1).is_nil());
+        assert!(matches!(heap[weak].get_int(2), Value::Table(table) if table == alive));
+        assert!(!heap[weak].get_int(3).is_nil());
+    }
+
+    #[test]
+    fn ephemerons_keep_values_only_while_their_keys_live() {
+        let mut heap = Heap::new();
+        let weak = new_weak_table(&mut heap, b"k");
+        let (dead, alive) = (heap.new_table(), heap.new_table());
+
+        // Each value refers to its own key, which must not keep the key alive.
+        for key in [dead, alive] {
+            let value = new_table_with(&mut heap, 1, Value::Table(key));
+            heap[weak]
+                .set(Value::Table(key), Value::Table(value))
+                .unwrap();
+        }
+
+        heap.gc_collect(&[Value::Table(weak), Value::Table(alive)]);
+        assert!(!is_live(&heap, dead));
+        let value = heap[weak].get(Value::Table(alive));
+        assert!(matches!(value, Value::Table(value) if is_live(&heap, value)));
+        let mut entries = 0;
+        heap[weak].for_each_entry(|_, _| entries += 1);
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn finalized_objects_are_resurrected_until_their_finalizer_runs() {
+        let mut heap = Heap::new();
+        let (weak_keys, weak_values) = (
+            new_weak_table(&mut heap, b"k"),
+            new_weak_table(&mut heap, b"v"),
+        );
+        let object = new_finalizable_table(&mut heap);
+        let child = heap.new_table();
+        heap[object].set_int(1, Value::Table(child));
+        heap[weak_keys]
+            .set(Value::Table(object), Value::Bool(true))
+            .unwrap();
+        heap[weak_values].set_int(1, Value::Table(object));
+
+        let roots = [Value::Table(weak_keys), Value::Table(weak_values)];
+        heap.gc_collect(&roots);
+        assert!(is_live(&heap, object));
+        assert!(is_live(&heap, child));
+        assert!(heap[weak_values].get_int(1).is_nil());
+        assert!(!heap[weak_keys].get(Value::Table(object)).is_nil());
+
+        let Some(Value::Table(finalized)) = heap.take_finalizer() else {
+            panic!("no finalizer is due");
+        };
+        assert_eq!(finalized, object);
+        assert!(heap.take_finalizer().is_none());
+
+        // The finalizer has run, so the next collection frees the object for good.
+        heap.gc_collect(&roots);
+        assert!(!is_live(&heap, object));
+        assert!(heap[weak_keys].get(Value::Table(object)).is_nil());
+    }
+
+    #[test]
+    fn finalizers_run_in_reverse_order_of_registration() {
+        let mut heap = Heap::new();
+        let objects: Vec<_> = (0..3).map(|_| new_finalizable_table(&mut heap)).collect();
+        heap.gc_collect(&[]);
+
+        let finalized: Vec<_> = std::iter::from_fn(|| heap.take_finalizer())
+            .map(|value| match value {
+                Value::Table(table) => table,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(finalized, objects.into_iter().rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn finalize_all_queues_reachable_objects_too() {
+        let mut heap = Heap::new();
+        let object = new_finalizable_table(&mut heap);
+        heap.gc_collect(&[Value::Table(object)]);
+        assert!(heap.take_finalizer().is_none());
+
+        heap.finalize_all();
+        assert!(matches!(heap.take_finalizer(), Some(Value::Table(table)) if table == object));
+    }
+}
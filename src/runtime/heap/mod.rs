@@ -0,0 +1,505 @@
+pub use gc::Mode;
+use {
+    self::{
+        arena::Arena,
+        gc::{Collector, Header},
+    },
+    super::{Function, Metamethod, Table, Thread, Upvalue, UserData, Value},
+    crate::entity_ref_type,
+    ahash::AHashMap,
+    cranelift_entity::{packed_option::ReservedValue, SecondaryMap},
+    std::{
+        mem,
+        ops::{Index, IndexMut},
+    },
+};
+
+mod arena;
+mod gc;
+
+entity_ref_type!(FunctionRef);
+entity_ref_type!(StrRef);
+entity_ref_type!(TableRef);
+entity_ref_type!(ThreadRef);
+entity_ref_type!(UpvalueRef);
+entity_ref_type!(UserDataRef);
+
+/// The number of basic types other than userdata, each of which has a metatable shared by all
+/// of its values other than tables.
+const TYPE_COUNT: usize = 7;
+
+/// Storage for every object a runtime [`Value`] can refer to, reclaimed by a precise tracing
+/// collector.
+///
+/// Strings are interned, so two string values are equal exactly when their references are.
+///
+/// The heap cannot see values held in Rust locals or native stack frames, so collection only
+/// happens when the owner asks for it and hands over the complete root set. Compiled code keeps
+/// every live value in the Lua value stack across calls and allocation sites, so that stack,
+/// together with the registry, is precisely the set of roots.
+pub struct Heap {
+    strings: Arena<StrRef, Box<[u8]>>,
+    string_dedup: AHashMap<&'static [u8], StrRef>,
+    tables: Arena<TableRef, Table>,
+    functions: Arena<FunctionRef, Function>,
+    upvalues: Arena<UpvalueRef, Upvalue>,
+    threads: Arena<ThreadRef, Thread>,
+    userdata: Arena<UserDataRef, UserData>,
+    headers: Headers,
+
+    /// The open upvalues of the running thread, in order of stack index. These are always
+    /// reachable, since the locals they refer to are.
+    open_upvalues: Vec<UpvalueRef>,
+
+    registry: TableRef,
+    metamethod_names: Vec<StrRef>,
+    type_metatables: [Option<TableRef>; TYPE_COUNT],
+    gc: Collector,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        let mut heap = Self {
+            strings: Arena::new(),
+            string_dedup: AHashMap::new(),
+            tables: Arena::new(),
+            functions: Arena::new(),
+            upvalues: Arena::new(),
+            threads: Arena::new(),
+            userdata: Arena::new(),
+            headers: Headers::default(),
+            open_upvalues: Vec::new(),
+            registry: TableRef::reserved_value(),
+            metamethod_names: Vec::new(),
+            type_metatables: [None; TYPE_COUNT],
+            gc: Collector::new(),
+        };
+
+        heap.registry = heap.new_table();
+        heap.metamethod_names = Metamethod::ALL
+            .iter()
+            .map(|event| heap.intern(event.name().as_bytes()))
+            .collect();
+        heap
+    }
+
+    /// The registry table, which is always reachable.
+    pub fn registry(&self) -> TableRef {
+        self.registry
+    }
+
+    pub fn metamethod_name(&self, event: Metamethod) -> StrRef {
+        self.metamethod_names[event as usize]
+    }
+
+    /// Looks up the handler for `event` in `metatable`, without invoking any metamethods.
+    pub fn metamethod(&self, metatable: Option<TableRef>, event: Metamethod) -> Value {
+        match metatable {
+            Some(metatable) => self[metatable].get_str(self.metamethod_name(event)),
+            None => Value::Nil,
+        }
+    }
+
+    /// The metatable of `value`: its own if it is a table or userdata, otherwise the one shared
+    /// by its type.
+    pub fn metatable_of(&self, value: Value) -> Option<TableRef> {
+        match value {
+            Value::Table(table) => self[table].metatable(),
+            Value::UserData(userdata) => self[userdata].metatable(),
+            _ => self.type_metatables[type_index(value)],
+        }
+    }
+
+    /// Sets the metatable of `value`, which for anything but a table or userdata means the
+    /// metatable of every value of its type.
+    ///
+    /// If the new metatable of a table or userdata has a `__gc` field, the object is registered
+    /// for finalization; a `__gc` field added to the metatable later has no effect.
+    pub fn set_metatable(&mut self, value: Value, metatable: Option<TableRef>) {
+        match value {
+            Value::Table(table) => {
+                self[table].set_metatable(metatable);
+
+                if !self.metamethod(metatable, Metamethod::Gc).is_nil() {
+                    self.gc.register_finalizer(
+                        ObjectRef::Table(table),
+                        &mut self.headers.tables[table],
+                    );
+                }
+            }
+
+            Value::UserData(userdata) => {
+                self[userdata].set_metatable(metatable);
+
+                if !self.metamethod(metatable, Metamethod::Gc).is_nil() {
+                    self.gc.register_finalizer(
+                        ObjectRef::UserData(userdata),
+                        &mut self.headers.userdata[userdata],
+                    );
+                }
+            }
+
+            _ => self.type_metatables[type_index(value)] = metatable,
+        }
+    }
+
+    /// Looks up the handler for `event` in the metatable of `value`.
+    pub fn metamethod_of(&self, value: Value, event: Metamethod) -> Value {
+        self.metamethod(self.metatable_of(value), event)
+    }
+
+    pub fn intern(&mut self, string: &[u8]) -> StrRef {
+        if let Some(&existing) = self.string_dedup.get(string) {
+            self.gc.resurrect(&mut self.headers.strings[existing]);
+            existing
+        } else {
+            let storage = Box::<[u8]>::from(string);
+
+            // The boxed bytes never move, and the dedup entry is removed before the box is freed.
+            let key = unsafe { &*(&*storage as *const [u8]) };
+            let header = self.gc.new_header(gc::size_of_string(&storage));
+            let new = self.strings.alloc(storage);
+            self.headers.strings[new] = header;
+            self.gc.track(ObjectRef::String(new));
+            self.string_dedup.insert(key, new);
+            new
+        }
+    }
+
+    pub fn new_table(&mut self) -> TableRef {
+        self.alloc_table(Table::new())
+    }
+
+    pub fn new_table_with_capacity(&mut self, array: usize, hash: usize) -> TableRef {
+        self.alloc_table(Table::with_capacity(array, hash))
+    }
+
+    fn alloc_table(&mut self, table: Table) -> TableRef {
+        let header = self.gc.new_header(table.size());
+        let new = self.tables.alloc(table);
+        self.headers.tables[new] = header;
+        self.gc.track(ObjectRef::Table(new));
+        new
+    }
+
+    pub fn new_function(&mut self, function: Function) -> FunctionRef {
+        let header = self.gc.new_header(function.size());
+        let new = self.functions.alloc(function);
+        self.headers.functions[new] = header;
+        self.gc.track(ObjectRef::Function(new));
+        new
+    }
+
+    pub fn new_upvalue(&mut self, upvalue: Upvalue) -> UpvalueRef {
+        let header = self.gc.new_header(mem::size_of::<Upvalue>());
+        let new = self.upvalues.alloc(upvalue);
+        self.headers.upvalues[new] = header;
+        self.gc.track(ObjectRef::Upvalue(new));
+        new
+    }
+
+    pub fn new_thread(&mut self, thread: Thread) -> ThreadRef {
+        let header = self.gc.new_header(mem::size_of::<Thread>());
+        let new = self.threads.alloc(thread);
+        self.headers.threads[new] = header;
+        self.gc.track(ObjectRef::Thread(new));
+        new
+    }
+
+    pub fn new_userdata(&mut self, userdata: UserData) -> UserDataRef {
+        let header = self.gc.new_header(userdata.size());
+        let new = self.userdata.alloc(userdata);
+        self.headers.userdata[new] = header;
+        self.gc.track(ObjectRef::UserData(new));
+        new
+    }
+
+    /// Returns the open upvalue for the local at `index` in the stack of the running
+    /// `thread`, creating it if no closure has captured the local yet.
+    pub fn find_upvalue(&mut self, thread: ThreadRef, index: usize) -> UpvalueRef {
+        let position =
+            self.open_upvalues
+                .binary_search_by_key(&index, |&key| match self.upvalues.get(key) {
+                    Some(Upvalue::Open(_, index)) => *index,
+                    _ => unreachable!("closed upvalue in the list of open upvalues"),
+                });
+
+        match position {
+            Ok(position) => self.open_upvalues[position],
+
+            Err(position) => {
+                let new = self.new_upvalue(Upvalue::Open(thread, index));
+                self.open_upvalues.insert(position, new);
+                new
+            }
+        }
+    }
+
+    /// Closes every open upvalue for a local at or above `level` in `stack`, as the locals'
+    /// scope ends.
+    pub fn close_upvalues(&mut self, stack: &[Value], level: usize) {
+        let position = self
+            .open_upvalues
+            .partition_point(|&key| match self.upvalues.get(key) {
+                Some(Upvalue::Open(_, index)) => *index < level,
+                _ => unreachable!("closed upvalue in the list of open upvalues"),
+            });
+
+        for key in self.open_upvalues.split_off(position) {
+            if let Upvalue::Open(_, index) = self[key] {
+                self[key] = Upvalue::Closed(stack[index]);
+            }
+        }
+    }
+
+    /// Exchanges the open upvalues of the running thread with those kept in `thread`, as one
+    /// thread stops running and another starts.
+    pub(super) fn exchange_open_upvalues(&mut self, thread: ThreadRef) {
+        let Self {
+            threads,
+            open_upvalues,
+            ..
+        } = self;
+        let thread = threads.get_mut(thread).expect("use of a collected thread");
+        mem::swap(open_upvalues, &mut thread.open_upvalues);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index<StrRef> for Heap {
+    type Output = [u8];
+
+    fn index(&self, index: StrRef) -> &Self::Output {
+        self.strings.get(index).expect("use of a collected string")
+    }
+}
+
+impl Index<TableRef> for Heap {
+    type Output = Table;
+
+    fn index(&self, index: TableRef) -> &Self::Output {
+        self.tables.get(index).expect("use of a collected table")
+    }
+}
+
+/// Mutable access goes through the collector's write barrier, so callers never need to invoke
+/// it themselves.
+impl IndexMut<TableRef> for Heap {
+    fn index_mut(&mut self, index: TableRef) -> &mut Self::Output {
+        self.gc
+            .barrier(ObjectRef::Table(index), &mut self.headers.tables[index]);
+        self.tables
+            .get_mut(index)
+            .expect("use of a collected table")
+    }
+}
+
+impl Index<FunctionRef> for Heap {
+    type Output = Function;
+
+    fn index(&self, index: FunctionRef) -> &Self::Output {
+        self.functions
+            .get(index)
+            .expect("use of a collected function")
+    }
+}
+
+impl IndexMut<FunctionRef> for Heap {
+    fn index_mut(&mut self, index: FunctionRef) -> &mut Self::Output {
+        self.gc.barrier(
+            ObjectRef::Function(index),
+            &mut self.headers.functions[index],
+        );
+        self.functions
+            .get_mut(index)
+            .expect("use of a collected function")
+    }
+}
+
+impl Index<ThreadRef> for Heap {
+    type Output = Thread;
+
+    fn index(&self, index: ThreadRef) -> &Self::Output {
+        self.threads.get(index).expect("use of a collected thread")
+    }
+}
+
+impl IndexMut<ThreadRef> for Heap {
+    fn index_mut(&mut self, index: ThreadRef) -> &mut Self::Output {
+        self.gc
+            .barrier(ObjectRef::Thread(index), &mut self.headers.threads[index]);
+        self.threads
+            .get_mut(index)
+            .expect("use of a collected thread")
+    }
+}
+
+impl Index<UpvalueRef> for Heap {
+    type Output = Upvalue;
+
+    fn index(&self, index: UpvalueRef) -> &Self::Output {
+        self.upvalues
+            .get(index)
+            .expect("use of a collected upvalue")
+    }
+}
+
+impl IndexMut<UpvalueRef> for Heap {
+    fn index_mut(&mut self, index: UpvalueRef) -> &mut Self::Output {
+        self.gc
+            .barrier(ObjectRef::Upvalue(index), &mut self.headers.upvalues[index]);
+        self.upvalues
+            .get_mut(index)
+            .expect("use of a collected upvalue")
+    }
+}
+
+impl Index<UserDataRef> for Heap {
+    type Output = UserData;
+
+    fn index(&self, index: UserDataRef) -> &Self::Output {
+        self.userdata
+            .get(index)
+            .expect("use of a collected userdata")
+    }
+}
+
+impl IndexMut<UserDataRef> for Heap {
+    fn index_mut(&mut self, index: UserDataRef) -> &mut Self::Output {
+        self.gc.barrier(
+            ObjectRef::UserData(index),
+            &mut self.headers.userdata[index],
+        );
+        self.userdata
+            .get_mut(index)
+            .expect("use of a collected userdata")
+    }
+}
+
+/// The collector's bookkeeping for every object, kept apart from the objects themselves so that
+/// it can be updated while an object is being traversed.
+#[derive(Default)]
+struct Headers {
+    strings: SecondaryMap<StrRef, Header>,
+    tables: SecondaryMap<TableRef, Header>,
+    functions: SecondaryMap<FunctionRef, Header>,
+    upvalues: SecondaryMap<UpvalueRef, Header>,
+    threads: SecondaryMap<ThreadRef, Header>,
+    userdata: SecondaryMap<UserDataRef, Header>,
+}
+
+impl Index<ObjectRef> for Headers {
+    type Output = Header;
+
+    fn index(&self, index: ObjectRef) -> &Self::Output {
+        match index {
+            ObjectRef::String(key) => &self.strings[key],
+            ObjectRef::Table(key) => &self.tables[key],
+            ObjectRef::Function(key) => &self.functions[key],
+            ObjectRef::Upvalue(key) => &self.upvalues[key],
+            ObjectRef::Thread(key) => &self.threads[key],
+            ObjectRef::UserData(key) => &self.userdata[key],
+        }
+    }
+}
+
+impl IndexMut<ObjectRef> for Headers {
+    fn index_mut(&mut self, index: ObjectRef) -> &mut Self::Output {
+        match index {
+            ObjectRef::String(key) => &mut self.strings[key],
+            ObjectRef::Table(key) => &mut self.tables[key],
+            ObjectRef::Function(key) => &mut self.functions[key],
+            ObjectRef::Upvalue(key) => &mut self.upvalues[key],
+            ObjectRef::Thread(key) => &mut self.threads[key],
+            ObjectRef::UserData(key) => &mut self.userdata[key],
+        }
+    }
+}
+
+/// A reference to any collectable object.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ObjectRef {
+    String(StrRef),
+    Table(TableRef),
+    Function(FunctionRef),
+    Upvalue(UpvalueRef),
+    Thread(ThreadRef),
+    UserData(UserDataRef),
+}
+
+impl ObjectRef {
+    fn of(value: Value) -> Option<Self> {
+        match value {
+            Value::String(key) => Some(Self::String(key)),
+            Value::Table(key) => Some(Self::Table(key)),
+            Value::Function(key) => Some(Self::Function(key)),
+            Value::Thread(key) => Some(Self::Thread(key)),
+            Value::UserData(key) => Some(Self::UserData(key)),
+            _ => None,
+        }
+    }
+}
+
+impl From<ObjectRef> for Value {
+    fn from(object: ObjectRef) -> Self {
+        match object {
+            ObjectRef::String(key) => Self::String(key),
+            ObjectRef::Table(key) => Self::Table(key),
+            ObjectRef::Function(key) => Self::Function(key),
+            ObjectRef::Thread(key) => Self::Thread(key),
+            ObjectRef::UserData(key) => Self::UserData(key),
+            ObjectRef::Upvalue(_) => unreachable!("upvalues are not values"),
+        }
+    }
+}
+
+fn type_index(value: Value) -> usize {
+    match value {
+        Value::Nil => 0,
+        Value::Bool(_) => 1,
+        Value::Int(_) | Value::Float(_) => 2,
+        Value::String(_) => 3,
+        Value::Table(_) => 4,
+        Value::Function(_) => 5,
+        Value::Thread(_) => 6,
+        Value::UserData(_) => unreachable!("every userdata has a metatable of its own"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, cranelift_entity::EntityRef};
+
+    #[test]
+    fn locals_are_captured_by_a_single_upvalue() {
+        let mut heap = Heap::new();
+        let thread = ThreadRef::new(0);
+        let a = heap.find_upvalue(thread, 3);
+        let b = heap.find_upvalue(thread, 1);
+        assert_eq!(heap.find_upvalue(thread, 3), a);
+        assert_ne!(a, b);
+        assert_eq!(heap.open_upvalues, [b, a]);
+    }
+
+    #[test]
+    fn closing_upvalues_keeps_the_values_of_their_locals() {
+        let mut heap = Heap::new();
+        let thread = ThreadRef::new(0);
+        let upvalues: Vec<_> = (0..3).map(|i| heap.find_upvalue(thread, i)).collect();
+        let stack = [Value::Int(10), Value::Int(11), Value::Int(12)];
+
+        heap.close_upvalues(&stack, 1);
+        assert!(matches!(heap[upvalues[0]], Upvalue::Open(_, 0)));
+        assert!(matches!(heap[upvalues[1]], Upvalue::Closed(Value::Int(11))));
+        assert!(matches!(heap[upvalues[2]], Upvalue::Closed(Value::Int(12))));
+        assert_eq!(heap.open_upvalues, [upvalues[0]]);
+
+        // A local that is captured again after its upvalue was closed gets a new one.
+        assert_ne!(heap.find_upvalue(thread, 1), upvalues[1]);
+    }
+}
@@ -0,0 +1,560 @@
+//! The outside world, as the `io` and `os` libraries and the file loading functions reach it.
+//!
+//! A [`State`](super::State) starts out with a [`StdHost`], which uses the real filesystem,
+//! processes and environment. Embedders can substitute a host of their own with
+//! [`State::set_host`](super::State::set_host), such as an in-memory filesystem, or [`NoHost`],
+//! which denies access to all of it.
+
+use std::{
+    convert::Infallible,
+    env, fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    process::{self, Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(not(unix))]
+use std::time::Instant;
+
+/// How a file is to be opened, as the mode given to `io.open` describes it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct OpenMode {
+    pub read: bool,
+    pub write: bool,
+
+    /// Every write goes to the end of the file.
+    pub append: bool,
+
+    /// An existing file is emptied.
+    pub truncate: bool,
+
+    /// A missing file is created.
+    pub create: bool,
+}
+
+impl OpenMode {
+    /// Parses a mode of the form C's `fopen` takes, which is `r`, `w` or `a`, optionally
+    /// followed by `+` and then by any number of `b`s, which make no difference.
+    pub fn parse(mode: &[u8]) -> Option<Self> {
+        let (&first, rest) = mode.split_first()?;
+        let (update, rest) = match rest.split_first() {
+            Some((b'+', rest)) => (true, rest),
+            _ => (false, rest),
+        };
+
+        if rest.iter().any(|&c| c != b'b') {
+            return None;
+        }
+
+        let mode = match first {
+            b'r' => Self {
+                read: true,
+                write: update,
+                ..Self::default()
+            },
+
+            b'w' => Self {
+                read: update,
+                write: true,
+                truncate: true,
+                create: true,
+                ..Self::default()
+            },
+
+            b'a' => Self {
+                read: update,
+                write: true,
+                append: true,
+                create: true,
+                ..Self::default()
+            },
+
+            _ => return None,
+        };
+
+        Some(mode)
+    }
+}
+
+/// How the process at the other end of a pipe ended.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Exit {
+    /// It exited with the given status.
+    Code(i32),
+
+    /// It was killed by the given signal.
+    Signal(i32),
+}
+
+/// An open file, pipe or standard stream. Operations that a stream does not support fail.
+pub trait Stream {
+    /// Reads into `buffer`, returning how many bytes were read, which is 0 only at the end.
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let _ = buffer;
+        Err(bad_descriptor())
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let _ = data;
+        Err(bad_descriptor())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Moves to a new position, returning it as an offset from the start.
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let _ = position;
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Illegal seek"))
+    }
+
+    /// Closes the stream. A pipe returns how the process it is connected to ended.
+    fn close(self: Box<Self>) -> io::Result<Option<Exit>> {
+        Ok(None)
+    }
+}
+
+/// What scripts can reach outside of the state. Every operation is denied unless a host
+/// implements it, apart from reading the time, which is harmless.
+///
+/// Paths, commands and environment variables are given as the bytes of the Lua strings that
+/// name them.
+pub trait Host {
+    fn open(&self, path: &[u8], mode: OpenMode) -> io::Result<Box<dyn Stream>> {
+        let _ = (path, mode);
+        Err(denied())
+    }
+
+    /// Runs `command` in a shell, connected to a pipe that reads its output or, with `write`,
+    /// writes its input.
+    fn popen(&self, command: &[u8], write: bool) -> io::Result<Box<dyn Stream>> {
+        let _ = (command, write);
+        Err(denied())
+    }
+
+    /// Creates a temporary file opened for update, which is removed once it is closed.
+    fn tmpfile(&self) -> io::Result<Box<dyn Stream>> {
+        Err(denied())
+    }
+
+    fn stdin(&self) -> Box<dyn Stream> {
+        Box::new(Denied)
+    }
+
+    fn stdout(&self) -> Box<dyn Stream> {
+        Box::new(Denied)
+    }
+
+    fn stderr(&self) -> Box<dyn Stream> {
+        Box::new(Denied)
+    }
+
+    fn remove(&self, path: &[u8]) -> io::Result<()> {
+        let _ = path;
+        Err(denied())
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> io::Result<()> {
+        let _ = (from, to);
+        Err(denied())
+    }
+
+    /// Creates an empty file with a name no other file has, returning the name.
+    fn tmpname(&self) -> io::Result<Vec<u8>> {
+        Err(denied())
+    }
+
+    fn getenv(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let _ = name;
+        None
+    }
+
+    /// The processor time used so far, in seconds. Hosts that cannot measure it report 0.
+    fn clock(&self) -> f64 {
+        0.0
+    }
+
+    /// The current time, in seconds since the Unix epoch.
+    fn time(&self) -> i64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(error) => -(error.duration().as_secs() as i64),
+        }
+    }
+
+    /// How many seconds local time is ahead of UTC at `time`. Local time is UTC by default.
+    fn utc_offset(&self, time: i64) -> i64 {
+        let _ = time;
+        0
+    }
+
+    /// Ends the process with the given status, if the host allows it.
+    fn exit(&self, code: i32) -> io::Result<Infallible> {
+        let _ = code;
+        Err(denied())
+    }
+}
+
+/// The host that denies access to everything outside of the state.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoHost;
+
+impl Host for NoHost {}
+
+/// The host that uses the real filesystem, processes and environment.
+///
+/// On Unix, processor time is what the C library's `clock` reports for the process. Elsewhere,
+/// without a way to ask the system for it, it is the wall-clock time since the host was
+/// created. Local time is always treated as UTC.
+#[derive(Clone, Copy, Debug)]
+pub struct StdHost {
+    #[cfg(not(unix))]
+    created: Instant,
+}
+
+impl StdHost {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(not(unix))]
+            created: Instant::now(),
+        }
+    }
+}
+
+impl Default for StdHost {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Host for StdHost {
+    fn open(&self, path: &[u8], mode: OpenMode) -> io::Result<Box<dyn Stream>> {
+        let file = fs::OpenOptions::new()
+            .read(mode.read)
+            .write(mode.write && !mode.append)
+            .append(mode.append)
+            .truncate(mode.truncate)
+            .create(mode.create)
+            .open(os_string(path))?;
+
+        Ok(Box::new(StdFile(file)))
+    }
+
+    fn popen(&self, command: &[u8], write: bool) -> io::Result<Box<dyn Stream>> {
+        let (shell, flag) = match cfg!(windows) {
+            true => ("cmd", "/C"),
+            false => ("/bin/sh", "-c"),
+        };
+
+        let mut process = Command::new(shell);
+        process.arg(flag).arg(os_string(command));
+        if write {
+            process.stdin(Stdio::piped());
+        } else {
+            process.stdout(Stdio::piped());
+        }
+
+        let mut child = process.spawn()?;
+        let end = match write {
+            true => PipeEnd::Write(child.stdin.take()),
+            false => PipeEnd::Read(child.stdout.take()),
+        };
+
+        Ok(Box::new(Pipe { child, end }))
+    }
+
+    fn tmpfile(&self) -> io::Result<Box<dyn Stream>> {
+        let path = self.tmpname()?;
+        let path = os_string(&path);
+        let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+
+        // Where a file can outlive its name, it is removed at once, and otherwise on closing.
+        let remove_on_close = match cfg!(unix) {
+            true => {
+                fs::remove_file(&path)?;
+                None
+            }
+            false => Some(path),
+        };
+
+        Ok(Box::new(TemporaryFile {
+            file: StdFile(file),
+            remove_on_close,
+        }))
+    }
+
+    fn stdin(&self) -> Box<dyn Stream> {
+        Box::new(StdStream::Stdin)
+    }
+
+    fn stdout(&self) -> Box<dyn Stream> {
+        Box::new(StdStream::Stdout)
+    }
+
+    fn stderr(&self) -> Box<dyn Stream> {
+        Box::new(StdStream::Stderr)
+    }
+
+    fn remove(&self, path: &[u8]) -> io::Result<()> {
+        let path = os_string(path);
+        match fs::metadata(&path)?.is_dir() {
+            true => fs::remove_dir(path),
+            false => fs::remove_file(path),
+        }
+    }
+
+    fn rename(&self, from: &[u8], to: &[u8]) -> io::Result<()> {
+        fs::rename(os_string(from), os_string(to))
+    }
+
+    fn tmpname(&self) -> io::Result<Vec<u8>> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        let directory = env::temp_dir();
+        loop {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .subsec_nanos();
+            let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+            let path = directory.join(format!("lua_{}_{count}_{nanos:x}", process::id()));
+
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(_) => return Ok(os_bytes(path.as_os_str())),
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn getenv(&self, name: &[u8]) -> Option<Vec<u8>> {
+        env::var_os(os_string(name)).map(|value| os_bytes(&value))
+    }
+
+    #[cfg(unix)]
+    fn clock(&self) -> f64 {
+        let mut time = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `time` is valid for writes, and the clock is one every Unix has.
+        match unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time) } {
+            0 => time.tv_sec as f64 + time.tv_nsec as f64 / 1e9,
+            _ => 0.0,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn clock(&self) -> f64 {
+        self.created.elapsed().as_secs_f64()
+    }
+
+    fn exit(&self, code: i32) -> io::Result<Infallible> {
+        let _ = io::stdout().flush();
+        process::exit(code)
+    }
+}
+
+/// The message of an I/O error, without the `(os error N)` that Rust adds to those of the
+/// operating system, so that it reads like C's `strerror`.
+pub fn error_message(error: &io::Error) -> String {
+    let message = error.to_string();
+    match (error.raw_os_error(), message.rfind(" (os error ")) {
+        (Some(_), Some(end)) => message[..end].to_owned(),
+        _ => message,
+    }
+}
+
+fn denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Permission denied")
+}
+
+pub(super) fn bad_descriptor() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Bad file descriptor")
+}
+
+#[cfg(unix)]
+fn os_string(bytes: &[u8]) -> std::ffi::OsString {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).to_owned()
+}
+
+#[cfg(not(unix))]
+fn os_string(bytes: &[u8]) -> std::ffi::OsString {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(unix)]
+fn os_bytes(string: &std::ffi::OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    string.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_bytes(string: &std::ffi::OsStr) -> Vec<u8> {
+    string.to_string_lossy().into_owned().into_bytes()
+}
+
+/// The standard stream of a host that denies access to them, on which everything fails.
+struct Denied;
+
+impl Stream for Denied {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(denied())
+    }
+
+    fn write(&mut self, _: &[u8]) -> io::Result<()> {
+        Err(denied())
+    }
+}
+
+enum StdStream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl Stream for StdStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdin => io::stdin().read(buffer),
+            _ => Err(bad_descriptor()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Stdin => Err(bad_descriptor()),
+            Self::Stdout => io::stdout().write_all(data),
+            Self::Stderr => io::stderr().write_all(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdin => Ok(()),
+            Self::Stdout => io::stdout().flush(),
+            Self::Stderr => io::stderr().flush(),
+        }
+    }
+}
+
+struct StdFile(fs::File);
+
+impl Stream for StdFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buffer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.0.seek(position)
+    }
+}
+
+struct TemporaryFile {
+    file: StdFile,
+    remove_on_close: Option<std::ffi::OsString>,
+}
+
+impl Stream for TemporaryFile {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buffer)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.file.write(data)
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.file.seek(position)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<Option<Exit>> {
+        let Self {
+            file,
+            remove_on_close,
+        } = *self;
+
+        drop(file);
+        match remove_on_close {
+            Some(path) => fs::remove_file(path).map(|()| None),
+            None => Ok(None),
+        }
+    }
+}
+
+enum PipeEnd {
+    Read(Option<ChildStdout>),
+    Write(Option<ChildStdin>),
+}
+
+struct Pipe {
+    child: Child,
+    end: PipeEnd,
+}
+
+impl Stream for Pipe {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match &mut self.end {
+            PipeEnd::Read(Some(output)) => output.read(buffer),
+            _ => Err(bad_descriptor()),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.end {
+            PipeEnd::Write(Some(input)) => input.write_all(data),
+            _ => Err(bad_descriptor()),
+        }
+    }
+
+    fn close(mut self: Box<Self>) -> io::Result<Option<Exit>> {
+        // Closing our end first lets a process that reads its input to the end finish.
+        self.end = PipeEnd::Read(None);
+        let status = self.child.wait()?;
+
+        #[cfg(unix)]
+        if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+            return Ok(Some(Exit::Signal(signal)));
+        }
+
+        Ok(Some(Exit::Code(status.code().unwrap_or(-1))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn getenv_returns_raw_bytes() {
+        env::set_var("SATIN_TEST_GETENV", os_string(b"caf\xe9"));
+        let value = StdHost::new().getenv(b"SATIN_TEST_GETENV");
+        assert_eq!(value.as_deref(), Some(&b"caf\xe9"[..]));
+        assert_eq!(StdHost::new().getenv(b"SATIN_TEST_UNSET"), None);
+    }
+
+    #[test]
+    fn clock_measures_processor_time() {
+        let host = StdHost::new();
+        let start = host.clock();
+        let mut sum = 0u64;
+        for n in 0..10_000_000u64 {
+            sum = sum.wrapping_add(n * n);
+        }
+
+        assert_ne!(std::hint::black_box(sum), 1);
+        assert!(host.clock() > start);
+    }
+}
@@ -0,0 +1,790 @@
+//! The basic functions, which live directly in the table of globals.
+
+use {
+    super::{
+        argument_error, check_any, check_integer, check_option, check_string, check_table,
+        opt_integer, opt_string, set_function, set_library, type_error,
+    },
+    crate::{
+        number,
+        runtime::{
+            host, Error, Function, FunctionRef, NativeFunction, OpenMode, Result, State, Value,
+        },
+    },
+    std::{cell::Cell, io, rc::Rc},
+};
+
+pub fn open_base(state: &mut State) {
+    let globals = state.globals();
+    set_function(state, globals, "assert", assert);
+    set_function(state, globals, "collectgarbage", collectgarbage);
+    set_function(state, globals, "dofile", dofile);
+    set_function(state, globals, "error", error);
+    set_function(state, globals, "getmetatable", getmetatable);
+    set_function(state, globals, "load", load);
+    set_function(state, globals, "loadfile", loadfile);
+    set_function(state, globals, "pcall", pcall);
+    set_function(state, globals, "print", print);
+    set_function(state, globals, "rawequal", rawequal);
+    set_function(state, globals, "rawget", rawget);
+    set_function(state, globals, "rawlen", rawlen);
+    set_function(state, globals, "rawset", rawset);
+    set_function(state, globals, "select", select);
+    set_function(state, globals, "setmetatable", setmetatable);
+    set_function(state, globals, "tonumber", tonumber);
+    set_function(state, globals, "tostring", tostring);
+    set_function(state, globals, "type", type_);
+    set_function(state, globals, "xpcall", xpcall);
+
+    // `pairs` returns `next` itself, and `ipairs` always the same iterator.
+    let next = state.heap.new_function(Function::native(next));
+    let name = state.heap.intern(b"next");
+    state.heap[globals].set_str(name, Value::Function(next));
+    set_function_with_upvalue(state, "pairs", pairs, Value::Function(next));
+
+    let ipairs_iterator = state.heap.new_function(Function::native(ipairs_iterator));
+    set_function_with_upvalue(state, "ipairs", ipairs, Value::Function(ipairs_iterator));
+
+    // Warnings are off until turned on with `warn("@on")`.
+    let warnings_on = Rc::new(Cell::new(false));
+    set_function(state, globals, "warn", move |state| {
+        warn(state, &warnings_on)
+    });
+
+    set_library(state, "_G", globals);
+    let name = state.heap.intern(b"_VERSION");
+    let version = state.heap.intern(b"Lua 5.4");
+    state.heap[globals].set_str(name, Value::String(version));
+}
+
+/// Sets a global to a native function with a single upvalue.
+fn set_function_with_upvalue(
+    state: &mut State,
+    name: &str,
+    callback: fn(&mut State) -> Result<usize>,
+    upvalue: Value,
+) {
+    let globals = state.globals();
+    let name = state.heap.intern(name.as_bytes());
+    let function = state.heap.new_function(Function::Native(NativeFunction {
+        callback: Rc::new(callback),
+        upvalues: Box::new([upvalue]),
+    }));
+    state.heap[globals].set_str(name, Value::Function(function));
+}
+
+/// `assert(v [, message, ...])`: returns all its arguments if `v` is true, and otherwise
+/// raises `message`, or "assertion failed!" if there is none.
+fn assert(state: &mut State) -> Result<usize> {
+    if check_any(state, 1, "assert")?.is_truthy() {
+        let nargs = state.arg_count();
+        for n in 1..=nargs {
+            state.push(state.arg(n));
+        }
+        return Ok(nargs);
+    }
+
+    match state.arg_count() {
+        1 => Err(Error::AssertionFailed),
+        _ => Err(Error::Value(state.arg(2))),
+    }
+}
+
+/// `collectgarbage([option [, ...]])`: controls the collector, as `option` says:
+///
+/// - "collect" (the default) performs a full collection;
+/// - "count" returns the memory in use, in kilobytes;
+/// - "step" performs collection work as if the given number of kilobytes had been allocated,
+///   returning whether a cycle completed;
+/// - "incremental" and "generational" switch modes, changing the given parameters that are
+///   not zero, and return the name of the previous mode;
+/// - "isrunning" returns whether the collector is running;
+/// - "stop" and "restart" stop it and start it again.
+fn collectgarbage(state: &mut State) -> Result<usize> {
+    const OPTIONS: [&str; 8] = [
+        "collect",
+        "count",
+        "step",
+        "incremental",
+        "generational",
+        "isrunning",
+        "stop",
+        "restart",
+    ];
+
+    let option = check_option(state, 1, "collectgarbage", Some("collect"), &OPTIONS)?;
+    state.release_refs();
+    state.clear_dead_slots();
+
+    let result = match OPTIONS[option] {
+        "collect" => {
+            state.heap.gc_collect(&state.stack);
+            Value::Int(0)
+        }
+
+        "count" => Value::Float(state.heap.gc_count()),
+
+        "step" => {
+            let kilobytes = opt_integer(state, 2, "collectgarbage", 0)?;
+            Value::Bool(state.heap.gc_step_by(&state.stack, kilobytes))
+        }
+
+        "incremental" => {
+            let pause = gc_parameter(state, 2)?;
+            let step_multiplier = gc_parameter(state, 3)?;
+            let step_size = gc_parameter(state, 4)?;
+            let previous = state
+                .heap
+                .gc_set_incremental(pause, step_multiplier, step_size);
+            Value::String(state.heap.intern(previous.name().as_bytes()))
+        }
+
+        "generational" => {
+            let minor_multiplier = gc_parameter(state, 2)?;
+            let major_multiplier = gc_parameter(state, 3)?;
+            let previous =
+                state
+                    .heap
+                    .gc_set_generational(&state.stack, minor_multiplier, major_multiplier);
+            Value::String(state.heap.intern(previous.name().as_bytes()))
+        }
+
+        "isrunning" => Value::Bool(state.heap.gc_is_running()),
+
+        "stop" => {
+            state.heap.gc_stop();
+            Value::Int(0)
+        }
+
+        _ => {
+            state.heap.gc_restart();
+            Value::Int(0)
+        }
+    };
+
+    // A collection may have found objects with finalizers to run.
+    state.push(result);
+    state.run_finalizers();
+    Ok(1)
+}
+
+/// The `position`th argument of `collectgarbage` as a collector parameter, where 0, the
+/// default, leaves the parameter as it is.
+fn gc_parameter(state: &State, position: usize) -> Result<u32> {
+    let value = opt_integer(state, position, "collectgarbage", 0)?;
+    Ok(value.clamp(0, u32::MAX as i64) as u32)
+}
+
+/// `print(...)`: writes its arguments to standard output, converted as `tostring` does,
+/// separated by tabs and followed by a newline.
+fn print(state: &mut State) -> Result<usize> {
+    let mut line = Vec::new();
+    for n in 1..=state.arg_count() {
+        if n > 1 {
+            line.push(b'\t');
+        }
+        let string = state.tostring(state.arg(n))?;
+        line.extend_from_slice(&state.heap[string]);
+    }
+    line.push(b'\n');
+
+    let mut stdout = state.host().stdout();
+    // Like the reference implementation, `print` has no way to report a failed write.
+    let _ = stdout.write(&line).and_then(|()| stdout.flush());
+    Ok(0)
+}
+
+/// `warn(message, ...)`: writes the concatenation of its arguments to standard error as a
+/// warning, if warnings are on. A single argument starting with `@` is a control message
+/// instead: `@on` and `@off` turn warnings on and off, and others are ignored.
+fn warn(state: &mut State, warnings_on: &Cell<bool>) -> Result<usize> {
+    check_string(state, 1, "warn")?;
+    let mut message = Vec::new();
+    for n in 1..=state.arg_count() {
+        let piece = check_string(state, n, "warn")?;
+        message.extend_from_slice(&state.heap[piece]);
+    }
+
+    if state.arg_count() == 1 && message.first() == Some(&b'@') {
+        match &message[..] {
+            b"@on" => warnings_on.set(true),
+            b"@off" => warnings_on.set(false),
+            _ => {}
+        }
+    } else if warnings_on.get() {
+        message.splice(..0, *b"Lua warning: ");
+        message.push(b'\n');
+
+        let mut stderr = state.host().stderr();
+        let _ = stderr.write(&message).and_then(|()| stderr.flush());
+    }
+
+    Ok(0)
+}
+
+/// `type(v)`: returns the name of the type of `v`.
+fn type_(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "type")?;
+    let name = state.heap.intern(value.type_name().as_bytes());
+    state.push(name);
+    Ok(1)
+}
+
+/// `tostring(v)`: converts `v` to a string, honouring `__tostring` and `__name`.
+fn tostring(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "tostring")?;
+    let string = state.tostring(value)?;
+    state.push(string);
+    Ok(1)
+}
+
+/// `tonumber(e [, base])`: converts `e` to a number, returning nil if it cannot be converted.
+///
+/// Without a base, `e` may be a number or a string holding any numeral. With a base from 2
+/// to 36, `e` must be a string of digits in that base, where letters stand for the digits
+/// from 10 upwards, and the result is an integer.
+fn tonumber(state: &mut State) -> Result<usize> {
+    let result = if state.arg(2).is_nil() {
+        let value = check_any(state, 1, "tonumber")?;
+        state.to_number(value).unwrap_or_default()
+    } else {
+        let base = check_integer(state, 2, "tonumber")?;
+        let Value::String(string) = state.arg(1) else {
+            return Err(type_error(state, 1, "tonumber", "string"));
+        };
+
+        if !(2..=36).contains(&base) {
+            return Err(argument_error(2, "tonumber", "base out of range"));
+        }
+
+        number::parse_int_in_base(&state.heap[string], base as u32).map_or(Value::Nil, Value::Int)
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+/// `rawequal(v1, v2)`: whether the two values are equal without calling `__eq`.
+fn rawequal(state: &mut State) -> Result<usize> {
+    let a = check_any(state, 1, "rawequal")?;
+    let b = check_any(state, 2, "rawequal")?;
+    state.push(a.raw_eq(b));
+    Ok(1)
+}
+
+/// `rawlen(v)`: the length of a table or string, without calling `__len`.
+fn rawlen(state: &mut State) -> Result<usize> {
+    let length = match state.arg(1) {
+        Value::Table(table) => state.heap[table].border(),
+        Value::String(string) => state.heap[string].len() as i64,
+        _ => return Err(argument_error(1, "rawlen", "table or string expected")),
+    };
+
+    state.push(length);
+    Ok(1)
+}
+
+/// `rawget(table, index)`: reads a field without calling `__index`.
+fn rawget(state: &mut State) -> Result<usize> {
+    let table = check_table(state, 1, "rawget")?;
+    let key = check_any(state, 2, "rawget")?;
+    state.push(state.heap[table].get(key));
+    Ok(1)
+}
+
+/// `rawset(table, index, value)`: assigns a field without calling `__newindex`, returning
+/// the table.
+fn rawset(state: &mut State) -> Result<usize> {
+    let table = check_table(state, 1, "rawset")?;
+    let key = check_any(state, 2, "rawset")?;
+    let value = check_any(state, 3, "rawset")?;
+    state.heap[table].set(key, value)?;
+    state.push(table);
+    Ok(1)
+}
+
+/// `getmetatable(v)`: returns the metatable of `v`, or its `__metatable` field if it has one.
+fn getmetatable(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "getmetatable")?;
+    state.push(state.get_metatable(value));
+    Ok(1)
+}
+
+/// `setmetatable(table, metatable)`: sets or, with nil, removes the metatable of `table`,
+/// returning `table`.
+fn setmetatable(state: &mut State) -> Result<usize> {
+    let table = check_table(state, 1, "setmetatable")?;
+    let metatable = match state.arg(2) {
+        Value::Table(metatable) => Some(metatable),
+        Value::Nil if state.arg_count() >= 2 => None,
+        _ => return Err(type_error(state, 2, "setmetatable", "nil or table")),
+    };
+
+    state.set_metatable(table, metatable)?;
+    state.push(table);
+    Ok(1)
+}
+
+/// `next(table [, index])`: returns the field of `table` that follows `index` in traversal
+/// order, starting with the first for a nil index, or nil after the last.
+fn next(state: &mut State) -> Result<usize> {
+    let table = check_table(state, 1, "next")?;
+    match state.heap[table].next(state.arg(2))? {
+        Some((key, value)) => {
+            state.push(key);
+            state.push(value);
+            Ok(2)
+        }
+
+        None => {
+            state.push(Value::Nil);
+            Ok(1)
+        }
+    }
+}
+
+/// `pairs(t)`: returns what the `__pairs` metamethod of `t` returns, or else `next`, `t` and
+/// nil, so that a generic `for` traverses every field of `t`.
+fn pairs(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "pairs")?;
+    let [iterator, state_value, control] = match state.pairs(value)? {
+        Some(triple) => triple,
+        None => [state.upvalue(1), value, Value::Nil],
+    };
+
+    state.push(iterator);
+    state.push(state_value);
+    state.push(control);
+    Ok(3)
+}
+
+/// `ipairs(t)`: returns an iterator, `t` and 0, so that a generic `for` visits `t[1]`, `t[2]`
+/// and so on up to the first nil, reading them as indexing would.
+fn ipairs(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "ipairs")?;
+    state.push(state.upvalue(1));
+    state.push(value);
+    state.push(0);
+    Ok(3)
+}
+
+fn ipairs_iterator(state: &mut State) -> Result<usize> {
+    let index = check_integer(state, 2, "ipairs")?.wrapping_add(1);
+    let value = state.index(state.arg(1), Value::Int(index))?;
+    if value.is_nil() {
+        state.push(Value::Nil);
+        return Ok(1);
+    }
+
+    state.push(index);
+    state.push(value);
+    Ok(2)
+}
+
+/// `select(n, ...)`: returns the arguments after the `n`th, where a negative `n` counts from
+/// the end, or with `n` equal to "#", how many arguments there are after it.
+fn select(state: &mut State) -> Result<usize> {
+    let count = state.arg_count().saturating_sub(1) as i64;
+    if let Value::String(string) = state.arg(1) {
+        if &state.heap[string] == b"#" {
+            state.push(count);
+            return Ok(1);
+        }
+    }
+
+    let n = check_integer(state, 1, "select")?;
+    let first = match n {
+        n if n < 0 => count + n,
+        n => n.min(count + 1) - 1,
+    };
+
+    if first < 0 {
+        return Err(argument_error(1, "select", "index out of range"));
+    }
+
+    for position in first as usize + 2..=state.arg_count() {
+        state.push(state.arg(position));
+    }
+    Ok((count - first) as usize)
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])`: compiles `chunk` and returns it as a
+/// function, or nil and an error message.
+///
+/// `chunk` is either a string or a function that returns its pieces, until it returns an
+/// empty string or nothing. The chunk name defaults to the chunk itself if it is a string and
+/// to "=(load)" otherwise. `mode` says which kinds of chunk are allowed, as with
+/// [`State::load`], and a given `env` becomes the function's environment, even if nil.
+fn load(state: &mut State) -> Result<usize> {
+    let (chunk, default_name) = match state.arg(1) {
+        Value::String(chunk) => {
+            let chunk = state.heap[chunk].to_vec();
+            (Ok(chunk.clone()), chunk)
+        }
+
+        reader @ Value::Function(_) => (read_chunk(state, reader), b"=(load)".to_vec()),
+        _ => return Err(type_error(state, 1, "load", "string")),
+    };
+
+    let chunk_name = match state.arg(2) {
+        Value::Nil => default_name,
+        _ => {
+            let name = check_string(state, 2, "load")?;
+            state.heap[name].to_vec()
+        }
+    };
+    let chunk_name = String::from_utf8_lossy(&chunk_name).into_owned();
+    let mode = String::from_utf8_lossy(&opt_string(state, 3, "load", "bt")?).into_owned();
+    let env = (state.arg_count() >= 4).then(|| state.arg(4));
+
+    let loaded = chunk.and_then(|chunk| state.load(&chunk, &chunk_name, &mode, env));
+    push_loaded(state, loaded)
+}
+
+/// Concatenates the pieces of a chunk that a reader function returns.
+fn read_chunk(state: &mut State, reader: Value) -> Result<Vec<u8>> {
+    let mut chunk = Vec::new();
+    loop {
+        state.push(reader);
+        state.call(0, Some(1))?;
+        match state.pop() {
+            Value::Nil => return Ok(chunk),
+            Value::String(piece) if state.heap[piece].is_empty() => return Ok(chunk),
+            Value::String(piece) => chunk.extend_from_slice(&state.heap[piece]),
+            _ => return Err(Error::ReaderNotString),
+        }
+    }
+}
+
+/// Returns a loaded function, or nil and the error that loading failed with.
+fn push_loaded(state: &mut State, loaded: Result<FunctionRef>) -> Result<usize> {
+    match loaded {
+        Ok(function) => {
+            state.push(function);
+            Ok(1)
+        }
+
+        Err(error) => {
+            let error = state.error_value(error);
+            state.push(Value::Nil);
+            state.push(error);
+            Ok(2)
+        }
+    }
+}
+
+/// `loadfile([filename [, mode [, env]]])`: like `load`, but reads the chunk from the named
+/// file, or from standard input if there is no name.
+fn loadfile(state: &mut State) -> Result<usize> {
+    let file = match state.arg(1) {
+        Value::Nil => None,
+        _ => {
+            let file = check_string(state, 1, "loadfile")?;
+            Some(String::from_utf8_lossy(&state.heap[file]).into_owned())
+        }
+    };
+    let mode = String::from_utf8_lossy(&opt_string(state, 2, "loadfile", "bt")?).into_owned();
+    let env = (state.arg_count() >= 3).then(|| state.arg(3));
+
+    let loaded = load_file(state, file.as_deref(), &mode, env);
+    push_loaded(state, loaded)
+}
+
+/// `dofile([filename])`: loads the named file, or standard input, as `loadfile` does and runs
+/// it, returning its results and propagating any error.
+fn dofile(state: &mut State) -> Result<usize> {
+    let file = match state.arg(1) {
+        Value::Nil => None,
+        _ => {
+            let file = check_string(state, 1, "dofile")?;
+            Some(String::from_utf8_lossy(&state.heap[file]).into_owned())
+        }
+    };
+
+    match load_file(state, file.as_deref(), "bt", None) {
+        Ok(function) => {
+            state.push(function);
+            state.call(0, None)
+        }
+
+        // The message is already complete, so it is raised without a position.
+        Err(error) => Err(Error::Value(state.error_value(error))),
+    }
+}
+
+/// Loads the named file, or standard input, skipping a first line that starts with `#` so
+/// that scripts can name their interpreter.
+pub(crate) fn load_file(
+    state: &mut State,
+    file: Option<&str>,
+    mode: &str,
+    env: Option<Value>,
+) -> Result<FunctionRef> {
+    let chunk_name = match file {
+        Some(file) => format!("@{file}"),
+        None => "=stdin".to_owned(),
+    };
+    let mut chunk = read_file(state, file)?;
+
+    if chunk.first() == Some(&b'#') {
+        // The newline is kept, so that line numbers are unchanged.
+        let end = chunk
+            .iter()
+            .position(|&c| c == b'\n')
+            .unwrap_or(chunk.len());
+        chunk.drain(..end);
+    }
+
+    state.load(&chunk, &chunk_name, mode, env)
+}
+
+/// Reads the whole of the named file, or of standard input, through the host.
+fn read_file(state: &State, file: Option<&str>) -> Result<Vec<u8>> {
+    let failed = |action| {
+        move |error: io::Error| Error::File {
+            action,
+            file: file.unwrap_or("stdin").to_owned(),
+            message: host::error_message(&error),
+        }
+    };
+
+    let host = state.host();
+    let mut stream = match file {
+        Some(file) => {
+            let mode = OpenMode {
+                read: true,
+                ..OpenMode::default()
+            };
+            host.open(file.as_bytes(), mode).map_err(failed("open"))?
+        }
+        None => host.stdin(),
+    };
+
+    let mut contents = Vec::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match stream.read(&mut buffer).map_err(failed("read"))? {
+            0 => break,
+            count => contents.extend_from_slice(&buffer[..count]),
+        }
+    }
+
+    let _ = stream.close();
+    Ok(contents)
+}
+
+/// `error(message [, level])`: raises `message`, which may be any value.
+///
+/// A string message is prefixed with the position of the function at the given level, where
+/// level 1, the default, is the function that called `error` and 0 adds no position.
+fn error(state: &mut State) -> Result<usize> {
+    let mut message = state.arg(1);
+    let level = opt_integer(state, 2, "error", 1)?;
+
+    if let (Value::String(text), Ok(level @ 1..)) = (message, usize::try_from(level)) {
+        let mut located = state.location(level).into_bytes();
+        located.extend_from_slice(&state.heap[text]);
+        message = Value::String(state.heap.intern(&located));
+    }
+
+    Err(Error::Value(message))
+}
+
+/// `pcall(f, ...)`: calls `f` with the remaining arguments, returning true followed by its
+/// results, or false and the error value if it raises an error.
+fn pcall(state: &mut State) -> Result<usize> {
+    check_any(state, 1, "pcall")?;
+    let nargs = state.arg_count();
+    for n in 1..=nargs {
+        state.push(state.arg(n));
+    }
+
+    match state.protected_call(nargs - 1, None) {
+        Ok(count) => {
+            state.insert_below(count, Value::Bool(true));
+            Ok(count + 1)
+        }
+
+        Err(value) => {
+            state.push(false);
+            state.push(value);
+            Ok(2)
+        }
+    }
+}
+
+/// `xpcall(f, msgh, ...)`: like `pcall`, but an error is passed through the message handler
+/// `msgh` while the stack where it was raised is still intact, and the handler's result
+/// becomes the error value.
+fn xpcall(state: &mut State) -> Result<usize> {
+    let handler = state.arg(2);
+    if !matches!(handler, Value::Function(_)) {
+        return Err(type_error(state, 2, "xpcall", "function"));
+    }
+
+    let nargs = state.arg_count();
+    state.push(state.arg(1));
+    for n in 3..=nargs {
+        state.push(state.arg(n));
+    }
+
+    match state.protected_call_with_handler(nargs - 2, None, handler) {
+        Ok(count) => {
+            state.insert_below(count, Value::Bool(true));
+            Ok(count + 1)
+        }
+
+        Err(value) => {
+            state.push(false);
+            state.push(value);
+            Ok(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::assert_runs;
+
+    #[test]
+    fn tonumber_reads_numerals_in_any_base() {
+        assert_runs(
+            "return tonumber('0x10') .. ' ' .. tonumber('  12  ') .. ' ' .. tonumber('1e2') \
+             .. ' ' .. tostring(tonumber('1e')) .. ' ' .. tonumber('z', 36) \
+             .. ' ' .. tonumber('ff', 16) .. ' ' .. tostring(tonumber('8', 8))",
+            "16 12 100.0 nil 35 255 nil",
+        );
+        assert_runs(
+            "return math.type(tonumber('10')) .. math.type(tonumber('10.0'))",
+            "integerfloat",
+        );
+        assert_runs(
+            "return select(2, pcall(tonumber, '10', 99))",
+            "bad argument #2 to 'tonumber' (base out of range)",
+        );
+    }
+
+    #[test]
+    fn tostring_and_type() {
+        assert_runs(
+            "return tostring(nil) .. tostring(true) .. tostring(1.5) .. tostring(-0.0) \
+             .. tostring(1e100) .. tostring(2^63)",
+            "niltrue1.5-0.01e+1009.2233720368548e+18",
+        );
+        assert_runs(
+            "return type(print) .. type(nil) .. type(2) .. type('') .. type({}) \
+             .. type(coroutine.create(print))",
+            "functionnilnumberstringtablethread",
+        );
+        assert_runs(
+            "return select(2, pcall(tostring))",
+            "bad argument #1 to 'tostring' (value expected)",
+        );
+    }
+
+    #[test]
+    fn assert_and_select() {
+        assert_runs("return select('#', assert(1, 2, 3))", "3");
+        assert_runs(
+            "return select(2, pcall(assert, false))",
+            "assertion failed!",
+        );
+        assert_runs("return select(2, pcall(assert, nil, 'custom'))", "custom");
+        assert_runs("return select(-1, 1, 2, 3)", "3");
+        assert_runs(
+            "return select(2, pcall(select, 0))",
+            "bad argument #1 to 'select' (index out of range)",
+        );
+    }
+
+    #[test]
+    fn load_takes_strings_and_readers() {
+        assert_runs("return load('return 1 + ...')(2)", "3");
+        assert_runs(
+            "local parts = {'return ', '4', '2'} local i = 0 \
+             return load(function() i = i + 1 return parts[i] end)()",
+            "42",
+        );
+        assert_runs(
+            "local env = {x = 5} return load('return x', 'chunk', 't', env)()",
+            "5",
+        );
+        assert_runs(
+            "return select(2, load('return +'))",
+            "[string \"return +\"]:1: unexpected symbol near '+'",
+        );
+        assert_runs(
+            "return select(2, load('\\27Lua', 'b', 't'))",
+            "attempt to load a binary chunk (mode is 't')",
+        );
+        assert_runs(
+            "return select(2, pcall(dofile, '/nonexistent/file.lua'))",
+            "cannot open /nonexistent/file.lua: No such file or directory",
+        );
+    }
+
+    #[test]
+    fn iteration() {
+        assert_runs(
+            "local n = 0 for _ in ipairs({1, 2, nil, 4}) do n = n + 1 end return n",
+            "2",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__index = function(_, i) if i < 4 then return i end end}) \
+             local s = 0 for _, v in ipairs(t) do s = s + v end return s",
+            "6",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__pairs = function(t) \
+               return function(_, k) if not k then return 1, 'one' end end, t, nil \
+             end}) \
+             for k, v in pairs(t) do return k .. v end",
+            "1one",
+        );
+        assert_runs(
+            "return select(2, pcall(next, {}, 'nope'))",
+            "invalid key to 'next'",
+        );
+    }
+
+    #[test]
+    fn raw_access() {
+        assert_runs(
+            "return tostring(rawequal({}, {})) .. rawlen({1, 2}) .. rawlen('abc')",
+            "false23",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__newindex = error}) rawset(t, 'x', 1) return t.x",
+            "1",
+        );
+        assert_runs(
+            "return select(2, pcall(rawset, {}, nil, 1))",
+            "index is nil",
+        );
+    }
+
+    #[test]
+    fn collectgarbage_options() {
+        assert_runs("return collectgarbage('count') > 0", "true");
+        assert_runs(
+            "collectgarbage('stop') local stopped = not collectgarbage('isrunning') \
+             collectgarbage('restart') return stopped and collectgarbage('isrunning')",
+            "true",
+        );
+        assert_runs(
+            "return collectgarbage('incremental') .. collectgarbage('generational') \
+             .. collectgarbage('incremental')",
+            "incrementalincrementalgenerational",
+        );
+        assert_runs(
+            "return select(2, pcall(collectgarbage, 'bogus'))",
+            "bad argument #1 to 'collectgarbage' (invalid option 'bogus')",
+        );
+    }
+
+    #[test]
+    fn globals() {
+        assert_runs("return _VERSION", "Lua 5.4");
+        assert_runs("return _G._G == _G", "true");
+    }
+}
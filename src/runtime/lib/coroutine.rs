@@ -0,0 +1,301 @@
+//! The `coroutine` library.
+
+use {
+    super::{set_function, set_library, type_error},
+    crate::runtime::{Error, Function, NativeFunction, Result, State, Status, ThreadRef, Value},
+    std::rc::Rc,
+};
+
+pub fn open_coroutine(state: &mut State) {
+    let coroutine = state.heap.new_table();
+    set_function(state, coroutine, "close", close);
+    set_function(state, coroutine, "create", create);
+    set_function(state, coroutine, "isyieldable", is_yieldable);
+    set_function(state, coroutine, "resume", resume);
+    set_function(state, coroutine, "running", running);
+    set_function(state, coroutine, "status", status);
+    set_function(state, coroutine, "wrap", wrap);
+    set_function(state, coroutine, "yield", yield_);
+
+    set_library(state, "coroutine", coroutine);
+}
+
+fn check_thread(state: &State, position: usize, function: &'static str) -> Result<ThreadRef> {
+    match state.arg(position) {
+        Value::Thread(thread) => Ok(thread),
+        _ => Err(type_error(state, position, function, "coroutine")),
+    }
+}
+
+fn check_function(state: &State, position: usize, function: &'static str) -> Result<Value> {
+    match state.arg(position) {
+        value @ Value::Function(_) => Ok(value),
+        _ => Err(type_error(state, position, function, "function")),
+    }
+}
+
+/// Pushes copies of the arguments from the `first`th onwards, returning how many there are.
+fn push_args_from(state: &mut State, first: usize) -> usize {
+    let nargs = state.arg_count();
+    for n in first..=nargs {
+        state.push(state.arg(n));
+    }
+    (nargs + 1).saturating_sub(first)
+}
+
+/// `coroutine.create(f)`: returns a new coroutine that calls `f` when first resumed.
+fn create(state: &mut State) -> Result<usize> {
+    let function = check_function(state, 1, "create")?;
+    let thread = state.new_thread(function);
+    state.push(thread);
+    Ok(1)
+}
+
+/// `coroutine.resume(co, ...)`: runs `co` until it yields or returns, returning true followed
+/// by the values it passed out, or false and an error value.
+fn resume(state: &mut State) -> Result<usize> {
+    let thread = check_thread(state, 1, "resume")?;
+    let nargs = push_args_from(state, 2);
+
+    match state.resume(thread, nargs) {
+        Ok(count) => {
+            state.insert_below(count, Value::Bool(true));
+            Ok(count + 1)
+        }
+
+        Err(error) => {
+            state.push(false);
+            state.push(error);
+            Ok(2)
+        }
+    }
+}
+
+/// `coroutine.yield(...)`: suspends the running coroutine, passing the arguments to its
+/// resumer, and returns the values the coroutine is next resumed with.
+fn yield_(state: &mut State) -> Result<usize> {
+    let count = push_args_from(state, 1);
+    state.yield_values(count)
+}
+
+/// `coroutine.wrap(f)`: returns a function that resumes a new coroutine calling `f`, returning
+/// the values it passes out, and that raises any error the coroutine fails with.
+fn wrap(state: &mut State) -> Result<usize> {
+    let function = check_function(state, 1, "wrap")?;
+    let thread = state.new_thread(function);
+
+    let wrapper = state.heap.new_function(Function::Native(NativeFunction {
+        callback: Rc::new(|state: &mut State| {
+            let Value::Thread(thread) = state.upvalue(1) else {
+                unreachable!("a wrapped coroutine has lost its thread");
+            };
+
+            let nargs = push_args_from(state, 1);
+            match state.resume(thread, nargs) {
+                Ok(count) => Ok(count),
+
+                Err(mut error) => {
+                    if state.heap[thread].status == Status::Dead {
+                        error = state.close_thread(thread)?.unwrap_or(error);
+                    }
+
+                    if let Value::String(message) = error {
+                        let mut located = state.location(1).into_bytes();
+                        located.extend_from_slice(&state.heap[message]);
+                        error = Value::String(state.heap.intern(&located));
+                    }
+
+                    Err(Error::Value(error))
+                }
+            }
+        }),
+        upvalues: Box::new([Value::Thread(thread)]),
+    }));
+
+    state.push(wrapper);
+    Ok(1)
+}
+
+/// `coroutine.status(co)`: returns "running", "suspended", "normal" or "dead".
+fn status(state: &mut State) -> Result<usize> {
+    let thread = check_thread(state, 1, "status")?;
+    let name = state.heap[thread].status.name();
+    let name = state.heap.intern(name.as_bytes());
+    state.push(name);
+    Ok(1)
+}
+
+/// `coroutine.close(co)`: closes a suspended or dead coroutine, returning true, or false and
+/// the error that it died with or that closing it raised.
+fn close(state: &mut State) -> Result<usize> {
+    let thread = check_thread(state, 1, "close")?;
+
+    match state.close_thread(thread)? {
+        None => {
+            state.push(true);
+            Ok(1)
+        }
+
+        Some(error) => {
+            state.push(false);
+            state.push(error);
+            Ok(2)
+        }
+    }
+}
+
+/// `coroutine.isyieldable([co])`: whether `co`, by default the running coroutine, can yield.
+fn is_yieldable(state: &mut State) -> Result<usize> {
+    let yieldable = match state.arg(1) {
+        Value::Nil if state.arg_count() == 0 => state.is_yieldable(),
+        _ => {
+            let thread = check_thread(state, 1, "isyieldable")?;
+            if thread == state.current_thread() {
+                state.is_yieldable()
+            } else {
+                thread != state.main_thread()
+            }
+        }
+    };
+
+    state.push(yieldable);
+    Ok(1)
+}
+
+/// `coroutine.running()`: returns the running coroutine and whether it is the main thread.
+fn running(state: &mut State) -> Result<usize> {
+    let thread = state.current_thread();
+    state.push(thread);
+    state.push(thread == state.main_thread());
+    Ok(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::assert_runs;
+
+    #[test]
+    fn resume_and_yield_exchange_values() {
+        assert_runs(
+            "local co = coroutine.create(function(a, b) \
+               local c = coroutine.yield(a + b) \
+               local d, e = coroutine.yield(c * 2) \
+               return d + e \
+             end) \
+             local r = {} \
+             for _, args in ipairs({{1, 2}, {10}, {3, 4}}) do \
+               local ok, v = coroutine.resume(co, table.unpack(args)) \
+               r[#r + 1] = tostring(ok) .. v \
+             end \
+             r[#r + 1] = coroutine.status(co) \
+             r[#r + 1] = select(2, coroutine.resume(co)) \
+             return table.concat(r, ' ')",
+            "true3 true20 true7 dead cannot resume dead coroutine",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__index = function(t, k) return coroutine.yield(k) end}) \
+             local co = coroutine.wrap(function() return t.foo end) \
+             return co() .. co('bar')",
+            "foobar",
+        );
+    }
+
+    #[test]
+    fn statuses() {
+        assert_runs(
+            "local co \
+             co = coroutine.create(function() return coroutine.status(co) end) \
+             return select(2, coroutine.resume(co)) .. coroutine.status(co)",
+            "runningdead",
+        );
+        assert_runs(
+            "local co = coroutine.create(function() \
+               local inner = coroutine.create(function() coroutine.yield('a') end) \
+               local _, v = coroutine.resume(inner) \
+               coroutine.yield(v .. 'b') \
+               return coroutine.status(inner) \
+             end) \
+             local _, x = coroutine.resume(co) \
+             local _, y = coroutine.resume(co) \
+             return x .. y",
+            "absuspended",
+        );
+        assert_runs(
+            "return tostring(coroutine.isyieldable()) .. tostring(select(2, coroutine.running()))",
+            "falsetrue",
+        );
+    }
+
+    #[test]
+    fn misuse_is_an_error() {
+        assert_runs(
+            "return select(2, pcall(coroutine.yield))",
+            "attempt to yield from outside a coroutine",
+        );
+        assert_runs(
+            "local co = coroutine.create(function() \
+               return coroutine.resume(coroutine.running()) \
+             end) \
+             return select(3, coroutine.resume(co))",
+            "cannot resume non-suspended coroutine",
+        );
+        assert_runs(
+            "return select(2, pcall(coroutine.close, coroutine.running()))",
+            "cannot close a running coroutine",
+        );
+    }
+
+    #[test]
+    fn wrap_propagates_errors() {
+        assert_runs(
+            "local co = coroutine.wrap(function() error('x') end) return select(2, pcall(co))",
+            "test:1: x",
+        );
+        assert_runs(
+            "local co = coroutine.wrap(function() end) co() return select(2, pcall(co))",
+            "cannot resume dead coroutine",
+        );
+        assert_runs(
+            "local gen = coroutine.wrap(function() for i = 1, 3 do coroutine.yield(i) end end) \
+             local s = 0 for v in gen do s = s + v end return s",
+            "6",
+        );
+    }
+
+    #[test]
+    fn errors_caught_inside_a_coroutine_survive_yields() {
+        assert_runs(
+            "local co = coroutine.create(function() \
+               local ok, e = pcall(function() coroutine.yield(1) error('after') end) \
+               return e \
+             end) \
+             coroutine.resume(co) \
+             return select(2, coroutine.resume(co))",
+            "test:1: after",
+        );
+    }
+
+    #[test]
+    fn close_closes_pending_variables() {
+        assert_runs(
+            "local log = {} \
+             local co = coroutine.create(function() \
+               local x <close> = setmetatable({}, {__close = function() log[#log + 1] = 'c' end}) \
+               coroutine.yield() \
+             end) \
+             coroutine.resume(co) \
+             local ok = coroutine.close(co) \
+             return tostring(ok) .. coroutine.status(co) .. table.concat(log)",
+            "truedeadc",
+        );
+        assert_runs(
+            "local co = coroutine.create(function() \
+               local x <close> = setmetatable({}, {__close = function() error('in close', 0) end}) \
+               coroutine.yield() \
+             end) \
+             coroutine.resume(co) \
+             return select(2, coroutine.close(co))",
+            "in close",
+        );
+    }
+}
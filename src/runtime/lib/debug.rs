@@ -0,0 +1,702 @@
+//! The `debug` library.
+//!
+//! Functions that take a thread as an optional first argument work on the call stack of that
+//! thread, and otherwise on that of the running one.
+
+use {
+    super::{
+        argument_error, check_any, check_bytes, check_integer, opt_integer, opt_string,
+        registry_table, set_function, set_library, type_error,
+    },
+    crate::runtime::{
+        FunctionInfo, FunctionRef, Hook, HookEvent, HookMask, Metamethod, Result, State, TableRef,
+        ThreadRef, Value, Variable,
+    },
+    cranelift_entity::EntityRef,
+};
+
+/// The registry key of the table of hook functions, keyed weakly by thread, which is the one
+/// the reference implementation uses.
+const HOOKS: &[u8] = b"_HOOKKEY";
+
+pub fn open_debug(state: &mut State) {
+    let debug = state.heap.new_table();
+    set_function(state, debug, "gethook", gethook);
+    set_function(state, debug, "getinfo", getinfo);
+    set_function(state, debug, "getlocal", getlocal);
+    set_function(state, debug, "getmetatable", getmetatable);
+    set_function(state, debug, "getupvalue", getupvalue);
+    set_function(state, debug, "getuservalue", getuservalue);
+    set_function(state, debug, "sethook", sethook);
+    set_function(state, debug, "setlocal", setlocal);
+    set_function(state, debug, "setmetatable", setmetatable);
+    set_function(state, debug, "setupvalue", setupvalue);
+    set_function(state, debug, "setuservalue", setuservalue);
+    set_function(state, debug, "traceback", traceback);
+    set_function(state, debug, "upvalueid", upvalueid);
+    set_function(state, debug, "upvaluejoin", upvaluejoin);
+
+    set_library(state, "debug", debug);
+}
+
+/// The thread that the optional first argument names, and the number of arguments it takes
+/// up, which the positions of the others are offset by.
+fn thread_argument(state: &State) -> (ThreadRef, usize) {
+    match state.arg(1) {
+        Value::Thread(thread) => (thread, 1),
+        _ => (state.current_thread(), 0),
+    }
+}
+
+fn check_function(state: &State, position: usize, function: &'static str) -> Result<FunctionRef> {
+    match state.arg(position) {
+        Value::Function(callee) => Ok(callee),
+        _ => Err(type_error(state, position, function, "function")),
+    }
+}
+
+/// The `position`th argument as a level of the call stack of `thread`, which must exist.
+fn check_level(
+    state: &State,
+    thread: ThreadRef,
+    position: usize,
+    function: &'static str,
+) -> Result<usize> {
+    let level = check_integer(state, position, function)?;
+    usize::try_from(level)
+        .ok()
+        .filter(|&level| level < state.call_depth(thread))
+        .ok_or_else(|| argument_error(position, function, "level out of range"))
+}
+
+fn set_field(state: &mut State, table: TableRef, name: &str, value: impl Into<Value>) {
+    let name = state.heap.intern(name.as_bytes());
+    state.heap[table].set_str(name, value.into());
+}
+
+fn push_string(state: &mut State, string: &str) {
+    let string = state.heap.intern(string.as_bytes());
+    state.push(string);
+}
+
+/// `debug.getinfo([thread,] f [, what])`: returns a table describing the function `f`, or
+/// the function running at level `f` of the call stack, with the fields that the letters of
+/// `what` select. Returns nil for a level beyond the bottom of the stack.
+fn getinfo(state: &mut State) -> Result<usize> {
+    const OPTIONS: &[u8] = b"SlnrutLf";
+
+    let (thread, offset) = thread_argument(state);
+    let options = opt_string(state, offset + 2, "getinfo", "flnSrtu")?;
+    if options.first() == Some(&b'>') {
+        return Err(argument_error(offset + 2, "getinfo", "invalid option '>'"));
+    }
+
+    let info = match state.arg(offset + 1) {
+        Value::Function(function) => state.function_info(function),
+
+        _ => {
+            let level = check_integer(state, offset + 1, "getinfo")?;
+            let info = usize::try_from(level)
+                .ok()
+                .and_then(|level| state.frame_info(thread, level));
+            match info {
+                Some(info) => info,
+                None => {
+                    state.push(Value::Nil);
+                    return Ok(1);
+                }
+            }
+        }
+    };
+
+    if options.iter().any(|option| !OPTIONS.contains(option)) {
+        return Err(argument_error(offset + 2, "getinfo", "invalid option"));
+    }
+
+    let table = state.heap.new_table();
+    for option in OPTIONS {
+        if options.contains(option) {
+            set_info_fields(state, table, &info, *option);
+        }
+    }
+
+    state.push(table);
+    Ok(1)
+}
+
+/// Sets the fields of a table returned by `getinfo` that `option` selects.
+fn set_info_fields(state: &mut State, table: TableRef, info: &FunctionInfo, option: u8) {
+    let line = |line: Option<u32>| line.map_or(-1, i64::from);
+
+    match option {
+        b'S' => {
+            let source = state.heap.intern(info.source.as_bytes());
+            set_field(state, table, "source", source);
+            let short_source = state.heap.intern(info.short_source.as_bytes());
+            set_field(state, table, "short_src", short_source);
+            set_field(state, table, "linedefined", line(info.line_defined));
+            set_field(
+                state,
+                table,
+                "lastlinedefined",
+                line(info.last_line_defined),
+            );
+            let what = state.heap.intern(info.what.as_bytes());
+            set_field(state, table, "what", what);
+        }
+
+        b'l' => set_field(state, table, "currentline", line(info.current_line)),
+
+        b'n' => {
+            let (name, kind) = match &info.name {
+                Some(Variable::Global(name)) => (Some(name), "global"),
+                Some(Variable::Local(name)) => (Some(name), "local"),
+                Some(Variable::Upvalue(name)) => (Some(name), "upvalue"),
+                Some(Variable::Field(name)) => (Some(name), "field"),
+                Some(Variable::Method(name)) => (Some(name), "method"),
+                None => (None, ""),
+            };
+
+            if let Some(name) = name {
+                let name = state.heap.intern(name.as_bytes());
+                set_field(state, table, "name", name);
+            }
+            let kind = state.heap.intern(kind.as_bytes());
+            set_field(state, table, "namewhat", kind);
+        }
+
+        b'r' => {
+            let (first, count) = info.transfer.unwrap_or_default();
+            set_field(state, table, "ftransfer", first as i64);
+            set_field(state, table, "ntransfer", count as i64);
+        }
+
+        b'u' => {
+            set_field(state, table, "nups", info.upvalues as i64);
+            set_field(state, table, "nparams", info.parameters as i64);
+            set_field(state, table, "isvararg", info.is_vararg);
+        }
+
+        b't' => set_field(state, table, "istailcall", info.tail_call),
+
+        b'L' => {
+            // A native function has no lines to speak of.
+            if info.line_defined.is_some() {
+                let lines = state.heap.new_table();
+                for &line in &info.lines {
+                    state.heap[lines].set_int(line.into(), Value::Bool(true));
+                }
+                set_field(state, table, "activelines", lines);
+            }
+        }
+
+        b'f' => set_field(state, table, "func", info.function),
+
+        _ => unreachable!("options are checked before any is handled"),
+    }
+}
+
+/// `debug.getlocal([thread,] f, local)`: returns the name and value of the local numbered
+/// `local` of the function running at level `f`, or nil if there is none. If `f` is a
+/// function, returns only the name of its parameter of that number.
+fn getlocal(state: &mut State) -> Result<usize> {
+    let (thread, offset) = thread_argument(state);
+    let n = check_integer(state, offset + 2, "getlocal")?;
+
+    if let Value::Function(function) = state.arg(offset + 1) {
+        match usize::try_from(n)
+            .ok()
+            .and_then(|n| state.parameter_name(function, n))
+        {
+            Some(name) => push_string(state, &name),
+            None => state.push(Value::Nil),
+        }
+        return Ok(1);
+    }
+
+    let level = check_level(state, thread, offset + 1, "getlocal")?;
+    match state.frame_local(thread, level, n) {
+        Some((name, value)) => {
+            push_string(state, &name);
+            state.push(value);
+            Ok(2)
+        }
+
+        None => {
+            state.push(Value::Nil);
+            Ok(1)
+        }
+    }
+}
+
+/// `debug.setlocal([thread,] level, local, value)`: sets the local that `getlocal` would find,
+/// returning its name, or nil if there is none.
+fn setlocal(state: &mut State) -> Result<usize> {
+    let (thread, offset) = thread_argument(state);
+    let level = check_level(state, thread, offset + 1, "setlocal")?;
+    let n = check_integer(state, offset + 2, "setlocal")?;
+    let value = check_any(state, offset + 3, "setlocal")?;
+
+    match state.set_frame_local(thread, level, n, value) {
+        Some(name) => push_string(state, &name),
+        None => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `debug.getmetatable(value)`: returns the metatable of `value`, whatever its `__metatable`
+/// field says.
+fn getmetatable(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "getmetatable")?;
+    match state.heap.metatable_of(value) {
+        Some(metatable) => state.push(metatable),
+        None => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `debug.setmetatable(value, table)`: sets the metatable of `value`, which for anything but
+/// a table or userdata is that of every value of its type, and returns `value`.
+fn setmetatable(state: &mut State) -> Result<usize> {
+    let metatable = match state.arg(2) {
+        Value::Table(metatable) => Some(metatable),
+        Value::Nil => None,
+        _ => return Err(type_error(state, 2, "setmetatable", "nil or table")),
+    };
+
+    let value = state.arg(1);
+    state.heap.set_metatable(value, metatable);
+    state.push(value);
+    Ok(1)
+}
+
+/// `debug.getuservalue(u [, n])`: returns the user value numbered `n`, by default 1, of the
+/// userdata `u` and true, or nil if there is no such user value.
+fn getuservalue(state: &mut State) -> Result<usize> {
+    let n = opt_integer(state, 2, "getuservalue", 1)?;
+    let Value::UserData(userdata) = state.arg(1) else {
+        state.push(Value::Nil);
+        return Ok(1);
+    };
+
+    match usize::try_from(n)
+        .ok()
+        .and_then(|n| state.heap[userdata].user_value(n))
+    {
+        Some(value) => {
+            state.push(value);
+            state.push(true);
+            Ok(2)
+        }
+
+        None => {
+            state.push(Value::Nil);
+            Ok(1)
+        }
+    }
+}
+
+/// `debug.setuservalue(udata, value [, n])`: sets the user value numbered `n`, by default 1,
+/// of `udata`, returning `udata`, or nil if there is no such user value.
+fn setuservalue(state: &mut State) -> Result<usize> {
+    let n = opt_integer(state, 3, "setuservalue", 1)?;
+    let Value::UserData(userdata) = state.arg(1) else {
+        return Err(type_error(state, 1, "setuservalue", "userdata"));
+    };
+    let value = check_any(state, 2, "setuservalue")?;
+
+    let set = usize::try_from(n).is_ok_and(|n| state.heap[userdata].set_user_value(n, value));
+    state.push(if set { state.arg(1) } else { Value::Nil });
+    Ok(1)
+}
+
+/// `debug.getupvalue(f, up)`: returns the name and value of the upvalue numbered `up` of the
+/// function `f`, or nothing if there is none.
+fn getupvalue(state: &mut State) -> Result<usize> {
+    let n = check_integer(state, 2, "getupvalue")?;
+    let function = check_function(state, 1, "getupvalue")?;
+
+    match usize::try_from(n)
+        .ok()
+        .and_then(|n| state.function_upvalue(function, n))
+    {
+        Some((name, value)) => {
+            push_string(state, &name);
+            state.push(value);
+            Ok(2)
+        }
+        None => Ok(0),
+    }
+}
+
+/// `debug.setupvalue(f, up, value)`: sets the upvalue numbered `up` of the function `f`,
+/// returning its name, or nothing if there is none.
+fn setupvalue(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 3, "setupvalue")?;
+    let n = check_integer(state, 2, "setupvalue")?;
+    let function = check_function(state, 1, "setupvalue")?;
+
+    match usize::try_from(n)
+        .ok()
+        .and_then(|n| state.set_function_upvalue(function, n, value))
+    {
+        Some(name) => {
+            push_string(state, &name);
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
+/// `debug.upvalueid(f, n)`: returns a number identifying the variable that the upvalue
+/// numbered `n` of the function `f` refers to, which is the same for every closure that shares
+/// it. Returns nil if there is no such upvalue, and for the upvalues of native functions,
+/// which are never shared.
+fn upvalueid(state: &mut State) -> Result<usize> {
+    let n = check_integer(state, 2, "upvalueid")?;
+    let function = check_function(state, 1, "upvalueid")?;
+
+    match usize::try_from(n)
+        .ok()
+        .and_then(|n| state.upvalue_id(function, n))
+    {
+        Some(id) => state.push(id.index() as i64),
+        None => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `debug.upvaluejoin(f1, n1, f2, n2)`: makes the upvalue numbered `n1` of the Lua function
+/// `f1` refer to the variable that the upvalue numbered `n2` of `f2` refers to.
+fn upvaluejoin(state: &mut State) -> Result<usize> {
+    let (f1, n1) = check_upvalue(state, 1, "upvaluejoin")?;
+    let (f2, n2) = check_upvalue(state, 3, "upvaluejoin")?;
+
+    for (position, function, n) in [(1, f1, n1), (3, f2, n2)] {
+        if state.upvalue_id(function, n).is_none() {
+            return Err(argument_error(
+                position,
+                "upvaluejoin",
+                "Lua function expected",
+            ));
+        }
+    }
+
+    if let Some(upvalue) = state.upvalue_id(f2, n2) {
+        state.join_upvalue(f1, n1, upvalue);
+    }
+    Ok(0)
+}
+
+/// The function at `position` and the number of one of its upvalues after it.
+fn check_upvalue(
+    state: &State,
+    position: usize,
+    function: &'static str,
+) -> Result<(FunctionRef, usize)> {
+    let n = check_integer(state, position + 1, function)?;
+    let callee = check_function(state, position, function)?;
+
+    usize::try_from(n)
+        .ok()
+        .filter(|&n| state.function_upvalue(callee, n).is_some())
+        .map(|n| (callee, n))
+        .ok_or_else(|| argument_error(position + 1, function, "invalid upvalue index"))
+}
+
+/// `debug.sethook([thread,] hook, mask [, count])`: makes `hook` be called with the name of
+/// each event that `mask` selects, and the line for line events. The mask holds `c` for
+/// calls, `r` for returns and `l` for lines, and a positive `count` adds an event every that
+/// many instructions. Without a hook, turns hooks off.
+fn sethook(state: &mut State) -> Result<usize> {
+    let (thread, offset) = thread_argument(state);
+
+    let (function, mask) = match state.arg(offset + 1) {
+        Value::Nil => (Value::Nil, HookMask::default()),
+
+        _ => {
+            let events = check_bytes(state, offset + 2, "sethook")?;
+            let function = check_function(state, offset + 1, "sethook")?;
+            let count = opt_integer(state, offset + 3, "sethook", 0)?;
+
+            let mask = HookMask {
+                call: events.contains(&b'c'),
+                returns: events.contains(&b'r'),
+                line: events.contains(&b'l'),
+                count: count.clamp(0, u32::MAX.into()) as u32,
+            };
+            (Value::Function(function), mask)
+        }
+    };
+
+    let hooks = hook_table(state);
+    state.heap[hooks].set(Value::Thread(thread), function)?;
+
+    let hook = (mask != HookMask::default()).then(|| Hook::new(mask, call_hook_function));
+    state.set_hook(thread, hook);
+    Ok(0)
+}
+
+/// The table of hook functions, keyed weakly by thread so that it does not keep them alive.
+fn hook_table(state: &mut State) -> TableRef {
+    let hooks = registry_table(state, HOOKS);
+    if state.heap.metatable_of(Value::Table(hooks)).is_none() {
+        let mode = state.heap.metamethod_name(Metamethod::Mode);
+        let weak_keys = state.heap.intern(b"k");
+        state.heap[hooks].set_str(mode, Value::String(weak_keys));
+        state.heap.set_metatable(Value::Table(hooks), Some(hooks));
+    }
+    hooks
+}
+
+/// The hook that `sethook` sets, which calls the running thread's hook function with the name
+/// of the event and the line of a line event.
+fn call_hook_function(state: &mut State, event: HookEvent) -> Result<()> {
+    let hooks = hook_table(state);
+    let thread = Value::Thread(state.current_thread());
+    let function = state.heap[hooks].get(thread);
+    if !matches!(function, Value::Function(_)) {
+        return Ok(());
+    }
+
+    state.push(function);
+    push_string(state, event.name());
+    match event {
+        HookEvent::Line(line) => state.push(i64::from(line)),
+        _ => state.push(Value::Nil),
+    }
+    state.call(2, Some(0))?;
+    Ok(())
+}
+
+/// `debug.gethook([thread])`: returns the hook function, mask and count that `sethook` set, or
+/// nil if hooks are off. A hook set from outside Lua is reported as `"external hook"`.
+fn gethook(state: &mut State) -> Result<usize> {
+    let (thread, _) = thread_argument(state);
+    let Some(hook) = state.hook(thread) else {
+        state.push(Value::Nil);
+        return Ok(1);
+    };
+
+    let hooks = hook_table(state);
+    match state.heap[hooks].get(Value::Thread(thread)) {
+        function @ Value::Function(_) => state.push(function),
+        _ => push_string(state, "external hook"),
+    }
+
+    let mask = [
+        (hook.mask.call, 'c'),
+        (hook.mask.returns, 'r'),
+        (hook.mask.line, 'l'),
+    ];
+    let mask: String = mask
+        .into_iter()
+        .filter_map(|(set, letter)| set.then_some(letter))
+        .collect();
+    push_string(state, &mask);
+    state.push(i64::from(hook.mask.count));
+    Ok(3)
+}
+
+/// `debug.traceback([thread,] [message [, level]])`: returns `message` followed by a
+/// traceback of the call stack from the given level. The level defaults to 1, the function
+/// that called `traceback`, or to 0 for another thread. A message that is neither a string, a
+/// number nor nil is returned untouched.
+fn traceback(state: &mut State) -> Result<usize> {
+    let (thread, offset) = thread_argument(state);
+    let message = match state.arg(offset + 1) {
+        Value::Nil => None,
+
+        message @ (Value::String(_) | Value::Int(_) | Value::Float(_)) => {
+            let message = state.tostring(message)?;
+            Some(String::from_utf8_lossy(&state.heap[message]).into_owned())
+        }
+
+        message => {
+            state.push(message);
+            return Ok(1);
+        }
+    };
+
+    let default = match thread == state.current_thread() {
+        true => 1,
+        false => 0,
+    };
+    let level = opt_integer(state, offset + 2, "traceback", default)?;
+    let traceback = state.thread_traceback(thread, message.as_deref(), level.max(0) as usize);
+    push_string(state, &traceback);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::assert_runs;
+
+    #[test]
+    fn getinfo_describes_functions_and_frames() {
+        assert_runs(
+            "local function f() local i = debug.getinfo(1, 'nSl') return i end local i = f() \
+             return table.concat({i.name, i.namewhat, i.what, i.short_src, i.source, \
+               i.currentline, i.linedefined, i.lastlinedefined}, '|')",
+            "f|local|Lua|test|=test|1|1|1",
+        );
+        assert_runs(
+            "local i = debug.getinfo(print) \
+             return table.concat({i.what, i.short_src, i.source, i.currentline, i.linedefined}, '|')",
+            "C|[C]|=[C]|-1|-1",
+        );
+        assert_runs(
+            "local x local i = debug.getinfo(function(a, b, ...) return x end, 'uf') \
+             return i.nups .. i.nparams .. tostring(i.isvararg) .. type(i.func)",
+            "12truefunction",
+        );
+        assert_runs(
+            "local function f() return debug.getinfo(1, 't').istailcall end \
+             local function g() return f() end \
+             return tostring(g()) .. tostring(debug.getinfo(1, 't').istailcall)",
+            "truefalse",
+        );
+        assert_runs("return tostring(debug.getinfo(100))", "nil");
+        assert_runs(
+            "return select(2, pcall(debug.getinfo, 1, '>'))",
+            "bad argument #2 to 'getinfo' (invalid option '>')",
+        );
+    }
+
+    #[test]
+    fn locals_by_level_and_parameters_by_function() {
+        assert_runs(
+            "local a, b = 1, 'x' local n1, v1 = debug.getlocal(1, 1) local n2, v2 = debug.getlocal(1, 2) \
+             return n1 .. v1 .. n2 .. v2",
+            "a1bx",
+        );
+        assert_runs(
+            "local function f(...) local n, v = debug.getlocal(1, -2) \
+               return n .. v .. tostring(debug.getlocal(1, -3)) end \
+             return (f('p', 'q'))",
+            "(vararg)qnil",
+        );
+        assert_runs(
+            "local f = function(a, b) end \
+             return debug.getlocal(f, 1) .. debug.getlocal(f, 2) .. tostring(debug.getlocal(f, 3))",
+            "abnil",
+        );
+        assert_runs(
+            "local a = 1 local name = debug.setlocal(1, 1, 42) \
+             return name .. a .. tostring(debug.setlocal(1, 50, 0))",
+            "a42nil",
+        );
+        assert_runs(
+            "return select(2, pcall(debug.getlocal, 50, 1))",
+            "bad argument #1 to 'getlocal' (level out of range)",
+        );
+        assert_runs(
+            "local co = coroutine.create(function() local z = 5 coroutine.yield() end) \
+             coroutine.resume(co) local name, value = debug.getlocal(co, 1, 1) return name .. value",
+            "z5",
+        );
+    }
+
+    #[test]
+    fn upvalues_can_be_read_set_and_shared() {
+        assert_runs(
+            "local x, y = 1, 2 local function f() return x + y end \
+             local n1, v1 = debug.getupvalue(f, 1) local n2, v2 = debug.getupvalue(f, 2) \
+             return n1 .. v1 .. n2 .. v2 .. select('#', debug.getupvalue(f, 3))",
+            "x1y20",
+        );
+        assert_runs(
+            "local x = 1 local function f() return x end \
+             return debug.setupvalue(f, 1, 10) .. x",
+            "x10",
+        );
+        assert_runs(
+            "local x, y = 1, 2 \
+             local function f() return x end local function g() return x end \
+             local function h() return y end \
+             return tostring(debug.upvalueid(f, 1) == debug.upvalueid(g, 1)) \
+             .. tostring(debug.upvalueid(f, 1) == debug.upvalueid(h, 1)) \
+             .. tostring(debug.upvalueid(print, 1))",
+            "truefalsenil",
+        );
+        assert_runs(
+            "local x, y = 1, 2 local function f() return x end local function h() return y end \
+             debug.upvaluejoin(f, 1, h, 1) y = 3 \
+             return f() .. tostring(debug.upvalueid(f, 1) == debug.upvalueid(h, 1))",
+            "3true",
+        );
+        assert_runs(
+            "return select(2, pcall(debug.upvaluejoin, function() end, 1, print, 1))",
+            "bad argument #2 to 'upvaluejoin' (invalid upvalue index)",
+        );
+    }
+
+    #[test]
+    fn hooks_see_calls_returns_lines_and_counts() {
+        assert_runs(
+            "local t = {} debug.sethook(function(e) t[#t + 1] = e end, 'cr') \
+             local function f() end f() debug.sethook() return table.concat(t, ',')",
+            "return,call,return,call",
+        );
+        assert_runs(
+            "local t = {} debug.sethook(function(e, l) t[#t + 1] = l end, 'l') local a = 1\n\
+             local b = 2\n\
+             debug.sethook() return table.concat(t, ',')",
+            "2,3",
+        );
+        assert_runs(
+            "local n = 0 debug.sethook(function() n = n + 1 end, '', 10) \
+             for i = 1, 1000 do end debug.sethook() return n > 10",
+            "true",
+        );
+        assert_runs(
+            "local r debug.sethook(function(e) \
+               if e == 'return' then r = debug.getinfo(2, 'r') end end, 'r') \
+             local function f() return 1, 2 end f() debug.sethook() return r.ftransfer .. r.ntransfer",
+            "12",
+        );
+        assert_runs(
+            "local f = function() end debug.sethook(f, 'crl', 7) local a, b, c = debug.gethook() \
+             debug.sethook() return tostring(a == f) .. b .. c .. tostring(debug.gethook())",
+            "truecrl7nil",
+        );
+        assert_runs(
+            "local co = coroutine.create(function() end) debug.sethook(co, function() end, 'c') \
+             return select(2, debug.gethook(co)) .. tostring(debug.gethook())",
+            "cnil",
+        );
+    }
+
+    #[test]
+    fn tail_calls_have_no_return_event_of_their_own() {
+        assert_runs(
+            "local t = {} debug.sethook(function(e) \
+               t[#t + 1] = e .. ':' .. tostring(debug.getinfo(2, 'n').name) end, 'cr') \
+             local function g() end local function f() return g() end f() debug.sethook() \
+             return table.concat(t, ',')",
+            "return:sethook,call:f,tail call:nil,return:nil,call:sethook",
+        );
+        assert_runs(
+            "debug.sethook(function() end, 'r') return select(2, 'a', 'b')",
+            "b",
+        );
+    }
+
+    #[test]
+    fn metatables_and_tracebacks() {
+        assert_runs(
+            "local mt = {} debug.setmetatable(10, mt) local r = getmetatable(1) == mt \
+             debug.setmetatable(10, nil) return tostring(r) .. tostring(debug.getmetatable(1))",
+            "truenil",
+        );
+        assert_runs(
+            "return type(debug.getmetatable(setmetatable({}, {__metatable = 'no'})))",
+            "table",
+        );
+        assert_runs(
+            "local function f() local s = debug.traceback('msg', 1) return s end return (f())",
+            "msg\nstack traceback:\n\ttest:1: in local 'f'\n\ttest:1: in main chunk",
+        );
+        assert_runs("local t = {} return debug.traceback(t) == t", "true");
+    }
+}
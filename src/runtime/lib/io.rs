@@ -0,0 +1,1232 @@
+//! The `io` library.
+//!
+//! File handles are userdata that share a metatable, which gives them their methods and the
+//! `FILE*` type name. The files themselves live outside of the heap, in a map from handle to
+//! file that all of the library's functions share, and reach the outside world through the
+//! state's [`Host`](crate::runtime::Host).
+
+use {
+    super::{
+        argument_error, check_any, check_bytes, check_option, check_string, file_result,
+        opt_integer, opt_string, registry, set_function, set_library, set_registry, type_error,
+    },
+    crate::{
+        number::{self, Number},
+        runtime::{
+            host::{self, Exit, OpenMode, Stream},
+            meta::address,
+            value::float_to_int,
+            Error, Function, NativeFunction, Result, State, TableRef, UserData, UserDataRef, Value,
+        },
+    },
+    ahash::AHashMap,
+    std::{
+        cell::RefCell,
+        io::{self, SeekFrom},
+        mem,
+        rc::Rc,
+    },
+};
+
+/// The registry keys of the metatable of file handles and of the default input and output
+/// files, which are those the reference implementation uses.
+const METATABLE: &[u8] = b"FILE*";
+const INPUT: &[u8] = b"_IO_input";
+const OUTPUT: &[u8] = b"_IO_output";
+
+/// The registry key of the function that flushes every open file, for `os.exit`.
+const FLUSH_ALL: &[u8] = b"_IO_flush";
+
+/// The size of the buffer that reads go through, and the default size of the one that writes
+/// go through.
+const BUFFER_SIZE: usize = 8192;
+
+/// The most formats that `lines` takes.
+const MAX_LINES_FORMATS: usize = 250;
+
+/// The longest numeral that the "n" format reads.
+const MAX_NUMERAL_LEN: usize = 200;
+
+/// The open files of a state, by handle. A handle stays here until it is collected, so that it
+/// can still be recognized once its file is closed.
+type Files = Rc<RefCell<AHashMap<UserDataRef, Handle>>>;
+
+struct Handle {
+    /// The file, or `None` once it has been closed.
+    file: Option<File>,
+
+    /// Whether this is one of the standard files, which cannot be closed.
+    standard: bool,
+}
+
+pub fn open_io(state: &mut State) {
+    let files = Files::default();
+
+    let io = state.heap.new_table();
+    set_io_function(state, io, "close", &files, close);
+    set_io_function(state, io, "flush", &files, |state, files| {
+        let handle = default_file(state, files, OUTPUT, "output")?;
+        let flushed = with_file(files, handle, File::flush);
+        Ok(file_result(state, flushed, None))
+    });
+    set_io_function(state, io, "input", &files, |state, files| {
+        set_default_file(state, files, INPUT, b"r", "input")
+    });
+    set_io_function(state, io, "lines", &files, lines);
+    set_io_function(state, io, "open", &files, open);
+    set_io_function(state, io, "output", &files, |state, files| {
+        set_default_file(state, files, OUTPUT, b"w", "output")
+    });
+    set_io_function(state, io, "popen", &files, popen);
+    set_io_function(state, io, "read", &files, |state, files| {
+        let handle = default_file(state, files, INPUT, "input")?;
+        let formats = (1..=state.arg_count()).map(|n| state.arg(n)).collect();
+        push_read(state, files, handle, formats, 1, "read")
+    });
+    set_io_function(state, io, "tmpfile", &files, tmpfile);
+    set_io_function(state, io, "type", &files, type_);
+    set_io_function(state, io, "write", &files, |state, files| {
+        let handle = default_file(state, files, OUTPUT, "output")?;
+        write(state, files, handle, 1, "write")
+    });
+
+    let methods = state.heap.new_table();
+    set_io_function(state, methods, "close", &files, close);
+    set_io_function(state, methods, "flush", &files, |state, files| {
+        let handle = check_file(state, files, 1, "flush")?;
+        let flushed = with_file(files, handle, File::flush);
+        Ok(file_result(state, flushed, None))
+    });
+    set_io_function(state, methods, "lines", &files, |state, files| {
+        let handle = check_file(state, files, 1, "lines")?;
+        let iterator = lines_iterator(state, files, handle, false, "lines")?;
+        state.push(iterator);
+        Ok(1)
+    });
+    set_io_function(state, methods, "read", &files, |state, files| {
+        let handle = check_file(state, files, 1, "read")?;
+        let formats = (2..=state.arg_count()).map(|n| state.arg(n)).collect();
+        push_read(state, files, handle, formats, 2, "read")
+    });
+    set_io_function(state, methods, "seek", &files, seek);
+    set_io_function(state, methods, "setvbuf", &files, setvbuf);
+    set_io_function(state, methods, "write", &files, |state, files| {
+        let handle = check_file(state, files, 1, "write")?;
+        write(state, files, handle, 2, "write")
+    });
+
+    let metatable = state.heap.new_table();
+    set_io_function(state, metatable, "__close", &files, |state, files| {
+        close_quietly(state, files);
+        Ok(0)
+    });
+    set_io_function(state, metatable, "__gc", &files, |state, files| {
+        close_quietly(state, files);
+        if let Value::UserData(handle) = state.arg(1) {
+            files.borrow_mut().remove(&handle);
+        }
+        Ok(0)
+    });
+    set_io_function(state, metatable, "__tostring", &files, tostring);
+
+    let fields = [
+        ("__index", Value::Table(methods)),
+        ("__name", Value::String(state.heap.intern(METATABLE))),
+    ];
+
+    for (name, value) in fields {
+        let name = state.heap.intern(name.as_bytes());
+        state.heap[metatable].set_str(name, value);
+    }
+
+    set_registry(state, METATABLE, Value::Table(metatable));
+
+    // Standard output goes straight to the host, which buffers it as it sees fit, so that
+    // `print` and `io.write` can be mixed freely.
+    let host = state.host();
+    let streams = [
+        (
+            "stdin",
+            host.stdin(),
+            Buffering::Full(BUFFER_SIZE),
+            false,
+            Some(INPUT),
+        ),
+        ("stdout", host.stdout(), Buffering::No, true, Some(OUTPUT)),
+        ("stderr", host.stderr(), Buffering::No, true, None),
+    ];
+
+    for (name, stream, buffering, writable, default) in streams {
+        let file = File::new(stream, buffering, writable);
+        let handle = new_handle(state, &files, file, true);
+        let name = state.heap.intern(name.as_bytes());
+        state.heap[io].set_str(name, Value::UserData(handle));
+
+        if let Some(key) = default {
+            set_registry(state, key, Value::UserData(handle));
+        }
+    }
+
+    let flush_all = files.clone();
+    let flush_all = state.heap.new_function(Function::native(move |_| {
+        for handle in flush_all.borrow_mut().values_mut() {
+            if let Some(file) = &mut handle.file {
+                let _ = file.flush();
+            }
+        }
+        Ok(0)
+    }));
+    set_registry(state, FLUSH_ALL, Value::Function(flush_all));
+
+    set_library(state, "io", io);
+}
+
+/// Flushes every open file, so that nothing written is lost when the process ends without
+/// the state being closed. Does nothing if the library is not open.
+pub(super) fn flush_all(state: &mut State) {
+    let function = registry(state, FLUSH_ALL);
+    if !function.is_nil() {
+        state.push(function);
+        let _ = state.call(0, Some(0));
+    }
+}
+
+/// Sets `table[name]` to a library function that works on the files in `files`.
+fn set_io_function(
+    state: &mut State,
+    table: TableRef,
+    name: &str,
+    files: &Files,
+    function: fn(&mut State, &Files) -> Result<usize>,
+) {
+    let files = files.clone();
+    set_function(state, table, name, move |state| function(state, &files));
+}
+
+/// Creates a handle for `file`.
+fn new_handle(state: &mut State, files: &Files, file: File, standard: bool) -> UserDataRef {
+    let handle = state.heap.new_userdata(UserData::new(Rc::new(()), 0));
+    let metatable = match registry(state, METATABLE) {
+        Value::Table(metatable) => metatable,
+        _ => unreachable!("the registry has lost the metatable of files"),
+    };
+
+    state
+        .heap
+        .set_metatable(Value::UserData(handle), Some(metatable));
+
+    let file = Some(file);
+    files.borrow_mut().insert(handle, Handle { file, standard });
+    handle
+}
+
+/// Whether `value` is a handle, and if so, whether its file is open.
+fn handle_state(files: &Files, value: Value) -> Option<(UserDataRef, bool)> {
+    match value {
+        Value::UserData(handle) => {
+            let open = files.borrow().get(&handle)?.file.is_some();
+            Some((handle, open))
+        }
+        _ => None,
+    }
+}
+
+/// The `position`th argument as the handle of an open file.
+fn check_file(
+    state: &State,
+    files: &Files,
+    position: usize,
+    function: &'static str,
+) -> Result<UserDataRef> {
+    match handle_state(files, state.arg(position)) {
+        Some((handle, true)) => Ok(handle),
+        Some((_, false)) => Err(Error::ClosedFile),
+        None => Err(type_error(state, position, function, "FILE*")),
+    }
+}
+
+/// The default input or output file, as named, which must be open.
+fn default_file(
+    state: &mut State,
+    files: &Files,
+    key: &[u8],
+    name: &'static str,
+) -> Result<UserDataRef> {
+    match handle_state(files, registry(state, key)) {
+        Some((handle, true)) => Ok(handle),
+        _ => Err(Error::ClosedDefaultFile(name)),
+    }
+}
+
+/// Runs `f` on the file of `handle`, which must be open.
+fn with_file<T>(files: &Files, handle: UserDataRef, f: impl FnOnce(&mut File) -> T) -> T {
+    let mut files = files.borrow_mut();
+    let file = files
+        .get_mut(&handle)
+        .and_then(|handle| handle.file.as_mut())
+        .expect("the file was checked to be open");
+    f(file)
+}
+
+/// Opens the named file, raising an error if it cannot be opened.
+fn open_or_raise(
+    state: &mut State,
+    files: &Files,
+    name: &[u8],
+    mode: &[u8],
+) -> Result<UserDataRef> {
+    let mode = OpenMode::parse(mode).expect("the mode is valid");
+    match state.host().open(name, mode) {
+        Ok(stream) => {
+            let file = File::new(stream, Buffering::Full(BUFFER_SIZE), mode.write);
+            Ok(new_handle(state, files, file, false))
+        }
+
+        Err(error) => Err(Error::OpenFile {
+            file: String::from_utf8_lossy(name).into_owned(),
+            message: host::error_message(&error),
+        }),
+    }
+}
+
+/// `io.open(filename [, mode])`: opens a file in the mode `fopen` takes, which defaults to
+/// "r", returning its handle.
+fn open(state: &mut State, files: &Files) -> Result<usize> {
+    let name = check_bytes(state, 1, "open")?;
+    let mode = opt_string(state, 2, "open", "r")?;
+    let mode = OpenMode::parse(&mode).ok_or_else(|| argument_error(2, "open", "invalid mode"))?;
+
+    let opened = state.host().open(&name, mode);
+    Ok(push_opened(state, files, opened, mode.write, Some(&name)))
+}
+
+/// `io.popen(prog [, mode])`: runs `prog` in a shell, returning a handle that reads its
+/// output, or with mode "w", writes its input.
+fn popen(state: &mut State, files: &Files) -> Result<usize> {
+    let command = check_bytes(state, 1, "popen")?;
+    let write = match &opt_string(state, 2, "popen", "r")?[..] {
+        b"r" => false,
+        b"w" => true,
+        _ => return Err(argument_error(2, "popen", "invalid mode")),
+    };
+
+    let opened = state.host().popen(&command, write);
+    Ok(push_opened(state, files, opened, write, Some(&command)))
+}
+
+/// `io.tmpfile()`: returns a handle to a temporary file opened for update, which is removed
+/// once it is closed.
+fn tmpfile(state: &mut State, files: &Files) -> Result<usize> {
+    let opened = state.host().tmpfile();
+    Ok(push_opened(state, files, opened, true, None))
+}
+
+/// Pushes the handle of a newly opened stream, which can be written to if `writable` says so,
+/// or the results of failing to open it, for the file or command `name`. Returns how many
+/// values were pushed.
+fn push_opened(
+    state: &mut State,
+    files: &Files,
+    opened: io::Result<Box<dyn Stream>>,
+    writable: bool,
+    name: Option<&[u8]>,
+) -> usize {
+    match opened {
+        Ok(stream) => {
+            let file = File::new(stream, Buffering::Full(BUFFER_SIZE), writable);
+            let handle = new_handle(state, files, file, false);
+            state.push(handle);
+            1
+        }
+
+        Err(error) => file_result(state, Err(error), name),
+    }
+}
+
+/// `io.input([file])` and `io.output([file])`: sets the default input or output file to a
+/// handle or to the named file, opened in `mode`, and returns the default.
+fn set_default_file(
+    state: &mut State,
+    files: &Files,
+    key: &[u8],
+    mode: &[u8],
+    function: &'static str,
+) -> Result<usize> {
+    let handle = match state.arg(1) {
+        Value::Nil => None,
+
+        Value::String(_) | Value::Int(_) | Value::Float(_) => {
+            let name = check_bytes(state, 1, function)?;
+            Some(open_or_raise(state, files, &name, mode)?)
+        }
+
+        _ => Some(check_file(state, files, 1, function)?),
+    };
+
+    if let Some(handle) = handle {
+        set_registry(state, key, Value::UserData(handle));
+    }
+
+    let default = registry(state, key);
+    state.push(default);
+    Ok(1)
+}
+
+/// `io.close([file])` and `file:close()`: closes a file, or without one, the default output
+/// file.
+fn close(state: &mut State, files: &Files) -> Result<usize> {
+    let handle = match state.arg_count() {
+        0 => match handle_state(files, registry(state, OUTPUT)) {
+            Some((handle, true)) => handle,
+            _ => return Err(Error::ClosedFile),
+        },
+        _ => check_file(state, files, 1, "close")?,
+    };
+
+    close_file(state, files, handle)
+}
+
+/// Closes the file of `handle`, which must be open, and pushes the results of doing so. A
+/// pipe gives those of `os.execute`.
+fn close_file(state: &mut State, files: &Files, handle: UserDataRef) -> Result<usize> {
+    let mut file = {
+        let mut files = files.borrow_mut();
+        let handle = files
+            .get_mut(&handle)
+            .expect("the file was checked to exist");
+        if handle.standard {
+            None
+        } else {
+            handle.file.take()
+        }
+    };
+
+    let Some(file) = &mut file else {
+        let message = state.heap.intern(b"cannot close standard file");
+        state.push(Value::Nil);
+        state.push(message);
+        return Ok(2);
+    };
+
+    let (what, code) = match file.close() {
+        Ok(None) => return Ok(file_result(state, Ok(()), None)),
+        Ok(Some(Exit::Code(code))) => ("exit", code),
+        Ok(Some(Exit::Signal(signal))) => ("signal", signal),
+        Err(error) => return Ok(file_result(state, Err(error), None)),
+    };
+
+    match code {
+        0 if what == "exit" => state.push(true),
+        _ => state.push(Value::Nil),
+    }
+
+    let what = state.heap.intern(what.as_bytes());
+    state.push(what);
+    state.push(code as i64);
+    Ok(3)
+}
+
+/// Closes the file of the handle given as the first argument, if it is open and not a
+/// standard file, ignoring the results.
+fn close_quietly(state: &mut State, files: &Files) {
+    if let Some((handle, true)) = handle_state(files, state.arg(1)) {
+        let top = state.top();
+        let _ = close_file(state, files, handle);
+        state.set_top(top);
+    }
+}
+
+/// `io.type(obj)`: "file" for a handle of an open file, "closed file" for one of a closed
+/// file, and nil for anything else.
+fn type_(state: &mut State, files: &Files) -> Result<usize> {
+    let value = check_any(state, 1, "type")?;
+    let result = match handle_state(files, value) {
+        Some((_, true)) => Value::String(state.heap.intern(b"file")),
+        Some((_, false)) => Value::String(state.heap.intern(b"closed file")),
+        None => Value::Nil,
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+fn tostring(state: &mut State, files: &Files) -> Result<usize> {
+    let string = match handle_state(files, state.arg(1)) {
+        Some((_, false)) => "file (closed)".to_owned(),
+        Some((handle, true)) => format!("file (0x{:08x})", address(Value::UserData(handle))),
+        None => return Err(type_error(state, 1, "tostring", "FILE*")),
+    };
+
+    let string = state.heap.intern(string.as_bytes());
+    state.push(string);
+    Ok(1)
+}
+
+/// `io.lines([filename, ...])`: returns an iterator that reads from the named file in the
+/// given formats, as `read` does, until it fails to read anything. Without a name, reads from
+/// the default input file instead.
+///
+/// A named file is closed once the iterator reaches its end. It is also returned as the
+/// fourth value, after two nils, so that a generic `for` closes it if the loop is left early.
+fn lines(state: &mut State, files: &Files) -> Result<usize> {
+    let (handle, close) = match state.arg(1) {
+        Value::Nil => match handle_state(files, registry(state, INPUT)) {
+            Some((handle, true)) => (handle, false),
+            _ => return Err(Error::ClosedFile),
+        },
+
+        _ => {
+            let name = check_bytes(state, 1, "lines")?;
+            (open_or_raise(state, files, &name, b"r")?, true)
+        }
+    };
+
+    let iterator = lines_iterator(state, files, handle, close, "lines")?;
+    state.push(iterator);
+    if !close {
+        return Ok(1);
+    }
+
+    state.push(Value::Nil);
+    state.push(Value::Nil);
+    state.push(handle);
+    Ok(4)
+}
+
+/// Creates the iterator of `lines`, which reads from the file of `handle` in the formats
+/// given as the arguments after the first. Its upvalues are the handle, whether to close the
+/// file at its end, the number of formats and the formats themselves.
+fn lines_iterator(
+    state: &mut State,
+    files: &Files,
+    handle: UserDataRef,
+    close: bool,
+    function: &'static str,
+) -> Result<Value> {
+    if state.arg_count() > MAX_LINES_FORMATS + 1 {
+        return Err(argument_error(
+            MAX_LINES_FORMATS + 2,
+            function,
+            "too many arguments",
+        ));
+    }
+
+    let count = state.arg_count().saturating_sub(1) as i64;
+    let mut upvalues = vec![
+        Value::UserData(handle),
+        Value::Bool(close),
+        Value::Int(count),
+    ];
+    upvalues.extend((2..=state.arg_count()).map(|n| state.arg(n)));
+
+    let files = files.clone();
+    let iterator = state.heap.new_function(Function::Native(NativeFunction {
+        callback: Rc::new(move |state| next_lines(state, &files)),
+        upvalues: upvalues.into_boxed_slice(),
+    }));
+
+    Ok(Value::Function(iterator))
+}
+
+/// The iterator that `lines` returns.
+fn next_lines(state: &mut State, files: &Files) -> Result<usize> {
+    let handle = match handle_state(files, state.upvalue(1)) {
+        Some((handle, true)) => handle,
+        _ => return Err(Error::FileAlreadyClosed),
+    };
+
+    let count = match state.upvalue(3) {
+        Value::Int(count) => count as usize,
+        _ => unreachable!("the iterator of 'lines' has lost its number of formats"),
+    };
+    let formats: Vec<_> = (4..4 + count).map(|n| state.upvalue(n)).collect();
+
+    let values = match read(state, files, handle, &formats, 2, "lines")? {
+        Ok(values) => values,
+        Err(error) => return Err(Error::ReadFailed(host::error_message(&error))),
+    };
+
+    if values[0].is_truthy() {
+        let count = values.len();
+        for value in values {
+            state.push(value);
+        }
+        return Ok(count);
+    }
+
+    if state.upvalue(2).is_truthy() {
+        let top = state.top();
+        close_file(state, files, handle)?;
+        state.set_top(top);
+    }
+
+    Ok(0)
+}
+
+/// A way of reading from a file, as given to `read`.
+enum Format {
+    /// A numeral, converted to a number.
+    Number,
+
+    /// The next line, with its newline if `keep_newline`.
+    Line { keep_newline: bool },
+
+    /// Everything up to the end of the file.
+    All,
+
+    /// Up to the given number of bytes, where 0 tests for the end of the file.
+    Count(u64),
+}
+
+/// Reads from the file of `handle` in each of `formats`, as `read` does, returning a value for
+/// each format up to the first that fails, which gives nil. With no formats, reads a line.
+///
+/// The formats are reported as arguments from position `first` on. Failing to read gives the
+/// error in place of the values.
+fn read(
+    state: &mut State,
+    files: &Files,
+    handle: UserDataRef,
+    formats: &[Value],
+    first: usize,
+    function: &'static str,
+) -> Result<io::Result<Vec<Value>>> {
+    let mut parsed = Vec::with_capacity(formats.len().max(1));
+    for (position, &format) in (first..).zip(formats) {
+        let format = match format {
+            Value::Int(count) => Format::Count(count as u64),
+
+            Value::Float(count) => match float_to_int(count) {
+                Some(count) => Format::Count(count as u64),
+                None => {
+                    let message = "number has no integer representation";
+                    return Err(argument_error(position, function, message));
+                }
+            },
+
+            Value::String(format) => {
+                let format = &state.heap[format];
+                match format.strip_prefix(b"*").unwrap_or(format).first() {
+                    Some(b'n') => Format::Number,
+                    Some(b'l') => Format::Line {
+                        keep_newline: false,
+                    },
+                    Some(b'L') => Format::Line { keep_newline: true },
+                    Some(b'a') => Format::All,
+                    _ => return Err(argument_error(position, function, "invalid format")),
+                }
+            }
+
+            _ => {
+                let message = format!("string expected, got {}", state.type_name(format));
+                return Err(argument_error(position, function, message));
+            }
+        };
+
+        parsed.push(format);
+    }
+
+    if parsed.is_empty() {
+        parsed.push(Format::Line {
+            keep_newline: false,
+        });
+    }
+
+    let mut files = files.borrow_mut();
+    let file = files
+        .get_mut(&handle)
+        .and_then(|handle| handle.file.as_mut())
+        .expect("the file was checked to be open");
+
+    let mut values = Vec::with_capacity(parsed.len());
+    for format in parsed {
+        let bytes = match format {
+            Format::Number => {
+                let value = match file.read_number() {
+                    Ok(number) => number.map_or(Value::Nil, Value::from),
+                    Err(error) => return Ok(Err(error)),
+                };
+
+                values.push(value);
+                if value.is_nil() {
+                    break;
+                }
+                continue;
+            }
+
+            Format::Line { keep_newline } => file.read_line(keep_newline),
+            Format::All => file.read_all().map(Some),
+            Format::Count(0) => file.peek().map(|next| next.map(|_| Vec::new())),
+            Format::Count(count) => file.read_count(count),
+        };
+
+        match bytes {
+            Ok(Some(bytes)) => values.push(Value::String(state.heap.intern(&bytes))),
+
+            Ok(None) => {
+                values.push(Value::Nil);
+                break;
+            }
+
+            Err(error) => return Ok(Err(error)),
+        }
+    }
+
+    Ok(Ok(values))
+}
+
+/// `io.read(...)` and `file:read(...)`: pushes the results of [`read`], where failing to read
+/// gives nil, a message and an error number.
+fn push_read(
+    state: &mut State,
+    files: &Files,
+    handle: UserDataRef,
+    formats: Vec<Value>,
+    first: usize,
+    function: &'static str,
+) -> Result<usize> {
+    match read(state, files, handle, &formats, first, function)? {
+        Ok(values) => {
+            let count = values.len();
+            for value in values {
+                state.push(value);
+            }
+            Ok(count)
+        }
+
+        Err(error) => Ok(file_result(state, Err(error), None)),
+    }
+}
+
+/// `io.write(...)` and `file:write(...)`: writes the arguments from position `first` on,
+/// which must be strings or numbers, and returns the file's handle.
+fn write(
+    state: &mut State,
+    files: &Files,
+    handle: UserDataRef,
+    first: usize,
+    function: &'static str,
+) -> Result<usize> {
+    let mut result = Ok(());
+    for position in first..=state.arg_count() {
+        // Floats are written as `%.14g` formats them, so without the ".0" of `tostring`.
+        let mut buffer = Vec::new();
+        match state.arg(position) {
+            Value::Int(n) => number::write_int(&mut buffer, n),
+            Value::Float(n) => number::write_general(&mut buffer, n, 14),
+            _ => {
+                let string = check_string(state, position, function)?;
+                buffer.extend_from_slice(&state.heap[string]);
+            }
+        }
+
+        // Once a write fails, the rest of the arguments are only checked.
+        if result.is_ok() {
+            result = with_file(files, handle, |file| file.write(&buffer));
+        }
+    }
+
+    match result {
+        Ok(()) => {
+            state.push(handle);
+            Ok(1)
+        }
+        Err(error) => Ok(file_result(state, Err(error), None)),
+    }
+}
+
+/// `file:seek([whence [, offset]])`: moves to `offset` bytes from the start, the current
+/// position or the end, as `whence` is "set", "cur" or "end", and returns the new position
+/// from the start. `whence` defaults to "cur" and `offset` to 0.
+fn seek(state: &mut State, files: &Files) -> Result<usize> {
+    let handle = check_file(state, files, 1, "seek")?;
+    let whence = check_option(state, 2, "seek", Some("cur"), &["set", "cur", "end"])?;
+    let offset = opt_integer(state, 3, "seek", 0)?;
+
+    let position = match whence {
+        0 => u64::try_from(offset)
+            .map(SeekFrom::Start)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid argument")),
+        1 => Ok(SeekFrom::Current(offset)),
+        _ => Ok(SeekFrom::End(offset)),
+    };
+
+    match position.and_then(|position| with_file(files, handle, |file| file.seek(position))) {
+        Ok(position) => {
+            state.push(position as i64);
+            Ok(1)
+        }
+        Err(error) => Ok(file_result(state, Err(error), None)),
+    }
+}
+
+/// `file:setvbuf(mode [, size])`: sets how writes are buffered, as "no", "full" or "line",
+/// with a buffer of `size` bytes.
+fn setvbuf(state: &mut State, files: &Files) -> Result<usize> {
+    let handle = check_file(state, files, 1, "setvbuf")?;
+    let mode = check_option(state, 2, "setvbuf", None, &["no", "full", "line"])?;
+    let size = opt_integer(state, 3, "setvbuf", BUFFER_SIZE as i64)?;
+    let size = usize::try_from(size).unwrap_or(0).max(1);
+
+    let buffering = match mode {
+        0 => Buffering::No,
+        1 => Buffering::Full(size),
+        _ => Buffering::Line(size),
+    };
+
+    let result = with_file(files, handle, |file| file.set_buffering(buffering));
+    Ok(file_result(state, result, None))
+}
+
+/// How writes to a file are held back before they reach its stream, with the size of the
+/// buffer they are held in.
+#[derive(Clone, Copy)]
+enum Buffering {
+    No,
+
+    /// Writes are held until a newline is written or the buffer is full.
+    Line(usize),
+
+    Full(usize),
+}
+
+/// An open stream, with the buffering of writes that `setvbuf` controls and the buffering of
+/// reads that lets formats like "n" look at a byte before consuming it.
+struct File {
+    stream: Box<dyn Stream>,
+    buffering: Buffering,
+
+    /// Bytes read from the stream that are yet to be consumed, from `consumed` on.
+    input: Vec<u8>,
+    consumed: usize,
+
+    /// Bytes written that are yet to reach the stream.
+    output: Vec<u8>,
+
+    /// Whether the stream was opened for writing. Writes to one that was not fail at once,
+    /// rather than once they are buffered and passed on.
+    writable: bool,
+}
+
+impl File {
+    fn new(stream: Box<dyn Stream>, buffering: Buffering, writable: bool) -> Self {
+        Self {
+            stream,
+            buffering,
+            input: Vec::new(),
+            consumed: 0,
+            output: Vec::new(),
+            writable,
+        }
+    }
+
+    /// The input that is yet to be consumed, reading more from the stream if there is none.
+    /// It is empty only at the end of the stream.
+    fn fill(&mut self) -> io::Result<&[u8]> {
+        if self.consumed == self.input.len() {
+            // What was written must reach the stream before what follows it can be read.
+            self.flush_output()?;
+
+            self.input.resize(BUFFER_SIZE, 0);
+            self.consumed = 0;
+            match self.stream.read(&mut self.input) {
+                Ok(count) => self.input.truncate(count),
+                Err(error) => {
+                    self.input.clear();
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(&self.input[self.consumed..])
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.fill()?.first().copied())
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.consumed += count;
+    }
+
+    /// Gives up the input that was read ahead, moving the stream back to where what was
+    /// consumed ends, if it can be moved.
+    fn discard_input(&mut self) {
+        let unread = self.input.len() - self.consumed;
+        if unread > 0 {
+            let _ = self.stream.seek(SeekFrom::Current(-(unread as i64)));
+        }
+
+        self.input.clear();
+        self.consumed = 0;
+    }
+
+    /// Reads the next line, or returns `None` at the end of the stream.
+    fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            let available = self.fill()?;
+            if available.is_empty() {
+                return Ok((!line.is_empty()).then_some(line));
+            }
+
+            match available.iter().position(|&c| c == b'\n') {
+                Some(end) => {
+                    let kept = if keep_newline { end + 1 } else { end };
+                    line.extend_from_slice(&available[..kept]);
+                    self.consume(end + 1);
+                    return Ok(Some(line));
+                }
+
+                None => {
+                    let count = available.len();
+                    line.extend_from_slice(available);
+                    self.consume(count);
+                }
+            }
+        }
+    }
+
+    fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        loop {
+            let available = self.fill()?;
+            if available.is_empty() {
+                return Ok(contents);
+            }
+
+            let count = available.len();
+            contents.extend_from_slice(available);
+            self.consume(count);
+        }
+    }
+
+    /// Reads up to `count` bytes, or returns `None` if there are none before the end of the
+    /// stream.
+    fn read_count(&mut self, count: u64) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        while (bytes.len() as u64) < count {
+            let available = self.fill()?;
+            if available.is_empty() {
+                break;
+            }
+
+            let wanted = (count - bytes.len() as u64).min(available.len() as u64) as usize;
+            bytes.extend_from_slice(&available[..wanted]);
+            self.consume(wanted);
+        }
+
+        Ok((!bytes.is_empty()).then_some(bytes))
+    }
+
+    /// Skips whitespace and reads the longest prefix of a numeral, as the reference
+    /// implementation does, and converts it. Only bytes that can continue a numeral are
+    /// consumed, so a prefix that is not a numeral by itself fails to convert.
+    fn read_number(&mut self) -> io::Result<Option<Number>> {
+        while self.peek()?.is_some_and(number::is_space) {
+            self.consume(1);
+        }
+
+        let mut numeral = Numeral {
+            current: self.peek()?,
+            file: self,
+            text: Vec::new(),
+            too_long: false,
+        };
+
+        numeral.accept_any(b"-+")?;
+
+        let mut digits = 0;
+        let mut hex = false;
+        if numeral.accept_any(b"0")? {
+            if numeral.accept_any(b"xX")? {
+                hex = true;
+            } else {
+                // The initial 0 is a digit.
+                digits = 1;
+            }
+        }
+
+        digits += numeral.accept_digits(hex)?;
+        if numeral.accept_any(b".")? {
+            digits += numeral.accept_digits(hex)?;
+        }
+
+        let exponent: &[u8] = if hex { b"pP" } else { b"eE" };
+        if digits > 0 && numeral.accept_any(exponent)? {
+            numeral.accept_any(b"-+")?;
+            numeral.accept_digits(false)?;
+        }
+
+        match numeral.too_long {
+            true => Ok(None),
+            false => Ok(number::parse_number(&numeral.text)),
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if !self.writable {
+            return Err(host::bad_descriptor());
+        }
+
+        if self.consumed < self.input.len() {
+            self.discard_input();
+        }
+
+        self.output.extend_from_slice(data);
+        let full = match self.buffering {
+            Buffering::No => true,
+            Buffering::Line(size) => data.contains(&b'\n') || self.output.len() >= size,
+            Buffering::Full(size) => self.output.len() >= size,
+        };
+
+        match full {
+            true => self.flush_output(),
+            false => Ok(()),
+        }
+    }
+
+    /// Passes what was written on to the stream.
+    fn flush_output(&mut self) -> io::Result<()> {
+        if self.output.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.stream.write(&self.output);
+        self.output.clear();
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_output()?;
+        self.stream.flush()
+    }
+
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.flush_output()?;
+
+        // The stream is ahead of the file by what was read but not consumed.
+        let unread = (self.input.len() - self.consumed) as i64;
+        let position = match position {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+            position => position,
+        };
+
+        self.input.clear();
+        self.consumed = 0;
+        self.stream.seek(position)
+    }
+
+    fn set_buffering(&mut self, buffering: Buffering) -> io::Result<()> {
+        self.flush_output()?;
+        self.buffering = buffering;
+        Ok(())
+    }
+
+    /// Flushes the file and closes its stream, returning how the process at the other end of
+    /// a pipe ended.
+    fn close(&mut self) -> io::Result<Option<Exit>> {
+        let flushed = self.flush();
+        let stream = mem::replace(&mut self.stream, Box::new(Closed));
+        let closed = stream.close();
+        flushed?;
+        closed
+    }
+}
+
+impl Drop for File {
+    /// Writes out what is left in the buffer of a file that was never closed, as happens to
+    /// those still open when the state is dropped.
+    fn drop(&mut self) {
+        let _ = self.flush_output();
+    }
+}
+
+/// What a [`File`] holds once its stream has been closed.
+struct Closed;
+
+impl Stream for Closed {}
+
+/// A numeral being read by [`File::read_number`], which holds the byte after it.
+struct Numeral<'a> {
+    file: &'a mut File,
+    current: Option<u8>,
+    text: Vec<u8>,
+
+    /// Set if the numeral was longer than any that is read, which makes it fail to convert.
+    too_long: bool,
+}
+
+impl Numeral<'_> {
+    /// Adds the current byte to the numeral if it is one of `bytes`, returning whether it was.
+    fn accept_any(&mut self, bytes: &[u8]) -> io::Result<bool> {
+        match self.current {
+            Some(c) if bytes.contains(&c) => self.accept(),
+            _ => Ok(false),
+        }
+    }
+
+    /// Adds the digits that follow to the numeral, returning how many there were.
+    fn accept_digits(&mut self, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(c) = self.current {
+            let digit = match hex {
+                true => c.is_ascii_hexdigit(),
+                false => c.is_ascii_digit(),
+            };
+
+            if !digit || !self.accept()? {
+                break;
+            }
+
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn accept(&mut self) -> io::Result<bool> {
+        if self.text.len() >= MAX_NUMERAL_LEN {
+            self.too_long = true;
+            return Ok(false);
+        }
+
+        self.text.extend(self.current);
+        self.file.consume(1);
+        self.current = self.file.peek()?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{runtime::NoHost, testing::assert_runs, Lua};
+
+    #[test]
+    fn files_are_written_and_read_back() {
+        assert_runs(
+            "local name = os.tmpname() \
+             local f = assert(io.open(name, 'w')) f:write('hello\\n', 42, ' ', 1.5, '\\nlast') f:close() \
+             f = io.open(name) local a, b, c, d = f:read('l', 'n', 'n', 'a') f:close() \
+             os.remove(name) return table.concat({a, b, c, d}, '|')",
+            "hello|42|1.5|\nlast",
+        );
+        assert_runs(
+            "local f = io.tmpfile() f:write('abc') f:seek('set') \
+             local s, eof = f:read('a'), f:read('a') return s .. '|' .. eof .. '|' .. tostring(f:read(1))",
+            "abc||nil",
+        );
+        assert_runs(
+            "local f = io.tmpfile() f:write('12 0x1F -3.5e1 nope') f:seek('set') \
+             local a, b, c, d = f:read('n', 'n', 'n', 'n') return a .. ' ' .. b .. ' ' .. c .. ' ' .. tostring(d)",
+            "12 31 -35.0 nil",
+        );
+        assert_runs(
+            "local f = io.tmpfile() f:write('x') return f:write('y') == f",
+            "true",
+        );
+    }
+
+    #[test]
+    fn lines_with_and_without_newlines() {
+        assert_runs(
+            "local name = os.tmpname() local f = io.open(name, 'w') f:write('a\\nb\\n\\nc') f:close() \
+             local t = {} for l in io.lines(name) do t[#t + 1] = '[' .. l .. ']' end \
+             for l in io.lines(name, 'L') do t[#t + 1] = '[' .. l .. ']' end \
+             os.remove(name) return table.concat(t)",
+            "[a][b][][c][a\n][b\n][\n][c]",
+        );
+        assert_runs(
+            "return select(2, pcall(io.lines, '/nonexistent/file'))",
+            "cannot open file '/nonexistent/file' (No such file or directory)",
+        );
+    }
+
+    #[test]
+    fn seek_and_setvbuf() {
+        assert_runs(
+            "local f = io.tmpfile() f:write('0123456789') \
+             local t = {f:seek('set', 2), f:read(3), f:seek('end'), f:seek('cur', -1), f:read(1)} \
+             return table.concat(t, '|')",
+            "2|234|10|9|9",
+        );
+        assert_runs(
+            "local f = io.tmpfile() return tostring(f:setvbuf('no')) .. tostring(f:setvbuf('full', 1024)) \
+             .. ' ' .. select(2, pcall(f.setvbuf, f, 'bad'))",
+            "truetrue bad argument #2 to 'setvbuf' (invalid option 'bad')",
+        );
+    }
+
+    #[test]
+    fn handles_and_their_states() {
+        assert_runs(
+            "local f = io.tmpfile() local open = io.type(f) f:close() \
+             return open .. '|' .. tostring(io.type(42)) .. '|' .. io.type(f) .. '|' \
+             .. select(2, pcall(f.read, f))",
+            "file|nil|closed file|attempt to use a closed file",
+        );
+        assert_runs(
+            "return io.type(io.stdout) .. tostring(io.output() == io.stdout) \
+             .. tostring(tostring(io.stdout):match('^file %(0x') ~= nil)",
+            "filetruetrue",
+        );
+        assert_runs(
+            "return table.concat({select(2, io.open('/nonexistent/dir/file'))}, '|')",
+            "/nonexistent/dir/file: No such file or directory|2",
+        );
+        assert_runs(
+            "return select(2, pcall(io.open, 'x', 'rw'))",
+            "bad argument #2 to 'open' (invalid mode)",
+        );
+    }
+
+    #[test]
+    fn handles_are_userdata() {
+        assert_runs(
+            "return type(io.stdout) .. ' ' .. tostring(getmetatable(io.stdout).__name) \
+             .. ' ' .. select(2, pcall(rawset, io.stdout, 'x', 1))",
+            "userdata FILE* bad argument #1 to 'rawset' (table expected, got FILE*)",
+        );
+        assert_runs(
+            "return select(2, pcall(next, io.stdout))",
+            "bad argument #1 to 'next' (table expected, got FILE*)",
+        );
+    }
+
+    #[test]
+    fn writes_to_files_not_open_for_writing_fail_at_once() {
+        assert_runs(
+            "local name = os.tmpname() local f = io.open(name) \
+             local ok, message = f:write('x') f:close() os.remove(name) \
+             return tostring(ok) .. ' ' .. message",
+            "nil Bad file descriptor",
+        );
+        assert_runs(
+            "local ok, message = io.stdin:write('x') return tostring(ok) .. ' ' .. message",
+            "nil Bad file descriptor",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn processes_report_how_they_ended() {
+        assert_runs(
+            "local p = io.popen('echo hi') return p:read('a') .. table.concat({select(2, p:close())}, ',')",
+            "hi\nexit,0",
+        );
+        assert_runs(
+            "return table.concat({select(2, io.popen('exit 3'):close())}, ',')",
+            "exit,3",
+        );
+    }
+
+    #[test]
+    fn files_go_through_the_host() {
+        let mut lua = Lua::new();
+        lua.set_host(NoHost);
+        let message = lua
+            .load("return select(2, io.open('anything'))", "=test")
+            .and_then(|function| function.call::<String>(&mut lua, ()));
+        assert_eq!(message.unwrap(), "anything: Permission denied");
+    }
+}
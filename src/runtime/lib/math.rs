@@ -0,0 +1,508 @@
+//! The `math` library.
+
+use {
+    super::{
+        argument_error, check_any, check_integer, check_number, opt_integer, set_function,
+        set_library, type_error,
+    },
+    crate::runtime::{value::float_to_int, Error, Result, State, Value},
+    std::{
+        cell::Cell,
+        f64::consts::PI,
+        rc::Rc,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+pub fn open_math(state: &mut State) {
+    let math = state.heap.new_table();
+    set_function(state, math, "abs", abs);
+    set_function(state, math, "acos", |state| unary(state, "acos", f64::acos));
+    set_function(state, math, "asin", |state| unary(state, "asin", f64::asin));
+    set_function(state, math, "atan", atan);
+    set_function(state, math, "ceil", |state| round(state, "ceil", f64::ceil));
+    set_function(state, math, "cos", |state| unary(state, "cos", f64::cos));
+    set_function(state, math, "exp", |state| unary(state, "exp", f64::exp));
+    set_function(state, math, "floor", |state| {
+        round(state, "floor", f64::floor)
+    });
+    set_function(state, math, "fmod", fmod);
+    set_function(state, math, "log", log);
+    set_function(state, math, "max", |state| extreme(state, "max", true));
+    set_function(state, math, "min", |state| extreme(state, "min", false));
+    set_function(state, math, "modf", modf);
+    set_function(state, math, "sin", |state| unary(state, "sin", f64::sin));
+    set_function(state, math, "sqrt", |state| unary(state, "sqrt", f64::sqrt));
+    set_function(state, math, "tan", |state| unary(state, "tan", f64::tan));
+    set_function(state, math, "tointeger", tointeger);
+    set_function(state, math, "type", type_);
+    set_function(state, math, "ult", ult);
+
+    // The generator is seeded differently every time, until `randomseed` is given a seed.
+    let random = Rc::new(Cell::new(Xoshiro256::new(random_seed(), 0)));
+    let generator = random.clone();
+    set_function(state, math, "random", move |state| {
+        random_(state, &generator)
+    });
+    set_function(state, math, "randomseed", move |state| {
+        randomseed(state, &random)
+    });
+
+    let constants = [
+        ("huge", Value::Float(f64::INFINITY)),
+        ("maxinteger", Value::Int(i64::MAX)),
+        ("mininteger", Value::Int(i64::MIN)),
+        ("pi", Value::Float(PI)),
+    ];
+
+    for (name, value) in constants {
+        let name = state.heap.intern(name.as_bytes());
+        state.heap[math].set_str(name, value);
+    }
+
+    set_library(state, "math", math);
+}
+
+/// Pushes a float as an integer if it has an integer representation.
+fn push_integral(state: &mut State, value: f64) {
+    match float_to_int(value) {
+        Some(value) => state.push(value),
+        None => state.push(value),
+    }
+}
+
+/// A function of one float argument with a float result.
+fn unary(state: &mut State, function: &'static str, f: fn(f64) -> f64) -> Result<usize> {
+    let x = check_number(state, 1, function)?;
+    state.push(f(x));
+    Ok(1)
+}
+
+/// `math.abs(x)`: the absolute value of `x`, which for the minimum integer is itself.
+fn abs(state: &mut State) -> Result<usize> {
+    match state.arg(1) {
+        Value::Int(n) => state.push(n.wrapping_abs()),
+        _ => state.push(check_number(state, 1, "abs")?.abs()),
+    }
+    Ok(1)
+}
+
+/// `math.floor(x)` and `math.ceil(x)`: rounds `x`, returning an integer if the result fits in
+/// one.
+fn round(state: &mut State, function: &'static str, f: fn(f64) -> f64) -> Result<usize> {
+    match state.arg(1) {
+        Value::Int(n) => state.push(n),
+        _ => push_integral(state, f(check_number(state, 1, function)?)),
+    }
+    Ok(1)
+}
+
+/// `math.fmod(x, y)`: the remainder of dividing `x` by `y` that rounds the quotient towards
+/// zero. Integers give an integer result.
+fn fmod(state: &mut State) -> Result<usize> {
+    if let (Value::Int(x), Value::Int(y)) = (state.arg(1), state.arg(2)) {
+        let result = match y {
+            0 => return Err(argument_error(2, "fmod", "zero")),
+            // This also avoids the overflow of the minimum integer divided by -1.
+            -1 => 0,
+            _ => x % y,
+        };
+
+        state.push(result);
+    } else {
+        let x = check_number(state, 1, "fmod")?;
+        let y = check_number(state, 2, "fmod")?;
+        state.push(x % y);
+    }
+
+    Ok(1)
+}
+
+/// `math.modf(x)`: the integral part of `x`, as a float, and its fractional part.
+fn modf(state: &mut State) -> Result<usize> {
+    if let Value::Int(n) = state.arg(1) {
+        state.push(n);
+        state.push(0.0);
+        return Ok(2);
+    }
+
+    let x = check_number(state, 1, "modf")?;
+    let integral = x.trunc();
+    state.push(integral);
+
+    // The fractional part of an infinity is zero rather than NaN.
+    state.push(if integral == x { 0.0 } else { x - integral });
+    Ok(2)
+}
+
+/// `math.atan(y [, x])`: the arc tangent of `y / x`, using the signs of both to find the
+/// quadrant. `x` defaults to 1.
+fn atan(state: &mut State) -> Result<usize> {
+    let y = check_number(state, 1, "atan")?;
+    let x = match state.arg(2) {
+        Value::Nil => 1.0,
+        _ => check_number(state, 2, "atan")?,
+    };
+
+    state.push(y.atan2(x));
+    Ok(1)
+}
+
+/// `math.log(x [, base])`: the logarithm of `x` in `base`, which defaults to e.
+fn log(state: &mut State) -> Result<usize> {
+    let x = check_number(state, 1, "log")?;
+    let result = match state.arg(2) {
+        Value::Nil => x.ln(),
+
+        _ => match check_number(state, 2, "log")? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        },
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+/// `math.max(x, ...)` and `math.min(x, ...)`: the largest or smallest argument, keeping its
+/// subtype. The first of equal arguments wins.
+fn extreme(state: &mut State, function: &'static str, max: bool) -> Result<usize> {
+    if state.arg_count() == 0 {
+        return Err(argument_error(1, function, "value expected"));
+    }
+
+    let mut result = number_arg(state, 1, function)?;
+    for position in 2..=state.arg_count() {
+        let value = number_arg(state, position, function)?;
+        let better = match max {
+            true => state.less_than(result, value)?,
+            false => state.less_than(value, result)?,
+        };
+
+        if better {
+            result = value;
+        }
+    }
+
+    state.push(result);
+    Ok(1)
+}
+
+/// The `position`th argument as an integer or float, which may also be given as a string.
+fn number_arg(state: &State, position: usize, function: &'static str) -> Result<Value> {
+    state
+        .to_number(state.arg(position))
+        .ok_or_else(|| type_error(state, position, function, "number"))
+}
+
+/// `math.tointeger(x)`: `x` as an integer if it has an integer representation, or nil.
+fn tointeger(state: &mut State) -> Result<usize> {
+    let value = check_any(state, 1, "tointeger")?;
+    let result = match state.to_number(value) {
+        Some(Value::Int(n)) => Value::Int(n),
+        Some(Value::Float(n)) => float_to_int(n).map_or(Value::Nil, Value::Int),
+        _ => Value::Nil,
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+/// `math.type(x)`: "integer" or "float" for numbers, and nil for anything else.
+fn type_(state: &mut State) -> Result<usize> {
+    let result = match check_any(state, 1, "type")? {
+        Value::Int(_) => Value::String(state.heap.intern(b"integer")),
+        Value::Float(_) => Value::String(state.heap.intern(b"float")),
+        _ => Value::Nil,
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+/// `math.ult(m, n)`: whether `m` is less than `n` when both are read as unsigned integers.
+fn ult(state: &mut State) -> Result<usize> {
+    let m = check_integer(state, 1, "ult")?;
+    let n = check_integer(state, 2, "ult")?;
+    state.push((m as u64) < (n as u64));
+    Ok(1)
+}
+
+/// `math.random([m [, n]])`: a float in [0, 1) without arguments, and otherwise an integer in
+/// [m, n], where `m` defaults to 1. `math.random(0)` gives an integer with all bits random.
+///
+/// The generator and the way its output is projected onto intervals are those of the
+/// reference implementation, so that the same seed gives the same sequence.
+fn random_(state: &mut State, generator: &Cell<Xoshiro256>) -> Result<usize> {
+    let mut random = generator.get();
+    let value = random.next();
+    generator.set(random);
+
+    let (low, up) = match state.arg_count() {
+        0 => {
+            state.push(float_from_bits(value));
+            return Ok(1);
+        }
+
+        1 => {
+            let up = check_integer(state, 1, "random")?;
+            if up == 0 {
+                state.push(value as i64);
+                return Ok(1);
+            }
+            (1, up)
+        }
+
+        2 => (
+            check_integer(state, 1, "random")?,
+            check_integer(state, 2, "random")?,
+        ),
+
+        _ => return Err(Error::WrongArgumentCount),
+    };
+
+    if low > up {
+        return Err(argument_error(1, "random", "interval is empty"));
+    }
+
+    let mut random = generator.get();
+    let offset = project(value, (up as u64).wrapping_sub(low as u64), &mut random);
+    generator.set(random);
+
+    state.push(offset.wrapping_add(low as u64) as i64);
+    Ok(1)
+}
+
+/// `math.randomseed([x [, y]])`: seeds the generator with the integers `x` and `y`, which
+/// defaults to 0, or without arguments, with a seed that differs every time. Returns the two
+/// parts of the seed.
+fn randomseed(state: &mut State, generator: &Cell<Xoshiro256>) -> Result<usize> {
+    let (n1, n2) = match state.arg_count() {
+        0 => (random_seed(), 0),
+        _ => (
+            check_integer(state, 1, "randomseed")?,
+            opt_integer(state, 2, "randomseed", 0)?,
+        ),
+    };
+
+    generator.set(Xoshiro256::new(n1, n2));
+    state.push(n1);
+    state.push(n2);
+    Ok(2)
+}
+
+/// The xoshiro256** generator.
+#[derive(Clone, Copy)]
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn new(n1: i64, n2: i64) -> Self {
+        // The constant word keeps the state from being all zeros, and the first outputs are
+        // discarded to spread the seed over the whole state.
+        let mut random = Self {
+            state: [n1 as u64, 0xff, n2 as u64, 0],
+        };
+
+        for _ in 0..16 {
+            random.next();
+        }
+
+        random
+    }
+
+    fn next(&mut self) -> u64 {
+        let [s0, s1, s2, s3] = self.state;
+        let result = s1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+
+        let s2 = s2 ^ s0;
+        let s3 = s3 ^ s1;
+        self.state = [s0 ^ s3, s1 ^ s2, s2 ^ (s1 << 17), s3.rotate_left(45)];
+        result
+    }
+}
+
+/// Converts random bits to a float in [0, 1) from their 53 most significant bits.
+fn float_from_bits(bits: u64) -> f64 {
+    (bits >> 11) as f64 * 0.5f64.powi(53)
+}
+
+/// Projects a random integer onto [0, n], drawing new ones from `random` until one falls in
+/// the interval once masked to the bits that `n` needs.
+fn project(mut value: u64, n: u64, random: &mut Xoshiro256) -> u64 {
+    if n & n.wrapping_add(1) == 0 {
+        // `n + 1` is a power of 2, so masking alone is uniform.
+        return value & n;
+    }
+
+    let mask = u64::MAX >> n.leading_zeros();
+    loop {
+        value &= mask;
+        if value <= n {
+            return value;
+        }
+        value = random.next();
+    }
+}
+
+/// A seed that differs every time, for when none is given.
+fn random_seed() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs() ^ ((now.subsec_nanos() as u64) << 32)) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::assert_runs};
+
+    #[test]
+    fn the_generator_matches_the_reference() {
+        let mut random = Xoshiro256::new(42, 0);
+        let outputs = [random.next(), random.next(), random.next()].map(|n| n as i64);
+        assert_eq!(
+            outputs,
+            [
+                -1276290044721465627,
+                8333941968102511665,
+                -8358531260401861301
+            ]
+        );
+
+        let mut random = Xoshiro256::new(42, 0);
+        assert_eq!(float_from_bits(random.next()), 0.9308121780395682);
+    }
+
+    #[test]
+    fn projection_stays_in_the_interval() {
+        let mut random = Xoshiro256::new(1, 2);
+        for n in [0, 1, 5, 99, 1 << 40, u64::MAX / 3, u64::MAX] {
+            for _ in 0..100 {
+                let value = random.next();
+                assert!(project(value, n, &mut random) <= n);
+            }
+        }
+        assert_eq!(project(0xabcd, 0xff, &mut random), 0xcd);
+    }
+
+    #[test]
+    fn seeded_sequences_repeat() {
+        assert_runs(
+            "math.randomseed(42) \
+             return math.random(0) .. ' ' .. math.random(0) .. ' ' .. math.random(0)",
+            "-1276290044721465627 8333941968102511665 -8358531260401861301",
+        );
+        assert_runs(
+            "math.randomseed(42) local t = {} for i = 1, 10 do t[i] = math.random(100) end \
+             return table.concat(t, ',')",
+            "50,76,86,54,64,7,25,3,24,24",
+        );
+        assert_runs(
+            "math.randomseed(-7, 123) local t = {} for i = 1, 10 do t[i] = math.random(10, 15) end \
+             return table.concat(t, ',')",
+            "14,10,12,12,15,11,10,15,12,13",
+        );
+        assert_runs(
+            "math.randomseed(42) return math.random()",
+            "0.93081217803957",
+        );
+        assert_runs("return table.concat({math.randomseed(5, 6)}, ',')", "5,6");
+    }
+
+    #[test]
+    fn random_ranges() {
+        assert_runs(
+            "return math.random(3, 3) .. math.type(math.random(math.mininteger, math.maxinteger))",
+            "3integer",
+        );
+        assert_runs(
+            "for i = 1, 1000 do local n = math.random(-2, 2) \
+               if n < -2 or n > 2 then return n end end \
+             for i = 1, 1000 do local x = math.random() if x < 0 or x >= 1 then return x end end \
+             return 'ok'",
+            "ok",
+        );
+        assert_runs(
+            "return select(2, pcall(math.random, 2, 1))",
+            "bad argument #1 to 'random' (interval is empty)",
+        );
+        assert_runs(
+            "return select(2, pcall(math.random, 1, 2, 3))",
+            "wrong number of arguments",
+        );
+    }
+
+    #[test]
+    fn rounding_keeps_integers_when_it_can() {
+        assert_runs(
+            "return math.floor(3.7) .. ' ' .. math.ceil(3.2) .. ' ' .. math.floor(-3.5) .. ' ' \
+             .. math.type(math.floor(1e100)) .. ' ' .. math.floor(5)",
+            "3 4 -4 float 5",
+        );
+        assert_runs(
+            "return table.concat({math.modf(3.7)}, ',') .. ' ' .. table.concat({math.modf(5)}, ',') \
+             .. ' ' .. table.concat({math.modf(-math.huge)}, ',')",
+            "3.0,0.7 5,0.0 -inf,0.0",
+        );
+        assert_runs(
+            "return tostring(math.tointeger(3.0)) .. tostring(math.tointeger(3.5)) \
+             .. tostring(math.tointeger('8')) .. tostring(math.tointeger(2^63)) \
+             .. tostring(math.tointeger({}))",
+            "3nil8nilnil",
+        );
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        assert_runs(
+            "return math.fmod(7, 3) .. math.fmod(-7, 3) .. math.fmod(7, -3) .. ' ' \
+             .. math.fmod(7.5, 2) .. ' ' .. math.fmod(math.mininteger, -1)",
+            "1-11 1.5 0",
+        );
+        assert_runs(
+            "return select(2, pcall(math.fmod, 1, 0))",
+            "bad argument #2 to 'fmod' (zero)",
+        );
+        assert_runs(
+            "return tostring(math.ult(1, 2)) .. tostring(math.ult(-1, 2)) \
+             .. tostring(math.ult(2, -1))",
+            "truefalsetrue",
+        );
+        assert_runs(
+            "return math.abs(-3) .. ' ' .. math.abs(math.mininteger) .. ' ' .. math.abs(-2.5)",
+            "3 -9223372036854775808 2.5",
+        );
+    }
+
+    #[test]
+    fn other_functions_and_constants() {
+        assert_runs(
+            "return math.maxinteger .. ' ' .. math.mininteger .. ' ' .. math.huge .. ' ' .. math.pi",
+            "9223372036854775807 -9223372036854775808 inf 3.1415926535898",
+        );
+        assert_runs(
+            "return math.type(1) .. math.type(1.0) .. tostring(math.type('1'))",
+            "integerfloatnil",
+        );
+        assert_runs(
+            "return math.max(1, 5, 3) .. math.min(1.5, -2, 3) .. math.type(math.max(1, 2.0))",
+            "5-2float",
+        );
+        assert_runs(
+            "return math.sqrt(16) .. ' ' .. math.log(8, 2) .. ' ' .. math.log(100, 10) .. ' ' \
+             .. math.log(27, 3) .. ' ' .. math.atan(0, -1)",
+            "4.0 3.0 2.0 3.0 3.1415926535898",
+        );
+        assert_runs(
+            "return select(2, pcall(math.max))",
+            "bad argument #1 to 'max' (value expected)",
+        );
+        assert_runs(
+            "return select(2, pcall(math.floor, 'x'))",
+            "bad argument #1 to 'floor' (number expected, got string)",
+        );
+    }
+}
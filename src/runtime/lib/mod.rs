@@ -0,0 +1,238 @@
+//! The standard library, as native functions installed into a [`State`].
+
+use super::{host, value::float_to_int, Error, Function, Result, State, StrRef, TableRef, Value};
+
+pub use {
+    base::open_base,
+    coroutine::open_coroutine,
+    debug::open_debug,
+    io::open_io,
+    math::open_math,
+    os::open_os,
+    package::{open_package, preload_module},
+    string::open_string,
+    table::open_table,
+    utf8::open_utf8,
+};
+
+pub(crate) use base::load_file;
+
+mod base;
+mod coroutine;
+mod debug;
+mod io;
+mod math;
+mod os;
+mod package;
+mod string;
+mod table;
+mod utf8;
+
+/// Sets `table[name]` to a native function.
+fn set_function(
+    state: &mut State,
+    table: TableRef,
+    name: &str,
+    callback: impl Fn(&mut State) -> Result<usize> + 'static,
+) {
+    let name = state.heap.intern(name.as_bytes());
+    let function = state.heap.new_function(Function::native(callback));
+    state.heap[table].set_str(name, Value::Function(function));
+}
+
+/// The registry keys of the table of loaded modules and of the loaders of preloaded ones,
+/// which are those the reference implementation uses.
+const LOADED: &[u8] = b"_LOADED";
+const PRELOAD: &[u8] = b"_PRELOAD";
+
+/// Installs a library: sets the global `name` to `table` and records it as the loaded module
+/// of that name, so that `require` finds it.
+fn set_library(state: &mut State, name: &str, table: TableRef) {
+    let globals = state.globals();
+    let name = state.heap.intern(name.as_bytes());
+    state.heap[globals].set_str(name, Value::Table(table));
+    let loaded = registry_table(state, LOADED);
+    state.heap[loaded].set_str(name, Value::Table(table));
+}
+
+fn registry(state: &mut State, key: &[u8]) -> Value {
+    let registry = state.heap.registry();
+    let key = state.heap.intern(key);
+    state.heap[registry].get_str(key)
+}
+
+fn set_registry(state: &mut State, key: &[u8], value: Value) {
+    let registry = state.heap.registry();
+    let key = state.heap.intern(key);
+    state.heap[registry].set_str(key, value);
+}
+
+/// The table in the registry under `key`, which is created if there is none.
+fn registry_table(state: &mut State, key: &[u8]) -> TableRef {
+    match registry(state, key) {
+        Value::Table(table) => table,
+        _ => {
+            let table = state.heap.new_table();
+            set_registry(state, key, Value::Table(table));
+            table
+        }
+    }
+}
+
+fn argument_error(position: usize, function: &'static str, message: impl Into<String>) -> Error {
+    Error::Argument {
+        position,
+        function,
+        message: message.into(),
+    }
+}
+
+/// The error for an argument of the wrong type, naming the type that was expected.
+fn type_error(state: &State, position: usize, function: &'static str, expected: &str) -> Error {
+    let actual = if position > state.arg_count() {
+        "no value".to_owned()
+    } else {
+        state.type_name(state.arg(position))
+    };
+
+    argument_error(
+        position,
+        function,
+        format!("{expected} expected, got {actual}"),
+    )
+}
+
+/// The `position`th argument, which may be any value but must be present.
+fn check_any(state: &State, position: usize, function: &'static str) -> Result<Value> {
+    if position > state.arg_count() {
+        return Err(argument_error(position, function, "value expected"));
+    }
+
+    Ok(state.arg(position))
+}
+
+fn check_table(state: &State, position: usize, function: &'static str) -> Result<TableRef> {
+    match state.arg(position) {
+        Value::Table(table) => Ok(table),
+        _ => Err(type_error(state, position, function, "table")),
+    }
+}
+
+/// The `position`th argument as a string, which may also be given as a number.
+fn check_string(state: &mut State, position: usize, function: &'static str) -> Result<StrRef> {
+    match state.arg(position) {
+        Value::String(string) => Ok(string),
+        number @ (Value::Int(_) | Value::Float(_)) => state.tostring(number),
+        _ => Err(type_error(state, position, function, "string")),
+    }
+}
+
+/// The `position`th argument as a string, copied out of the heap so that it can be used while
+/// calling back into Lua.
+fn check_bytes(state: &mut State, position: usize, function: &'static str) -> Result<Vec<u8>> {
+    let string = check_string(state, position, function)?;
+    Ok(state.heap[string].to_vec())
+}
+
+/// The `position`th argument as a string, or `default` if it is absent or nil.
+fn opt_string(
+    state: &mut State,
+    position: usize,
+    function: &'static str,
+    default: &str,
+) -> Result<Vec<u8>> {
+    match state.arg(position) {
+        Value::Nil => Ok(default.as_bytes().to_vec()),
+        _ => check_bytes(state, position, function),
+    }
+}
+
+/// The index in `options` of the `position`th argument, which must be one of them. An absent
+/// or nil argument is taken to be `default`, if there is one.
+fn check_option(
+    state: &mut State,
+    position: usize,
+    function: &'static str,
+    default: Option<&str>,
+    options: &[&str],
+) -> Result<usize> {
+    let name = match default {
+        Some(default) => opt_string(state, position, function, default)?,
+        None => check_bytes(state, position, function)?,
+    };
+
+    options
+        .iter()
+        .position(|option| option.as_bytes() == name)
+        .ok_or_else(|| {
+            let name = String::from_utf8_lossy(&name);
+            argument_error(position, function, format!("invalid option '{name}'"))
+        })
+}
+
+fn check_integer(state: &State, position: usize, function: &'static str) -> Result<i64> {
+    match state.arg(position) {
+        Value::Nil => Err(type_error(state, position, function, "number")),
+        _ => opt_integer(state, position, function, 0),
+    }
+}
+
+/// The `position`th argument as a float, which may also be given as a string holding a
+/// numeral.
+fn check_number(state: &State, position: usize, function: &'static str) -> Result<f64> {
+    match state.to_number(state.arg(position)) {
+        Some(Value::Int(n)) => Ok(n as f64),
+        Some(Value::Float(n)) => Ok(n),
+        _ => Err(type_error(state, position, function, "number")),
+    }
+}
+
+/// The `position`th argument as an integer, or `default` if it is absent or nil. Like
+/// numbers, strings holding a numeral are converted.
+fn opt_integer(
+    state: &State,
+    position: usize,
+    function: &'static str,
+    default: i64,
+) -> Result<i64> {
+    let value = state.arg(position);
+    if value.is_nil() {
+        return Ok(default);
+    }
+
+    match state.to_number(value) {
+        Some(Value::Int(value)) => Ok(value),
+
+        Some(Value::Float(value)) => float_to_int(value).ok_or_else(|| {
+            argument_error(position, function, "number has no integer representation")
+        }),
+
+        _ => Err(type_error(state, position, function, "number")),
+    }
+}
+
+/// Pushes the results of an operation on files, which reports failure with its results rather
+/// than an error: true on success, and otherwise nil, the message, prefixed with the name of
+/// the file if one is given, and the system's error number. Returns how many were pushed.
+fn file_result(state: &mut State, result: std::io::Result<()>, file: Option<&[u8]>) -> usize {
+    let error = match result {
+        Ok(()) => {
+            state.push(true);
+            return 1;
+        }
+        Err(error) => error,
+    };
+
+    let mut message = Vec::new();
+    if let Some(file) = file {
+        message.extend_from_slice(file);
+        message.extend_from_slice(b": ");
+    }
+    message.extend_from_slice(host::error_message(&error).as_bytes());
+
+    let message = state.heap.intern(&message);
+    state.push(Value::Nil);
+    state.push(message);
+    state.push(error.raw_os_error().unwrap_or(0) as i64);
+    3
+}
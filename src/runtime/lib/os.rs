@@ -0,0 +1,546 @@
+//! The `os` library.
+//!
+//! Everything but the calendar arithmetic goes through the state's
+//! [`Host`](crate::runtime::Host), including the offset of local time from UTC.
+
+use {
+    super::{
+        argument_error, check_bytes, check_integer, check_option, check_table, file_result, io,
+        opt_integer, opt_string, set_function, set_library,
+    },
+    crate::runtime::{host, value::float_to_int, Error, Result, State, TableRef, Value},
+    std::io::Write,
+};
+
+/// The conversions that `date` accepts after a `%`, which are those of C99's `strftime`. Those
+/// with an `E` or `O` modifier are the same as without it in the C locale.
+const CONVERSIONS: [&[u8]; 2] = [
+    b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%",
+    b"EcECExEXEyEYOdOeOHOIOmOMOSOuOUOVOwOWOy",
+];
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+pub fn open_os(state: &mut State) {
+    let os = state.heap.new_table();
+    set_function(state, os, "clock", |state| {
+        let clock = state.host().clock();
+        state.push(clock);
+        Ok(1)
+    });
+    set_function(state, os, "date", date);
+    set_function(state, os, "difftime", difftime);
+    set_function(state, os, "exit", exit);
+    set_function(state, os, "getenv", getenv);
+    set_function(state, os, "remove", |state| {
+        let name = check_bytes(state, 1, "remove")?;
+        let removed = state.host().remove(&name);
+        Ok(file_result(state, removed, Some(&name)))
+    });
+    set_function(state, os, "rename", |state| {
+        let from = check_bytes(state, 1, "rename")?;
+        let to = check_bytes(state, 2, "rename")?;
+        let renamed = state.host().rename(&from, &to);
+        Ok(file_result(state, renamed, None))
+    });
+    set_function(state, os, "setlocale", setlocale);
+    set_function(state, os, "time", time);
+    set_function(state, os, "tmpname", |state| {
+        let name = state.host().tmpname().map_err(|_| Error::UniqueFilename)?;
+        let name = state.heap.intern(&name);
+        state.push(name);
+        Ok(1)
+    });
+
+    set_library(state, "os", os);
+}
+
+/// `os.difftime(t2 [, t1])`: the number of seconds from `t1` to `t2`, as a float.
+fn difftime(state: &mut State) -> Result<usize> {
+    let t2 = check_integer(state, 1, "difftime")?;
+    let t1 = opt_integer(state, 2, "difftime", 0)?;
+    state.push(t2 as f64 - t1 as f64);
+    Ok(1)
+}
+
+/// `os.exit([code [, close]])`: ends the process with `code`, where true and the default mean
+/// success and false failure. Closes the state first if `close` is true, and otherwise only
+/// flushes the open files.
+fn exit(state: &mut State) -> Result<usize> {
+    let code = match state.arg(1) {
+        Value::Bool(success) => (!success) as i32,
+        _ => opt_integer(state, 1, "exit", 0)? as i32,
+    };
+
+    if state.arg(2).is_truthy() {
+        state.finalize_all();
+    } else {
+        io::flush_all(state);
+    }
+
+    match state.host().exit(code) {
+        Ok(never) => match never {},
+        Err(error) => Err(Error::Exit(host::error_message(&error))),
+    }
+}
+
+/// `os.getenv(name)`: the value of an environment variable, or nil if it is not set.
+fn getenv(state: &mut State) -> Result<usize> {
+    let name = check_bytes(state, 1, "getenv")?;
+    match state.host().getenv(&name) {
+        Some(value) => {
+            let value = state.heap.intern(&value);
+            state.push(value);
+        }
+        None => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `os.setlocale([locale [, category]])`: only the C locale is available, so this succeeds
+/// only in setting it, by the names "C", "POSIX" or "", and querying it.
+fn setlocale(state: &mut State) -> Result<usize> {
+    let categories = ["all", "collate", "ctype", "monetary", "numeric", "time"];
+    check_option(state, 2, "setlocale", Some("all"), &categories)?;
+
+    let locale = match state.arg(1) {
+        Value::Nil => None,
+        _ => Some(check_bytes(state, 1, "setlocale")?),
+    };
+
+    match locale.as_deref() {
+        None | Some(b"C" | b"POSIX" | b"") => {
+            let name = state.heap.intern(b"C");
+            state.push(name);
+        }
+        Some(_) => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `os.time([table])`: the current time, or the local time that `table` gives in the fields
+/// of `os.date("*t")`, of which `year`, `month` and `day` are required. The fields may be out
+/// of their usual ranges, and are updated to the date they amount to.
+fn time(state: &mut State) -> Result<usize> {
+    let host = state.host();
+    if state.arg(1).is_nil() {
+        state.push(host.time());
+        return Ok(1);
+    }
+
+    let table = check_table(state, 1, "time")?;
+    let year = date_field(state, table, "year", None, 1900)? + 1900;
+    let month = date_field(state, table, "month", None, 1)?;
+    let day = date_field(state, table, "day", None, 0)?;
+    let hour = date_field(state, table, "hour", Some(12), 0)?;
+    let min = date_field(state, table, "min", Some(0), 0)?;
+    let sec = date_field(state, table, "sec", Some(0), 0)?;
+
+    let year = year + month.div_euclid(12);
+    let month = month.rem_euclid(12) + 1;
+    let days = days_from_civil(year, month, 1) + day - 1;
+    let local = days * SECONDS_PER_DAY + hour * 60 * 60 + min * 60 + sec;
+    let time = local - host.utc_offset(local);
+
+    let date = Date::new(time + host.utc_offset(time)).ok_or(Error::TimeOutOfRange("time"))?;
+    set_date_fields(state, table, &date)?;
+
+    state.push(time);
+    Ok(1)
+}
+
+/// A field of the table given to `os.time`, less `delta`, or `default` if it is nil.
+fn date_field(
+    state: &mut State,
+    table: TableRef,
+    name: &'static str,
+    default: Option<i64>,
+    delta: i64,
+) -> Result<i64> {
+    let key = state.heap.intern(name.as_bytes());
+    let value = state.index(Value::Table(table), Value::String(key))?;
+
+    let field = match state.to_number(value) {
+        Some(Value::Int(field)) => Some(field),
+        Some(Value::Float(field)) => float_to_int(field),
+        _ => None,
+    };
+
+    match (field, value, default) {
+        (Some(field), ..) => {
+            // The reference implementation keeps fields in a C int.
+            let field = field
+                .checked_sub(delta)
+                .filter(|&field| i32::try_from(field).is_ok())
+                .ok_or(Error::DateFieldOutOfBounds(name))?;
+            Ok(field)
+        }
+        (None, Value::Nil, Some(default)) => Ok(default),
+        (None, Value::Nil, None) => Err(Error::MissingDateField(name)),
+        (None, ..) => Err(Error::DateFieldNotInteger(name)),
+    }
+}
+
+/// Sets the fields of `table` to those of `os.date("*t")`.
+fn set_date_fields(state: &mut State, table: TableRef, date: &Date) -> Result<()> {
+    let fields = [
+        ("year", Value::Int(date.year)),
+        ("month", Value::Int(date.month)),
+        ("day", Value::Int(date.day)),
+        ("hour", Value::Int(date.hour)),
+        ("min", Value::Int(date.min)),
+        ("sec", Value::Int(date.sec)),
+        ("yday", Value::Int(date.yday)),
+        ("wday", Value::Int(date.wday + 1)),
+        ("isdst", Value::Bool(false)),
+    ];
+
+    for (name, value) in fields {
+        let name = state.heap.intern(name.as_bytes());
+        state.set_index(Value::Table(table), Value::String(name), value)?;
+    }
+
+    Ok(())
+}
+
+/// `os.date([format [, time]])`: formats `time`, which defaults to the current time, as
+/// `strftime` does in the C locale. A format starting with `!` gives the time in UTC rather
+/// than local time, and a format of "*t", after any `!`, gives a table of the date's fields
+/// instead. The format defaults to "%c".
+fn date(state: &mut State) -> Result<usize> {
+    let format = opt_string(state, 1, "date", "%c")?;
+    let host = state.host();
+    let time = match state.arg(2) {
+        Value::Nil => host.time(),
+        _ => check_integer(state, 2, "date")?,
+    };
+
+    let (offset, format) = match format.strip_prefix(b"!") {
+        Some(format) => (None, format),
+        None => (Some(host.utc_offset(time)), &format[..]),
+    };
+
+    let date = time
+        .checked_add(offset.unwrap_or(0))
+        .and_then(Date::new)
+        .ok_or(Error::TimeOutOfRange("date"))?;
+
+    if format == b"*t" {
+        let table = state.heap.new_table();
+        state.push(table);
+        set_date_fields(state, table, &date)?;
+        return Ok(1);
+    }
+
+    let mut buffer = Vec::new();
+    let mut rest = format;
+    while let Some((&c, after)) = rest.split_first() {
+        rest = after;
+        if c != b'%' {
+            buffer.push(c);
+            continue;
+        }
+
+        let conversion = CONVERSIONS
+            .iter()
+            .enumerate()
+            .find_map(|(len, conversions)| {
+                let conversion = rest.get(..len + 1)?;
+                conversions
+                    .chunks(len + 1)
+                    .any(|valid| valid == conversion)
+                    .then_some(conversion)
+            })
+            .ok_or_else(|| {
+                let message = format!(
+                    "invalid conversion specifier '%{}'",
+                    String::from_utf8_lossy(rest)
+                );
+                argument_error(1, "date", message)
+            })?;
+
+        rest = &rest[conversion.len()..];
+        date.format(&mut buffer, conversion[conversion.len() - 1], offset);
+    }
+
+    let string = state.heap.intern(&buffer);
+    state.push(string);
+    Ok(1)
+}
+
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// The fields of a point in time, in the proleptic Gregorian calendar.
+struct Date {
+    year: i64,
+
+    /// From 1.
+    month: i64,
+
+    day: i64,
+    hour: i64,
+    min: i64,
+    sec: i64,
+
+    /// The day of the year, from 1.
+    yday: i64,
+
+    /// The day of the week, from 0 for Sunday.
+    wday: i64,
+}
+
+impl Date {
+    /// The date of `time` seconds since the Unix epoch, if its year fits where the reference
+    /// implementation keeps it, in a C int counting from 1900.
+    fn new(time: i64) -> Option<Self> {
+        let days = time.div_euclid(SECONDS_PER_DAY);
+        let seconds = time.rem_euclid(SECONDS_PER_DAY);
+        let (year, month, day) = civil_from_days(days);
+        i32::try_from(year - 1900).ok()?;
+
+        Some(Self {
+            year,
+            month,
+            day,
+            hour: seconds / (60 * 60),
+            min: seconds / 60 % 60,
+            sec: seconds % 60,
+            yday: days - days_from_civil(year, 1, 1) + 1,
+            // The epoch was a Thursday.
+            wday: (days + 4).rem_euclid(7),
+        })
+    }
+
+    /// Appends the result of a `strftime` conversion. `offset` is how far ahead of UTC the
+    /// date is, or `None` if it is in UTC.
+    fn format(&self, buffer: &mut Vec<u8>, conversion: u8, offset: Option<i64>) {
+        let weekday = WEEKDAYS[self.wday as usize];
+        let month = MONTHS[self.month as usize - 1];
+        let hour12 = (self.hour + 11) % 12 + 1;
+        // Days since Monday.
+        let monday_wday = (self.wday + 6) % 7;
+
+        match conversion {
+            b'a' => buffer.extend_from_slice(&weekday.as_bytes()[..3]),
+            b'A' => buffer.extend_from_slice(weekday.as_bytes()),
+            b'b' | b'h' => buffer.extend_from_slice(&month.as_bytes()[..3]),
+            b'B' => buffer.extend_from_slice(month.as_bytes()),
+            b'c' => self.format_all(buffer, b"%a %b %e %H:%M:%S %Y", offset),
+            b'C' => write!(buffer, "{:02}", self.year.div_euclid(100)).unwrap(),
+            b'd' => write!(buffer, "{:02}", self.day).unwrap(),
+            b'D' | b'x' => self.format_all(buffer, b"%m/%d/%y", offset),
+            b'e' => write!(buffer, "{:2}", self.day).unwrap(),
+            b'F' => self.format_all(buffer, b"%Y-%m-%d", offset),
+            b'g' => write!(buffer, "{:02}", self.iso_week().0.rem_euclid(100)).unwrap(),
+            b'G' => write!(buffer, "{}", self.iso_week().0).unwrap(),
+            b'H' => write!(buffer, "{:02}", self.hour).unwrap(),
+            b'I' => write!(buffer, "{hour12:02}").unwrap(),
+            b'j' => write!(buffer, "{:03}", self.yday).unwrap(),
+            b'm' => write!(buffer, "{:02}", self.month).unwrap(),
+            b'M' => write!(buffer, "{:02}", self.min).unwrap(),
+            b'n' => buffer.push(b'\n'),
+            b'p' => buffer.extend_from_slice(if self.hour < 12 { b"AM" } else { b"PM" }),
+            b'r' => self.format_all(buffer, b"%I:%M:%S %p", offset),
+            b'R' => self.format_all(buffer, b"%H:%M", offset),
+            b'S' => write!(buffer, "{:02}", self.sec).unwrap(),
+            b't' => buffer.push(b'\t'),
+            b'T' | b'X' => self.format_all(buffer, b"%H:%M:%S", offset),
+            b'u' => write!(buffer, "{}", monday_wday + 1).unwrap(),
+            b'U' => write!(buffer, "{:02}", (self.yday + 6 - self.wday) / 7).unwrap(),
+            b'V' => write!(buffer, "{:02}", self.iso_week().1).unwrap(),
+            b'w' => write!(buffer, "{}", self.wday).unwrap(),
+            b'W' => write!(buffer, "{:02}", (self.yday + 6 - monday_wday) / 7).unwrap(),
+            b'y' => write!(buffer, "{:02}", self.year.rem_euclid(100)).unwrap(),
+            b'Y' => write!(buffer, "{}", self.year).unwrap(),
+
+            b'z' => {
+                let offset = offset.unwrap_or(0);
+                let sign = if offset < 0 { '-' } else { '+' };
+                let minutes = offset.abs() / 60;
+                write!(buffer, "{sign}{:02}{:02}", minutes / 60, minutes % 60).unwrap();
+            }
+
+            b'Z' => match offset {
+                None | Some(0) => buffer.extend_from_slice(b"UTC"),
+                Some(_) => self.format(buffer, b'z', offset),
+            },
+
+            _ => buffer.push(conversion),
+        }
+    }
+
+    /// Appends the conversions of a format that is known to be valid.
+    fn format_all(&self, buffer: &mut Vec<u8>, format: &[u8], offset: Option<i64>) {
+        let mut bytes = format.iter();
+        while let Some(&c) = bytes.next() {
+            match c {
+                b'%' => self.format(buffer, *bytes.next().unwrap(), offset),
+                _ => buffer.push(c),
+            }
+        }
+    }
+
+    /// The year and week of the ISO 8601 week date, where weeks start on Monday and the first
+    /// week of a year is the one with its first Thursday.
+    fn iso_week(&self) -> (i64, i64) {
+        let weekday = (self.wday + 6) % 7 + 1;
+        let week = (self.yday - weekday + 10) / 7;
+
+        if week < 1 {
+            (self.year - 1, weeks_in_year(self.year - 1))
+        } else if week > weeks_in_year(self.year) {
+            (self.year + 1, 1)
+        } else {
+            (self.year, week)
+        }
+    }
+}
+
+/// The number of ISO 8601 weeks in `year`, which is 53 if it starts or ends on a Thursday.
+fn weeks_in_year(year: i64) -> i64 {
+    // The day of the week of the last day of a year, from 0 for Sunday.
+    let last_day = |year: i64| {
+        (year + year.div_euclid(4) - year.div_euclid(100) + year.div_euclid(400)).rem_euclid(7)
+    };
+
+    match last_day(year) == 4 || last_day(year - 1) == 3 {
+        true => 53,
+        false => 52,
+    }
+}
+
+/// The number of days from the Unix epoch to a date, where `month` counts from 1.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years are counted from March, so that leap days end them, in eras of 400 years.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date that is `days` days from the Unix epoch, as its year, month from 1 and day.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::assert_runs};
+
+    #[test]
+    fn civil_dates_round_trip() {
+        for (year, month, day) in [(1970, 1, 1), (2000, 2, 29), (1969, 12, 31), (-1, 3, 1)] {
+            let days = days_from_civil(year, month, day);
+            assert_eq!(civil_from_days(days), (year, month, day));
+        }
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+    }
+
+    #[test]
+    fn time_normalizes_date_tables() {
+        assert_runs(
+            "return os.time{year = 2000, month = 1, day = 1, hour = 0}",
+            "946684800",
+        );
+        assert_runs(
+            "return os.time{year = 2024, month = 2, day = 30, hour = 12} \
+             == os.time{year = 2024, month = 3, day = 1, hour = 12}",
+            "true",
+        );
+        assert_runs("return os.time(os.date('*t', 1e9)) == 1e9", "true");
+        assert_runs(
+            "return select(2, pcall(os.time, {year = 2000}))",
+            "field 'month' missing in date table",
+        );
+    }
+
+    #[test]
+    fn date_formats_like_strftime() {
+        assert_runs(
+            "return os.date('!%Y-%m-%d %H:%M:%S %j %a %b %p', 86400 * 365)",
+            "1971-01-01 00:00:00 001 Fri Jan AM",
+        );
+        assert_runs(
+            "return os.date('!%c|%x %X %D %F %R %T', 0)",
+            "Thu Jan  1 00:00:00 1970|01/01/70 00:00:00 01/01/70 1970-01-01 00:00 00:00:00",
+        );
+        assert_runs(
+            "return os.date('!%G-W%V-%u %g %U %W %e|%n|%t|%%', 0)",
+            "1970-W01-4 70 00 00  1|\n|\t|%",
+        );
+        assert_runs(
+            "local t = os.date('!*t', 0) \
+             return table.concat({t.year, t.month, t.day, t.hour, t.wday, t.yday, tostring(t.isdst)}, ',')",
+            "1970,1,1,0,5,1,false",
+        );
+        assert_runs(
+            "return select(2, pcall(os.date, '%Ez'))",
+            "bad argument #1 to 'date' (invalid conversion specifier '%Ez')",
+        );
+    }
+
+    #[test]
+    fn the_environment_and_the_filesystem() {
+        assert_runs(
+            "return os.difftime(10, 4) .. math.type(os.time()) .. type(os.clock()) \
+             .. tostring(os.getenv('SATIN_SURELY_UNSET'))",
+            "6.0integernumbernil",
+        );
+        assert_runs(
+            "return table.concat({select(2, os.remove('/nonexistent/file'))}, '|') .. ' ' \
+             .. table.concat({select(2, os.rename('/nonexistent/a', '/nonexistent/b'))}, '|')",
+            "/nonexistent/file: No such file or directory|2 No such file or directory|2",
+        );
+        assert_runs(
+            "local name = os.tmpname() local renamed = name .. '.renamed' \
+             io.open(name, 'w'):close() assert(os.rename(name, renamed)) \
+             local gone = io.open(name) == nil assert(os.remove(renamed)) \
+             return tostring(gone) .. tostring(io.open(renamed) == nil)",
+            "truetrue",
+        );
+        assert_runs(
+            "return os.setlocale() .. os.setlocale('C') .. tostring(os.setlocale('xx_YY'))",
+            "CCnil",
+        );
+    }
+}
@@ -0,0 +1,482 @@
+//! The `package` library and `require`.
+//!
+//! Modules are found the way the reference implementation finds them, minus the C searchers:
+//! first among the loaders in `package.preload`, which is where [`preload_module`] puts native
+//! modules, then as Lua files along `package.path`. Files are opened through the state's
+//! [`Host`](crate::runtime::Host).
+
+use {
+    super::{
+        base::load_file, check_bytes, check_string, opt_string, registry_table, set_function,
+        set_library, LOADED, PRELOAD,
+    },
+    crate::runtime::{Error, Function, NativeFunction, OpenMode, Result, State, TableRef, Value},
+    std::rc::Rc,
+};
+
+/// The separator of directories, and the characters that `package.config` describes: that
+/// separator, the separator of templates in a path, the mark that a template replaces with the
+/// name of the module, the mark of the executable's directory, and the mark for `luaopen_`
+/// names, which mean nothing without C modules but are kept for scripts that parse the string.
+const DIRECTORY_SEPARATOR: &str = if cfg!(windows) { "\\" } else { "/" };
+const PATH_SEPARATOR: &str = ";";
+const PATH_MARK: &str = "?";
+const EXECUTABLE_DIRECTORY: &str = "!";
+const IGNORE_MARK: &str = "-";
+
+const DEFAULT_PATH: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+                            /usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;\
+                            ./?.lua;./?/init.lua";
+
+pub fn open_package(state: &mut State) {
+    let package = state.heap.new_table();
+
+    let config = [
+        DIRECTORY_SEPARATOR,
+        PATH_SEPARATOR,
+        PATH_MARK,
+        EXECUTABLE_DIRECTORY,
+        IGNORE_MARK,
+    ]
+    .map(|line| format!("{line}\n"))
+    .concat();
+    set_string(state, package, "config", config.as_bytes());
+
+    let path = initial_path(state);
+    set_string(state, package, "path", &path);
+
+    let loaded = registry_table(state, LOADED);
+    set_table(state, package, "loaded", loaded);
+    let preload = registry_table(state, PRELOAD);
+    set_table(state, package, "preload", preload);
+
+    let searchers = state.heap.new_table();
+    for (index, searcher) in [search_preload, search_lua].into_iter().enumerate() {
+        let searcher = with_package(state, package, searcher);
+        state.heap[searchers].set_int(index as i64 + 1, searcher);
+    }
+    set_table(state, package, "searchers", searchers);
+
+    set_function(state, package, "searchpath", searchpath);
+
+    let require = with_package(state, package, require);
+    let globals = state.globals();
+    let name = state.heap.intern(b"require");
+    state.heap[globals].set_str(name, require);
+
+    set_library(state, "package", package);
+}
+
+/// Makes `name` a native module: `require(name)` calls `loader` with the name and
+/// `":preload:"`, and what it returns is the module. Works whether or not the `package`
+/// library is open, since the loaders live in the registry.
+pub fn preload_module(
+    state: &mut State,
+    name: &str,
+    loader: impl Fn(&mut State) -> Result<usize> + 'static,
+) {
+    let preload = registry_table(state, PRELOAD);
+    set_function(state, preload, name, loader);
+}
+
+fn set_string(state: &mut State, table: TableRef, name: &str, value: &[u8]) {
+    let name = state.heap.intern(name.as_bytes());
+    let value = state.heap.intern(value);
+    state.heap[table].set_str(name, Value::String(value));
+}
+
+fn set_table(state: &mut State, table: TableRef, name: &str, value: TableRef) {
+    let name = state.heap.intern(name.as_bytes());
+    state.heap[table].set_str(name, Value::Table(value));
+}
+
+/// A native function with the `package` table as its upvalue.
+fn with_package(
+    state: &mut State,
+    package: TableRef,
+    callback: fn(&mut State) -> Result<usize>,
+) -> Value {
+    let function = state.heap.new_function(Function::Native(NativeFunction {
+        callback: Rc::new(callback),
+        upvalues: Box::new([Value::Table(package)]),
+    }));
+    Value::Function(function)
+}
+
+/// The path from `LUA_PATH_5_4` or else `LUA_PATH`, with the first `;;` replaced by the default
+/// path, or the default path if neither variable is set.
+fn initial_path(state: &State) -> Vec<u8> {
+    let host = state.host();
+    let Some(path) = host
+        .getenv(b"LUA_PATH_5_4")
+        .or_else(|| host.getenv(b"LUA_PATH"))
+    else {
+        return DEFAULT_PATH.as_bytes().to_vec();
+    };
+
+    let Some(mark) = path.windows(2).position(|pair| pair == b";;") else {
+        return path;
+    };
+
+    let mut result = Vec::new();
+    if mark > 0 {
+        result.extend_from_slice(&path[..mark]);
+        result.extend_from_slice(PATH_SEPARATOR.as_bytes());
+    }
+    result.extend_from_slice(DEFAULT_PATH.as_bytes());
+    if mark + 2 < path.len() {
+        result.extend_from_slice(PATH_SEPARATOR.as_bytes());
+        result.extend_from_slice(&path[mark + 2..]);
+    }
+    result
+}
+
+/// `require(name)`: loads the named module, unless it is already loaded, and returns it along
+/// with what the searcher that found it said about where.
+fn require(state: &mut State) -> Result<usize> {
+    let name = check_string(state, 1, "require")?;
+    let loaded = Value::Table(registry_table(state, LOADED));
+    let module = state.index(loaded, Value::String(name))?;
+    if module.is_truthy() {
+        state.push(module);
+        return Ok(1);
+    }
+
+    let bytes = state.heap[name].to_vec();
+    let (loader, data) = find_loader(state, &bytes)?;
+    // The extra value stays on the stack under the call, to be returned at the end.
+    state.push(data);
+    state.push(loader);
+    state.push(name);
+    state.push(data);
+    state.call(2, Some(1))?;
+    let module = state.pop();
+    if !module.is_nil() {
+        state.set_index(loaded, Value::String(name), module)?;
+    }
+
+    // A module that returns nothing, and does not set its entry itself, is loaded as `true`.
+    let mut module = state.index(loaded, Value::String(name))?;
+    if module.is_nil() {
+        module = Value::Bool(true);
+        state.set_index(loaded, Value::String(name), module)?;
+    }
+
+    let data = state.pop();
+    state.push(module);
+    state.push(data);
+    Ok(2)
+}
+
+/// Asks each of `package.searchers` in turn for a loader for `name`, returning the first
+/// loader found and the extra value its searcher returned.
+fn find_loader(state: &mut State, name: &[u8]) -> Result<(Value, Value)> {
+    let searchers = match package_field(state, "searchers")? {
+        Value::Table(searchers) => searchers,
+        _ => return Err(Error::PackageField("searchers", "table")),
+    };
+
+    let mut message = Vec::new();
+    for index in 1.. {
+        let searcher = state.heap[searchers].get_int(index);
+        if searcher.is_nil() {
+            break;
+        }
+
+        let base = state.top();
+        let name = state.heap.intern(name);
+        state.push(searcher);
+        state.push(name);
+        state.call(1, Some(2))?;
+        let data = state.pop();
+        let result = state.pop();
+        state.set_top(base);
+
+        match result {
+            Value::Function(_) => return Ok((result, data)),
+            Value::String(_) | Value::Int(_) | Value::Float(_) => {
+                let result = state.tostring(result)?;
+                message.extend_from_slice(b"\n\t");
+                message.extend_from_slice(&state.heap[result]);
+            }
+            _ => {}
+        }
+    }
+
+    Err(Error::ModuleNotFound {
+        name: String::from_utf8_lossy(name).into_owned(),
+        message: String::from_utf8_lossy(&message).into_owned(),
+    })
+}
+
+/// The field of the `package` table that is the upvalue of the running function.
+fn package_field(state: &mut State, field: &str) -> Result<Value> {
+    let field = state.heap.intern(field.as_bytes());
+    state.index(state.upvalue(1), Value::String(field))
+}
+
+/// The searcher for modules in `package.preload`.
+fn search_preload(state: &mut State) -> Result<usize> {
+    let name = check_string(state, 1, "searcher")?;
+    let preload = Value::Table(registry_table(state, PRELOAD));
+    let loader = state.index(preload, Value::String(name))?;
+    if loader.is_nil() {
+        let message = [b"no field package.preload['", &state.heap[name][..], b"']"].concat();
+        let message = state.heap.intern(&message);
+        state.push(message);
+        return Ok(1);
+    }
+
+    let data = state.heap.intern(b":preload:");
+    state.push(loader);
+    state.push(data);
+    Ok(2)
+}
+
+/// The searcher for Lua files along `package.path`, whose loader is the compiled file and
+/// whose extra value is the file's name.
+fn search_lua(state: &mut State) -> Result<usize> {
+    let name = check_bytes(state, 1, "searcher")?;
+    let path = match package_field(state, "path")? {
+        Value::String(path) => state.heap[path].to_vec(),
+        number @ (Value::Int(_) | Value::Float(_)) => {
+            let path = state.tostring(number)?;
+            state.heap[path].to_vec()
+        }
+        _ => return Err(Error::PackageField("path", "string")),
+    };
+
+    let file = match search_path(state, &name, &path, b".", DIRECTORY_SEPARATOR.as_bytes()) {
+        Ok(file) => file,
+        Err(message) => {
+            let message = state.heap.intern(&message);
+            state.push(message);
+            return Ok(1);
+        }
+    };
+
+    let file = String::from_utf8_lossy(&file).into_owned();
+    match load_file(state, Some(&file), "bt", None) {
+        Ok(function) => {
+            let file = state.heap.intern(file.as_bytes());
+            state.push(function);
+            state.push(file);
+            Ok(2)
+        }
+
+        Err(error) => {
+            let message = state.error_value(error);
+            let message = match message {
+                Value::String(message) => {
+                    String::from_utf8_lossy(&state.heap[message]).into_owned()
+                }
+                _ => state.type_name(message),
+            };
+            Err(Error::LoadModule {
+                name: String::from_utf8_lossy(&name).into_owned(),
+                file,
+                message,
+            })
+        }
+    }
+}
+
+/// `package.searchpath(name, path [, sep [, rep]])`: the first file along `path` that can be
+/// read, with each `sep` in `name` replaced by `rep` and the result put in place of each `?`.
+/// Returns nil and a message listing the files tried if there is none.
+fn searchpath(state: &mut State) -> Result<usize> {
+    let name = check_bytes(state, 1, "searchpath")?;
+    let path = check_bytes(state, 2, "searchpath")?;
+    let separator = opt_string(state, 3, "searchpath", ".")?;
+    let replacement = opt_string(state, 4, "searchpath", DIRECTORY_SEPARATOR)?;
+
+    match search_path(state, &name, &path, &separator, &replacement) {
+        Ok(file) => {
+            let file = state.heap.intern(&file);
+            state.push(file);
+            Ok(1)
+        }
+
+        Err(message) => {
+            let message = state.heap.intern(&message);
+            state.push(Value::Nil);
+            state.push(message);
+            Ok(2)
+        }
+    }
+}
+
+/// The first readable file among the templates of `path`, or the message saying which files
+/// were tried.
+fn search_path(
+    state: &State,
+    name: &[u8],
+    path: &[u8],
+    separator: &[u8],
+    replacement: &[u8],
+) -> std::result::Result<Vec<u8>, Vec<u8>> {
+    // Only the first character of the separator is looked for, as in the reference
+    // implementation, but the whole of it is replaced.
+    let name = match separator.first() {
+        Some(first) if name.contains(first) => replace(name, separator, replacement),
+        _ => name.to_vec(),
+    };
+
+    let path = replace(path, PATH_MARK.as_bytes(), &name);
+    let separator = PATH_SEPARATOR.as_bytes()[0];
+    let host = state.host();
+    let read = OpenMode {
+        read: true,
+        ..OpenMode::default()
+    };
+
+    for file in path
+        .split(|&c| c == separator)
+        .filter(|file| !file.is_empty())
+    {
+        if let Ok(stream) = host.open(file, read) {
+            let _ = stream.close();
+            return Ok(file.to_vec());
+        }
+    }
+
+    let mut message = b"no file '".to_vec();
+    message.extend(replace(&path, PATH_SEPARATOR.as_bytes(), b"'\n\tno file '"));
+    message.push(b'\'');
+    Err(message)
+}
+
+/// `string` with every occurrence of `pattern`, which must not be empty, replaced by
+/// `replacement`.
+fn replace(string: &[u8], pattern: &[u8], replacement: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(string.len());
+    let mut rest = string;
+    while let Some(position) = rest
+        .windows(pattern.len())
+        .position(|window| window == pattern)
+    {
+        result.extend_from_slice(&rest[..position]);
+        result.extend_from_slice(replacement);
+        rest = &rest[position + pattern.len()..];
+    }
+    result.extend_from_slice(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{testing::assert_runs, Lua},
+    };
+
+    #[test]
+    fn preloaded_modules_load_once() {
+        assert_runs(
+            "local n = 0 \
+             package.preload.m = function(name, data) n = n + 1 return {name = name, data = data} end \
+             local a, extra = require('m') local b = require('m') \
+             return table.concat({a.name, a.data, extra, tostring(a == b), n, \
+               tostring(package.loaded.m == a)}, '|')",
+            "m|:preload:|:preload:|true|1|true",
+        );
+        assert_runs(
+            "package.preload.t = function() end return tostring(require('t')) .. tostring(package.loaded.t)",
+            "truetrue",
+        );
+        assert_runs(
+            "package.preload.s = function(name) package.loaded[name] = 'set' end return require('s')",
+            "set",
+        );
+        assert_runs(
+            "return tostring(require('string') == string) .. tostring(package.loaded._G == _G)",
+            "truetrue",
+        );
+    }
+
+    #[test]
+    fn native_modules_are_preloaded() {
+        let mut lua = Lua::new();
+        preload_module(&mut lua, "native", |state| {
+            let module = state.heap.new_table();
+            let answer = state.heap.intern(b"answer");
+            state.heap[module].set_str(answer, Value::Int(42));
+            state.push(Value::Table(module));
+            Ok(1)
+        });
+
+        let answer = lua
+            .load("return require('native').answer", "=test")
+            .and_then(|function| function.call::<i64>(&mut lua, ()));
+        assert_eq!(answer.unwrap(), 42);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn modules_are_found_along_the_path() {
+        assert_runs(
+            "local name = os.tmpname() \
+             local f = io.open(name .. '.lua', 'w') f:write('return ...') f:close() \
+             package.path = '/nonexistent/?.lua;' .. name .. '.?' \
+             local a, b = require('lua') os.remove(name .. '.lua') os.remove(name) \
+             return a .. tostring(b == name .. '.lua')",
+            "luatrue",
+        );
+        assert_runs(
+            "local name = os.tmpname() \
+             local f = io.open(name .. '.lua', 'w') f:write('return +') f:close() \
+             package.path = name .. '.?' \
+             local _, message = pcall(require, 'lua') os.remove(name .. '.lua') os.remove(name) \
+             return (message:gsub(name, 'NAME'))",
+            "error loading module 'lua' from file 'NAME.lua':\n\tNAME.lua:1: unexpected symbol near '+'",
+        );
+        assert_runs(
+            "package.path = '/nonexistent/?.lua;/nonexistent/?/init.lua' \
+             return select(2, pcall(require, 'a.b'))",
+            "module 'a.b' not found:\n\tno field package.preload['a.b']\
+             \n\tno file '/nonexistent/a/b.lua'\n\tno file '/nonexistent/a/b/init.lua'",
+        );
+    }
+
+    #[test]
+    fn searchers_can_be_replaced() {
+        assert_runs(
+            "table.insert(package.searchers, 1, function(name) \
+               return function(n, d) return n .. d end, '!' end) \
+             return table.concat({require('q')}, '|')",
+            "q!|!",
+        );
+        assert_runs(
+            "table.insert(package.searchers, 1, function(name) return 'custom ' .. name end) \
+             package.path = '' return select(2, pcall(require, 'q'))",
+            "module 'q' not found:\n\tcustom q\n\tno field package.preload['q']\n\tno file ''",
+        );
+        assert_runs(
+            "package.searchers = nil return select(2, pcall(require, 'zz'))",
+            "'package.searchers' must be a table",
+        );
+        assert_runs(
+            "package.path = nil return select(2, pcall(require, 'zz'))",
+            "'package.path' must be a string",
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn searchpath_reports_every_file_tried() {
+        assert_runs(
+            "return select(2, package.searchpath('a.b', '/nonexistent/?.lua;/x/?.so'))",
+            "no file '/nonexistent/a/b.lua'\n\tno file '/x/a/b.so'",
+        );
+        assert_runs(
+            "return select(2, package.searchpath('a_b', '/nonexistent/?.lua', '_', '-'))",
+            "no file '/nonexistent/a-b.lua'",
+        );
+        assert_runs(
+            "local name = os.tmpname() local found = package.searchpath('x', '/nonexistent;' .. name) \
+             os.remove(name) return found == name",
+            "true",
+        );
+        assert_runs("return package.config", "/\n;\n?\n!\n-\n");
+    }
+}
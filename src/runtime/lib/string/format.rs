@@ -0,0 +1,653 @@
+//! `string.format`, which formats its arguments as C's `printf` would.
+
+use {
+    super::check_bytes,
+    crate::{
+        number,
+        runtime::{
+            lib::{argument_error, check_integer, check_number},
+            meta, Error, Result, State, Value,
+        },
+    },
+    std::io::Write,
+};
+
+/// The longest a conversion specification may be after its `%`, counting the conversion.
+const MAX_SPECIFICATION: usize = 21;
+
+/// The longest string that `%s` pads or truncates, when no precision is given. Longer strings
+/// are copied whole.
+const MAX_PADDED_STRING: usize = 100;
+
+/// The flags, width and precision of a conversion.
+#[derive(Default)]
+struct Specification {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// `string.format(format, ...)`: returns `format` with each conversion specification, a `%`
+/// followed by flags, a width, a precision and a conversion, replaced by the next argument
+/// formatted accordingly.
+pub(super) fn format(state: &mut State) -> Result<usize> {
+    let format = check_bytes(state, 1, "format")?;
+    let mut result = Vec::with_capacity(format.len());
+    let mut position = 1;
+    let mut rest = &format[..];
+
+    while let Some((&c, tail)) = rest.split_first() {
+        rest = tail;
+        if c != b'%' {
+            result.push(c);
+            continue;
+        }
+
+        if let Some(tail) = rest.strip_prefix(b"%") {
+            rest = tail;
+            result.push(b'%');
+            continue;
+        }
+
+        position += 1;
+        if position > state.arg_count() {
+            return Err(argument_error(position, "format", "no value"));
+        }
+
+        // The specification runs to the first character that cannot be a flag, a digit or a
+        // point, which should be the conversion.
+        let length = rest
+            .iter()
+            .take_while(|c| b"-+ #0123456789.".contains(c))
+            .count();
+
+        if length + 1 > MAX_SPECIFICATION {
+            return Err(Error::FormatTooLong);
+        }
+
+        let conversion = rest.get(length).copied().unwrap_or(0);
+        let text = &rest[..length];
+        rest = rest.get(length + 1..).unwrap_or_default();
+
+        let parse =
+            |flags: &[u8], precision: bool| parse_specification(text, conversion, flags, precision);
+        let value = state.arg(position);
+
+        match conversion {
+            b'c' => {
+                let specification = parse(b"-", false)?;
+                let c = check_integer(state, position, "format")? as u8;
+                pad(&mut result, &specification, b"", &[c], false);
+            }
+
+            b'd' | b'i' => {
+                let specification = parse(b"-+ 0", true)?;
+                let n = check_integer(state, position, "format")?;
+                let sign = sign(n < 0, &specification);
+                let digits = n.unsigned_abs().to_string();
+                format_integer(&mut result, &specification, sign, digits.as_bytes());
+            }
+
+            b'u' | b'o' | b'x' | b'X' => {
+                let flags: &[u8] = if conversion == b'u' { b"-0" } else { b"-#0" };
+                let specification = parse(flags, true)?;
+                let n = check_integer(state, position, "format")? as u64;
+                format_unsigned(&mut result, &specification, conversion, n);
+            }
+
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                let specification = parse(b"-+ #0", true)?;
+                let n = check_number(state, position, "format")?;
+                format_float(&mut result, &specification, conversion, n);
+            }
+
+            b'p' => {
+                let specification = parse(b"-", false)?;
+                let pointer = match meta::address(value) {
+                    0 => "(null)".to_owned(),
+                    address => format!("0x{address:08x}"),
+                };
+                pad(&mut result, &specification, b"", pointer.as_bytes(), false);
+            }
+
+            b'q' => {
+                if !text.is_empty() {
+                    return Err(Error::QuoteModifiers);
+                }
+
+                add_literal(state, &mut result, position, value)?;
+            }
+
+            b's' => {
+                let string = state.tostring(value)?;
+                let string = &state.heap[string];
+
+                if text.is_empty() {
+                    result.extend_from_slice(string);
+                } else {
+                    if string.contains(&0) {
+                        return Err(argument_error(position, "format", "string contains zeros"));
+                    }
+
+                    let specification = parse(b"-", true)?;
+                    match specification.precision {
+                        None if string.len() >= MAX_PADDED_STRING => {
+                            result.extend_from_slice(string)
+                        }
+
+                        precision => {
+                            let end = precision.unwrap_or(string.len()).min(string.len());
+                            pad(&mut result, &specification, b"", &string[..end], false);
+                        }
+                    }
+                }
+            }
+
+            _ => {
+                let mut specification = vec![b'%'];
+                specification.extend_from_slice(text);
+                specification.extend(conversion.is_ascii_graphic().then_some(conversion));
+                let specification = String::from_utf8_lossy(&specification).into_owned();
+                return Err(Error::InvalidConversion(specification));
+            }
+        }
+    }
+
+    let result = state.heap.intern(&result);
+    state.push(result);
+    Ok(1)
+}
+
+/// Parses the flags, width and precision of a conversion, of which only the given flags, at
+/// most two digits of width and, if allowed, at most two digits of precision are accepted.
+fn parse_specification(
+    text: &[u8],
+    conversion: u8,
+    flags: &[u8],
+    precision_allowed: bool,
+) -> Result<Specification> {
+    let mut specification = Specification::default();
+    let mut rest = text;
+
+    while let Some((&flag, tail)) = rest.split_first().filter(|(c, _)| flags.contains(c)) {
+        match flag {
+            b'-' => specification.left = true,
+            b'+' => specification.plus = true,
+            b' ' => specification.space = true,
+            b'#' => specification.alternate = true,
+            _ => specification.zero = true,
+        }
+        rest = tail;
+    }
+
+    // A width cannot start with 0, which is a flag.
+    if rest.first() != Some(&b'0') {
+        let (width, tail) = two_digits(rest);
+        specification.width = width;
+        rest = tail;
+
+        if precision_allowed {
+            if let Some(tail) = rest.strip_prefix(b".") {
+                let (precision, tail) = two_digits(tail);
+                specification.precision = Some(precision);
+                rest = tail;
+            }
+        }
+    }
+
+    if !rest.is_empty() || !conversion.is_ascii_alphabetic() {
+        let mut text = [b"%", text].concat();
+        text.push(conversion);
+        let text = String::from_utf8_lossy(&text).into_owned();
+        return Err(Error::InvalidConversionSpecification(text));
+    }
+
+    Ok(specification)
+}
+
+/// Reads up to two decimal digits.
+fn two_digits(text: &[u8]) -> (usize, &[u8]) {
+    let count = text
+        .iter()
+        .take(2)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    let value = text[..count]
+        .iter()
+        .fold(0, |value, &c| value * 10 + (c - b'0') as usize);
+    (value, &text[count..])
+}
+
+/// The sign a signed conversion starts with.
+fn sign(negative: bool, specification: &Specification) -> &'static [u8] {
+    if negative {
+        b"-"
+    } else if specification.plus {
+        b"+"
+    } else if specification.space {
+        b" "
+    } else {
+        b""
+    }
+}
+
+/// Appends `prefix` and `body` padded to the width of the conversion: on the right if it is
+/// left-justified, otherwise with zeros between them if `zeros` is set and with spaces on the
+/// left if not.
+fn pad(
+    buffer: &mut Vec<u8>,
+    specification: &Specification,
+    prefix: &[u8],
+    body: &[u8],
+    zeros: bool,
+) {
+    let padding = specification
+        .width
+        .saturating_sub(prefix.len() + body.len());
+
+    if specification.left {
+        buffer.extend_from_slice(prefix);
+        buffer.extend_from_slice(body);
+        buffer.resize(buffer.len() + padding, b' ');
+    } else if zeros && specification.zero {
+        buffer.extend_from_slice(prefix);
+        buffer.resize(buffer.len() + padding, b'0');
+        buffer.extend_from_slice(body);
+    } else {
+        buffer.resize(buffer.len() + padding, b' ');
+        buffer.extend_from_slice(prefix);
+        buffer.extend_from_slice(body);
+    }
+}
+
+/// Appends the digits of an integer, extended with zeros to the precision if there is one,
+/// which also disables padding with zeros. A zero precision formats zero as no digits.
+fn format_integer(
+    buffer: &mut Vec<u8>,
+    specification: &Specification,
+    prefix: &[u8],
+    digits: &[u8],
+) {
+    match specification.precision {
+        None => pad(buffer, specification, prefix, digits, true),
+
+        Some(precision) => {
+            let digits = if precision == 0 && digits == b"0" {
+                &[][..]
+            } else {
+                digits
+            };
+
+            let mut body = vec![b'0'; precision.saturating_sub(digits.len())];
+            body.extend_from_slice(digits);
+            pad(buffer, specification, prefix, &body, false);
+        }
+    }
+}
+
+fn format_unsigned(buffer: &mut Vec<u8>, specification: &Specification, conversion: u8, n: u64) {
+    let digits = match conversion {
+        b'u' => n.to_string(),
+        b'o' => format!("{n:o}"),
+        b'x' => format!("{n:x}"),
+        _ => format!("{n:X}"),
+    };
+
+    let prefix: &[u8] = match conversion {
+        b'x' if specification.alternate && n != 0 => b"0x",
+        b'X' if specification.alternate && n != 0 => b"0X",
+        _ => b"",
+    };
+
+    // The alternate form of an octal conversion makes its first digit a zero.
+    if conversion == b'o' && specification.alternate {
+        let precision = specification.precision.unwrap_or(0).max(digits.len() + 1);
+        let specification = Specification {
+            precision: Some(if n == 0 { 1 } else { precision }),
+            ..*specification
+        };
+        return format_integer(buffer, &specification, prefix, digits.as_bytes());
+    }
+
+    format_integer(buffer, specification, prefix, digits.as_bytes());
+}
+
+fn format_float(buffer: &mut Vec<u8>, specification: &Specification, conversion: u8, n: f64) {
+    let sign = sign(n.is_sign_negative(), specification);
+    let upper = conversion.is_ascii_uppercase();
+
+    if !n.is_finite() {
+        let body: &[u8] = match (n.is_nan(), upper) {
+            (true, false) => b"nan",
+            (true, true) => b"NAN",
+            (false, false) => b"inf",
+            (false, true) => b"INF",
+        };
+        return pad(buffer, specification, sign, body, false);
+    }
+
+    let n = n.abs();
+    let alternate = specification.alternate;
+    let mut body = Vec::new();
+
+    match conversion.to_ascii_lowercase() {
+        b'a' => write_hex_float(&mut body, n, specification.precision, alternate),
+
+        b'e' => write_scientific(
+            &mut body,
+            n,
+            specification.precision.unwrap_or(6),
+            alternate,
+        ),
+
+        b'f' => {
+            let precision = specification.precision.unwrap_or(6);
+            write!(body, "{n:.precision$}").unwrap();
+            if alternate && precision == 0 {
+                body.push(b'.');
+            }
+        }
+
+        _ => write_general(
+            &mut body,
+            n,
+            specification.precision.unwrap_or(6),
+            alternate,
+        ),
+    }
+
+    let mut prefix = sign.to_vec();
+    if conversion.eq_ignore_ascii_case(&b'a') {
+        // The `0x` goes before any padding zeros.
+        prefix.extend_from_slice(b"0x");
+        body.drain(..2);
+    }
+
+    if upper {
+        prefix.make_ascii_uppercase();
+        body.make_ascii_uppercase();
+    }
+
+    pad(buffer, specification, &prefix, &body, true);
+}
+
+/// Writes a non-negative finite float as `%e` does.
+fn write_scientific(buffer: &mut Vec<u8>, n: f64, precision: usize, alternate: bool) {
+    let scientific = format!("{n:.precision$e}");
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    buffer.extend_from_slice(mantissa.as_bytes());
+    if alternate && precision == 0 {
+        buffer.push(b'.');
+    }
+
+    let sign = if exponent < 0 { '-' } else { '+' };
+    write!(buffer, "e{sign}{:02}", exponent.abs()).unwrap();
+}
+
+/// Writes a non-negative finite float as `%g` does, which without the alternate form drops
+/// trailing zeros from the fraction.
+fn write_general(buffer: &mut Vec<u8>, n: f64, precision: usize, alternate: bool) {
+    if !alternate {
+        return number::write_general(buffer, n, precision);
+    }
+
+    let precision = precision.max(1);
+    let scientific = format!("{n:.*e}", precision - 1);
+    let exponent: i32 = scientific.split_once('e').unwrap().1.parse().unwrap();
+
+    if exponent < -4 || exponent >= precision as i32 {
+        write_scientific(buffer, n, precision - 1, true);
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        write!(buffer, "{n:.decimals$}").unwrap();
+        if decimals == 0 {
+            buffer.push(b'.');
+        }
+    }
+}
+
+/// Writes a non-negative finite float as `%a` does: `0x`, then a hexadecimal mantissa with
+/// one digit before the point, which is 1 unless the float is zero or subnormal, then a
+/// binary exponent. Without a precision, the fraction has as many digits as it needs.
+fn write_hex_float(buffer: &mut Vec<u8>, n: f64, precision: Option<usize>, alternate: bool) {
+    const FRACTION_BITS: u32 = 52;
+    const FRACTION_DIGITS: usize = 13;
+
+    let bits = n.to_bits();
+    let biased_exponent = (bits >> FRACTION_BITS) as i32;
+    let mut fraction = bits & ((1 << FRACTION_BITS) - 1);
+
+    let (mut leading, exponent) = match (biased_exponent, fraction) {
+        (0, 0) => (0, 0),
+        (0, _) => (0, -1022),
+        _ => (1, biased_exponent - 1023),
+    };
+
+    let digits = match precision {
+        Some(precision) if precision < FRACTION_DIGITS => {
+            // Round the fraction to the precision, half to even.
+            let dropped = 4 * (FRACTION_DIGITS - precision) as u32;
+            let half = 1 << (dropped - 1);
+            let remainder = fraction & ((1 << dropped) - 1);
+            fraction >>= dropped;
+
+            if remainder > half || (remainder == half && fraction & 1 == 1) {
+                fraction += 1;
+                if fraction >> (4 * precision) != 0 {
+                    fraction = 0;
+                    leading += 1;
+                }
+            }
+
+            precision
+        }
+
+        Some(precision) => precision,
+
+        None if fraction == 0 => 0,
+
+        None => {
+            let trailing = fraction.trailing_zeros() as usize / 4;
+            fraction >>= 4 * trailing;
+            FRACTION_DIGITS - trailing
+        }
+    };
+
+    write!(buffer, "0x{leading}").unwrap();
+    if digits > 0 || alternate {
+        buffer.push(b'.');
+    }
+
+    if digits > 0 {
+        let shown = digits.min(FRACTION_DIGITS);
+        write!(buffer, "{fraction:0shown$x}").unwrap();
+        buffer.resize(buffer.len() + digits - shown, b'0');
+    }
+
+    let sign = if exponent < 0 { '-' } else { '+' };
+    write!(buffer, "p{sign}{}", exponent.abs()).unwrap();
+}
+
+/// Appends a value as `%q` formats it: as a Lua literal that reads back as the same value.
+fn add_literal(
+    state: &mut State,
+    buffer: &mut Vec<u8>,
+    position: usize,
+    value: Value,
+) -> Result<()> {
+    match value {
+        Value::String(string) => {
+            let string = &state.heap[string];
+            buffer.push(b'"');
+
+            for (i, &c) in string.iter().enumerate() {
+                match c {
+                    b'"' | b'\\' | b'\n' => buffer.extend_from_slice(&[b'\\', c]),
+
+                    // A numeric escape followed by a digit needs all three of its digits.
+                    _ if c.is_ascii_control() => {
+                        if string.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                            write!(buffer, "\\{c:03}").unwrap();
+                        } else {
+                            write!(buffer, "\\{c}").unwrap();
+                        }
+                    }
+
+                    _ => buffer.push(c),
+                }
+            }
+
+            buffer.push(b'"');
+        }
+
+        // The smallest integer has no literal of its own, since its magnitude would be read as
+        // a float, but it does have a hexadecimal one.
+        Value::Int(i64::MIN) => buffer.extend_from_slice(b"0x8000000000000000"),
+        Value::Int(n) => number::write_int(buffer, n),
+
+        Value::Float(n) if n == f64::INFINITY => buffer.extend_from_slice(b"1e9999"),
+        Value::Float(n) if n == f64::NEG_INFINITY => buffer.extend_from_slice(b"-1e9999"),
+        Value::Float(n) if n.is_nan() => buffer.extend_from_slice(b"(0/0)"),
+
+        // Hexadecimal is exact, so the literal reads back as the very same float.
+        Value::Float(n) => {
+            if n.is_sign_negative() {
+                buffer.push(b'-');
+            }
+            write_hex_float(buffer, n.abs(), None, false);
+        }
+
+        Value::Nil | Value::Bool(_) => {
+            let string = state.tostring(value)?;
+            buffer.extend_from_slice(&state.heap[string]);
+        }
+
+        _ => {
+            return Err(argument_error(
+                position,
+                "format",
+                "value has no literal form",
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::assert_runs};
+
+    fn hex_float(n: f64, precision: Option<usize>) -> String {
+        let mut buffer = Vec::new();
+        write_hex_float(&mut buffer, n, precision, false);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn hex_floats() {
+        assert_eq!(hex_float(1.0, None), "0x1p+0");
+        assert_eq!(hex_float(0.1, None), "0x1.999999999999ap-4");
+        assert_eq!(hex_float(0.0, None), "0x0p+0");
+        assert_eq!(
+            hex_float(f64::from_bits(1), None),
+            "0x0.0000000000001p-1022"
+        );
+        assert_eq!(hex_float(1.5, Some(3)), "0x1.800p+0");
+        assert_eq!(hex_float(1.96875, Some(1)), "0x2.0p+0");
+        assert_eq!(hex_float(1.03125, Some(1)), "0x1.0p+0");
+    }
+
+    #[test]
+    fn scientific_and_general_floats() {
+        let mut buffer = Vec::new();
+        write_scientific(&mut buffer, 12345.678, 6, false);
+        buffer.push(b' ');
+        write_scientific(&mut buffer, 5e-300, 0, true);
+        buffer.push(b' ');
+        write_general(&mut buffer, 100.0, 6, true);
+        buffer.push(b' ');
+        write_general(&mut buffer, 1e-5, 2, true);
+        assert_eq!(buffer, b"1.234568e+04 5.e-300 100.000 1.0e-05");
+    }
+
+    #[test]
+    fn integer_conversions() {
+        assert_runs(
+            "return string.format('%d %5d %-5d| %05d %+d %x %X %o %c %i', \
+               42, 42, 42, 42, 42, 255, 255, 8, 65, 3.0)",
+            "42    42 42   | 00042 +42 ff FF 10 A 3",
+        );
+        assert_runs(
+            "return select(2, pcall(string.format, '%d', 3.5))",
+            "bad argument #2 to 'format' (number has no integer representation)",
+        );
+        assert_runs(
+            "return select(2, pcall(string.format, '%d', 'x'))",
+            "bad argument #2 to 'format' (number expected, got string)",
+        );
+    }
+
+    #[test]
+    fn float_conversions() {
+        assert_runs(
+            "return string.format('%.3f %e %g %g %g %a %.14g', \
+               3.14159, 12345.678, 0.0001, 1e20, 100, 1, 2^53)",
+            "3.142 1.234568e+04 0.0001 1e+20 100 0x1p+0 9.007199254741e+15",
+        );
+        assert_runs(
+            "return string.format('%5.2f|%-10.3e|', 3.14159, 2.5)",
+            " 3.14|2.500e+00 |",
+        );
+    }
+
+    #[test]
+    fn string_conversions() {
+        assert_runs(
+            "return string.format('%5.1s|%-4s|%s|%%', 'abc', 'x', 12)",
+            "    a|x   |12|%",
+        );
+        assert_runs(
+            "return string.format('%s', setmetatable({}, {__tostring = function() return 'T' end}))",
+            "T",
+        );
+    }
+
+    #[test]
+    fn quoted_literals_read_back() {
+        assert_runs(
+            "return string.format('%q', 'a\\nb\\0c\"\\\\')",
+            "\"a\\\nb\\0c\\\"\\\\\"",
+        );
+        assert_runs(
+            "return string.format('%q %q %q %q %q', 1/0, -1/0, 0/0, math.mininteger, 0.1)",
+            "1e9999 -1e9999 (0/0) 0x8000000000000000 0x1.999999999999ap-4",
+        );
+        assert_runs(
+            "local s = '\\1\\200\\r\\0001' return load('return ' .. string.format('%q', s))() == s",
+            "true",
+        );
+    }
+
+    #[test]
+    fn invalid_specifications() {
+        assert_runs(
+            "return select(2, pcall(string.format, '%y', 1))",
+            "invalid conversion '%y' to 'format'",
+        );
+        assert_runs(
+            "return select(2, pcall(string.format, '%100d', 1))",
+            "invalid conversion specification: '%100d'",
+        );
+        assert_runs(
+            "return select(2, pcall(string.format, '%d'))",
+            "bad argument #2 to 'format' (no value)",
+        );
+    }
+}
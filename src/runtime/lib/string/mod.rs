@@ -0,0 +1,618 @@
+//! The `string` library.
+
+use {
+    super::{
+        argument_error, check_bytes, check_integer, check_string, opt_integer, set_function,
+        set_library, type_error,
+    },
+    crate::{
+        number,
+        runtime::{
+            pattern::{self, Capture, Matcher},
+            Arith, Error, Function, Metamethod, Result, State, Value,
+        },
+    },
+    std::{cell::Cell, ops::Range},
+};
+
+mod format;
+mod pack;
+
+/// The longest string that `string.rep` builds, beyond which it fails rather than trying to
+/// allocate the string.
+const MAX_REP_SIZE: usize = i32::MAX as usize;
+
+pub fn open_string(state: &mut State) {
+    let string = state.heap.new_table();
+    set_function(state, string, "byte", byte);
+    set_function(state, string, "char", char);
+    set_function(state, string, "find", find);
+    set_function(state, string, "format", format::format);
+    set_function(state, string, "gmatch", gmatch);
+    set_function(state, string, "gsub", gsub);
+    set_function(state, string, "len", len);
+    set_function(state, string, "lower", lower);
+    set_function(state, string, "match", match_);
+    set_function(state, string, "pack", pack::pack);
+    set_function(state, string, "packsize", pack::packsize);
+    set_function(state, string, "rep", rep);
+    set_function(state, string, "reverse", reverse);
+    set_function(state, string, "sub", sub);
+    set_function(state, string, "unpack", pack::unpack);
+    set_function(state, string, "upper", upper);
+
+    set_library(state, "string", string);
+
+    // Strings index the library, so that `s:upper()` calls `string.upper(s)`.
+    let metatable = state.heap.new_table();
+    let index = state.heap.metamethod_name(Metamethod::Index);
+    state.heap[metatable].set_str(index, Value::Table(string));
+
+    for op in [
+        Arith::Add,
+        Arith::Sub,
+        Arith::Mul,
+        Arith::Mod,
+        Arith::Pow,
+        Arith::Div,
+        Arith::Idiv,
+        Arith::Unm,
+    ] {
+        let name = state.heap.metamethod_name(op.event());
+        let handler = Function::native(move |state| arith(state, op));
+        let handler = state.heap.new_function(handler);
+        state.heap[metatable].set_str(name, Value::Function(handler));
+    }
+
+    // All strings share one metatable, so any string will do to set it.
+    let string = state.heap.intern(b"");
+    state
+        .heap
+        .set_metatable(Value::String(string), Some(metatable));
+}
+
+/// The arithmetic metamethods of strings, which convert strings holding numerals to numbers,
+/// so that `"10" + 1` is 11. When an operand cannot be converted, the handler of the second
+/// one is tried instead, as it would have been if strings had none.
+fn arith(state: &mut State, op: Arith) -> Result<usize> {
+    let (a, b) = (state.arg(1), state.arg(2));
+    let result = match (state.to_number(a), state.to_number(b)) {
+        (Some(x), Some(y)) => state.arith(op, x, y)?,
+
+        (x, _) => {
+            let handler = state.heap.metamethod_of(b, op.event());
+            if matches!(b, Value::String(_)) || handler.is_nil() {
+                let (value, operand) = match x {
+                    None => (a, 0),
+                    Some(_) => (b, 1),
+                };
+
+                return Err(state.operand_error("perform arithmetic on", value, Some(operand)));
+            }
+
+            state.call_metamethod(handler, &[a, b])?
+        }
+    };
+
+    state.push(result);
+    Ok(1)
+}
+
+/// Converts a position in a string, which counts from 1 or, if negative, back from the end,
+/// to an offset. Positions before the start are clamped to it, but positions after the end
+/// are not clamped.
+fn start_offset(position: i64, len: usize) -> usize {
+    match position {
+        1.. => position as usize - 1,
+        0 => 0,
+        _ if position.unsigned_abs() > len as u64 => 0,
+        _ => len - position.unsigned_abs() as usize,
+    }
+}
+
+/// Converts a position in a string that ends a range, which counts from 1 or, if negative,
+/// back from the end, to the offset after the range, clamped to the string.
+fn end_offset(position: i64, len: usize) -> usize {
+    match position {
+        0.. => (position as u64).min(len as u64) as usize,
+        _ if position.unsigned_abs() > len as u64 => 0,
+        _ => len + 1 - position.unsigned_abs() as usize,
+    }
+}
+
+/// Splits the `^` that anchors a pattern to the start of the subject from the rest.
+fn split_anchor(pattern: &[u8]) -> (bool, &[u8]) {
+    match pattern.strip_prefix(b"^") {
+        Some(pattern) => (true, pattern),
+        None => (false, pattern),
+    }
+}
+
+fn capture_value(state: &mut State, source: &[u8], capture: Capture) -> Value {
+    match capture {
+        Capture::Substring(range) => Value::String(state.heap.intern(&source[range])),
+        Capture::Position(offset) => Value::Int(offset as i64 + 1),
+    }
+}
+
+/// Pushes the values of captures, returning how many there are.
+fn push_captures(state: &mut State, source: &[u8], captures: Vec<Capture>) -> usize {
+    let count = captures.len();
+    for capture in captures {
+        let value = capture_value(state, source, capture);
+        state.push(value);
+    }
+    count
+}
+
+/// `string.len(s)`: returns the length of `s` in bytes.
+fn len(state: &mut State) -> Result<usize> {
+    let string = check_string(state, 1, "len")?;
+    state.push(state.heap[string].len() as i64);
+    Ok(1)
+}
+
+/// `string.sub(s, i [, j])`: returns the substring of `s` from position `i` to position `j`,
+/// which defaults to the end.
+fn sub(state: &mut State) -> Result<usize> {
+    let string = check_string(state, 1, "sub")?;
+    let len = state.heap[string].len();
+    let start = start_offset(check_integer(state, 2, "sub")?, len);
+    let end = end_offset(opt_integer(state, 3, "sub", -1)?, len);
+
+    let sub = match start < end {
+        true => state.heap[string][start..end].to_vec(),
+        false => Vec::new(),
+    };
+
+    let sub = state.heap.intern(&sub);
+    state.push(sub);
+    Ok(1)
+}
+
+/// `string.byte(s [, i [, j]])`: returns the bytes of `s` from position `i`, by default 1,
+/// to position `j`, by default `i`, as integers.
+fn byte(state: &mut State) -> Result<usize> {
+    let string = check_string(state, 1, "byte")?;
+    let len = state.heap[string].len();
+    let i = opt_integer(state, 2, "byte", 1)?;
+    let start = start_offset(i, len);
+    let end = end_offset(opt_integer(state, 3, "byte", i)?, len);
+
+    for offset in start..end {
+        state.push(state.heap[string][offset] as i64);
+    }
+    Ok(end.saturating_sub(start))
+}
+
+/// `string.char(...)`: returns the string whose bytes are the arguments.
+fn char(state: &mut State) -> Result<usize> {
+    let mut string = Vec::with_capacity(state.arg_count());
+    for position in 1..=state.arg_count() {
+        let c = check_integer(state, position, "char")?;
+        let c =
+            u8::try_from(c).map_err(|_| argument_error(position, "char", "value out of range"))?;
+        string.push(c);
+    }
+
+    let string = state.heap.intern(&string);
+    state.push(string);
+    Ok(1)
+}
+
+/// `string.rep(s, n [, sep])`: returns `n` copies of `s` separated by `sep`, by default the
+/// empty string.
+fn rep(state: &mut State) -> Result<usize> {
+    let string = check_bytes(state, 1, "rep")?;
+    let count = check_integer(state, 2, "rep")?;
+    let separator = match state.arg(3) {
+        Value::Nil => Vec::new(),
+        _ => check_bytes(state, 3, "rep")?,
+    };
+
+    // Repeating nothing many times takes no time.
+    let count = match string.is_empty() && separator.is_empty() {
+        true => 0,
+        false => count.max(0) as usize,
+    };
+
+    let size = count
+        .checked_mul(string.len() + separator.len())
+        .filter(|&size| size <= MAX_REP_SIZE)
+        .ok_or(Error::StringTooLarge)?;
+
+    let mut result = Vec::new();
+    result
+        .try_reserve_exact(size)
+        .map_err(|_| Error::StringTooLarge)?;
+    for i in 0..count {
+        if i > 0 {
+            result.extend_from_slice(&separator);
+        }
+        result.extend_from_slice(&string);
+    }
+
+    let result = state.heap.intern(&result);
+    state.push(result);
+    Ok(1)
+}
+
+/// `string.reverse(s)`: returns `s` with its bytes in reverse order.
+fn reverse(state: &mut State) -> Result<usize> {
+    let mut string = check_bytes(state, 1, "reverse")?;
+    string.reverse();
+    let string = state.heap.intern(&string);
+    state.push(string);
+    Ok(1)
+}
+
+/// `string.lower(s)`: returns `s` with its upper-case ASCII letters made lower case.
+fn lower(state: &mut State) -> Result<usize> {
+    let mut string = check_bytes(state, 1, "lower")?;
+    string.make_ascii_lowercase();
+    let string = state.heap.intern(&string);
+    state.push(string);
+    Ok(1)
+}
+
+/// `string.upper(s)`: returns `s` with its lower-case ASCII letters made upper case.
+fn upper(state: &mut State) -> Result<usize> {
+    let mut string = check_bytes(state, 1, "upper")?;
+    string.make_ascii_uppercase();
+    let string = state.heap.intern(&string);
+    state.push(string);
+    Ok(1)
+}
+
+/// `string.find(s, pattern [, init [, plain]])`: returns the start and end positions of the
+/// first match of `pattern` in `s` from position `init`, followed by its captures, or nil if
+/// there is none. With `plain` true, `pattern` is searched for as plain text.
+fn find(state: &mut State) -> Result<usize> {
+    find_or_match(state, "find")
+}
+
+/// `string.match(s, pattern [, init])`: returns the captures of the first match of
+/// `pattern` in `s` from position `init`, or the whole match if it has no captures, or nil if
+/// there is none.
+fn match_(state: &mut State) -> Result<usize> {
+    find_or_match(state, "match")
+}
+
+fn find_or_match(state: &mut State, function: &'static str) -> Result<usize> {
+    let find = function == "find";
+    let source = check_bytes(state, 1, function)?;
+    let pattern = check_bytes(state, 2, function)?;
+    let start = start_offset(opt_integer(state, 3, function, 1)?, source.len());
+
+    if start > source.len() {
+        state.push(Value::Nil);
+        return Ok(1);
+    }
+
+    if find && (state.arg(4).is_truthy() || pattern::is_plain(&pattern)) {
+        if let Some(offset) = pattern::find_plain(&source[start..], &pattern) {
+            let first = start + offset;
+            state.push(first as i64 + 1);
+            state.push((first + pattern.len()) as i64);
+            return Ok(2);
+        }
+    } else {
+        let (anchored, pattern) = split_anchor(&pattern);
+        let mut matcher = Matcher::new(&source, pattern);
+
+        for first in start..=source.len() {
+            if let Some(end) = matcher.match_at(first)? {
+                if !find {
+                    let captures = matcher.captures(Some(first..end))?;
+                    return Ok(push_captures(state, &source, captures));
+                }
+
+                state.push(first as i64 + 1);
+                state.push(end as i64);
+                let captures = matcher.captures(None)?;
+                return Ok(2 + push_captures(state, &source, captures));
+            }
+
+            if anchored {
+                break;
+            }
+        }
+    }
+
+    state.push(Value::Nil);
+    Ok(1)
+}
+
+/// `string.gmatch(s, pattern [, init])`: returns an iterator that returns the captures of
+/// each successive match of `pattern` in `s` from position `init`, or the whole match if it
+/// has no captures. An empty match directly after the previous match is skipped.
+fn gmatch(state: &mut State) -> Result<usize> {
+    let source = check_bytes(state, 1, "gmatch")?;
+    let pattern = check_bytes(state, 2, "gmatch")?;
+    let start = start_offset(opt_integer(state, 3, "gmatch", 1)?, source.len());
+
+    // The iterator owns copies of the strings, which are plain bytes the collector need not
+    // know about.
+    let next = Cell::new(start.min(source.len() + 1));
+    let last_match = Cell::new(None);
+
+    let iterator = state.heap.new_function(Function::native(move |state| {
+        let mut matcher = Matcher::new(&source, &pattern);
+
+        for first in next.get()..=source.len() {
+            match matcher.match_at(first)? {
+                Some(end) if Some(end) != last_match.get() => {
+                    next.set(end);
+                    last_match.set(Some(end));
+                    let captures = matcher.captures(Some(first..end))?;
+                    return Ok(push_captures(state, &source, captures));
+                }
+
+                _ => {}
+            }
+        }
+
+        Ok(0)
+    }));
+
+    state.push(iterator);
+    Ok(1)
+}
+
+/// `string.gsub(s, pattern, repl [, n])`: returns a copy of `s` in which the first `n`, or by
+/// default all, matches of `pattern` are replaced, followed by how many were.
+///
+/// A string `repl` is copied with `%1` to `%9` standing for captures, `%0` for the whole
+/// match and `%%` for `%`. A table is indexed with the first capture, and a function is called
+/// with all of them. If either gives false or nil, the match is kept as it was.
+fn gsub(state: &mut State) -> Result<usize> {
+    let source = check_bytes(state, 1, "gsub")?;
+    let pattern = check_bytes(state, 2, "gsub")?;
+
+    let replacement = match state.arg(3) {
+        Value::Int(_) | Value::Float(_) | Value::String(_) => {
+            Replacement::Text(check_bytes(state, 3, "gsub")?)
+        }
+        value @ Value::Table(_) => Replacement::Table(value),
+        value @ Value::Function(_) => Replacement::Function(value),
+        _ => return Err(type_error(state, 3, "gsub", "string/function/table")),
+    };
+
+    let max_count = opt_integer(state, 4, "gsub", source.len() as i64 + 1)?;
+    let (anchored, pattern) = split_anchor(&pattern);
+    let mut matcher = Matcher::new(&source, pattern);
+    let mut result = Vec::with_capacity(source.len());
+    let mut offset = 0;
+    let mut last_match = None;
+    let mut count = 0;
+
+    while count < max_count {
+        match matcher.match_at(offset)? {
+            Some(end) if Some(end) != last_match => {
+                count += 1;
+                replacement.append(state, &mut result, &matcher, &source, offset..end)?;
+                offset = end;
+                last_match = Some(end);
+            }
+
+            _ if offset < source.len() => {
+                result.push(source[offset]);
+                offset += 1;
+            }
+
+            _ => break,
+        }
+
+        if anchored {
+            break;
+        }
+    }
+
+    result.extend_from_slice(&source[offset..]);
+    let result = state.heap.intern(&result);
+    state.push(result);
+    state.push(count);
+    Ok(2)
+}
+
+enum Replacement {
+    Text(Vec<u8>),
+    Table(Value),
+    Function(Value),
+}
+
+impl Replacement {
+    /// Appends the replacement for the match of `whole` in `source` to `buffer`.
+    fn append(
+        &self,
+        state: &mut State,
+        buffer: &mut Vec<u8>,
+        matcher: &Matcher,
+        source: &[u8],
+        whole: Range<usize>,
+    ) -> Result<()> {
+        let value = match *self {
+            Self::Text(ref text) => return append_text(buffer, text, matcher, source, whole),
+
+            Self::Table(table) => {
+                let key = capture_value(state, source, matcher.capture(0, whole.clone())?);
+                state.index(table, key)?
+            }
+
+            Self::Function(function) => {
+                state.push(function);
+                let nargs = push_captures(state, source, matcher.captures(Some(whole.clone()))?);
+                state.call(nargs, Some(1))?;
+                state.pop()
+            }
+        };
+
+        match value {
+            Value::Nil | Value::Bool(false) => buffer.extend_from_slice(&source[whole]),
+
+            Value::String(_) | Value::Int(_) | Value::Float(_) => {
+                let string = state.tostring(value)?;
+                buffer.extend_from_slice(&state.heap[string]);
+            }
+
+            _ => return Err(Error::InvalidReplacement(value.type_name())),
+        }
+
+        Ok(())
+    }
+}
+
+/// Appends a replacement string to `buffer`, expanding its `%` escapes.
+fn append_text(
+    buffer: &mut Vec<u8>,
+    text: &[u8],
+    matcher: &Matcher,
+    source: &[u8],
+    whole: Range<usize>,
+) -> Result<()> {
+    let mut bytes = text.iter();
+    while let Some(&c) = bytes.next() {
+        if c != b'%' {
+            buffer.push(c);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'%') => buffer.push(b'%'),
+            Some(b'0') => buffer.extend_from_slice(&source[whole.clone()]),
+
+            Some(&digit) if digit.is_ascii_digit() => {
+                match matcher.capture((digit - b'1') as usize, whole.clone())? {
+                    Capture::Substring(range) => buffer.extend_from_slice(&source[range]),
+                    Capture::Position(offset) => number::write_int(buffer, offset as i64 + 1),
+                }
+            }
+
+            _ => return Err(Error::InvalidReplacementEscape),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            runtime::Function,
+            testing::{assert_fails, assert_runs},
+        },
+    };
+
+    /// Calls `function` with `args`, returning its results.
+    fn call(
+        state: &mut State,
+        function: fn(&mut State) -> Result<usize>,
+        args: &[Value],
+    ) -> Result<Vec<Value>> {
+        let base = state.top();
+        let function = state.heap.new_function(Function::native(function));
+        state.push(function);
+        for &arg in args {
+            state.push(arg);
+        }
+
+        state.call(args.len(), None)?;
+        let results = state.stack.split_off(base);
+        Ok(results)
+    }
+
+    fn string(state: &mut State, string: &str) -> Value {
+        Value::String(state.heap.intern(string.as_bytes()))
+    }
+
+    fn integers(values: &[Value]) -> Vec<i64> {
+        values
+            .iter()
+            .map(|value| match value {
+                Value::Int(n) => *n,
+                value => panic!("{value:?} is not an integer"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn byte_ends_at_start_by_default() {
+        let mut state = State::new();
+        let hello = string(&mut state, "hello");
+        let bytes = |state: &mut State, args: &[Value]| integers(&call(state, byte, args).unwrap());
+
+        assert_eq!(bytes(&mut state, &[hello]), [104]);
+        assert_eq!(bytes(&mut state, &[hello, Value::Int(-1)]), [111]);
+        assert_eq!(
+            bytes(&mut state, &[hello, Value::Int(2), Value::Int(4)]),
+            [101, 108, 108]
+        );
+        assert!(bytes(&mut state, &[hello, Value::Int(0)]).is_empty());
+        assert!(bytes(&mut state, &[hello, Value::Int(-10)]).is_empty());
+        assert_eq!(
+            bytes(&mut state, &[hello, Value::Int(-10), Value::Int(1)]),
+            [104]
+        );
+    }
+
+    #[test]
+    fn substrings_and_case() {
+        assert_runs(
+            "return ('hello'):sub(2, -2) .. ('hello'):sub(-3) .. ('hello'):sub(0) \
+             .. ('hello'):sub(10)",
+            "ellllohello",
+        );
+        assert_runs(
+            "return ('Hello'):upper() .. ('Hello'):lower() .. ('abc'):reverse() .. ('abc'):len()",
+            "HELLOhellocba3",
+        );
+        assert_runs(
+            "return string.rep('ab', 3, ',') .. string.rep('x', 0) .. string.rep('x', -1)",
+            "ab,ab,ab",
+        );
+        assert_runs("return #string.rep('', 1e10, '')", "0");
+    }
+
+    #[test]
+    fn rep_refuses_strings_too_large_to_build() {
+        assert_fails(
+            "local s = string.rep('x', 1e10)",
+            "test:1: resulting string too large",
+        );
+        assert_fails(
+            "local s = string.rep('x', 1 << 31)",
+            "test:1: resulting string too large",
+        );
+        assert_fails(
+            "local s = string.rep('xy', math.maxinteger)",
+            "test:1: resulting string too large",
+        );
+    }
+
+    #[test]
+    fn char_builds_strings_from_bytes() {
+        assert_runs("return string.char(72, 105)", "Hi");
+        assert_runs(
+            "return select(2, pcall(string.char, 256))",
+            "bad argument #1 to 'char' (value out of range)",
+        );
+    }
+
+    #[test]
+    fn strings_coerce_in_arithmetic() {
+        assert_runs(
+            "return ('10') + 1 .. ' ' .. '3' * '4' .. ' ' .. '0x10' + 0",
+            "11 12 16",
+        );
+        assert_runs(
+            "return math.type('10' + 0) .. math.type('1e1' + 0)",
+            "integerfloat",
+        );
+        assert_runs(
+            "return select(2, pcall(function() return 'a' + 1 end))",
+            "test:1: attempt to perform arithmetic on a string value",
+        );
+    }
+}
@@ -0,0 +1,543 @@
+//! `string.pack`, `string.unpack` and `string.packsize`, which convert values to and from
+//! binary data laid out as a format string describes.
+
+use {
+    super::{check_bytes, start_offset},
+    crate::runtime::{
+        lib::{argument_error, check_integer, check_number, opt_integer},
+        Error, Result, State, Value,
+    },
+};
+
+/// The largest size of an integer option.
+const MAX_INTEGER_SIZE: usize = 16;
+
+/// The size of a Lua integer.
+const INTEGER_SIZE: usize = 8;
+
+/// The alignment that `!` without a size sets, which is the largest any native type needs.
+const NATIVE_ALIGNMENT: usize = 8;
+
+/// The largest number a format can give as a size.
+const MAX_SIZE: usize = i32::MAX as usize;
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Kind {
+    Int,
+    Unsigned,
+    Float,
+    Double,
+    /// A fixed-size string.
+    Char,
+    /// A string preceded by its length.
+    String,
+    /// A string followed by a zero byte.
+    ZeroTerminated,
+    Padding,
+    /// Padding to the alignment of the following option.
+    Align,
+    /// An option that only changes how the rest are read.
+    Nop,
+}
+
+/// A format item: what it holds, its size, and the padding that aligns it.
+struct Item {
+    kind: Kind,
+    size: usize,
+    padding: usize,
+}
+
+/// Reads the items of a format, keeping track of the endianness and maximum alignment that
+/// options set along the way.
+struct Format<'a> {
+    rest: &'a [u8],
+    little_endian: bool,
+    max_alignment: usize,
+    function: &'static str,
+}
+
+impl<'a> Format<'a> {
+    fn new(format: &'a [u8], function: &'static str) -> Self {
+        Self {
+            rest: format,
+            little_endian: cfg!(target_endian = "little"),
+            max_alignment: 1,
+            function,
+        }
+    }
+
+    /// Reads the next item, given how many bytes come before it.
+    fn next_item(&mut self, offset: usize) -> Result<Option<Item>> {
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+
+        let (kind, size) = self.option()?;
+        let mut alignment = size;
+
+        if kind == Kind::Align {
+            alignment = match self.rest.is_empty() {
+                true => 0,
+                false => match self.option()? {
+                    (Kind::Char, _) => 0,
+                    (_, size) => size,
+                },
+            };
+
+            if alignment == 0 {
+                return Err(argument_error(
+                    1,
+                    self.function,
+                    "invalid next option for option 'X'",
+                ));
+            }
+        }
+
+        let padding = if alignment <= 1 || kind == Kind::Char {
+            0
+        } else {
+            let alignment = alignment.min(self.max_alignment);
+            if !alignment.is_power_of_two() {
+                return Err(argument_error(
+                    1,
+                    self.function,
+                    "format asks for alignment not power of 2",
+                ));
+            }
+
+            (alignment - (offset & (alignment - 1))) & (alignment - 1)
+        };
+
+        Ok(Some(Item {
+            kind,
+            size,
+            padding,
+        }))
+    }
+
+    fn option(&mut self) -> Result<(Kind, usize)> {
+        let (&option, rest) = self.rest.split_first().unwrap();
+        self.rest = rest;
+
+        Ok(match option {
+            b'b' => (Kind::Int, 1),
+            b'B' => (Kind::Unsigned, 1),
+            b'h' => (Kind::Int, 2),
+            b'H' => (Kind::Unsigned, 2),
+            b'i' => (Kind::Int, self.size_limit(4)?),
+            b'I' => (Kind::Unsigned, self.size_limit(4)?),
+            b'l' | b'j' => (Kind::Int, 8),
+            b'L' | b'J' | b'T' => (Kind::Unsigned, 8),
+            b'f' => (Kind::Float, 4),
+            b'n' | b'd' => (Kind::Double, 8),
+            b's' => (Kind::String, self.size_limit(8)?),
+
+            b'c' => match self.number() {
+                Some(size) => (Kind::Char, size),
+                None => return Err(Error::MissingCharSize),
+            },
+
+            b'z' => (Kind::ZeroTerminated, 0),
+            b'x' => (Kind::Padding, 1),
+            b'X' => (Kind::Align, 0),
+            b' ' => (Kind::Nop, 0),
+
+            b'<' | b'>' | b'=' => {
+                self.little_endian = match option {
+                    b'<' => true,
+                    b'>' => false,
+                    _ => cfg!(target_endian = "little"),
+                };
+                (Kind::Nop, 0)
+            }
+
+            b'!' => {
+                self.max_alignment = self.size_limit(NATIVE_ALIGNMENT)?;
+                (Kind::Nop, 0)
+            }
+
+            _ => return Err(Error::InvalidFormatOption(option as char)),
+        })
+    }
+
+    /// Reads the decimal number that follows an option, if there is one.
+    fn number(&mut self) -> Option<usize> {
+        if !self.rest.first().is_some_and(u8::is_ascii_digit) {
+            return None;
+        }
+
+        let mut number = 0;
+        while let Some((&c, rest)) = self.rest.split_first() {
+            if !c.is_ascii_digit() || number > (MAX_SIZE - 9) / 10 {
+                break;
+            }
+
+            number = number * 10 + (c - b'0') as usize;
+            self.rest = rest;
+        }
+
+        Some(number)
+    }
+
+    /// Reads the size that follows an integer option, or uses `default` if there is none.
+    fn size_limit(&mut self, default: usize) -> Result<usize> {
+        let size = self.number().unwrap_or(default);
+        if !(1..=MAX_INTEGER_SIZE).contains(&size) {
+            return Err(Error::IntegralSize(size));
+        }
+
+        Ok(size)
+    }
+}
+
+/// `string.pack(format, v1, v2, ...)`: returns the values packed into a binary string as
+/// `format` describes.
+pub(super) fn pack(state: &mut State) -> Result<usize> {
+    let format = check_bytes(state, 1, "pack")?;
+    let mut format = Format::new(&format, "pack");
+    let mut result = Vec::new();
+    let mut position = 1;
+
+    while let Some(item) = format.next_item(result.len())? {
+        result.resize(result.len() + item.padding, 0);
+        let little_endian = format.little_endian;
+
+        position += 1;
+        match item.kind {
+            Kind::Int => {
+                let n = check_integer(state, position, "pack")?;
+                if item.size < INTEGER_SIZE {
+                    let limit = 1 << (item.size * 8 - 1);
+                    if !(-limit..limit).contains(&n) {
+                        return Err(argument_error(position, "pack", "integer overflow"));
+                    }
+                }
+
+                pack_int(&mut result, n as u64, little_endian, item.size, n < 0);
+            }
+
+            Kind::Unsigned => {
+                let n = check_integer(state, position, "pack")?;
+                if item.size < INTEGER_SIZE && n as u64 >= 1 << (item.size * 8) {
+                    return Err(argument_error(position, "pack", "unsigned overflow"));
+                }
+
+                pack_int(&mut result, n as u64, little_endian, item.size, false);
+            }
+
+            Kind::Float => {
+                let n = check_number(state, position, "pack")? as f32;
+                match little_endian {
+                    true => result.extend_from_slice(&n.to_le_bytes()),
+                    false => result.extend_from_slice(&n.to_be_bytes()),
+                }
+            }
+
+            Kind::Double => {
+                let n = check_number(state, position, "pack")?;
+                match little_endian {
+                    true => result.extend_from_slice(&n.to_le_bytes()),
+                    false => result.extend_from_slice(&n.to_be_bytes()),
+                }
+            }
+
+            Kind::Char => {
+                let string = check_bytes(state, position, "pack")?;
+                if string.len() > item.size {
+                    return Err(argument_error(
+                        position,
+                        "pack",
+                        "string longer than given size",
+                    ));
+                }
+
+                result.extend_from_slice(&string);
+                result.resize(result.len() + item.size - string.len(), 0);
+            }
+
+            Kind::String => {
+                let string = check_bytes(state, position, "pack")?;
+                if item.size < INTEGER_SIZE && string.len() as u64 >= 1 << (item.size * 8) {
+                    return Err(argument_error(
+                        position,
+                        "pack",
+                        "string length does not fit in given size",
+                    ));
+                }
+
+                pack_int(
+                    &mut result,
+                    string.len() as u64,
+                    little_endian,
+                    item.size,
+                    false,
+                );
+                result.extend_from_slice(&string);
+            }
+
+            Kind::ZeroTerminated => {
+                let string = check_bytes(state, position, "pack")?;
+                if string.contains(&0) {
+                    return Err(argument_error(position, "pack", "string contains zeros"));
+                }
+
+                result.extend_from_slice(&string);
+                result.push(0);
+            }
+
+            Kind::Padding => {
+                result.push(0);
+                position -= 1;
+            }
+
+            Kind::Align | Kind::Nop => position -= 1,
+        }
+    }
+
+    let result = state.heap.intern(&result);
+    state.push(result);
+    Ok(1)
+}
+
+/// `string.packsize(format)`: returns the size of the string `string.pack` would produce for
+/// `format`, which must not have variable-length options.
+pub(super) fn packsize(state: &mut State) -> Result<usize> {
+    let format = check_bytes(state, 1, "packsize")?;
+    let mut format = Format::new(&format, "packsize");
+    let mut total = 0usize;
+
+    while let Some(item) = format.next_item(total)? {
+        if matches!(item.kind, Kind::String | Kind::ZeroTerminated) {
+            return Err(argument_error(1, "packsize", "variable-length format"));
+        }
+
+        total = total
+            .checked_add(item.padding + item.size)
+            .filter(|&total| total <= MAX_SIZE)
+            .ok_or_else(|| argument_error(1, "packsize", "format result too large"))?;
+    }
+
+    state.push(total as i64);
+    Ok(1)
+}
+
+/// `string.unpack(format, s [, pos])`: returns the values packed in `s` from position `pos`
+/// as `format` describes, followed by the position after the last of them.
+pub(super) fn unpack(state: &mut State) -> Result<usize> {
+    let format = check_bytes(state, 1, "unpack")?;
+    let data = check_bytes(state, 2, "unpack")?;
+    let mut offset = start_offset(opt_integer(state, 3, "unpack", 1)?, data.len());
+    if offset > data.len() {
+        return Err(argument_error(
+            3,
+            "unpack",
+            "initial position out of string",
+        ));
+    }
+
+    let too_short = || argument_error(2, "unpack", "data string too short");
+    let mut format = Format::new(&format, "unpack");
+    let mut count = 0;
+
+    while let Some(item) = format.next_item(offset)? {
+        if item.padding + item.size > data.len() - offset {
+            return Err(too_short());
+        }
+
+        offset += item.padding;
+        let bytes = &data[offset..offset + item.size];
+        let little_endian = format.little_endian;
+
+        let value = match item.kind {
+            Kind::Int | Kind::Unsigned => {
+                let n = unpack_int(bytes, little_endian, item.kind == Kind::Int)?;
+                Value::Int(n)
+            }
+
+            Kind::Float => {
+                let bytes = bytes.try_into().unwrap();
+                Value::Float(match little_endian {
+                    true => f32::from_le_bytes(bytes),
+                    false => f32::from_be_bytes(bytes),
+                } as f64)
+            }
+
+            Kind::Double => {
+                let bytes = bytes.try_into().unwrap();
+                Value::Float(match little_endian {
+                    true => f64::from_le_bytes(bytes),
+                    false => f64::from_be_bytes(bytes),
+                })
+            }
+
+            Kind::Char => Value::String(state.heap.intern(bytes)),
+
+            Kind::String => {
+                let length = unpack_int(bytes, little_endian, false)? as u64 as usize;
+                if length > data.len() - offset - item.size {
+                    return Err(too_short());
+                }
+
+                let start = offset + item.size;
+                offset += length;
+                Value::String(state.heap.intern(&data[start..start + length]))
+            }
+
+            Kind::ZeroTerminated => {
+                let Some(length) = data[offset..].iter().position(|&c| c == 0) else {
+                    return Err(argument_error(
+                        2,
+                        "unpack",
+                        "unfinished string for format 'z'",
+                    ));
+                };
+
+                let string = state.heap.intern(&data[offset..offset + length]);
+                offset += length + 1;
+                Value::String(string)
+            }
+
+            Kind::Padding | Kind::Align | Kind::Nop => {
+                offset += item.size;
+                continue;
+            }
+        };
+
+        state.push(value);
+        count += 1;
+        offset += item.size;
+    }
+
+    state.push(offset as i64 + 1);
+    Ok(count + 1)
+}
+
+/// Appends the `size` lowest bytes of `n`, extended with ones if it is `negative` and wider
+/// than a Lua integer.
+fn pack_int(buffer: &mut Vec<u8>, n: u64, little_endian: bool, size: usize, negative: bool) {
+    let start = buffer.len();
+    buffer.extend((0..size).map(|i| match i {
+        0..=7 => (n >> (8 * i)) as u8,
+        _ if negative => 0xff,
+        _ => 0,
+    }));
+
+    if !little_endian {
+        buffer[start..].reverse();
+    }
+}
+
+/// Reads an integer of any size, which must fit in a Lua integer.
+fn unpack_int(bytes: &[u8], little_endian: bool, signed: bool) -> Result<i64> {
+    let size = bytes.len();
+    let byte = |i: usize| match little_endian {
+        true => bytes[i],
+        false => bytes[size - 1 - i],
+    };
+
+    let limit = size.min(INTEGER_SIZE);
+    let mut n = (0..limit).rev().fold(0u64, |n, i| n << 8 | byte(i) as u64);
+
+    if size < INTEGER_SIZE {
+        if signed {
+            let sign = 1 << (size * 8 - 1);
+            n = (n ^ sign).wrapping_sub(sign);
+        }
+    } else {
+        // The bytes beyond a Lua integer may only extend its sign.
+        let extension = if signed && (n as i64) < 0 { 0xff } else { 0 };
+        if (limit..size).any(|i| byte(i) != extension) {
+            return Err(Error::IntegerTooLarge(size));
+        }
+    }
+
+    Ok(n as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::assert_runs};
+
+    #[test]
+    fn integers_of_any_size_round_trip() {
+        for (n, size) in [(-5i64, 3), (258, 2), (-1, 9), (i64::MIN, 8), (1, 16)] {
+            for little_endian in [true, false] {
+                let mut buffer = Vec::new();
+                pack_int(&mut buffer, n as u64, little_endian, size, n < 0);
+                assert_eq!(buffer.len(), size);
+                assert_eq!(unpack_int(&buffer, little_endian, true).unwrap(), n);
+            }
+        }
+
+        let mut buffer = Vec::new();
+        pack_int(&mut buffer, 258, false, 2, false);
+        assert_eq!(buffer, [1, 2]);
+    }
+
+    #[test]
+    fn wide_integers_must_fit() {
+        assert_eq!(unpack_int(&[0xff; 9], true, true).unwrap(), -1);
+        let mut bytes = [0; 9];
+        bytes[8] = 1;
+        assert!(matches!(
+            unpack_int(&bytes, true, false),
+            Err(Error::IntegerTooLarge(9))
+        ));
+        assert_eq!(unpack_int(&[0xff, 0xff], true, false).unwrap(), 0xffff);
+    }
+
+    #[test]
+    fn pack_and_unpack() {
+        assert_runs(
+            "return #string.pack('i4', 7) .. ' ' .. string.unpack('<i4', string.pack('<i4', -2))",
+            "4 -2",
+        );
+        assert_runs(
+            "return string.unpack('>I2', '\\1\\2') .. ' ' .. string.unpack('<I2', '\\1\\2')",
+            "258 513",
+        );
+        assert_runs(
+            "local s, next = string.unpack('z', 'hello\\0rest') return s .. next",
+            "hello7",
+        );
+        assert_runs(
+            "local s, next = string.unpack('s1', string.pack('s1', 'abc')) return s .. next",
+            "abc5",
+        );
+        assert_runs("return (string.unpack('d', string.pack('d', 1.5)))", "1.5");
+        assert_runs(
+            "return string.unpack('j', string.pack('j', math.maxinteger)) == math.maxinteger",
+            "true",
+        );
+        assert_runs("return select('#', string.unpack('bbb', 'abc'))", "4");
+    }
+
+    #[test]
+    fn sizes_and_alignment() {
+        assert_runs(
+            "return string.packsize('i4i8') .. ' ' .. string.packsize('!8i4i8') \
+             .. ' ' .. string.packsize('bhd')",
+            "12 16 11",
+        );
+        assert_runs(
+            "return select(2, pcall(string.packsize, 's'))",
+            "bad argument #1 to 'packsize' (variable-length format)",
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_runs(
+            "return select(2, pcall(string.pack, 'i1', 200))",
+            "bad argument #2 to 'pack' (integer overflow)",
+        );
+        assert_runs(
+            "return select(2, pcall(string.pack, 'i17', 1))",
+            "integral size (17) out of limits [1,16]",
+        );
+        assert_runs(
+            "return select(2, pcall(string.unpack, 'i4', 'ab'))",
+            "bad argument #2 to 'unpack' (data string too short)",
+        );
+    }
+}
@@ -0,0 +1,576 @@
+//! The `table` library.
+//!
+//! Like the reference implementation, these functions read and write elements through
+//! `__index` and `__newindex` and take lengths from `__len`, so they also work on proxies.
+
+use {
+    super::{
+        argument_error, check_integer, opt_integer, opt_string, set_function, set_library,
+        type_error,
+    },
+    crate::{
+        number,
+        runtime::{value::float_to_int, Error, Metamethod, Result, State, Value},
+    },
+    std::time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The most values `unpack` returns at once.
+const MAX_RESULTS: u64 = 1_000_000;
+
+/// The longest array `sort` sorts.
+const MAX_SORT_LENGTH: i64 = i32::MAX as i64;
+
+/// Below this length, `sort` always takes the middle element as its pivot.
+const RANDOM_PIVOT_LIMIT: i64 = 100;
+
+pub fn open_table(state: &mut State) {
+    let table = state.heap.new_table();
+    set_function(state, table, "concat", concat);
+    set_function(state, table, "insert", insert);
+    set_function(state, table, "move", move_);
+    set_function(state, table, "pack", pack);
+    set_function(state, table, "remove", remove);
+    set_function(state, table, "sort", sort);
+    set_function(state, table, "unpack", unpack);
+
+    set_library(state, "table", table);
+}
+
+/// The `position`th argument, which must be a table or have a metatable with all of `events`.
+fn check_table_like(
+    state: &State,
+    position: usize,
+    function: &'static str,
+    events: &[Metamethod],
+) -> Result<Value> {
+    let value = state.arg(position);
+    let supported = matches!(value, Value::Table(_))
+        || state.heap.metatable_of(value).is_some()
+            && events
+                .iter()
+                .all(|&event| !state.heap.metamethod_of(value, event).is_nil());
+
+    match supported {
+        true => Ok(value),
+        false => Err(type_error(state, position, function, "table")),
+    }
+}
+
+/// The length of `value` as the `#` operator gives it, which must be an integer.
+fn length(state: &mut State, value: Value) -> Result<i64> {
+    let length = state.len(value)?;
+    match state.to_number(length) {
+        Some(Value::Int(length)) => Ok(length),
+        Some(Value::Float(length)) => float_to_int(length).ok_or(Error::LengthNotInteger),
+        _ => Err(Error::LengthNotInteger),
+    }
+}
+
+/// `table.insert(list, [pos,] value)`: inserts `value` at `pos`, shifting up the elements
+/// from there on. Without `pos`, `value` is appended.
+fn insert(state: &mut State) -> Result<usize> {
+    use Metamethod::*;
+    let list = check_table_like(state, 1, "insert", &[Index, Newindex, Len])?;
+    let end = length(state, list)?.wrapping_add(1);
+
+    let position = match state.arg_count() {
+        2 => end,
+
+        3 => {
+            let position = check_integer(state, 2, "insert")?;
+            if (position as u64).wrapping_sub(1) >= end as u64 {
+                return Err(argument_error(2, "insert", "position out of bounds"));
+            }
+
+            for i in (position + 1..=end).rev() {
+                let value = state.index(list, Value::Int(i - 1))?;
+                state.set_index(list, Value::Int(i), value)?;
+            }
+
+            position
+        }
+
+        _ => return Err(Error::WrongArgumentCountTo("insert")),
+    };
+
+    let value = state.arg(state.arg_count());
+    state.set_index(list, Value::Int(position), value)?;
+    Ok(0)
+}
+
+/// `table.remove(list [, pos])`: removes and returns the element at `pos`, shifting down the
+/// elements after it. `pos` defaults to the last element.
+fn remove(state: &mut State) -> Result<usize> {
+    use Metamethod::*;
+    let list = check_table_like(state, 1, "remove", &[Index, Newindex, Len])?;
+    let size = length(state, list)?;
+    let mut position = opt_integer(state, 2, "remove", size)?;
+
+    // The position just after the end may be removed too, which only returns nil.
+    if position != size && (position as u64).wrapping_sub(1) > size as u64 {
+        return Err(argument_error(2, "remove", "position out of bounds"));
+    }
+
+    let removed = state.index(list, Value::Int(position))?;
+    state.push(removed);
+
+    while position < size {
+        let value = state.index(list, Value::Int(position + 1))?;
+        state.set_index(list, Value::Int(position), value)?;
+        position += 1;
+    }
+
+    state.set_index(list, Value::Int(position), Value::Nil)?;
+    Ok(1)
+}
+
+/// `table.move(a1, f, e, t [, a2])`: copies `a1[f..=e]` to `a2[t..]`, in whichever order
+/// keeps overlapping ranges intact, and returns `a2`, which defaults to `a1`.
+fn move_(state: &mut State) -> Result<usize> {
+    let first = check_integer(state, 2, "move")?;
+    let end = check_integer(state, 3, "move")?;
+    let target = check_integer(state, 4, "move")?;
+    let destination_position = if state.arg(5).is_nil() { 1 } else { 5 };
+
+    let source = check_table_like(state, 1, "move", &[Metamethod::Index])?;
+    let destination =
+        check_table_like(state, destination_position, "move", &[Metamethod::Newindex])?;
+
+    if end >= first {
+        if first <= 0 && end >= i64::MAX + first {
+            return Err(argument_error(3, "move", "too many elements to move"));
+        }
+
+        let count = end - first;
+        if target > i64::MAX - count {
+            return Err(argument_error(4, "move", "destination wrap around"));
+        }
+
+        let forward = target > end
+            || target <= first
+            || destination_position != 1 && !state.equals(source, destination)?;
+
+        let copy = |state: &mut State, i: i64| {
+            let value = state.index(source, Value::Int(first + i))?;
+            state.set_index(destination, Value::Int(target + i), value)
+        };
+
+        if forward {
+            (0..=count).try_for_each(|i| copy(state, i))?;
+        } else {
+            (0..=count).rev().try_for_each(|i| copy(state, i))?;
+        }
+    }
+
+    state.push(destination);
+    Ok(1)
+}
+
+/// `table.concat(list [, sep [, i [, j]]])`: joins the strings or numbers `list[i..=j]` with
+/// `sep` between them. `i` defaults to 1 and `j` to the length of the list.
+fn concat(state: &mut State) -> Result<usize> {
+    let list = check_table_like(state, 1, "concat", &[Metamethod::Index, Metamethod::Len])?;
+    let separator = opt_string(state, 2, "concat", "")?;
+    let first = opt_integer(state, 3, "concat", 1)?;
+    let last = match state.arg(4) {
+        Value::Nil => length(state, list)?,
+        _ => check_integer(state, 4, "concat")?,
+    };
+
+    let mut buffer = Vec::new();
+    let mut i = first;
+    while i <= last {
+        match state.index(list, Value::Int(i))? {
+            Value::String(string) => buffer.extend_from_slice(&state.heap[string]),
+            Value::Int(n) => number::write_int(&mut buffer, n),
+            Value::Float(n) => number::write_float(&mut buffer, n),
+            _ => return Err(Error::InvalidConcatValue(i)),
+        }
+
+        if i == last {
+            break;
+        }
+
+        buffer.extend_from_slice(&separator);
+        i += 1;
+    }
+
+    let result = state.heap.intern(&buffer);
+    state.push(result);
+    Ok(1)
+}
+
+/// `table.pack(...)`: returns a new table holding the arguments, with their count in `n`.
+fn pack(state: &mut State) -> Result<usize> {
+    let count = state.arg_count();
+    let table = state.heap.new_table_with_capacity(count, 1);
+    state.push(table);
+
+    for n in 1..=count {
+        let value = state.arg(n);
+        state.heap[table].set_int(n as i64, value);
+    }
+
+    let n = state.heap.intern(b"n");
+    state.heap[table].set_str(n, Value::Int(count as i64));
+    Ok(1)
+}
+
+/// `table.unpack(list [, i [, j]])`: returns `list[i..=j]`, where `i` defaults to 1 and `j`
+/// to the length of the list.
+fn unpack(state: &mut State) -> Result<usize> {
+    let list = state.arg(1);
+    let first = opt_integer(state, 2, "unpack", 1)?;
+    let last = match state.arg(3) {
+        Value::Nil => length(state, list)?,
+        _ => check_integer(state, 3, "unpack")?,
+    };
+
+    if first > last {
+        return Ok(0);
+    }
+
+    let count = (last as u64).wrapping_sub(first as u64);
+    if count >= MAX_RESULTS {
+        return Err(Error::TooManyResults);
+    }
+
+    for i in first..=last {
+        let value = state.index(list, Value::Int(i))?;
+        state.push(value);
+    }
+
+    Ok(count as usize + 1)
+}
+
+/// `table.sort(list [, comp])`: sorts `list[1..=#list]` in place with `comp` as the less-than
+/// function, or `<` without one. The sort is not stable.
+fn sort(state: &mut State) -> Result<usize> {
+    use Metamethod::*;
+    let list = check_table_like(state, 1, "sort", &[Index, Newindex, Len])?;
+    let length = length(state, list)?;
+    if length <= 1 {
+        return Ok(0);
+    }
+
+    if length >= MAX_SORT_LENGTH {
+        return Err(argument_error(1, "sort", "array too big"));
+    }
+
+    let comparator = match state.arg(2) {
+        comparator @ (Value::Nil | Value::Function(_)) => comparator,
+        _ => return Err(type_error(state, 2, "sort", "function")),
+    };
+
+    let mut sort = Sort {
+        list,
+        comparator,
+        seed: 0,
+    };
+
+    sort.sort(state, 1, length)?;
+    Ok(0)
+}
+
+/// The quicksort of the reference implementation, which an order function that is not
+/// consistent cannot send out of bounds: the pivot stays where the scans stop at it, and a
+/// scan that passes it instead fails with [`Error::InvalidOrder`].
+///
+/// Every element read is kept on the stack while it is in use, where the collector can see it
+/// even if the order function removes it from the list.
+struct Sort {
+    list: Value,
+    comparator: Value,
+
+    /// The seed that picks pivots at random, or zero to take the middle element. It is only
+    /// set once a partition turns out badly unbalanced.
+    seed: u64,
+}
+
+impl Sort {
+    /// Sorts `list[lo..=up]`.
+    fn sort(&mut self, state: &mut State, mut lo: i64, mut up: i64) -> Result<()> {
+        while lo < up {
+            // The first, middle and last elements are sorted among themselves, and the middle
+            // one becomes the pivot.
+            let a = self.push(state, lo)?;
+            let b = self.push(state, up)?;
+            if self.less(state, b, a)? {
+                self.set2(state, lo, up)?;
+            } else {
+                state.set_top(state.top() - 2);
+            }
+
+            if up - lo == 1 {
+                break;
+            }
+
+            let p = if up - lo < RANDOM_PIVOT_LIMIT || self.seed == 0 {
+                (lo + up) / 2
+            } else {
+                let quarter = (up - lo) / 4;
+                (self.seed % (quarter as u64 * 2)) as i64 + lo + quarter
+            };
+
+            let a = self.push(state, p)?;
+            let b = self.push(state, lo)?;
+            if self.less(state, a, b)? {
+                self.set2(state, p, lo)?;
+            } else {
+                state.pop();
+                let c = self.push(state, up)?;
+                if self.less(state, c, a)? {
+                    self.set2(state, p, up)?;
+                } else {
+                    state.set_top(state.top() - 2);
+                }
+            }
+
+            if up - lo == 2 {
+                break;
+            }
+
+            // The pivot is swapped next to the end, where it bounds the scan from below.
+            let pivot = self.push(state, p)?;
+            state.push(pivot);
+            self.push(state, up - 1)?;
+            self.set2(state, p, up - 1)?;
+
+            let p = self.partition(state, lo, up, pivot)?;
+
+            // The smaller side is sorted first, so that the recursion stays shallow.
+            let smaller = if p - lo < up - p {
+                self.sort(state, lo, p - 1)?;
+                let smaller = p - lo;
+                lo = p + 1;
+                smaller
+            } else {
+                self.sort(state, p + 1, up)?;
+                let smaller = up - p;
+                up = p - 1;
+                smaller
+            };
+
+            if (up - lo) / 128 > smaller {
+                self.seed = random_seed();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Partitions `list[lo..=up]` around `pivot`, which is at `up - 1` and on top of the stack,
+    /// and returns its final position.
+    fn partition(&self, state: &mut State, lo: i64, up: i64, pivot: Value) -> Result<i64> {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            loop {
+                i += 1;
+                let a = self.push(state, i)?;
+                if !self.less(state, a, pivot)? {
+                    break;
+                }
+
+                if i == up - 1 {
+                    return Err(Error::InvalidOrder);
+                }
+                state.pop();
+            }
+
+            loop {
+                j -= 1;
+                let b = self.push(state, j)?;
+                if !self.less(state, pivot, b)? {
+                    break;
+                }
+
+                if j < i {
+                    return Err(Error::InvalidOrder);
+                }
+                state.pop();
+            }
+
+            if j < i {
+                state.pop();
+                self.set2(state, up - 1, i)?;
+                return Ok(i);
+            }
+
+            self.set2(state, i, j)?;
+        }
+    }
+
+    /// Pushes `list[i]`, returning it.
+    fn push(&self, state: &mut State, i: i64) -> Result<Value> {
+        let value = state.index(self.list, Value::Int(i))?;
+        state.push(value);
+        Ok(value)
+    }
+
+    /// Pops the top two values, storing the top one in `list[i]` and the other in `list[j]`.
+    fn set2(&self, state: &mut State, i: i64, j: i64) -> Result<()> {
+        let value = state.pop();
+        state.set_index(self.list, Value::Int(i), value)?;
+        let value = state.pop();
+        state.set_index(self.list, Value::Int(j), value)
+    }
+
+    fn less(&self, state: &mut State, a: Value, b: Value) -> Result<bool> {
+        if self.comparator.is_nil() {
+            return state.less_than(a, b);
+        }
+
+        state.push(self.comparator);
+        state.push(a);
+        state.push(b);
+        state.call(2, Some(1))?;
+        Ok(state.pop().is_truthy())
+    }
+}
+
+/// A seed for picking pivots that an adversary cannot predict from the list alone.
+fn random_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() ^ now.subsec_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::assert_runs;
+
+    #[test]
+    fn insert_and_remove() {
+        assert_runs(
+            "local t = {1, 2, 3} table.insert(t, 4) table.insert(t, 1, 0) \
+             return table.concat(t, ',')",
+            "0,1,2,3,4",
+        );
+        assert_runs(
+            "local t = {1, 2, 3} return table.remove(t) .. table.remove(t, 1) .. #t .. t[1]",
+            "3112",
+        );
+        assert_runs(
+            "local t = {} return tostring(table.remove(t)) .. tostring(table.remove(t, 0)) .. #t",
+            "nilnil0",
+        );
+        assert_runs(
+            "return select(2, pcall(table.insert, {1}, 5, 2))",
+            "bad argument #2 to 'insert' (position out of bounds)",
+        );
+        assert_runs(
+            "return select(2, pcall(table.insert, {1}, 1, 2, 3))",
+            "wrong number of arguments to 'insert'",
+        );
+        assert_runs(
+            "return select(2, pcall(table.remove, {1, 2}, 5))",
+            "bad argument #2 to 'remove' (position out of bounds)",
+        );
+    }
+
+    #[test]
+    fn concat_pack_and_unpack() {
+        assert_runs(
+            "return table.concat({1, 2.5, 'x'}, ', ', 2, 3) .. '|' .. table.concat({}, 'x') \
+             .. '|' .. table.concat({1, 2, 3}, '', 3, 2)",
+            "2.5, x||",
+        );
+        assert_runs(
+            "return select(2, pcall(table.concat, {1, {}, 3}))",
+            "invalid value (at index 2) in table for 'concat'",
+        );
+        assert_runs(
+            "local t = table.pack(1, nil, 3) return t.n .. t[1] .. tostring(t[2]) .. t[3]",
+            "31nil3",
+        );
+        assert_runs(
+            "return select('#', table.unpack({1, 2, 3}, 2)) .. select('#', table.unpack({}, 1, 0)) \
+             .. select('#', table.unpack({1, 2, 3}, -1, 1))",
+            "203",
+        );
+        assert_runs(
+            "return select(2, pcall(table.unpack, {}, 1, 1e7))",
+            "too many results to unpack",
+        );
+    }
+
+    #[test]
+    fn move_handles_overlaps() {
+        assert_runs(
+            "return table.concat(table.move({1, 2, 3}, 1, 3, 2), ',') .. ' ' \
+             .. table.concat(table.move({1, 2, 3}, 2, 3, 1), ',') .. ' ' \
+             .. table.concat(table.move({1, 2, 3}, 1, 3, 1, {}), ',')",
+            "1,1,2,3 2,3,3 1,2,3",
+        );
+        assert_runs(
+            "return select(2, pcall(table.move, {}, 1, math.maxinteger, 2))",
+            "bad argument #4 to 'move' (destination wrap around)",
+        );
+    }
+
+    #[test]
+    fn sort_orders_by_less_than_or_a_comparator() {
+        assert_runs(
+            "local t = {5, 2, 8, 1, 9, 3} table.sort(t) return table.concat(t, ',')",
+            "1,2,3,5,8,9",
+        );
+        assert_runs(
+            "local t = {5, 2, 8, 1, 9, 3} table.sort(t, function(a, b) return a > b end) \
+             return table.concat(t, ',')",
+            "9,8,5,3,2,1",
+        );
+        assert_runs(
+            "local t = {} for i = 1, 500 do t[i] = (i * 7919) % 1009 end table.sort(t) \
+             for i = 2, #t do if t[i - 1] > t[i] then return i end end return 'sorted'",
+            "sorted",
+        );
+        assert_runs(
+            "return select(2, pcall(table.sort, {1, 'x', 2}))",
+            "attempt to compare string with number",
+        );
+        assert_runs(
+            "return select(2, pcall(table.sort, {1, 2}, 3))",
+            "bad argument #2 to 'sort' (function expected, got number)",
+        );
+    }
+
+    #[test]
+    fn sort_detects_invalid_order_functions() {
+        assert_runs(
+            "local t = {} for i = 1, 100 do t[i] = i % 10 end \
+             return select(2, pcall(function() table.sort(t, function() return true end) end))",
+            "test:1: invalid order function for sorting",
+        );
+    }
+
+    #[test]
+    fn functions_go_through_metamethods() {
+        assert_runs(
+            "local p = setmetatable({}, {__len = function() return 2 end, \
+               __index = function(_, k) return k * 10 end}) \
+             return table.concat(p, ',') .. '|' .. table.unpack(p, 2)",
+            "10,20|20",
+        );
+        assert_runs(
+            "local log = {} \
+             local p = setmetatable({}, {__newindex = function(t, k, v) \
+               log[#log + 1] = k rawset(t, k, v) end}) \
+             table.insert(p, 'a') table.insert(p, 'b') return table.concat(log, ',')",
+            "1,2",
+        );
+        assert_runs(
+            "local data = {30, 10, 20} \
+             local p = setmetatable({}, {__index = data, __newindex = data, \
+               __len = function() return #data end}) \
+             table.sort(p) return table.concat(data, ',')",
+            "10,20,30",
+        );
+        assert_runs(
+            "return select(2, pcall(table.insert, setmetatable({}, \
+               {__len = function() return 'x' end}), 1))",
+            "object length is not an integer",
+        );
+    }
+}
@@ -0,0 +1,354 @@
+//! The `utf8` library.
+//!
+//! Functions that decode take an optional `lax` flag, which lets them accept any sequence up
+//! to the six bytes that encode 2^31 - 1 instead of only the code points Unicode allows.
+
+use {
+    super::{
+        argument_error, check_bytes, check_integer, check_string, opt_integer, set_function,
+        set_library,
+    },
+    crate::{
+        runtime::{Error, Function, NativeFunction, Result, State, Value},
+        utf8::{self, MAX_CODE_POINT},
+    },
+    std::rc::Rc,
+};
+
+/// A pattern that matches exactly one sequence, assuming the subject is valid UTF-8.
+const CHAR_PATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
+
+/// The most code points `codepoint` returns at once.
+const MAX_RESULTS: i64 = i32::MAX as i64;
+
+pub fn open_utf8(state: &mut State) {
+    let table = state.heap.new_table();
+    set_function(state, table, "char", char);
+    set_function(state, table, "codepoint", codepoint);
+    set_function(state, table, "len", len);
+    set_function(state, table, "offset", offset);
+
+    // `codes` returns one of two iterators, which it keeps as upvalues.
+    let strict = state
+        .heap
+        .new_function(Function::native(|state| iterate(state, true)));
+    let lax = state
+        .heap
+        .new_function(Function::native(|state| iterate(state, false)));
+    let codes = state.heap.new_function(Function::Native(NativeFunction {
+        callback: Rc::new(codes),
+        upvalues: Box::new([Value::Function(strict), Value::Function(lax)]),
+    }));
+    let name = state.heap.intern(b"codes");
+    state.heap[table].set_str(name, Value::Function(codes));
+
+    let name = state.heap.intern(b"charpattern");
+    let pattern = state.heap.intern(CHAR_PATTERN);
+    state.heap[table].set_str(name, Value::String(pattern));
+
+    set_library(state, "utf8", table);
+}
+
+/// Converts a position that counts from 1 or, if negative, back from the end, to one that
+/// counts from 1. Positions before the start become 0.
+fn absolute_position(position: i64, len: usize) -> i64 {
+    match position {
+        0.. => position,
+        _ if position.unsigned_abs() > len as u64 => 0,
+        _ => len as i64 + position + 1,
+    }
+}
+
+/// `utf8.char(...)`: the string of the encodings of the integer arguments.
+fn char(state: &mut State) -> Result<usize> {
+    let mut buffer = Vec::new();
+    for position in 1..=state.arg_count() {
+        let code = check_integer(state, position, "char")?;
+        if code as u64 > MAX_CODE_POINT as u64 {
+            return Err(argument_error(position, "char", "value out of range"));
+        }
+
+        utf8::encode(&mut buffer, code as u32);
+    }
+
+    let result = state.heap.intern(&buffer);
+    state.push(result);
+    Ok(1)
+}
+
+/// `utf8.codepoint(s [, i [, j [, lax]]])`: the code points of the sequences that start
+/// between byte positions `i` and `j`, which default to 1 and `i`.
+fn codepoint(state: &mut State) -> Result<usize> {
+    let bytes = check_bytes(state, 1, "codepoint")?;
+    let start = absolute_position(opt_integer(state, 2, "codepoint", 1)?, bytes.len());
+    let end = absolute_position(opt_integer(state, 3, "codepoint", start)?, bytes.len());
+    let strict = !state.arg(4).is_truthy();
+
+    if start < 1 {
+        return Err(argument_error(2, "codepoint", "out of bounds"));
+    }
+
+    if end > bytes.len() as i64 {
+        return Err(argument_error(3, "codepoint", "out of bounds"));
+    }
+
+    if start > end {
+        return Ok(0);
+    }
+
+    if end - start >= MAX_RESULTS {
+        return Err(Error::StringSliceTooLong);
+    }
+
+    let mut offset = start as usize - 1;
+    let mut count = 0;
+    while offset < end as usize {
+        let (code, len) = utf8::decode(&bytes[offset..], strict).ok_or(Error::InvalidUtf8)?;
+        state.push(code as i64);
+        offset += len;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// `utf8.len(s [, i [, j [, lax]]])`: the number of sequences that start between byte
+/// positions `i` and `j`, which default to 1 and -1. If a sequence is invalid, returns nil and
+/// its position instead.
+fn len(state: &mut State) -> Result<usize> {
+    let bytes = check_bytes(state, 1, "len")?;
+    let start = absolute_position(opt_integer(state, 2, "len", 1)?, bytes.len());
+    let end = absolute_position(opt_integer(state, 3, "len", -1)?, bytes.len());
+    let strict = !state.arg(4).is_truthy();
+
+    if !(1..=bytes.len() as i64 + 1).contains(&start) {
+        return Err(argument_error(2, "len", "initial position out of bounds"));
+    }
+
+    if end > bytes.len() as i64 {
+        return Err(argument_error(3, "len", "final position out of bounds"));
+    }
+
+    let mut offset = start as usize - 1;
+    let mut count = 0;
+    while (offset as i64) < end {
+        match utf8::decode(&bytes[offset..], strict) {
+            Some((_, len)) => offset += len,
+
+            None => {
+                state.push(Value::Nil);
+                state.push(offset as i64 + 1);
+                return Ok(2);
+            }
+        }
+
+        count += 1;
+    }
+
+    state.push(count);
+    Ok(1)
+}
+
+/// `utf8.offset(s, n [, i])`: the byte position where the `n`th sequence counting from the
+/// one at position `i` starts, or nil if there is none. Negative `n` counts back from `i`,
+/// and zero finds the start of the sequence that holds byte `i`. `i` defaults to 1 for
+/// positive `n` and to just past the end of `s` otherwise.
+fn offset(state: &mut State) -> Result<usize> {
+    let bytes = check_bytes(state, 1, "offset")?;
+    let mut n = check_integer(state, 2, "offset")?;
+    let default = if n >= 0 { 1 } else { bytes.len() as i64 + 1 };
+    let position = absolute_position(opt_integer(state, 3, "offset", default)?, bytes.len());
+
+    if !(1..=bytes.len() as i64 + 1).contains(&position) {
+        return Err(argument_error(3, "offset", "position out of bounds"));
+    }
+
+    // The byte past the end acts as the terminator that starts no sequence.
+    let is_continuation =
+        |offset: usize| bytes.get(offset).is_some_and(|&b| utf8::is_continuation(b));
+    let mut offset = position as usize - 1;
+
+    if n == 0 {
+        while offset > 0 && is_continuation(offset) {
+            offset -= 1;
+        }
+    } else {
+        if is_continuation(offset) {
+            return Err(Error::ContinuationByte);
+        }
+
+        if n < 0 {
+            while n < 0 && offset > 0 {
+                offset -= 1;
+                while offset > 0 && is_continuation(offset) {
+                    offset -= 1;
+                }
+                n += 1;
+            }
+        } else {
+            // The sequence at `i` is the first.
+            n -= 1;
+            while n > 0 && offset < bytes.len() {
+                offset += 1;
+                while is_continuation(offset) {
+                    offset += 1;
+                }
+                n -= 1;
+            }
+        }
+    }
+
+    match n {
+        0 => state.push(offset as i64 + 1),
+        _ => state.push(Value::Nil),
+    }
+    Ok(1)
+}
+
+/// `utf8.codes(s [, lax])`: returns an iterator over the sequences of `s`, giving the byte
+/// position and code point of each.
+fn codes(state: &mut State) -> Result<usize> {
+    let string = check_string(state, 1, "codes")?;
+    if state.heap[string]
+        .first()
+        .is_some_and(|&b| utf8::is_continuation(b))
+    {
+        return Err(argument_error(1, "codes", "invalid UTF-8 code"));
+    }
+
+    let iterator = match state.arg(2).is_truthy() {
+        false => state.upvalue(1),
+        true => state.upvalue(2),
+    };
+
+    state.push(iterator);
+    state.push(string);
+    state.push(0);
+    Ok(3)
+}
+
+/// The iterator that `codes` returns, which given the position of the last sequence, moves
+/// past its continuation bytes to the next one.
+fn iterate(state: &mut State, strict: bool) -> Result<usize> {
+    let bytes = check_bytes(state, 1, "codes")?;
+    let mut offset = check_integer(state, 2, "codes")? as u64 as usize;
+
+    while offset < bytes.len() && utf8::is_continuation(bytes[offset]) {
+        offset += 1;
+    }
+
+    // A position past the end, or a negative one read as unsigned, ends the iteration.
+    if offset >= bytes.len() {
+        return Ok(0);
+    }
+
+    let (code, len) = utf8::decode(&bytes[offset..], strict).ok_or(Error::InvalidUtf8)?;
+    if bytes
+        .get(offset + len)
+        .is_some_and(|&b| utf8::is_continuation(b))
+    {
+        return Err(Error::InvalidUtf8);
+    }
+
+    state.push(offset as i64 + 1);
+    state.push(code as i64);
+    Ok(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::assert_runs;
+
+    #[test]
+    fn char_encodes_up_to_2_31() {
+        assert_runs(
+            "return table.concat({utf8.char(72, 228, 8364, 0x10FFFF, 0x7FFFFFFF):byte(1, -1)}, ',')",
+            "72,195,164,226,130,172,244,143,191,191,253,191,191,191,191,191",
+        );
+        assert_runs("return #utf8.char()", "0");
+        assert_runs(
+            "return select(2, pcall(utf8.char, 0x80000000))",
+            "bad argument #1 to 'char' (value out of range)",
+        );
+        assert_runs(
+            "local n = 0 for c in ('hä€'):gmatch(utf8.charpattern) do n = n + 1 end return n",
+            "3",
+        );
+    }
+
+    #[test]
+    fn codepoint_is_strict_unless_lax() {
+        assert_runs(
+            "return table.concat({utf8.codepoint('häé', 1, -1)}, ',')",
+            "104,228,233",
+        );
+        assert_runs("return select('#', utf8.codepoint('abc', 4, 3))", "0");
+        assert_runs(
+            "return utf8.codepoint('\\u{D800}', 1, 1, true) .. ' ' \
+             .. utf8.codepoint('\\u{7FFFFFFF}', 1, 1, true)",
+            "55296 2147483647",
+        );
+        for text in ["h\\xffé", "\\u{D800}", "\\u{110000}", "\\xC0\\x80"] {
+            assert_runs(
+                &format!("return select(2, pcall(utf8.codepoint, '{text}', 1, -1))"),
+                "invalid UTF-8 code",
+            );
+        }
+        assert_runs(
+            "return select(2, pcall(utf8.codepoint, 'abc', 4))",
+            "bad argument #3 to 'codepoint' (out of bounds)",
+        );
+    }
+
+    #[test]
+    fn len_reports_the_first_invalid_byte() {
+        assert_runs(
+            "return utf8.len('häé') .. utf8.len('') .. utf8.len('häé', -2) .. utf8.len('abc', 4)",
+            "3010",
+        );
+        assert_runs(
+            "return table.concat({tostring(utf8.len('ab\\xffcd')), select(2, utf8.len('ab\\xffcd'))}, ',') \
+             .. ' ' .. tostring(utf8.len('häé', 3)) .. ' ' .. tostring(utf8.len('\\u{D800}')) \
+             .. ' ' .. utf8.len('\\u{D800}', 1, -1, true)",
+            "nil,3 nil nil 1",
+        );
+        assert_runs(
+            "return select(2, pcall(utf8.len, 'abc', 5))",
+            "bad argument #2 to 'len' (initial position out of bounds)",
+        );
+    }
+
+    #[test]
+    fn offset_counts_characters() {
+        assert_runs(
+            "return utf8.offset('häé', 3) .. utf8.offset('häé', -1) .. utf8.offset('häé', 0, 3) \
+             .. utf8.offset('häé', 4) .. tostring(utf8.offset('häé', 5))",
+            "4426nil",
+        );
+        assert_runs(
+            "return select(2, pcall(utf8.offset, 'häé', 1, 3))",
+            "initial position is a continuation byte",
+        );
+    }
+
+    #[test]
+    fn codes_iterates_and_validates() {
+        assert_runs(
+            "local t = {} for p, c in utf8.codes('hä€') do t[#t + 1] = p .. ':' .. c end \
+             return table.concat(t, ' ')",
+            "1:104 2:228 4:8364",
+        );
+        assert_runs(
+            "for _, c in utf8.codes('\\u{D800}', true) do return c end",
+            "55296",
+        );
+        for text in ["a\\xff", "a\\x80", "\\u{D800}"] {
+            assert_runs(
+                &format!(
+                    "return select(2, pcall(function() for _ in utf8.codes('{text}') do end end))"
+                ),
+                "test:1: invalid UTF-8 code",
+            );
+        }
+    }
+}
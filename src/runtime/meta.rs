@@ -0,0 +1,766 @@
+//! The semantics of every operation that can fall back to a metamethod.
+//!
+//! Each operation first tries its primitive meaning and only then looks for a handler, first in
+//! the metatable of the left operand and then in that of the right one. Handlers of binary
+//! events always receive the operands in their original order, and those of unary events
+//! receive the operand twice, as in the reference implementation.
+
+use {
+    super::{value::float_to_int, Error, Metamethod, Result, State, StrRef, TableRef, Value},
+    crate::number,
+    cranelift_entity::EntityRef,
+    std::io::Write,
+};
+
+/// How many `__index` or `__newindex` handlers may be followed for a single access.
+const MAX_TAG_LOOP: usize = 2000;
+
+/// The arithmetic and bitwise operators.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arith {
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    Idiv,
+    Band,
+    Bor,
+    Bxor,
+    Shl,
+    Shr,
+    Unm,
+    Bnot,
+}
+
+impl Arith {
+    pub fn event(self) -> Metamethod {
+        match self {
+            Self::Add => Metamethod::Add,
+            Self::Sub => Metamethod::Sub,
+            Self::Mul => Metamethod::Mul,
+            Self::Mod => Metamethod::Mod,
+            Self::Pow => Metamethod::Pow,
+            Self::Div => Metamethod::Div,
+            Self::Idiv => Metamethod::Idiv,
+            Self::Band => Metamethod::Band,
+            Self::Bor => Metamethod::Bor,
+            Self::Bxor => Metamethod::Bxor,
+            Self::Shl => Metamethod::Shl,
+            Self::Shr => Metamethod::Shr,
+            Self::Unm => Metamethod::Unm,
+            Self::Bnot => Metamethod::Bnot,
+        }
+    }
+
+    pub fn is_bitwise(self) -> bool {
+        matches!(
+            self,
+            Self::Band | Self::Bor | Self::Bxor | Self::Shl | Self::Shr | Self::Bnot
+        )
+    }
+
+    /// Applies the operator to numbers, returning `None` if the operands are not numbers, or
+    /// for bitwise operators, not numbers with integer representations.
+    pub fn apply(self, a: Value, b: Value) -> Result<Option<Value>> {
+        if self.is_bitwise() {
+            let (Some(x), Some(y)) = (to_integer(a), to_integer(b)) else {
+                return Ok(None);
+            };
+
+            return Ok(Some(Value::Int(match self {
+                Self::Band => x & y,
+                Self::Bor => x | y,
+                Self::Bxor => x ^ y,
+                Self::Shl => shift_left(x, y),
+                Self::Shr => shift_left(x, y.wrapping_neg()),
+                _ => !x,
+            })));
+        }
+
+        if let (Value::Int(x), Value::Int(y)) = (a, b) {
+            let result = match self {
+                Self::Add => Some(x.wrapping_add(y)),
+                Self::Sub => Some(x.wrapping_sub(y)),
+                Self::Mul => Some(x.wrapping_mul(y)),
+                Self::Mod => Some(int_mod(x, y)?),
+                Self::Idiv => Some(int_idiv(x, y)?),
+                Self::Unm => Some(x.wrapping_neg()),
+                _ => None,
+            };
+
+            if let Some(result) = result {
+                return Ok(Some(Value::Int(result)));
+            }
+        }
+
+        let (Some(x), Some(y)) = (to_float(a), to_float(b)) else {
+            return Ok(None);
+        };
+
+        Ok(Some(Value::Float(match self {
+            Self::Add => x + y,
+            Self::Sub => x - y,
+            Self::Mul => x * y,
+            Self::Div => x / y,
+            Self::Pow if y == 2.0 => x * x,
+            Self::Pow => x.powf(y),
+            Self::Idiv => (x / y).floor(),
+            Self::Mod => float_mod(x, y),
+            _ => -x,
+        })))
+    }
+}
+
+impl State {
+    /// Performs an arithmetic or bitwise operation. Unary operators take the operand as both
+    /// `a` and `b`.
+    ///
+    /// Bitwise operators convert strings holding numerals to numbers themselves, while
+    /// arithmetic on strings is left to the metamethods of the string library.
+    pub fn arith(&mut self, op: Arith, a: Value, b: Value) -> Result<Value> {
+        if let Some(result) = op.apply(a, b)? {
+            return Ok(result);
+        }
+
+        if op.is_bitwise() {
+            let coerce = |value| self.to_number(value).unwrap_or(value);
+            if let Some(result) = op.apply(coerce(a), coerce(b))? {
+                return Ok(result);
+            }
+        }
+
+        if let Some(handler) = self.binary_handler(a, b, op.event()) {
+            return self.call_metamethod(handler, &[a, b]);
+        }
+
+        let both_numbers = to_float(a).is_some() && to_float(b).is_some();
+        Err(if op.is_bitwise() && both_numbers {
+            Error::NoIntegerRepresentation
+        } else {
+            let operation = if op.is_bitwise() {
+                "perform bitwise operation on"
+            } else {
+                "perform arithmetic on"
+            };
+
+            if to_float(a).is_some() {
+                self.operand_error(operation, b, Some(1))
+            } else {
+                self.operand_error(operation, a, Some(0))
+            }
+        })
+    }
+
+    /// Concatenates two values, which as strings or numbers are joined directly.
+    pub fn concat(&mut self, a: Value, b: Value) -> Result<Value> {
+        let mut buffer = Vec::new();
+        if self.write_concat_operand(&mut buffer, a) && self.write_concat_operand(&mut buffer, b) {
+            return Ok(Value::String(self.heap.intern(&buffer)));
+        }
+
+        if let Some(handler) = self.binary_handler(a, b, Metamethod::Concat) {
+            return self.call_metamethod(handler, &[a, b]);
+        }
+
+        Err(if matches!(a, Value::String(_)) || to_float(a).is_some() {
+            self.operand_error("concatenate", b, Some(1))
+        } else {
+            self.operand_error("concatenate", a, Some(0))
+        })
+    }
+
+    fn write_concat_operand(&self, buffer: &mut Vec<u8>, value: Value) -> bool {
+        match value {
+            Value::String(string) => buffer.extend_from_slice(&self.heap[string]),
+            Value::Int(i) => number::write_int(buffer, i),
+            Value::Float(f) => number::write_float(buffer, f),
+            _ => return false,
+        }
+
+        true
+    }
+
+    /// Converts a value to a number: numbers as they are, and strings holding a numeral as
+    /// `tonumber` reads them.
+    pub fn to_number(&self, value: Value) -> Option<Value> {
+        match value {
+            Value::Int(_) | Value::Float(_) => Some(value),
+            Value::String(string) => number::parse_number(&self.heap[string]).map(Value::from),
+            _ => None,
+        }
+    }
+
+    /// Compares two values with `==`. Only tables and userdata that are not primitively equal
+    /// consult `__eq`.
+    pub fn equals(&mut self, a: Value, b: Value) -> Result<bool> {
+        match (a, b) {
+            (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_))
+                if !a.raw_eq(b) =>
+            {
+                match self.binary_handler(a, b, Metamethod::Eq) {
+                    Some(handler) => Ok(self.call_metamethod(handler, &[a, b])?.is_truthy()),
+                    None => Ok(false),
+                }
+            }
+
+            _ => Ok(a.raw_eq(b)),
+        }
+    }
+
+    pub fn less_than(&mut self, a: Value, b: Value) -> Result<bool> {
+        let result = match (a, b) {
+            (Value::Int(x), Value::Int(y)) => x < y,
+            (Value::Float(x), Value::Float(y)) => x < y,
+            (Value::Int(x), Value::Float(y)) => int_lt_float(x, y),
+            (Value::Float(x), Value::Int(y)) => float_lt_int(x, y),
+            (Value::String(x), Value::String(y)) => self.heap[x] < self.heap[y],
+            _ => return self.order_metamethod(a, b, Metamethod::Lt),
+        };
+
+        Ok(result)
+    }
+
+    pub fn less_equal(&mut self, a: Value, b: Value) -> Result<bool> {
+        let result = match (a, b) {
+            (Value::Int(x), Value::Int(y)) => x <= y,
+            (Value::Float(x), Value::Float(y)) => x <= y,
+            (Value::Int(x), Value::Float(y)) => int_le_float(x, y),
+            (Value::Float(x), Value::Int(y)) => float_le_int(x, y),
+            (Value::String(x), Value::String(y)) => self.heap[x] <= self.heap[y],
+            _ => return self.order_metamethod(a, b, Metamethod::Le),
+        };
+
+        Ok(result)
+    }
+
+    fn order_metamethod(&mut self, a: Value, b: Value, event: Metamethod) -> Result<bool> {
+        if let Some(handler) = self.binary_handler(a, b, event) {
+            return Ok(self.call_metamethod(handler, &[a, b])?.is_truthy());
+        }
+
+        let (a, b) = (self.type_name(a), self.type_name(b));
+        Err(if a == b {
+            Error::CompareSame(a)
+        } else {
+            Error::Compare(a, b)
+        })
+    }
+
+    /// The length operator `#`.
+    pub fn len(&mut self, value: Value) -> Result<Value> {
+        if let Value::String(string) = value {
+            return Ok(Value::Int(self.heap[string].len() as i64));
+        }
+
+        let handler = self.heap.metamethod_of(value, Metamethod::Len);
+        if !handler.is_nil() {
+            return self.call_metamethod(handler, &[value, value]);
+        }
+
+        match value {
+            Value::Table(table) => Ok(Value::Int(self.heap[table].border())),
+            _ => Err(self.operand_error("get length of", value, Some(0))),
+        }
+    }
+
+    /// Reads `object[key]`, following `__index` handlers for absent keys.
+    pub fn index(&mut self, object: Value, key: Value) -> Result<Value> {
+        let mut object = object;
+
+        for depth in 0..MAX_TAG_LOOP {
+            let handler = match object {
+                Value::Table(table) => {
+                    let value = self.heap[table].get(key);
+                    if !value.is_nil() {
+                        return Ok(value);
+                    }
+
+                    let handler = self.heap.metamethod_of(object, Metamethod::Index);
+                    if handler.is_nil() {
+                        return Ok(Value::Nil);
+                    }
+
+                    handler
+                }
+
+                _ => {
+                    let handler = self.heap.metamethod_of(object, Metamethod::Index);
+                    if handler.is_nil() {
+                        let operand = (depth == 0).then_some(0);
+                        return Err(self.operand_error("index", object, operand));
+                    }
+
+                    handler
+                }
+            };
+
+            if let Value::Function(_) = handler {
+                return self.call_metamethod(handler, &[object, key]);
+            }
+
+            object = handler;
+        }
+
+        Err(Error::ChainTooLong("__index"))
+    }
+
+    /// Performs `object[key] = value`, following `__newindex` handlers for absent keys.
+    pub fn set_index(&mut self, object: Value, key: Value, value: Value) -> Result<()> {
+        let mut object = object;
+
+        for depth in 0..MAX_TAG_LOOP {
+            let handler = match object {
+                Value::Table(table) => {
+                    let handler = if self.heap[table].get(key).is_nil() {
+                        self.heap.metamethod_of(object, Metamethod::Newindex)
+                    } else {
+                        Value::Nil
+                    };
+
+                    if handler.is_nil() {
+                        return self.heap[table].set(key, value);
+                    }
+
+                    handler
+                }
+
+                _ => {
+                    let handler = self.heap.metamethod_of(object, Metamethod::Newindex);
+                    if handler.is_nil() {
+                        let operand = (depth == 0).then_some(0);
+                        return Err(self.operand_error("index", object, operand));
+                    }
+
+                    handler
+                }
+            };
+
+            if let Value::Function(_) = handler {
+                self.call_metamethod(handler, &[object, key, value])?;
+                return Ok(());
+            }
+
+            object = handler;
+        }
+
+        Err(Error::ChainTooLong("__newindex"))
+    }
+
+    /// Converts any value to a string as `tostring` does, honouring `__tostring` and
+    /// `__name`.
+    pub fn tostring(&mut self, value: Value) -> Result<StrRef> {
+        let handler = self.heap.metamethod_of(value, Metamethod::Tostring);
+        let value = if handler.is_nil() {
+            value
+        } else {
+            match self.call_metamethod(handler, &[value])? {
+                result @ (Value::String(_) | Value::Int(_) | Value::Float(_)) => result,
+                _ => return Err(Error::TostringNotString),
+            }
+        };
+
+        let mut buffer = Vec::new();
+        match value {
+            Value::Nil => buffer.extend_from_slice(b"nil"),
+            Value::Bool(b) => write!(buffer, "{b}").unwrap(),
+            Value::Int(i) => number::write_int(&mut buffer, i),
+            Value::Float(f) => number::write_float(&mut buffer, f),
+            Value::String(string) => return Ok(string),
+
+            Value::Table(_) | Value::Function(_) | Value::Thread(_) | Value::UserData(_) => {
+                match self.heap.metamethod_of(value, Metamethod::Name) {
+                    Value::String(name) => buffer.extend_from_slice(&self.heap[name]),
+                    _ => buffer.extend_from_slice(value.type_name().as_bytes()),
+                }
+
+                write!(buffer, ": 0x{:08x}", address(value)).unwrap();
+            }
+        }
+
+        Ok(self.heap.intern(&buffer))
+    }
+
+    /// The metatable of `value` as `getmetatable` reports it, which is the `__metatable` field
+    /// of the metatable if it has one.
+    pub fn get_metatable(&self, value: Value) -> Value {
+        match self.heap.metatable_of(value) {
+            Some(metatable) => match self.protection(metatable) {
+                Value::Nil => Value::Table(metatable),
+                protection => protection,
+            },
+
+            None => Value::Nil,
+        }
+    }
+
+    /// Sets the metatable of `table` as `setmetatable` does, which is not allowed if the
+    /// current metatable has a `__metatable` field.
+    pub fn set_metatable(&mut self, table: TableRef, metatable: Option<TableRef>) -> Result<()> {
+        if let Some(current) = self.heap[table].metatable() {
+            if !self.protection(current).is_nil() {
+                return Err(Error::ProtectedMetatable);
+            }
+        }
+
+        self.heap.set_metatable(Value::Table(table), metatable);
+        Ok(())
+    }
+
+    fn protection(&self, metatable: TableRef) -> Value {
+        self.heap.metamethod(Some(metatable), Metamethod::Metatable)
+    }
+
+    /// Calls the `__pairs` metamethod of `value`, returning the iterator function, state and
+    /// initial control value it produces, or `None` if there is no such metamethod and `next`
+    /// should be used.
+    pub fn pairs(&mut self, value: Value) -> Result<Option<[Value; 3]>> {
+        let handler = self.heap.metamethod_of(value, Metamethod::Pairs);
+        if handler.is_nil() {
+            return Ok(None);
+        }
+
+        self.push(handler);
+        self.push(value);
+        self.call(1, Some(3))?;
+
+        let control = self.pop();
+        let state = self.pop();
+        let iterator = self.pop();
+        Ok(Some([iterator, state, control]))
+    }
+
+    /// Calls the `__close` metamethod of a to-be-closed variable's value as its scope is left,
+    /// with the error being propagated, or nil if the scope is left normally.
+    pub fn close_value(&mut self, value: Value, error: Value) -> Result<()> {
+        let handler = self.heap.metamethod_of(value, Metamethod::Close);
+        self.push(handler);
+        self.push(value);
+        self.push(error);
+        self.call(2, Some(0))?;
+        Ok(())
+    }
+
+    /// The name of the type of `value` for error messages, which for a table or userdata is the
+    /// `__name` field of its metatable if that is a string.
+    pub fn type_name(&self, value: Value) -> String {
+        if let Value::Table(_) | Value::UserData(_) = value {
+            if let Value::String(name) = self.heap.metamethod_of(value, Metamethod::Name) {
+                return String::from_utf8_lossy(&self.heap[name]).into_owned();
+            }
+        }
+
+        value.type_name().to_owned()
+    }
+
+    pub(super) fn operand_error(
+        &self,
+        operation: &'static str,
+        value: Value,
+        operand: Option<usize>,
+    ) -> Error {
+        Error::Operand {
+            operation,
+            type_name: self.type_name(value),
+            operand,
+            variable: None,
+        }
+    }
+
+    /// Finds the handler for a binary event in the metatable of either operand.
+    fn binary_handler(&self, a: Value, b: Value, event: Metamethod) -> Option<Value> {
+        let handler = match self.heap.metamethod_of(a, event) {
+            Value::Nil => self.heap.metamethod_of(b, event),
+            handler => handler,
+        };
+
+        (!handler.is_nil()).then_some(handler)
+    }
+}
+
+fn to_integer(value: Value) -> Option<i64> {
+    match value {
+        Value::Int(i) => Some(i),
+        Value::Float(f) => float_to_int(f),
+        _ => None,
+    }
+}
+
+fn to_float(value: Value) -> Option<f64> {
+    match value {
+        Value::Int(i) => Some(i as f64),
+        Value::Float(f) => Some(f),
+        _ => None,
+    }
+}
+
+/// Shifts left by `shift` bits, or right for negative shifts, filling with zeros.
+fn shift_left(value: i64, shift: i64) -> i64 {
+    match shift {
+        0..=63 => ((value as u64) << shift) as i64,
+        -63..=-1 => ((value as u64) >> -shift) as i64,
+        _ => 0,
+    }
+}
+
+/// Integer division rounded towards negative infinity.
+fn int_idiv(x: i64, y: i64) -> Result<i64> {
+    if y == 0 {
+        return Err(Error::DivideByZero);
+    }
+
+    let quotient = x.wrapping_div(y);
+    if x.wrapping_rem(y) != 0 && (x ^ y) < 0 {
+        Ok(quotient - 1)
+    } else {
+        Ok(quotient)
+    }
+}
+
+/// The remainder of [`int_idiv`], which has the sign of `y`.
+fn int_mod(x: i64, y: i64) -> Result<i64> {
+    if y == 0 {
+        return Err(Error::ModuloByZero);
+    }
+
+    let remainder = x.wrapping_rem(y);
+    if remainder != 0 && (remainder ^ y) < 0 {
+        Ok(remainder + y)
+    } else {
+        Ok(remainder)
+    }
+}
+
+fn float_mod(x: f64, y: f64) -> f64 {
+    let remainder = x % y;
+    if (remainder > 0.0 && y < 0.0) || (remainder < 0.0 && y > 0.0) {
+        remainder + y
+    } else {
+        remainder
+    }
+}
+
+/// 2^63, the first float beyond the range of `i64`.
+const INT_END: f64 = 9223372036854775808.0;
+
+// Integers and floats are compared exactly, by rounding the float to an integer in the
+// direction that preserves the result, rather than by converting the integer to a float.
+
+fn int_lt_float(i: i64, f: f64) -> bool {
+    if f >= INT_END {
+        true
+    } else if f.ceil() >= -INT_END {
+        i < f.ceil() as i64
+    } else {
+        false
+    }
+}
+
+fn int_le_float(i: i64, f: f64) -> bool {
+    if f >= INT_END {
+        true
+    } else if f.floor() >= -INT_END {
+        i <= f.floor() as i64
+    } else {
+        false
+    }
+}
+
+fn float_lt_int(f: f64, i: i64) -> bool {
+    if f >= INT_END {
+        false
+    } else if f.floor() >= -INT_END {
+        (f.floor() as i64) < i
+    } else {
+        !f.is_nan()
+    }
+}
+
+fn float_le_int(f: f64, i: i64) -> bool {
+    if f >= INT_END {
+        false
+    } else if f.ceil() >= -INT_END {
+        f.ceil() as i64 <= i
+    } else {
+        !f.is_nan()
+    }
+}
+
+/// A stand-in for the address of an object, which identifies it for as long as it lives.
+pub(super) fn address(value: Value) -> usize {
+    match value {
+        Value::String(key) => key.index(),
+        Value::Table(key) => key.index(),
+        Value::Function(key) => key.index(),
+        Value::Thread(key) => key.index(),
+        Value::UserData(key) => key.index(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::testing::{assert_fails, assert_runs},
+    };
+
+    fn apply(op: Arith, a: impl Into<Value>, b: impl Into<Value>) -> Option<Value> {
+        op.apply(a.into(), b.into()).unwrap()
+    }
+
+    #[test]
+    fn integer_arithmetic_wraps_and_floors() {
+        assert!(matches!(
+            apply(Arith::Add, i64::MAX, 1),
+            Some(Value::Int(i64::MIN))
+        ));
+        assert!(matches!(apply(Arith::Idiv, 7, -2), Some(Value::Int(-4))));
+        assert!(matches!(apply(Arith::Mod, 7, -2), Some(Value::Int(-1))));
+        assert!(matches!(apply(Arith::Mod, -7, 2), Some(Value::Int(1))));
+        assert!(matches!(
+            apply(Arith::Idiv, i64::MIN, -1),
+            Some(Value::Int(i64::MIN))
+        ));
+        assert!(matches!(
+            Arith::Idiv.apply(Value::Int(1), Value::Int(0)),
+            Err(Error::DivideByZero)
+        ));
+        assert!(matches!(
+            Arith::Mod.apply(Value::Int(1), Value::Int(0)),
+            Err(Error::ModuloByZero)
+        ));
+    }
+
+    #[test]
+    fn division_and_exponentiation_are_always_float() {
+        assert!(matches!(apply(Arith::Div, 6, 3), Some(Value::Float(f)) if f == 2.0));
+        assert!(matches!(apply(Arith::Pow, 2, 10), Some(Value::Float(f)) if f == 1024.0));
+        assert!(matches!(apply(Arith::Mod, 5.5, -2), Some(Value::Float(f)) if f == -0.5));
+        assert!(matches!(apply(Arith::Idiv, 1, 0.0), Some(Value::Float(f)) if f == f64::INFINITY));
+    }
+
+    #[test]
+    fn bitwise_operators_need_integer_representations() {
+        assert!(matches!(apply(Arith::Band, 3.0, 6), Some(Value::Int(2))));
+        assert!(matches!(apply(Arith::Shl, 1, 64), Some(Value::Int(0))));
+        assert!(matches!(apply(Arith::Shr, -1, 60), Some(Value::Int(15))));
+        assert!(matches!(apply(Arith::Shl, 8, -2), Some(Value::Int(2))));
+        assert!(apply(Arith::Bor, 1.5, 1).is_none());
+        assert_fails(
+            "return 1.5 | 1",
+            "test:1: number has no integer representation",
+        );
+    }
+
+    #[test]
+    fn integers_and_floats_compare_exactly() {
+        assert!(int_lt_float(i64::MAX, INT_END));
+        assert!(!float_le_int(INT_END, i64::MAX));
+        assert!(int_le_float(i64::MIN, -INT_END));
+        assert!(!int_lt_float(1, f64::NAN) && !float_lt_int(f64::NAN, 1));
+        assert!(float_lt_int(0.5, 1) && !float_lt_int(1.5, 1));
+        assert_runs("return math.maxinteger < 2^63", "true");
+        assert_runs("return math.maxinteger + 0.0 == math.maxinteger", "false");
+    }
+
+    #[test]
+    fn binary_handlers_come_from_either_operand() {
+        assert_runs(
+            "local mt = {__add = function(a, b) return type(a) .. '+' .. type(b) end} \
+             local t = setmetatable({}, mt) \
+             return (t + 1) .. ' ' .. (1 + t)",
+            "table+number number+table",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__unm = function(a, b) return rawequal(a, b) end}) \
+             return -t",
+            "true",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__concat = function(a, b) return 'c' end}) \
+             return 1 .. t .. 2",
+            "1c",
+        );
+    }
+
+    #[test]
+    fn equality_consults_handlers_only_for_distinct_objects() {
+        assert_runs(
+            "local n = 0 \
+             local mt = {__eq = function() n = n + 1 return false end} \
+             local a, b = setmetatable({}, mt), setmetatable({}, mt) \
+             return tostring(a == a) .. tostring(a == b) .. tostring(a == 1) .. n",
+            "truefalsefalse1",
+        );
+    }
+
+    #[test]
+    fn comparisons_without_handlers_fail() {
+        assert_fails(
+            "return {} < {}",
+            "test:1: attempt to compare two table values",
+        );
+        assert_fails(
+            "return 1 < 'x'",
+            "test:1: attempt to compare number with string",
+        );
+        assert_runs("return 'a' < 'b' and 'a\\0' > 'a'", "true");
+    }
+
+    #[test]
+    fn index_follows_tables_and_functions() {
+        assert_runs(
+            "local base = {x = 'base'} \
+             local middle = setmetatable({}, {__index = base}) \
+             local top = setmetatable({}, {__index = middle}) \
+             local f = setmetatable({}, {__index = function(t, k) return k .. '!' end}) \
+             return top.x .. f.y",
+            "basey!",
+        );
+        assert_runs(
+            "local store = {} \
+             local t = setmetatable({}, {__newindex = store}) \
+             t.x = 1 \
+             return tostring(rawget(t, 'x')) .. store.x",
+            "nil1",
+        );
+        assert_fails(
+            "local t = {} setmetatable(t, {__index = t}) return t.x",
+            "test:1: '__index' chain too long; possible loop",
+        );
+    }
+
+    #[test]
+    fn length_and_tostring_handlers() {
+        assert_runs(
+            "return #setmetatable({1, 2}, {__len = function() return 10 end})",
+            "10",
+        );
+        assert_runs(
+            "return tostring(setmetatable({}, {__tostring = function() return 'obj' end}))",
+            "obj",
+        );
+        assert_runs(
+            "return tostring(setmetatable({}, {__name = 'Point'})):sub(1, 7)",
+            "Point: ",
+        );
+        assert_fails(
+            "return tostring(setmetatable({}, {__tostring = function() return {} end}))",
+            "test:1: '__tostring' must return a string",
+        );
+    }
+
+    #[test]
+    fn protected_metatables() {
+        assert_runs(
+            "local t = setmetatable({}, {__metatable = 'locked'}) return getmetatable(t)",
+            "locked",
+        );
+        assert_fails(
+            "local t = setmetatable({}, {__metatable = 'locked'}) setmetatable(t, {})",
+            "test:1: cannot change a protected metatable",
+        );
+    }
+}
@@ -0,0 +1,103 @@
+/// The events a metatable can define behavior for, along with the other fields of a metatable
+/// the runtime looks up by name.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Metamethod {
+    Index,
+    Newindex,
+    Gc,
+    Mode,
+    Len,
+    Eq,
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    Idiv,
+    Band,
+    Bor,
+    Bxor,
+    Shl,
+    Shr,
+    Unm,
+    Bnot,
+    Lt,
+    Le,
+    Concat,
+    Call,
+    Close,
+    Tostring,
+    Name,
+    Metatable,
+    Pairs,
+}
+
+impl Metamethod {
+    /// Every event, in declaration order.
+    pub const ALL: [Self; 29] = [
+        Self::Index,
+        Self::Newindex,
+        Self::Gc,
+        Self::Mode,
+        Self::Len,
+        Self::Eq,
+        Self::Add,
+        Self::Sub,
+        Self::Mul,
+        Self::Mod,
+        Self::Pow,
+        Self::Div,
+        Self::Idiv,
+        Self::Band,
+        Self::Bor,
+        Self::Bxor,
+        Self::Shl,
+        Self::Shr,
+        Self::Unm,
+        Self::Bnot,
+        Self::Lt,
+        Self::Le,
+        Self::Concat,
+        Self::Call,
+        Self::Close,
+        Self::Tostring,
+        Self::Name,
+        Self::Metatable,
+        Self::Pairs,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Index => "__index",
+            Self::Newindex => "__newindex",
+            Self::Gc => "__gc",
+            Self::Mode => "__mode",
+            Self::Len => "__len",
+            Self::Eq => "__eq",
+            Self::Add => "__add",
+            Self::Sub => "__sub",
+            Self::Mul => "__mul",
+            Self::Mod => "__mod",
+            Self::Pow => "__pow",
+            Self::Div => "__div",
+            Self::Idiv => "__idiv",
+            Self::Band => "__band",
+            Self::Bor => "__bor",
+            Self::Bxor => "__bxor",
+            Self::Shl => "__shl",
+            Self::Shr => "__shr",
+            Self::Unm => "__unm",
+            Self::Bnot => "__bnot",
+            Self::Lt => "__lt",
+            Self::Le => "__le",
+            Self::Concat => "__concat",
+            Self::Call => "__call",
+            Self::Close => "__close",
+            Self::Tostring => "__tostring",
+            Self::Name => "__name",
+            Self::Metatable => "__metatable",
+            Self::Pairs => "__pairs",
+        }
+    }
+}
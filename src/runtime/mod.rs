@@ -0,0 +1,46 @@
+//! Runtime representation of Lua values and the heap objects they refer to.
+
+pub use {
+    debug::{FunctionInfo, Hook, HookEvent, HookFn, HookMask},
+    error::{Error, Variable},
+    function::{
+        Callback, Capture, Closure, Compiler, Function, LocalVariable, NativeFunction, Position,
+        Prototype, Upvalue,
+    },
+    heap::{
+        FunctionRef, Heap, Mode as GcMode, StrRef, TableRef, ThreadRef, UpvalueRef, UserDataRef,
+    },
+    host::{Exit, Host, NoHost, OpenMode, StdHost, Stream},
+    lib::{
+        open_base, open_coroutine, open_debug, open_io, open_math, open_os, open_package,
+        open_string, open_table, open_utf8, preload_module,
+    },
+    meta::Arith,
+    metamethod::Metamethod,
+    reference::Ref,
+    state::State,
+    table::Table,
+    thread::{Status, Thread},
+    userdata::UserData,
+    value::{float_to_int, Value},
+};
+
+pub(crate) use {lib::load_file, state::short_source};
+
+mod debug;
+mod error;
+mod function;
+mod heap;
+mod host;
+mod lib;
+mod meta;
+mod metamethod;
+mod pattern;
+mod reference;
+mod state;
+mod table;
+mod thread;
+mod userdata;
+mod value;
+
+pub type Result<T> = std::result::Result<T, Error>;
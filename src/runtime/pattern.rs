@@ -0,0 +1,613 @@
+//! Lua patterns, which the string library searches byte strings with.
+//!
+//! Matching backtracks as the reference implementation does, recursing for each single-char
+//! class that repeats, each capture and each optional item, and gives up with an error rather
+//! than exhausting the native stack once the recursion gets too deep.
+
+use {crate::number::is_space, std::ops::Range, thiserror::Error};
+
+/// How many captures a pattern may make.
+const MAX_CAPTURES: usize = 32;
+
+/// How deeply matching may recurse before the pattern is considered too complex.
+const MAX_DEPTH: usize = 200;
+
+const ESCAPE: u8 = b'%';
+
+/// The characters that make a pattern more than a plain string to search for.
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+#[derive(Clone, Debug, Error)]
+pub enum Error {
+    #[error("malformed pattern (ends with '%')")]
+    EndsWithEscape,
+
+    #[error("malformed pattern (missing ']')")]
+    MissingBracket,
+
+    #[error("malformed pattern (missing arguments to '%b')")]
+    MissingBalanceArguments,
+
+    #[error("missing '[' after '%f' in pattern")]
+    MissingFrontierSet,
+
+    /// A back reference or replacement named a capture that does not exist or is unfinished.
+    /// The index counts from 1.
+    #[error("invalid capture index %{0}")]
+    InvalidCaptureIndex(usize),
+
+    /// A `)` closed no capture.
+    #[error("invalid pattern capture")]
+    InvalidCapture,
+
+    #[error("unfinished capture")]
+    UnfinishedCapture,
+
+    #[error("too many captures")]
+    TooManyCaptures,
+
+    #[error("pattern too complex")]
+    TooComplex,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A value captured by a match.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Capture {
+    /// The part of the source a parenthesized subpattern matched.
+    Substring(Range<usize>),
+
+    /// The offset in the source of an empty capture, `()`.
+    Position(usize),
+}
+
+/// Whether `pattern` has no special characters, and so can be searched for as plain text.
+pub fn is_plain(pattern: &[u8]) -> bool {
+    !pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Finds the first occurrence of `needle` in `haystack`.
+pub fn find_plain(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum CaptureEnd {
+    Unfinished,
+    Position,
+    At(usize),
+}
+
+/// Matches one pattern against one source, at whatever offsets it is asked to, keeping the
+/// captures of the last successful match.
+///
+/// The pattern does not include the `^` that anchors it, which is for the caller to honour by
+/// matching at only one offset.
+pub struct Matcher<'a> {
+    source: &'a [u8],
+    pattern: &'a [u8],
+    depth: usize,
+    captures: Vec<(usize, CaptureEnd)>,
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(source: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self {
+            source,
+            pattern,
+            depth: MAX_DEPTH,
+            captures: Vec::new(),
+        }
+    }
+
+    /// Matches the whole pattern against the source starting at `start`, returning where the
+    /// match ends.
+    pub fn match_at(&mut self, start: usize) -> Result<Option<usize>> {
+        self.depth = MAX_DEPTH;
+        self.captures.clear();
+        self.do_match(start, 0)
+    }
+
+    /// The `index`th capture of the last match, counting from 0. A pattern without captures
+    /// is taken to capture the whole match, `whole`.
+    pub fn capture(&self, index: usize, whole: Range<usize>) -> Result<Capture> {
+        match self.captures.get(index) {
+            None if index == 0 && self.captures.is_empty() => Ok(Capture::Substring(whole)),
+            None => Err(Error::InvalidCaptureIndex(index + 1)),
+            Some(&(_, CaptureEnd::Unfinished)) => Err(Error::UnfinishedCapture),
+            Some(&(start, CaptureEnd::Position)) => Ok(Capture::Position(start)),
+            Some(&(start, CaptureEnd::At(end))) => Ok(Capture::Substring(start..end)),
+        }
+    }
+
+    /// All the captures of the last match, or the whole match, if given, for a pattern
+    /// without captures.
+    pub fn captures(&self, whole: Option<Range<usize>>) -> Result<Vec<Capture>> {
+        match whole {
+            Some(whole) if self.captures.is_empty() => Ok(vec![Capture::Substring(whole)]),
+            _ => (0..self.captures.len())
+                .map(|index| self.capture(index, 0..0))
+                .collect(),
+        }
+    }
+
+    fn do_match(&mut self, source: usize, pattern: usize) -> Result<Option<usize>> {
+        if self.depth == 0 {
+            return Err(Error::TooComplex);
+        }
+
+        self.depth -= 1;
+        let result = self.match_here(source, pattern);
+        self.depth += 1;
+        result
+    }
+
+    /// Matches the pattern from `p` against the source from `s`. Items that cannot
+    /// backtrack are matched by going around the loop rather than by recursing.
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>> {
+        loop {
+            let Some(&c) = self.pattern.get(p) else {
+                return Ok(Some(s));
+            };
+
+            match (c, self.pattern.get(p + 1).copied()) {
+                (b'(', Some(b')')) => return self.start_capture(s, p + 2, CaptureEnd::Position),
+                (b'(', _) => return self.start_capture(s, p + 1, CaptureEnd::Unfinished),
+                (b')', _) => return self.end_capture(s, p + 1),
+                (b'$', None) => return Ok((s == self.source.len()).then_some(s)),
+
+                (ESCAPE, Some(b'b')) => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                    }
+                    None => return Ok(None),
+                },
+
+                (ESCAPE, Some(b'f')) => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return Err(Error::MissingFrontierSet);
+                    }
+
+                    let end = self.class_end(p)?;
+                    let previous = s.checked_sub(1).map_or(0, |i| self.source[i]);
+                    let current = self.source.get(s).copied().unwrap_or(0);
+                    if self.match_bracket_class(previous, p, end - 1)
+                        || !self.match_bracket_class(current, p, end - 1)
+                    {
+                        return Ok(None);
+                    }
+
+                    p = end;
+                }
+
+                (ESCAPE, Some(digit)) if digit.is_ascii_digit() => {
+                    match self.match_back_reference(s, digit)? {
+                        Some(end) => {
+                            s = end;
+                            p += 2;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+
+                _ => {
+                    let end = self.class_end(p)?;
+                    let matches =
+                        s < self.source.len() && self.single_match(self.source[s], p, end);
+
+                    match self.pattern.get(end) {
+                        Some(b'?') => {
+                            if matches {
+                                if let Some(result) = self.do_match(s + 1, end + 1)? {
+                                    return Ok(Some(result));
+                                }
+                            }
+                            p = end + 1;
+                        }
+
+                        Some(b'+') if matches => return self.max_expand(s + 1, p, end),
+                        Some(b'+') => return Ok(None),
+                        Some(b'*') => return self.max_expand(s, p, end),
+                        Some(b'-') => return self.min_expand(s, p, end),
+
+                        _ if matches => {
+                            s += 1;
+                            p = end;
+                        }
+                        _ => return Ok(None),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Matches as many repetitions of the single-char class at `p` as possible, then backs
+    /// off one at a time until the rest of the pattern, after `end`, matches.
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>> {
+        let count = self.source[s..]
+            .iter()
+            .take_while(|&&c| self.single_match(c, p, end))
+            .count();
+
+        for count in (0..=count).rev() {
+            if let Some(result) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(result));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Matches as few repetitions of the single-char class at `p` as possible, adding one at
+    /// a time until the rest of the pattern, after `end`, matches.
+    fn min_expand(&mut self, mut s: usize, p: usize, end: usize) -> Result<Option<usize>> {
+        loop {
+            if let Some(result) = self.do_match(s, end + 1)? {
+                return Ok(Some(result));
+            }
+
+            if s < self.source.len() && self.single_match(self.source[s], p, end) {
+                s += 1;
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, end: CaptureEnd) -> Result<Option<usize>> {
+        if self.captures.len() >= MAX_CAPTURES {
+            return Err(Error::TooManyCaptures);
+        }
+
+        self.captures.push((s, end));
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures.pop();
+        }
+
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>> {
+        let index = self
+            .captures
+            .iter()
+            .rposition(|&(_, end)| end == CaptureEnd::Unfinished)
+            .ok_or(Error::InvalidCapture)?;
+
+        self.captures[index].1 = CaptureEnd::At(s);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].1 = CaptureEnd::Unfinished;
+        }
+
+        Ok(result)
+    }
+
+    /// Matches `%bxy` whose `x` is at `p`: a substring starting with `x` and ending with the
+    /// `y` that balances it.
+    fn match_balance(&self, s: usize, p: usize) -> Result<Option<usize>> {
+        let (Some(&open), Some(&close)) = (self.pattern.get(p), self.pattern.get(p + 1)) else {
+            return Err(Error::MissingBalanceArguments);
+        };
+
+        if self.source.get(s) != Some(&open) {
+            return Ok(None);
+        }
+
+        let mut depth = 1;
+        for (i, &c) in self.source.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Matches `%1` to `%9`: a repetition of what the given capture matched.
+    fn match_back_reference(&self, s: usize, digit: u8) -> Result<Option<usize>> {
+        let index = (digit - b'0') as usize;
+        let range = match index.checked_sub(1).and_then(|i| self.captures.get(i)) {
+            Some(&(start, CaptureEnd::At(end))) => start..end,
+            // A position is never repeated.
+            Some(&(_, CaptureEnd::Position)) => return Ok(None),
+            _ => return Err(Error::InvalidCaptureIndex(index)),
+        };
+
+        let captured = &self.source[range];
+        Ok(self.source[s..]
+            .starts_with(captured)
+            .then_some(s + captured.len()))
+    }
+
+    /// The end of the single-char class starting at `p`: a character, `.`, a `%` escape or a
+    /// bracketed set.
+    fn class_end(&self, mut p: usize) -> Result<usize> {
+        let c = self.pattern[p];
+        p += 1;
+
+        match c {
+            ESCAPE if p >= self.pattern.len() => Err(Error::EndsWithEscape),
+            ESCAPE => Ok(p + 1),
+
+            b'[' => {
+                if self.pattern.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+
+                // The first character of the set is never its end, so `[]]` is a set of `]`.
+                loop {
+                    let Some(&c) = self.pattern.get(p) else {
+                        return Err(Error::MissingBracket);
+                    };
+
+                    p += 1;
+                    if c == ESCAPE && p < self.pattern.len() {
+                        p += 1;
+                    }
+
+                    if self.pattern.get(p) == Some(&b']') {
+                        return Ok(p + 1);
+                    }
+                }
+            }
+
+            _ => Ok(p),
+        }
+    }
+
+    /// Whether `c` belongs to the single-char class that spans `p..end` in the pattern.
+    fn single_match(&self, c: u8, p: usize, end: usize) -> bool {
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pattern[p + 1]),
+            b'[' => self.match_bracket_class(c, p, end - 1),
+            literal => literal == c,
+        }
+    }
+
+    /// Whether `c` belongs to the set between the `[` at `p` and the `]` at `end`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, end: usize) -> bool {
+        let mut found = true;
+        if self.pattern[p + 1] == b'^' {
+            found = false;
+            p += 1;
+        }
+
+        p += 1;
+        while p < end {
+            if self.pattern[p] == ESCAPE {
+                p += 1;
+                if match_class(c, self.pattern[p]) {
+                    return found;
+                }
+            } else if self.pattern[p + 1] == b'-' && p + 2 < end {
+                if (self.pattern[p]..=self.pattern[p + 2]).contains(&c) {
+                    return found;
+                }
+                p += 2;
+            } else if self.pattern[p] == c {
+                return found;
+            }
+
+            p += 1;
+        }
+
+        !found
+    }
+}
+
+/// Whether `c` belongs to the class that `%` followed by `class` stands for, as classified
+/// in the C locale. An upper-case letter stands for the complement of its lower-case class,
+/// and any other character stands for itself.
+fn match_class(c: u8, class: u8) -> bool {
+    let matches = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => is_space(c),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+
+    matches != class.is_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testing::assert_runs};
+
+    /// Finds the first match of `pattern` in `source` as `string.find` does, returning the
+    /// matched text and the text of each capture, with positions as numbers.
+    fn find(source: &str, pattern: &str) -> Result<Option<(String, Vec<String>)>> {
+        let (source, pattern) = (source.as_bytes(), pattern.as_bytes());
+        let (anchored, pattern) = match pattern.strip_prefix(b"^") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+
+        let mut matcher = Matcher::new(source, pattern);
+        for start in 0..=source.len() {
+            if let Some(end) = matcher.match_at(start)? {
+                let text =
+                    |range: Range<usize>| String::from_utf8_lossy(&source[range]).into_owned();
+                let captures = matcher
+                    .captures(None)?
+                    .into_iter()
+                    .map(|capture| match capture {
+                        Capture::Substring(range) => text(range),
+                        Capture::Position(position) => (position + 1).to_string(),
+                    })
+                    .collect();
+                return Ok(Some((text(start..end), captures)));
+            }
+
+            if anchored {
+                break;
+            }
+        }
+
+        Ok(None)
+    }
+
+    #[track_caller]
+    fn assert_matches(source: &str, pattern: &str, expected: &str, captures: &[&str]) {
+        let found = find(source, pattern).unwrap();
+        assert_eq!(
+            found,
+            Some((
+                expected.to_owned(),
+                captures.iter().map(|&c| c.to_owned()).collect()
+            )),
+            "{pattern:?} in {source:?}"
+        );
+    }
+
+    #[track_caller]
+    fn assert_no_match(source: &str, pattern: &str) {
+        assert_eq!(
+            find(source, pattern).unwrap(),
+            None,
+            "{pattern:?} in {source:?}"
+        );
+    }
+
+    #[test]
+    fn classes_and_sets() {
+        assert_matches("x = 0x1F;", "%x+;", "1F;", &[]);
+        assert_matches("a1 b2", "%a%d", "a1", &[]);
+        assert_matches("hello, world", "%p", ",", &[]);
+        assert_matches("ABCdef", "%l+", "def", &[]);
+        assert_matches("tab\there", "%s", "\t", &[]);
+        assert_matches("123abc", "%D+", "abc", &[]);
+        assert_matches("a-b", "[a%-]+", "a-", &[]);
+        assert_matches("[]]", "[]]", "]", &[]);
+        assert_matches("b3", "[^%a]", "3", &[]);
+        assert_matches("m", "[a-z]", "m", &[]);
+        assert_matches("a.b", "%.", ".", &[]);
+        assert_no_match("hello", "%d");
+    }
+
+    #[test]
+    fn repetition() {
+        assert_matches("aaab", "a*", "aaa", &[]);
+        assert_matches("baaa", "a*", "", &[]);
+        assert_matches("baaa", "a+", "aaa", &[]);
+        assert_matches("aaab", "a-b", "aaab", &[]);
+        assert_matches("<a><b>", "<.->", "<a>", &[]);
+        assert_matches("<a><b>", "<.*>", "<a><b>", &[]);
+        assert_matches("color", "colou?r", "color", &[]);
+        assert_no_match("aaa", "a-b");
+    }
+
+    #[test]
+    fn anchors() {
+        assert_matches("2024-01-15", "(%d+)-(%d+)$", "01-15", &["01", "15"]);
+        assert_matches("abc", "^ab", "ab", &[]);
+        assert_no_match("cab", "^ab");
+        assert_matches("a$b", "a$b", "a$b", &[]);
+    }
+
+    #[test]
+    fn captures() {
+        assert_matches(
+            "  key = value  ",
+            "^%s*(%S+)%s*=%s*(%S+)",
+            "  key = value",
+            &["key", "value"],
+        );
+        assert_matches("hello", "()ll()", "ll", &["3", "5"]);
+        assert_matches("abcabc", "(abc)%1", "abcabc", &["abc"]);
+        assert_matches("xyyx", "(x)(y+)%1", "xyyx", &["x", "yy"]);
+        assert_matches("ab", "((a)b)", "ab", &["ab", "a"]);
+    }
+
+    #[test]
+    fn balance_and_frontier() {
+        assert_matches("f(a(b)c)d", "%b()", "(a(b)c)", &[]);
+        assert_no_match("f(a(b", "%b()");
+        assert_matches("THE (quick) fox", "%f[%a]%a+", "THE", &[]);
+        assert_matches("hello world", "%f[%w]%w+$", "world", &[]);
+        assert_matches("a1", "%f[%d]", "", &[]);
+    }
+
+    #[test]
+    fn malformed_patterns() {
+        let error = |pattern| find("a", pattern).unwrap_err().to_string();
+        assert_eq!(error("%"), "malformed pattern (ends with '%')");
+        assert_eq!(error("[a"), "malformed pattern (missing ']')");
+        assert_eq!(error("%b"), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(error("%f"), "missing '[' after '%f' in pattern");
+        assert_eq!(error("(a)%2"), "invalid capture index %2");
+        assert_eq!(error("(a"), "unfinished capture");
+        assert_eq!(error("a)"), "invalid pattern capture");
+        assert_eq!(error(&"()".repeat(33)), "too many captures");
+
+        let source = "a".repeat(300);
+        let pattern = "a?".repeat(300);
+        let error = find(&source, &pattern).unwrap_err();
+        assert!(matches!(error, Error::TooComplex));
+    }
+
+    #[test]
+    fn plain_search() {
+        assert!(is_plain(b"a)b"));
+        assert!(!is_plain(b"a.b"));
+        assert_eq!(find_plain(b"a.b", b"."), Some(1));
+        assert_eq!(find_plain(b"abc", b""), Some(0));
+        assert_eq!(find_plain(b"abc", b"abcd"), None);
+    }
+
+    #[test]
+    fn string_library_functions() {
+        assert_runs(
+            "return (string.gsub('hello world', '(%w+)', '<%1>'))",
+            "<hello> <world>",
+        );
+        assert_runs("return (string.gsub('abc', '', '-'))", "-a-b-c-");
+        assert_runs("return (string.gsub('hello', 'l', {l = 'L'}))", "heLLo");
+        assert_runs(
+            "return (string.gsub('hello', '%w', function(c) \
+               if c ~= 'l' then return c:upper() end \
+             end))",
+            "HEllO",
+        );
+        assert_runs("return select(2, string.gsub('abc', '%w', '%%%0', 2))", "2");
+        assert_runs(
+            "return select(2, pcall(string.gsub, 'a', 'a', '%x'))",
+            "invalid use of '%' in replacement string",
+        );
+        assert_runs(
+            "local r = {} \
+             for k, v in string.gmatch('a=1, b=2', '(%w+)=(%w+)') do r[#r + 1] = k .. v end \
+             return table.concat(r, ';')",
+            "a1;b2",
+        );
+        assert_runs(
+            "local a, b = string.find('a.b', '.', 1, true) return a .. b",
+            "22",
+        );
+        assert_runs("local a, b = string.find('abc', '', 4) return a .. b", "43");
+        assert_runs("return string.find('abc', '', 5)", "nil");
+    }
+}
@@ -0,0 +1,87 @@
+//! References that keep values alive for Rust code, as the reference implementation's
+//! `luaL_ref` does, but released by dropping them.
+
+use {
+    super::{State, Value},
+    std::{cell::RefCell, rc::Rc},
+};
+
+/// The first key in the registry that references use, after those of the main thread and the
+/// table of globals.
+const FIRST_KEY: i64 = 3;
+
+/// A value that the collector will not free for as long as a clone of this exists.
+///
+/// The value is held in the registry of the state that created the reference, and must only
+/// be used with that state.
+#[derive(Clone, Debug)]
+pub struct Ref(Rc<Entry>);
+
+#[derive(Debug)]
+struct Entry {
+    key: i64,
+    value: Value,
+    released: Released,
+}
+
+impl Ref {
+    pub fn value(&self) -> Value {
+        self.0.value
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.released.borrow_mut().push(self.key);
+    }
+}
+
+/// The keys of dropped references, whose registry entries are yet to be cleared. They are
+/// shared with the references, which may outlive the state.
+type Released = Rc<RefCell<Vec<i64>>>;
+
+/// The keys of the registry that references use.
+#[derive(Default)]
+pub(super) struct References {
+    released: Released,
+
+    /// Keys that have been cleared and can be used again.
+    free: Vec<i64>,
+
+    /// The key after the highest in use.
+    next: i64,
+}
+
+impl State {
+    /// Keeps `value` alive until the returned reference, and every clone of it, is dropped.
+    pub fn create_ref(&mut self, value: Value) -> Ref {
+        self.release_refs();
+
+        let references = &mut self.references;
+        let key = references.free.pop().unwrap_or_else(|| {
+            let key = references.next.max(FIRST_KEY);
+            references.next = key + 1;
+            key
+        });
+
+        let registry = self.heap.registry();
+        self.heap[registry].set_int(key, value);
+
+        Ref(Rc::new(Entry {
+            key,
+            value,
+            released: self.references.released.clone(),
+        }))
+    }
+
+    /// Clears the registry entries of the references that have been dropped, so that their
+    /// values can be collected.
+    pub(super) fn release_refs(&mut self) {
+        let released = self.references.released.take();
+        let registry = self.heap.registry();
+        for key in released {
+            self.heap[registry].set_int(key, Value::Nil);
+            self.references.free.push(key);
+        }
+    }
+}
@@ -0,0 +1,1488 @@
+use {
+    super::{
+        reference::References,
+        thread::{self, Status},
+        Capture, Closure, Compiler, Error, Function, FunctionRef, Heap, Hook, HookEvent, Host,
+        Metamethod, Prototype, Result, StdHost, TableRef, Thread, ThreadRef, Upvalue, UpvalueRef,
+        Value, Variable,
+    },
+    corosensei::CoroutineResult,
+    std::{fmt::Write, mem, ops::Range, ptr, rc::Rc},
+};
+
+/// How deeply calls through [`State::call`] may nest before the native stack is considered at
+/// risk. Calls that compiled code runs itself do not count.
+const MAX_CALL_DEPTH: usize = 200;
+
+/// How many values the stack of a thread may hold, which is what limits how deeply calls that
+/// compiled code runs itself may nest.
+const MAX_STACK_SIZE: usize = 1_000_000;
+
+/// The index in the registry of the main thread.
+const MAIN_THREAD: i64 = 1;
+
+/// The index in the registry of the table of globals.
+const GLOBALS: i64 = 2;
+
+/// The first byte of a precompiled chunk, which no source text starts with.
+const BINARY_SIGNATURE: u8 = 0x1b;
+
+/// A Lua state: the heap together with the value stack that functions communicate through.
+///
+/// A call pushes the function followed by its arguments and invokes [`State::call`], which
+/// replaces them with the results. The stack is also the collector's root set, so a value held
+/// only in a Rust local must not be relied upon after anything that can allocate or call.
+///
+/// A coroutine that yields from within native calls keeps pointers to the state on its native
+/// stack, so the state must not move while any coroutine is suspended, which is why
+/// [`crate::Lua`] keeps its state in a box.
+pub struct State {
+    pub heap: Heap,
+
+    /// The running thread, whose stack, frames, to-be-closed variables, message handler and
+    /// hook are the ones below.
+    pub(super) thread: ThreadRef,
+
+    pub(super) stack: Vec<Value>,
+    pub(super) frames: Vec<Frame>,
+
+    /// The number of arguments of the tail call the running function has asked for with
+    /// [`State::tail_call`].
+    tail_call: Option<usize>,
+
+    /// The stack indices of the live to-be-closed variables, in the order they were declared.
+    to_be_closed: Vec<usize>,
+
+    /// The handler of the innermost `xpcall`, which has yet to see an error. It is taken by the
+    /// first error raised, so that it runs only once, at the point the error was raised.
+    message_handler: Option<Value>,
+
+    /// What is called as the thread runs, with how many more instructions are to run before
+    /// its next count event, and whether it is running, in which case it is not called again.
+    pub(super) hook: Option<Hook>,
+    pub(super) hook_count: u32,
+    pub(super) in_hook: bool,
+
+    /// Set while `__gc` metamethods are running, so that they are not started again from
+    /// within themselves.
+    finalizing: bool,
+
+    /// How many calls through [`State::call`] are running inside one another on the running
+    /// thread's native stack.
+    call_depth: usize,
+
+    /// How many coroutines are running inside one another, each on a native stack of its own.
+    resume_depth: usize,
+
+    /// What [`State::load`] turns source text into functions with.
+    compiler: Option<Rc<Compiler>>,
+
+    /// What the libraries that reach outside of the state go through.
+    host: Rc<dyn Host>,
+
+    /// The registry keys of the values that Rust code holds references to.
+    pub(super) references: References,
+}
+
+pub(super) struct Frame {
+    pub(super) function: FunctionRef,
+
+    /// The stack index of the value that was called, below the extra arguments.
+    pub(super) function_index: usize,
+
+    /// The stack index of the first argument.
+    pub(super) base: usize,
+
+    pub(super) nargs: usize,
+
+    /// Where the extra arguments of a vararg Lua function are kept, below its frame.
+    pub(super) varargs: Range<usize>,
+
+    /// The instruction that a Lua function is running, as last reported with
+    /// [`State::set_position`], which indexes [`Prototype::positions`].
+    pub(super) position: Option<u32>,
+
+    /// Whether the function was entered by a tail call, which left no trace of its caller.
+    pub(super) tail_call: bool,
+
+    /// While a call or return hook runs, the values that are passed in or out, as the
+    /// position in the frame of the first, counting from 1, and their number.
+    pub(super) transfer: Option<(usize, usize)>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let globals = heap.new_table();
+        let registry = heap.registry();
+        heap[registry].set_int(GLOBALS, Value::Table(globals));
+
+        let mut main = Thread::new(Value::Nil);
+        main.stack.clear();
+        main.status = Status::Running;
+        let thread = heap.new_thread(main);
+        heap[registry].set_int(MAIN_THREAD, Value::Thread(thread));
+
+        Self {
+            heap,
+            thread,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            tail_call: None,
+            to_be_closed: Vec::new(),
+            message_handler: None,
+            hook: None,
+            hook_count: 0,
+            in_hook: false,
+            finalizing: false,
+            call_depth: 0,
+            resume_depth: 0,
+            compiler: None,
+            host: Rc::new(StdHost::new()),
+            references: References::default(),
+        }
+    }
+
+    pub fn globals(&self) -> TableRef {
+        match self.heap[self.heap.registry()].get_int(GLOBALS) {
+            Value::Table(globals) => globals,
+            _ => unreachable!("the registry has lost the table of globals"),
+        }
+    }
+
+    pub fn push(&mut self, value: impl Into<Value>) {
+        self.stack.push(value.into());
+    }
+
+    pub fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+
+    /// Inserts `value` below the top `count` values on the stack.
+    pub fn insert_below(&mut self, count: usize, value: Value) {
+        let index = self.stack.len() - count;
+        self.stack.insert(index, value);
+    }
+
+    /// The number of values on the stack.
+    pub fn top(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// The values on the stack from `index` to the top.
+    pub fn values_from(&self, index: usize) -> &[Value] {
+        &self.stack[index..]
+    }
+
+    /// Pops values or pushes nils until the stack holds `top` values.
+    pub fn set_top(&mut self, top: usize) {
+        self.stack.resize(top, Value::Nil);
+    }
+
+    /// The number of arguments passed to the running function.
+    pub fn arg_count(&self) -> usize {
+        self.frame().nargs
+    }
+
+    /// The running function's `n`th argument, counting from 1, or nil if there is no such
+    /// argument.
+    pub fn arg(&self, n: usize) -> Value {
+        let frame = self.frame();
+        if (1..=frame.nargs).contains(&n) {
+            self.stack[frame.base + n - 1]
+        } else {
+            Value::Nil
+        }
+    }
+
+    /// Pushes the extra arguments of the running vararg function, returning how many there
+    /// are.
+    pub fn push_varargs(&mut self) -> usize {
+        let varargs = self.frame().varargs.clone();
+        let count = varargs.len();
+        self.stack.extend_from_within(varargs);
+        count
+    }
+
+    /// The value in the given slot of the running function's frame, where the first slots
+    /// hold its arguments and the rest its locals.
+    pub fn local(&self, slot: usize) -> Value {
+        self.stack[self.frame().base + slot]
+    }
+
+    pub fn set_local(&mut self, slot: usize, value: Value) {
+        let base = self.frame().base;
+        self.stack[base + slot] = value;
+    }
+
+    /// The running function's `n`th upvalue, counting from 1.
+    pub fn upvalue(&self, n: usize) -> Value {
+        match &self.heap[self.frame().function] {
+            Function::Native(native) => native.upvalues[n - 1],
+            Function::Lua(closure) => self.upvalue_value(closure.upvalues[n - 1]),
+        }
+    }
+
+    pub fn set_upvalue(&mut self, n: usize, value: Value) {
+        let function = self.frame().function;
+        match &mut self.heap[function] {
+            Function::Native(native) => native.upvalues[n - 1] = value,
+
+            Function::Lua(closure) => {
+                let key = closure.upvalues[n - 1];
+                self.set_upvalue_value(key, value);
+            }
+        }
+    }
+
+    /// The value of a variable captured by a closure, wherever it currently lives.
+    pub(super) fn upvalue_value(&self, key: UpvalueRef) -> Value {
+        match self.heap[key] {
+            Upvalue::Open(thread, index) if thread == self.thread => self.stack[index],
+            Upvalue::Open(thread, index) => self.heap[thread].stack[index],
+            Upvalue::Closed(value) => value,
+        }
+    }
+
+    pub(super) fn set_upvalue_value(&mut self, key: UpvalueRef, value: Value) {
+        match self.heap[key] {
+            Upvalue::Open(thread, index) if thread == self.thread => self.stack[index] = value,
+            Upvalue::Open(thread, index) => self.heap[thread].stack[index] = value,
+            Upvalue::Closed(_) => self.heap[key] = Upvalue::Closed(value),
+        }
+    }
+
+    /// Creates a closure of `prototype`, capturing locals from the running function's frame
+    /// and upvalues from the running function itself as the prototype dictates.
+    pub fn new_closure(&mut self, prototype: Rc<Prototype>) -> FunctionRef {
+        let upvalues = prototype
+            .captures
+            .iter()
+            .map(|&capture| match capture {
+                Capture::Local(slot) => {
+                    let index = self.frame().base + slot;
+                    self.heap.find_upvalue(self.thread, index)
+                }
+
+                Capture::Upvalue(index) => self.enclosing_upvalue(index),
+
+                Capture::Environment => {
+                    let globals = Value::Table(self.globals());
+                    self.heap.new_upvalue(Upvalue::Closed(globals))
+                }
+            })
+            .collect();
+
+        self.heap.new_function(Function::Lua(Closure {
+            prototype,
+            upvalues,
+        }))
+    }
+
+    /// Sets what [`State::load`] compiles source text with.
+    pub fn set_compiler(
+        &mut self,
+        compiler: impl Fn(&mut State, &[u8], &str) -> Result<Rc<Prototype>> + 'static,
+    ) {
+        self.compiler = Some(Rc::new(compiler));
+    }
+
+    /// Sets what the `io` and `os` libraries and the functions that load files reach the
+    /// filesystem, processes and environment through. A new state uses [`StdHost`].
+    pub fn set_host(&mut self, host: impl Host + 'static) {
+        self.host = Rc::new(host);
+    }
+
+    pub fn host(&self) -> Rc<dyn Host> {
+        self.host.clone()
+    }
+
+    /// Loads a chunk as `load` does, returning its main function without running it.
+    ///
+    /// `chunk_name` becomes the function's [`Prototype::source`], and `mode` says whether the
+    /// chunk may be text (`t`), binary (`b`) or either. If `env` is given, it replaces the
+    /// table of globals as the value of the function's first upvalue, its environment.
+    pub fn load(
+        &mut self,
+        chunk: &[u8],
+        chunk_name: &str,
+        mode: &str,
+        env: Option<Value>,
+    ) -> Result<FunctionRef> {
+        let kind = if chunk.first() == Some(&BINARY_SIGNATURE) {
+            "binary"
+        } else {
+            "text"
+        };
+
+        if !mode.contains(&kind[..1]) {
+            return Err(Error::ChunkMode(kind, mode.to_owned()));
+        }
+
+        let compiler = self.compiler.clone().ok_or(Error::NoCompiler)?;
+        let prototype = compiler(self, chunk, chunk_name)?;
+        let function = self.new_closure(prototype);
+
+        if let Some(env) = env {
+            let upvalue = self.heap.new_upvalue(Upvalue::Closed(env));
+            if let Function::Lua(closure) = &mut self.heap[function] {
+                if let Some(first) = closure.upvalues.first_mut() {
+                    *first = upvalue;
+                }
+            }
+        }
+
+        Ok(function)
+    }
+
+    fn enclosing_upvalue(&self, index: usize) -> UpvalueRef {
+        match &self.heap[self.frame().function] {
+            Function::Lua(closure) => closure.upvalues[index],
+            Function::Native(_) => unreachable!("a native function has no shared upvalues"),
+        }
+    }
+
+    /// Makes the local in the given slot of the running function's frame a to-be-closed
+    /// variable, whose value's `__close` metamethod is called when its scope is left. Nil and
+    /// false are accepted and ignored.
+    pub fn mark_to_be_closed(&mut self, slot: usize, name: &str) -> Result<()> {
+        let index = self.frame().base + slot;
+        let value = self.stack[index];
+        if !value.is_truthy() {
+            return Ok(());
+        }
+
+        if self.heap.metamethod_of(value, Metamethod::Close).is_nil() {
+            return Err(Error::NonClosable(name.to_owned()));
+        }
+
+        self.to_be_closed.push(index);
+        Ok(())
+    }
+
+    /// Closes the locals in the given slot of the running function's frame and above, as
+    /// their scope ends normally: their upvalues are closed, and the to-be-closed variables
+    /// among them are closed in reverse order.
+    pub fn close(&mut self, slot: usize) -> Result<()> {
+        let level = self.frame().base + slot;
+        self.close_from(level)
+    }
+
+    fn close_from(&mut self, level: usize) -> Result<()> {
+        self.heap.close_upvalues(&self.stack, level);
+
+        while let Some(&index) = self.to_be_closed.last() {
+            if index < level {
+                break;
+            }
+
+            // The variable is removed first, so that if its handler fails, the error closes
+            // only the variables that remain.
+            self.to_be_closed.pop();
+            self.close_value(self.stack[index], Value::Nil)?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes everything at `level` in the stack and above as an error propagates through
+    /// them. Each `__close` handler receives the error, and an error raised by a handler
+    /// replaces the one being propagated.
+    fn close_on_error(&mut self, level: usize, mut error: Error) -> Error {
+        self.heap.close_upvalues(&self.stack, level);
+
+        while let Some(&index) = self.to_be_closed.last() {
+            if index < level {
+                break;
+            }
+
+            self.to_be_closed.pop();
+            let value = self.error_value(error.clone());
+            if let Err(new) = self.close_value(self.stack[index], value) {
+                error = new;
+            }
+        }
+
+        error
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("no function is running")
+    }
+
+    /// Calls the value below the top `nargs` values on the stack, passing those values as its
+    /// arguments, and replaces them all with the results. If `nresults` is given, the results
+    /// are truncated or padded with nils to that many. Returns the number of results left on
+    /// the stack.
+    ///
+    /// Nothing is allocated beyond the stack itself, so a call with a fixed number of
+    /// arguments and results costs no more than the frame it pushes.
+    ///
+    /// Values that are not functions are called through their `__call` metamethod, which
+    /// receives the value itself as an extra first argument. If the call fails, the function
+    /// and its arguments are removed from the stack.
+    pub fn call(&mut self, nargs: usize, nresults: Option<usize>) -> Result<usize> {
+        let function_index = self.stack.len() - nargs - 1;
+
+        // Each call runs on the native stack of the one that made it, unlike the calls that
+        // compiled code runs itself, which only use the value stack.
+        if self.call_depth >= MAX_CALL_DEPTH {
+            let error = self.locate_error(Error::StackOverflow, 0);
+            let error = self.handle_error(error);
+            self.stack.truncate(function_index);
+            return Err(error);
+        }
+
+        self.call_depth += 1;
+        let result = self.run_call(nargs);
+        self.call_depth -= 1;
+        result?;
+
+        if let Some(nresults) = nresults {
+            self.stack.resize(function_index + nresults, Value::Nil);
+        }
+
+        Ok(self.stack.len() - function_index)
+    }
+
+    /// Runs a call begun with the top `nargs` values as its arguments to its end, leaving its
+    /// results in place of the function and its arguments.
+    fn run_call(&mut self, nargs: usize) -> Result<()> {
+        let mut function = self.enter_call(nargs)?;
+
+        // Tail calls replace the function that made them by going around again, so they grow
+        // neither the frames nor the native stack.
+        loop {
+            let callback = match &self.heap[function] {
+                Function::Native(native) => native.callback.clone(),
+                Function::Lua(closure) => closure.prototype.code.clone(),
+            };
+
+            let count = match callback(self) {
+                Ok(count) => count,
+                Err(error) => {
+                    self.tail_call = None;
+                    return Err(self.unwind_call(error));
+                }
+            };
+
+            match self.tail_call.take() {
+                Some(nargs) => function = self.replace_call(nargs)?,
+                None => return self.leave_call(count),
+            }
+        }
+    }
+
+    /// Begins a call of the value below the top `nargs` values on the stack, with those values
+    /// as its arguments: finds the function to run, pushes its frame and runs the call hook.
+    /// The function is then to be run in the frame, and the call ended with
+    /// [`State::leave_call`] or [`State::unwind_call`].
+    ///
+    /// If the call cannot begin, the function and its arguments are removed from the stack and
+    /// the error is returned ready to propagate, as it is from the other steps of a call.
+    pub(crate) fn enter_call(&mut self, nargs: usize) -> Result<FunctionRef> {
+        let function_index = self.stack.len() - nargs - 1;
+        self.push_frame(function_index, nargs, false)
+    }
+
+    /// Ends the running function's call with the top `nargs` values on the stack, as
+    /// `return f(...)` does: the function below them replaces the running one, as though it
+    /// had been called in its place, and its frame is pushed as [`State::enter_call`] does.
+    pub(crate) fn replace_call(&mut self, nargs: usize) -> Result<FunctionRef> {
+        let function_index = self.frame().function_index;
+        self.pop_frame(nargs + 1, true)?;
+        self.push_frame(function_index, nargs, true)
+    }
+
+    /// Ends the running function's call once its `count` results are on top of the stack,
+    /// which then replace the function and its arguments.
+    pub(crate) fn leave_call(&mut self, count: usize) -> Result<()> {
+        self.pop_frame(count, false)
+    }
+
+    /// Ends the running function's call with an error raised in it, returning the error as it
+    /// propagates to the caller. The message handler runs before anything is unwound, so that
+    /// it can still inspect the frame that raised the error, and then everything the function
+    /// left open is closed, and it is removed from the stack with its arguments.
+    pub(crate) fn unwind_call(&mut self, error: Error) -> Error {
+        let frame = self.frame();
+        let (function, function_index) = (frame.function, frame.function_index);
+
+        // A native function's errors are reported at the point it was called from.
+        let level = match self.heap[function] {
+            Function::Native(_) => 1,
+            Function::Lua(_) => 0,
+        };
+
+        let error = self.locate_error(error, level);
+        let error = self.handle_error(error);
+        let error = self.close_on_error(function_index + 1, error);
+        self.frames.pop();
+        self.stack.truncate(function_index);
+        error
+    }
+
+    fn push_frame(
+        &mut self,
+        function_index: usize,
+        mut nargs: usize,
+        tail_call: bool,
+    ) -> Result<FunctionRef> {
+        let function = match self.callee(function_index, &mut nargs) {
+            Ok(function) => function,
+            Err(error) => {
+                // The error is raised by the calling function, not the value it called,
+                // which is named after the variable the caller read it from.
+                let error =
+                    error.name_operand(|_| self.callee_name(&self.frames, self.frames.len()));
+                let error = self.locate_error(error, 0);
+                let error = self.handle_error(error);
+                self.stack.truncate(function_index);
+                return Err(error);
+            }
+        };
+
+        self.check_gc();
+
+        let mut base = function_index + 1;
+        let mut varargs = base..base;
+
+        if let Function::Lua(closure) = &self.heap[function] {
+            let prototype = closure.prototype.clone();
+            self.stack
+                .resize(base + nargs.max(prototype.parameters), Value::Nil);
+
+            if prototype.is_vararg {
+                // The fixed parameters are moved above the extra arguments, so that the extra
+                // arguments stay put and the frame's slots are contiguous.
+                let parameters = base..base + prototype.parameters;
+                varargs = parameters.end..self.stack.len();
+                base = self.stack.len();
+                self.stack.extend_from_within(parameters.clone());
+                self.stack[parameters].fill(Value::Nil);
+            } else {
+                self.stack.truncate(base + prototype.parameters);
+            }
+
+            nargs = prototype.parameters;
+        }
+
+        self.frames.push(Frame {
+            function,
+            function_index,
+            base,
+            nargs,
+            varargs,
+            position: None,
+            tail_call,
+            transfer: None,
+        });
+
+        let event = match tail_call {
+            true => HookEvent::TailCall,
+            false => HookEvent::Call,
+        };
+
+        match self.run_hook(event, (1, nargs)) {
+            Ok(()) => Ok(function),
+            Err(error) => Err(self.unwind_call(error)),
+        }
+    }
+
+    /// Removes the running function's frame, leaving the top `count` values in place of the
+    /// function and its arguments.
+    ///
+    /// Whatever the function has left open is closed first, as `return` would have done, and
+    /// the return hook sees the results last. A function that makes a tail call returns when
+    /// the function it called does, so it has no return event of its own.
+    fn pop_frame(&mut self, count: usize, tail_call: bool) -> Result<()> {
+        let frame = self.frame();
+        let (function_index, base) = (frame.function_index, frame.base);
+
+        let result = self
+            .close_from(function_index + 1)
+            .and_then(|()| match tail_call {
+                true => Ok(()),
+                false => {
+                    let first = self.stack.len() - count - base + 1;
+                    self.run_hook(HookEvent::Return, (first, count))
+                }
+            });
+
+        if let Err(error) = result {
+            return Err(self.unwind_call(error));
+        }
+
+        self.frames.pop();
+        debug_assert!(count < self.stack.len() - base + 1);
+        let results = self.stack.len() - count;
+        self.stack.drain(function_index..results);
+        Ok(())
+    }
+
+    /// Makes the running function return the results of calling the value below the top
+    /// `nargs` values on the stack with those values, as `return f(...)` does, once it returns
+    /// what this returns.
+    ///
+    /// The call is made after the running function's frame has been removed, so a chain of
+    /// tail calls of any length runs in constant space.
+    pub fn tail_call(&mut self, nargs: usize) -> Result<usize> {
+        self.tail_call = Some(nargs);
+        Ok(nargs + 1)
+    }
+
+    /// Like [`State::call`], but an error is caught rather than returned: the stack is
+    /// restored to what it was below the function, everything the failed calls left open is
+    /// closed, and the error is returned as a Lua value.
+    ///
+    /// The message handler of an enclosing [`State::protected_call_with_handler`] does not see
+    /// errors caught here.
+    pub fn protected_call(
+        &mut self,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> std::result::Result<usize, Value> {
+        let previous = self.message_handler.take();
+        let result = self.call(nargs, nresults);
+        self.message_handler = previous;
+        result.map_err(|error| self.error_value(error))
+    }
+
+    /// Like [`State::protected_call`], but calls `handler` with the error value at the point
+    /// the error is raised, before anything is unwound, and returns its result instead.
+    pub fn protected_call_with_handler(
+        &mut self,
+        nargs: usize,
+        nresults: Option<usize>,
+        handler: Value,
+    ) -> std::result::Result<usize, Value> {
+        // The handler is kept below the function, where the collector can see it.
+        let handler_index = self.stack.len() - nargs - 1;
+        self.stack.insert(handler_index, handler);
+
+        let previous = self.message_handler.replace(handler);
+        let result = self.call(nargs, nresults);
+        self.message_handler = previous;
+
+        self.stack.remove(handler_index);
+        result.map_err(|error| self.error_value(error))
+    }
+
+    /// Records the instruction that the running Lua function has reached, which error
+    /// messages and tracebacks report the source position of. Compiled code calls this before
+    /// anything that can raise an error or call a function, much as a return address would
+    /// record it in native code.
+    pub fn set_position(&mut self, position: u32) {
+        self.frames
+            .last_mut()
+            .expect("no function is running")
+            .position = Some(position);
+    }
+
+    /// The position in the source of the function running at the given level of the call
+    /// stack, where level 0 is the running function, as `chunk:line: `. This is empty for a
+    /// native function or if no position has been reported.
+    pub fn location(&self, level: usize) -> String {
+        match self.frames.iter().rev().nth(level) {
+            Some(frame) => match (&self.heap[frame.function], self.current_line(frame)) {
+                (Function::Lua(closure), Some(line)) => {
+                    format!("{}:{line}: ", short_source(&closure.prototype.source))
+                }
+
+                _ => String::new(),
+            },
+
+            None => String::new(),
+        }
+    }
+
+    pub(super) fn current_line(&self, frame: &Frame) -> Option<u32> {
+        match &self.heap[frame.function] {
+            Function::Lua(closure) => {
+                let position = frame.position?;
+                Some(closure.prototype.positions[position as usize].line)
+            }
+
+            Function::Native(_) => None,
+        }
+    }
+
+    /// Describes the call stack from the given level down, one frame per line, as
+    /// `debug.traceback` does. `message` is put on the line before.
+    ///
+    /// Only the first and last few frames of a deep stack are listed.
+    pub fn traceback(&self, message: Option<&str>, level: usize) -> String {
+        self.thread_traceback(self.thread, message, level)
+    }
+
+    /// Like [`State::traceback`], but describes the call stack of `thread`, which need not be
+    /// the running one.
+    pub fn thread_traceback(
+        &self,
+        thread: ThreadRef,
+        message: Option<&str>,
+        level: usize,
+    ) -> String {
+        const FIRST_LEVELS: usize = 10;
+        const LAST_LEVELS: usize = 11;
+
+        let mut traceback = String::new();
+        if let Some(message) = message {
+            traceback.push_str(message);
+            traceback.push('\n');
+        }
+        traceback.push_str("stack traceback:");
+
+        let frames = self.frames_of(thread);
+        let depth = frames.len().saturating_sub(level);
+        let skipped = depth.saturating_sub(FIRST_LEVELS + LAST_LEVELS);
+
+        for n in 0..depth {
+            if skipped > 0 && n == FIRST_LEVELS {
+                let _ = write!(traceback, "\n\t...\t(skipping {skipped} levels)");
+            }
+
+            if skipped > 0 && (FIRST_LEVELS..FIRST_LEVELS + skipped).contains(&n) {
+                continue;
+            }
+
+            let index = frames.len() - 1 - level - n;
+            let frame = &frames[index];
+            traceback.push_str("\n\t");
+
+            match &self.heap[frame.function] {
+                Function::Native(_) => traceback.push_str("[C]:"),
+
+                Function::Lua(closure) => {
+                    traceback.push_str(&short_source(&closure.prototype.source));
+                    match self.current_line(frame) {
+                        Some(line) => {
+                            let _ = write!(traceback, ":{line}:");
+                        }
+                        None => traceback.push(':'),
+                    }
+                }
+            }
+
+            traceback.push_str(" in ");
+            traceback.push_str(&self.function_description(frames, index));
+            if frame.tail_call {
+                traceback.push_str("\n\t(...tail calls...)");
+            }
+        }
+
+        traceback
+    }
+
+    /// Describes the function running in the frame at `index` for a traceback: by the global
+    /// it is stored in, by how its caller reached it, or by where it was defined.
+    fn function_description(&self, frames: &[Frame], index: usize) -> String {
+        let frame = &frames[index];
+
+        let globals = &self.heap[self.globals()];
+        let mut key = Value::Nil;
+        while let Ok(Some((next, value))) = globals.next(key) {
+            if let (Value::String(name), Value::Function(function)) = (next, value) {
+                if function == frame.function {
+                    let name = String::from_utf8_lossy(&self.heap[name]);
+                    return format!("function '{name}'");
+                }
+            }
+            key = next;
+        }
+
+        match (self.callee_name(frames, index), &self.heap[frame.function]) {
+            (Some(Variable::Global(name)), _) => format!("function '{name}'"),
+            (Some(variable), _) => variable.to_string(),
+            (None, Function::Native(_)) => "?".to_owned(),
+
+            (None, Function::Lua(closure)) if closure.prototype.line_defined == 0 => {
+                "main chunk".to_owned()
+            }
+
+            (None, Function::Lua(closure)) => format!(
+                "function <{}:{}>",
+                short_source(&closure.prototype.source),
+                closure.prototype.line_defined
+            ),
+        }
+    }
+
+    /// The variable that the function running in the frame at `index` was read from by its
+    /// caller, if the caller is a Lua function that knows. A function entered by a tail call
+    /// has no name, since the frame below it is not the one that called it.
+    pub(super) fn callee_name(&self, frames: &[Frame], index: usize) -> Option<Variable> {
+        if frames.get(index).is_some_and(|frame| frame.tail_call) {
+            return None;
+        }
+
+        let caller = &frames[index.checked_sub(1)?];
+        match &self.heap[caller.function] {
+            Function::Lua(closure) => closure.prototype.positions[caller.position? as usize]
+                .callee
+                .clone(),
+            Function::Native(_) => None,
+        }
+    }
+
+    /// Attaches the location of the function at `level` to an error as it is raised. Values
+    /// raised by `error` and errors that already have a location are left as they are.
+    fn locate_error(&self, error: Error, level: usize) -> Error {
+        match error {
+            Error::Value(_) | Error::Located { .. } | Error::Syntax(_) => error,
+
+            error => Error::Located {
+                location: self.location(level),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Like [`State::protected_call`], but an error is returned as a report for the user: its
+    /// message followed by a traceback of the stack where it was raised, as a standalone
+    /// interpreter prints for an error that nothing caught.
+    pub fn call_with_traceback(
+        &mut self,
+        nargs: usize,
+        nresults: Option<usize>,
+    ) -> std::result::Result<usize, String> {
+        let handler = self.heap.new_function(Function::native(|state| {
+            let message = state.error_message(state.arg(1))?;
+            let traceback = state.traceback(Some(&message), 1);
+            let traceback = state.heap.intern(traceback.as_bytes());
+            state.push(traceback);
+            Ok(1)
+        }));
+
+        self.protected_call_with_handler(nargs, nresults, Value::Function(handler))
+            .map_err(|report| match report {
+                Value::String(report) => String::from_utf8_lossy(&self.heap[report]).into_owned(),
+                _ => unreachable!("the message handler returns a string"),
+            })
+    }
+
+    /// Runs the message handler on a newly raised error, if there is a handler that has yet to
+    /// see one.
+    fn handle_error(&mut self, error: Error) -> Error {
+        let Some(handler) = self.message_handler.take() else {
+            return error;
+        };
+
+        let value = self.error_value(error);
+        match self.call_metamethod(handler, &[value]) {
+            Ok(value) => Error::Value(value),
+            Err(_) => Error::ErrorHandling,
+        }
+    }
+
+    /// The message of an error value: a string as it is, a value with a `__tostring`
+    /// metamethod as that converts it, and anything else described by its type.
+    pub fn error_message(&mut self, value: Value) -> Result<String> {
+        match value {
+            Value::String(message) => Ok(String::from_utf8_lossy(&self.heap[message]).into_owned()),
+
+            value
+                if !self
+                    .heap
+                    .metamethod_of(value, Metamethod::Tostring)
+                    .is_nil() =>
+            {
+                let message = self.tostring(value)?;
+                Ok(String::from_utf8_lossy(&self.heap[message]).into_owned())
+            }
+
+            value => Ok(format!(
+                "(error object is a {} value)",
+                self.type_name(value)
+            )),
+        }
+    }
+
+    /// The Lua value of an error: the value itself if one was raised, otherwise the message.
+    pub fn error_value(&mut self, error: Error) -> Value {
+        match error {
+            Error::Value(value) => value,
+            error => Value::String(self.heap.intern(error.to_string().as_bytes())),
+        }
+    }
+
+    /// Finds the function to run for a call of the value at `function_index` in the stack,
+    /// inserting `__call` handlers below the value as needed, and checks that there is room
+    /// for another frame.
+    fn callee(&mut self, function_index: usize, nargs: &mut usize) -> Result<FunctionRef> {
+        let original_nargs = *nargs;
+
+        let function = loop {
+            match self.stack[function_index] {
+                Value::Function(function) => break function,
+
+                callee => {
+                    let handler = self.heap.metamethod_of(callee, Metamethod::Call);
+                    if handler.is_nil() {
+                        let operand = (*nargs == original_nargs).then_some(0);
+                        return Err(self.operand_error("call", callee, operand));
+                    }
+
+                    // A handler can itself be called through `__call`, each level adding an
+                    // argument, so a cycle of them would otherwise go on forever.
+                    if *nargs - original_nargs >= MAX_CALL_DEPTH {
+                        return Err(Error::StackOverflow);
+                    }
+
+                    self.stack.insert(function_index, handler);
+                    *nargs += 1;
+                }
+            }
+        };
+
+        if self.stack.len() >= MAX_STACK_SIZE {
+            return Err(Error::StackOverflow);
+        }
+
+        Ok(function)
+    }
+
+    /// The function that a call of `value` runs, which for a value that is not a function is
+    /// found through its `__call` metamethod, if it has one.
+    pub fn function_called(&self, mut value: Value) -> Option<FunctionRef> {
+        for _ in 0..MAX_CALL_DEPTH {
+            match value {
+                Value::Function(function) => return Some(function),
+                _ => value = self.heap.metamethod_of(value, Metamethod::Call),
+            }
+
+            if value.is_nil() {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    /// Calls a metamethod handler with `args`, returning its first result.
+    pub(super) fn call_metamethod(&mut self, handler: Value, args: &[Value]) -> Result<Value> {
+        self.stack.push(handler);
+        self.stack.extend_from_slice(args);
+        self.call(args.len(), Some(1))?;
+        Ok(self.pop())
+    }
+
+    /// Performs a step of garbage collection if one is due, then runs any pending finalizers.
+    pub fn check_gc(&mut self) {
+        self.release_refs();
+        if self.heap.gc_is_due() {
+            self.clear_dead_slots();
+            self.heap.gc_step(&self.stack);
+        }
+        self.run_finalizers();
+    }
+
+    /// Clears the slots of the running thread's frames that their functions do not read again
+    /// from the instruction they are at, as their prototypes record, so that the collector
+    /// does not keep alive what was left in them.
+    pub(super) fn clear_dead_slots(&mut self) {
+        for frame in &self.frames {
+            let (Function::Lua(closure), Some(position)) =
+                (&self.heap[frame.function], frame.position)
+            else {
+                continue;
+            };
+
+            if let Some(slots) = closure.prototype.dead_slots.get(position as usize) {
+                for &slot in slots.iter() {
+                    self.stack[frame.base + slot] = Value::Nil;
+                }
+            }
+        }
+    }
+
+    /// Calls the `__gc` metamethod of every object that the collector has found to be
+    /// unreachable.
+    pub fn run_finalizers(&mut self) {
+        if mem::replace(&mut self.finalizing, true) {
+            return;
+        }
+
+        while let Some(object) = self.heap.take_finalizer() {
+            let handler = self.heap.metamethod_of(object, Metamethod::Gc);
+            if !handler.is_nil() {
+                // An error in a finalizer has nowhere to propagate to, since the code that
+                // triggered the collection has nothing to do with it.
+                self.push(handler);
+                self.push(object);
+                let _ = self.protected_call(1, Some(0));
+            }
+        }
+
+        self.finalizing = false;
+    }
+
+    /// Runs the finalizers of every object that has one, reachable or not, as closing the
+    /// state does. Objects stay usable afterwards, but are not finalized again.
+    pub fn finalize_all(&mut self) {
+        self.heap.finalize_all();
+        self.run_finalizers();
+    }
+
+    pub fn main_thread(&self) -> ThreadRef {
+        match self.heap[self.heap.registry()].get_int(MAIN_THREAD) {
+            Value::Thread(main) => main,
+            _ => unreachable!("the registry has lost the main thread"),
+        }
+    }
+
+    pub fn current_thread(&self) -> ThreadRef {
+        self.thread
+    }
+
+    /// Creates a coroutine that calls `function` when it is first resumed. It starts with the
+    /// running thread's hook.
+    pub fn new_thread(&mut self, function: Value) -> ThreadRef {
+        let mut thread = Thread::new(function);
+        thread.hook = self.hook.clone();
+        thread.hook_count = self.hook_count;
+        self.heap.new_thread(thread)
+    }
+
+    /// Whether the running thread may yield, which it may if it is a coroutine and is not
+    /// running a finalizer or being closed.
+    pub fn is_yieldable(&self) -> bool {
+        !self.finalizing && self.heap[self.thread].yielder.is_some()
+    }
+
+    /// Resumes `thread`, passing it the top `nargs` values on the stack, and replaces them with
+    /// the values it yields or returns, returning how many there are. If the coroutine fails,
+    /// or cannot be resumed, the arguments are removed and the error value is returned
+    /// instead.
+    pub fn resume(&mut self, thread: ThreadRef, nargs: usize) -> std::result::Result<usize, Value> {
+        let args = self.stack.len() - nargs;
+        let message = match self.heap[thread].status {
+            Status::Suspended if self.resume_depth >= MAX_CALL_DEPTH => Some("C stack overflow"),
+            Status::Suspended => None,
+            Status::Dead => Some("cannot resume dead coroutine"),
+            Status::Running | Status::Normal => Some("cannot resume non-suspended coroutine"),
+        };
+
+        if let Some(message) = message {
+            self.stack.truncate(args);
+            return Err(Value::String(self.heap.intern(message.as_bytes())));
+        }
+
+        // The coroutine stays on the resumer's stack while it runs, where the collector can see
+        // it, and where the resumer is found in turn.
+        let args = self.stack.split_off(args);
+        self.stack.push(Value::Thread(thread));
+        let resumer = self.thread;
+        self.heap[resumer].status = Status::Normal;
+        self.heap[thread].status = Status::Running;
+        self.switch_to(thread);
+        self.stack.extend(args);
+
+        let mut body = self.heap[thread]
+            .body
+            .take()
+            .unwrap_or_else(thread::new_body);
+        self.resume_depth += 1;
+        let result = body.resume((self as *mut Self, nargs));
+        self.resume_depth -= 1;
+
+        let (status, result) = match result {
+            CoroutineResult::Yield(count) => {
+                self.heap[thread].body = Some(body);
+                (Status::Suspended, Ok(count))
+            }
+
+            CoroutineResult::Return(result) => {
+                self.heap[thread].yielder = None;
+                (
+                    Status::Dead,
+                    result.map_err(|error| self.error_value(error)),
+                )
+            }
+        };
+
+        let values = match result {
+            Ok(count) => self.stack.split_off(self.stack.len() - count),
+            Err(error) => {
+                self.heap[thread].error = Some(error);
+                Vec::new()
+            }
+        };
+
+        self.switch_to(resumer);
+        self.heap[thread].status = status;
+        self.heap[resumer].status = Status::Running;
+        self.stack.pop();
+        self.stack.extend_from_slice(&values);
+        result
+    }
+
+    /// Suspends the running coroutine, passing the top `count` values on the stack to its
+    /// resumer. Once the coroutine is resumed again, the values passed to it are on top of
+    /// the stack, and this returns how many there are.
+    pub fn yield_values(&mut self, count: usize) -> Result<usize> {
+        let yielder = match self.heap[self.thread].yielder {
+            Some(yielder) if !self.finalizing => yielder,
+            _ if self.thread == self.main_thread() => return Err(Error::YieldOutsideCoroutine),
+            _ => return Err(Error::YieldAcrossBoundary),
+        };
+
+        // SAFETY: the yielder lives on the native stack of the coroutine, which is the one
+        // running this.
+        let (state, nargs) = unsafe { yielder.as_ref() }.suspend(count);
+        assert!(
+            ptr::eq(state, self),
+            "a state must not move while it has suspended coroutines"
+        );
+        Ok(nargs)
+    }
+
+    /// Closes a suspended or dead coroutine, as `coroutine.close` does: the pending
+    /// to-be-closed variables of a suspended one are closed, and it becomes dead. Returns the
+    /// error that the coroutine died with or that a `__close` metamethod raised, if any.
+    pub fn close_thread(&mut self, thread: ThreadRef) -> Result<Option<Value>> {
+        match self.heap[thread].status {
+            Status::Suspended => (),
+            Status::Dead => return Ok(self.heap[thread].error),
+            status => return Err(Error::CloseActive(status.name())),
+        }
+
+        self.stack.push(Value::Thread(thread));
+        let resumer = self.thread;
+        self.switch_to(thread);
+
+        // The coroutine's native stack is not running, so there is nothing to yield from.
+        self.heap[thread].yielder = None;
+
+        while let Some(index) = self.to_be_closed.pop() {
+            let error = self.heap[thread].error.unwrap_or_default();
+            if let Err(error) = self.close_value(self.stack[index], error) {
+                let error = self.error_value(error);
+                self.heap[thread].error = Some(error);
+            }
+        }
+
+        self.heap.close_upvalues(&self.stack, 0);
+        self.stack.clear();
+        self.frames.clear();
+        self.switch_to(resumer);
+        self.stack.pop();
+
+        // Dropping the native side of the coroutine unwinds its stack.
+        let thread = &mut self.heap[thread];
+        thread.status = Status::Dead;
+        thread.body = None;
+        Ok(thread.error)
+    }
+
+    /// Makes `thread` the running thread, putting away the context of the one that was.
+    fn switch_to(&mut self, thread: ThreadRef) {
+        // The stack of the thread put away stays as it is until it runs again, which is when
+        // its slots are to be cleared if the collector is to see them as they are.
+        self.clear_dead_slots();
+        self.exchange_context(self.thread);
+        self.exchange_context(thread);
+        self.thread = thread;
+    }
+
+    fn exchange_context(&mut self, thread: ThreadRef) {
+        self.heap.exchange_open_upvalues(thread);
+        let saved = &mut self.heap[thread];
+        mem::swap(&mut self.stack, &mut saved.stack);
+        mem::swap(&mut self.frames, &mut saved.frames);
+        mem::swap(&mut self.call_depth, &mut saved.call_depth);
+        mem::swap(&mut self.to_be_closed, &mut saved.to_be_closed);
+        mem::swap(&mut self.message_handler, &mut saved.message_handler);
+        mem::swap(&mut self.hook, &mut saved.hook);
+        mem::swap(&mut self.hook_count, &mut saved.hook_count);
+        mem::swap(&mut self.in_hook, &mut saved.in_hook);
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The form of a chunk name used in messages: a file name without its `@`, a description
+/// without its `=`, or the first line of source text in brackets.
+pub(crate) fn short_source(source: &str) -> String {
+    const MAX_LENGTH: usize = 60;
+
+    if let Some(name) = source.strip_prefix('=') {
+        name.chars().take(MAX_LENGTH - 1).collect()
+    } else if let Some(name) = source.strip_prefix('@') {
+        if name.chars().count() < MAX_LENGTH {
+            name.to_owned()
+        } else {
+            let tail: Vec<char> = name.chars().rev().take(MAX_LENGTH - 4).collect();
+            format!("...{}", tail.into_iter().rev().collect::<String>())
+        }
+    } else {
+        let line = source.lines().next().unwrap_or("");
+        let limit = MAX_LENGTH - "[string \"...\"]".len() - 1;
+        if line.len() < source.len() || line.chars().count() > limit {
+            let line: String = line.chars().take(limit).collect();
+            format!("[string \"{line}...\"]")
+        } else {
+            format!("[string \"{line}\"]")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{assert_fails, assert_runs};
+
+    #[test]
+    fn collection_runs_finalizers() {
+        assert_runs(
+            "local log = {} \
+             setmetatable({}, {__gc = function() log[#log + 1] = 'a' end}) \
+             setmetatable({}, {__gc = function() log[#log + 1] = 'b' end}) \
+             collectgarbage() \
+             return table.concat(log)",
+            "ba",
+        );
+        assert_runs(
+            "local log = {} \
+             local mt = {} setmetatable({}, mt) mt.__gc = function() log[1] = 'late' end \
+             collectgarbage() \
+             return #log",
+            "0",
+        );
+    }
+
+    #[test]
+    fn values_left_in_temporaries_are_collected() {
+        assert_runs(
+            "local log = {} \
+             local o = setmetatable({}, {__gc = function() log[1] = 'collected' end}) \
+             o = nil collectgarbage() \
+             return tostring(log[1])",
+            "collected",
+        );
+        assert_runs(
+            "local wk = setmetatable({}, {__mode = 'k'}) \
+             local k = {} wk[k] = {k} \
+             k = nil collectgarbage() \
+             return tostring(next(wk))",
+            "nil",
+        );
+        assert_runs(
+            "local log = {} \
+             local co = coroutine.wrap(function() \
+               local o = setmetatable({}, {__gc = function() log[1] = 'collected' end}) \
+               o = nil coroutine.yield() \
+             end) \
+             co() collectgarbage() \
+             return tostring(log[1])",
+            "collected",
+        );
+    }
+
+    #[test]
+    fn errors_in_finalizers_go_nowhere() {
+        assert_runs(
+            "setmetatable({}, {__gc = function() error('x') end}) collectgarbage() return 'ok'",
+            "ok",
+        );
+    }
+
+    #[test]
+    fn finalizers_see_their_objects_cleared_from_weak_values() {
+        assert_runs(
+            "local cache = setmetatable({}, {__mode = 'v'}) \
+             local seen \
+             local function f(o) seen = cache[1] == nil end \
+             cache[1] = setmetatable({}, {__gc = f}) \
+             collectgarbage() \
+             return tostring(seen)",
+            "true",
+        );
+        assert_runs(
+            "local t = setmetatable({}, {__mode = 'k'}) \
+             t[{}] = 1 t[t] = 2 \
+             collectgarbage() \
+             local n = 0 for _ in pairs(t) do n = n + 1 end return n",
+            "1",
+        );
+    }
+
+    #[test]
+    fn protected_calls_catch_errors() {
+        assert_runs(
+            "local ok, e = pcall(error, 'x') return tostring(ok) .. e",
+            "falsex",
+        );
+        assert_runs(
+            "local ok, e = pcall(error, {code = 42}) return e.code",
+            "42",
+        );
+        assert_runs(
+            "local ok, e = pcall(function() local x = nil + 1 end) return e",
+            "test:1: attempt to perform arithmetic on a nil value",
+        );
+        assert_runs(
+            "return select('#', pcall(function() return 1, 2, 3 end))",
+            "4",
+        );
+        assert_runs(
+            "local ok, e = pcall(pcall) return tostring(ok) .. ' ' .. e",
+            "false bad argument #1 to 'pcall' (value expected)",
+        );
+    }
+
+    #[test]
+    fn error_levels_blame_callers() {
+        assert_runs(
+            "local function check(x) if not x then error('missing', 2) end end \n\
+             local ok, e = pcall(function() \n\
+               check(false) \n\
+             end) \n\
+             return e",
+            "test:3: missing",
+        );
+        assert_runs("local ok, e = pcall(error, 'bare', 0) return e", "bare");
+    }
+
+    #[test]
+    fn message_handlers_run_before_unwinding() {
+        assert_runs(
+            "local function handler(e) return 'handled: ' .. e end \
+             local ok, e = xpcall(error, handler, 'x', 0) \
+             return e",
+            "handled: x",
+        );
+        assert_runs(
+            "local depth \
+             local function handler(e) depth = debug.traceback() return e end \
+             xpcall(function() error('x') end, handler) \
+             return select(2, depth:gsub('\\n', '')) > 2",
+            "true",
+        );
+        assert_runs(
+            "local ok, e = xpcall(function() return pcall(error, 'inner') end, \
+               function() return 'outer' end) \
+             return tostring(ok) .. tostring(e)",
+            "truefalse",
+        );
+    }
+
+    #[test]
+    fn errors_close_to_be_closed_variables() {
+        assert_runs(
+            "local seen \
+             local ok, e = pcall(function() \
+               local x <close> = setmetatable({}, {__close = function(_, e) seen = e end}) \
+               error('boom', 0) \
+             end) \
+             return seen .. e",
+            "boomboom",
+        );
+        assert_runs(
+            "local ok, e = pcall(function() \
+               local x <close> = setmetatable({}, {__close = function() error('close', 0) end}) \
+               error('boom', 0) \
+             end) \
+             return e",
+            "close",
+        );
+        assert_fails(
+            "local x <close> = 1",
+            "test:1: variable 'x' got a non-closable value",
+        );
+    }
+
+    #[test]
+    fn tracebacks_name_functions_and_lines() {
+        assert_runs(
+            "function g()\n\
+               local t = debug.traceback()\n\
+               return t\n\
+             end\n\
+             local function f()\n\
+               local t = g()\n\
+               return t\n\
+             end\n\
+             return (f())",
+            "stack traceback:\n\ttest:2: in function 'g'\n\ttest:6: in local 'f'\n\
+             \ttest:9: in main chunk",
+        );
+        assert_runs(
+            "local o = {}\n\
+             function o:m()\n\
+               local t = debug.traceback('m')\n\
+               return t\n\
+             end\n\
+             return (o:m())",
+            "m\nstack traceback:\n\ttest:3: in method 'm'\n\ttest:6: in main chunk",
+        );
+        assert_runs(
+            "local t = {h = function() local t = debug.traceback() return t end}\n\
+             return (t.h())",
+            "stack traceback:\n\ttest:1: in field 'h'\n\ttest:2: in main chunk",
+        );
+        assert_runs(
+            "local ok, tb = xpcall(function() local x = nil; x() end, debug.traceback) \
+             return tb",
+            "test:1: attempt to call a nil value (local 'x')\nstack traceback:\n\
+             \ttest:1: in function <test:1>\n\t[C]: in function 'xpcall'\n\
+             \ttest:1: in main chunk",
+        );
+    }
+
+    #[test]
+    fn tracebacks_leave_tail_calls_unnamed() {
+        assert_runs(
+            "local function g()\n\
+               local t = debug.traceback()\n\
+               return t\n\
+             end\n\
+             local function f()\n\
+               return g()\n\
+             end\n\
+             return (f())",
+            "stack traceback:\n\ttest:2: in function <test:1>\n\t(...tail calls...)\n\
+             \ttest:8: in main chunk",
+        );
+        assert_runs(
+            "local function g() local t = debug.traceback() return t end\n\
+             function f() return g() end\n\
+             return (f())",
+            "stack traceback:\n\ttest:1: in function <test:1>\n\t(...tail calls...)\n\
+             \ttest:3: in main chunk",
+        );
+        assert_runs(
+            "local function g() return debug.getinfo(1, 'n').name end\n\
+             local function f() return g() end\n\
+             return tostring((f()))",
+            "nil",
+        );
+    }
+
+    #[test]
+    fn tracebacks_skip_the_middle_of_deep_stacks() {
+        assert_runs(
+            "local function r(n) \
+               if n == 0 then local t = debug.traceback() return t end \
+               return (r(n - 1)) \
+             end \
+             local t = r(30) \
+             return select(2, t:gsub('\\n', '')) .. t:match('\\t%.%.%.[^\\n]*')",
+            "22\t...\t(skipping 11 levels)",
+        );
+    }
+
+    #[test]
+    fn tracebacks_of_other_threads() {
+        assert_runs(
+            "local co = coroutine.create(function() coroutine.yield() end) \
+             coroutine.resume(co) \
+             return debug.traceback(co, 'suspended')",
+            "suspended\nstack traceback:\n\t[C]: in field 'yield'\n\ttest:1: in function <test:1>",
+        );
+        assert_runs("local t = {} return debug.traceback(t) == t", "true");
+    }
+}
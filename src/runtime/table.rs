@@ -0,0 +1,514 @@
+use {
+    super::{value::float_to_int, Error, Result, StrRef, TableRef, Value},
+    ahash::AHashMap,
+    cranelift_entity::EntityRef,
+    std::{
+        hash::{Hash, Hasher},
+        mem,
+    },
+};
+
+/// A Lua table, split into an array part holding the keys `1..=n` and a hash part holding
+/// everything else.
+///
+/// Hash entries are kept in insertion order and are never removed while they are merely set to
+/// nil; dead entries are only dropped when a new key is inserted. This is what keeps [`next`]
+/// stable when existing fields are assigned (including to nil) during a traversal, while
+/// inserting new keys mid-traversal remains undefined, as in the reference implementation.
+///
+/// [`next`]: Table::next
+pub struct Table {
+    array: Vec<Value>,
+    nodes: Vec<(Key, Value)>,
+    node_index: AHashMap<Key, usize>,
+    dead_nodes: usize,
+    metatable: Option<TableRef>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self::with_capacity(0, 0)
+    }
+
+    pub fn with_capacity(array: usize, hash: usize) -> Self {
+        Self {
+            array: Vec::with_capacity(array),
+            nodes: Vec::with_capacity(hash),
+            node_index: AHashMap::with_capacity(hash),
+            dead_nodes: 0,
+            metatable: None,
+        }
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable
+    }
+
+    /// Only the heap may change a metatable, since doing so can register the table for
+    /// finalization.
+    pub(super) fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+
+    pub fn get(&self, key: Value) -> Value {
+        match key {
+            Value::Nil => Value::Nil,
+            Value::Int(i) => self.get_int(i),
+
+            Value::Float(f) => match float_to_int(f) {
+                Some(i) => self.get_int(i),
+                None if f.is_nan() => Value::Nil,
+                None => self.get_node(Key(key)),
+            },
+
+            _ => self.get_node(Key(key)),
+        }
+    }
+
+    pub fn get_int(&self, key: i64) -> Value {
+        match self.array_index(key) {
+            Some(i) => self.array[i],
+            None => self.get_node(Key(Value::Int(key))),
+        }
+    }
+
+    pub fn get_str(&self, key: StrRef) -> Value {
+        self.get_node(Key(Value::String(key)))
+    }
+
+    pub fn set(&mut self, key: Value, value: Value) -> Result<()> {
+        match Key::new(key)? {
+            Key(Value::Int(i)) => self.set_int(i, value),
+            key => self.set_node(key, value),
+        }
+
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, key: i64, value: Value) {
+        if let Some(i) = self.array_index(key) {
+            self.array[i] = value;
+        } else if key as u64 == self.array.len() as u64 + 1 && !value.is_nil() {
+            self.array.push(value);
+            self.migrate_to_array();
+        } else {
+            self.set_node(Key(Value::Int(key)), value)
+        }
+    }
+
+    pub fn set_str(&mut self, key: StrRef, value: Value) {
+        self.set_node(Key(Value::String(key)), value)
+    }
+
+    /// Returns a border of the table, as defined for the length operator: an index `n` such
+    /// that `t[n]` is non-nil (or `n` is zero) and `t[n + 1]` is nil.
+    pub fn border(&self) -> i64 {
+        let limit = self.array.len();
+
+        if limit > 0 && self.array[limit - 1].is_nil() {
+            let (mut i, mut j) = (0, limit);
+            while j - i > 1 {
+                let m = (i + j) / 2;
+                if self.array[m - 1].is_nil() {
+                    j = m;
+                } else {
+                    i = m;
+                }
+            }
+            i as i64
+        } else if self.get_node(Key(Value::Int(limit as i64 + 1))).is_nil() {
+            limit as i64
+        } else {
+            self.hash_border(limit as u64)
+        }
+    }
+
+    /// Returns the field following `key` in traversal order, or `None` once the traversal is
+    /// complete. A nil key starts a new traversal.
+    pub fn next(&self, key: Value) -> Result<Option<(Value, Value)>> {
+        let start = match key {
+            Value::Nil => 0,
+
+            _ => {
+                let key = Key::new(key).map_err(|_| Error::InvalidNextKey)?;
+                match key.0 {
+                    Value::Int(i) if self.array_index(i).is_some() => i as usize,
+                    _ => match self.node_index.get(&key) {
+                        Some(&node) => self.array.len() + node + 1,
+                        None => return Err(Error::InvalidNextKey),
+                    },
+                }
+            }
+        };
+
+        for (i, &value) in self.array.iter().enumerate().skip(start) {
+            if !value.is_nil() {
+                return Ok(Some((Value::Int(i as i64 + 1), value)));
+            }
+        }
+
+        let start = start.saturating_sub(self.array.len());
+        Ok(self.nodes[start..]
+            .iter()
+            .find(|(_, value)| !value.is_nil())
+            .map(|&(key, value)| (key.0, value)))
+    }
+
+    /// Calls `mark` with every value the table refers to.
+    pub(super) fn trace(&self, mut mark: impl FnMut(Value)) {
+        if let Some(metatable) = self.metatable {
+            mark(Value::Table(metatable));
+        }
+
+        self.for_each_entry(|key, value| {
+            mark(key);
+            mark(value);
+        })
+    }
+
+    /// Calls `f` with the key and value of every non-nil field.
+    pub(super) fn for_each_entry(&self, mut f: impl FnMut(Value, Value)) {
+        for (i, &value) in self.array.iter().enumerate() {
+            if !value.is_nil() {
+                f(Value::Int(i as i64 + 1), value);
+            }
+        }
+
+        for &(key, value) in &self.nodes {
+            if !value.is_nil() {
+                f(key.0, value);
+            }
+        }
+    }
+
+    /// Sets to nil every field for which `cleared` returns true, as the collector does to
+    /// weak tables. Like any other assignment of nil, this leaves traversals intact.
+    pub(super) fn clear_entries(&mut self, mut cleared: impl FnMut(Value, Value) -> bool) {
+        for (i, value) in self.array.iter_mut().enumerate() {
+            if !value.is_nil() && cleared(Value::Int(i as i64 + 1), *value) {
+                *value = Value::Nil;
+            }
+        }
+
+        for (key, value) in &mut self.nodes {
+            if !value.is_nil() && cleared(key.0, *value) {
+                *value = Value::Nil;
+                self.dead_nodes += 1;
+            }
+        }
+    }
+
+    /// Estimates the memory used by the table, in bytes.
+    pub(super) fn size(&self) -> usize {
+        mem::size_of::<Self>()
+            + self.array.capacity() * mem::size_of::<Value>()
+            + self.nodes.capacity() * mem::size_of::<(Key, Value)>()
+            + self.node_index.capacity() * mem::size_of::<(Key, usize)>()
+    }
+
+    fn array_index(&self, key: i64) -> Option<usize> {
+        let index = (key as u64).wrapping_sub(1);
+        (index < self.array.len() as u64).then_some(index as usize)
+    }
+
+    fn get_node(&self, key: Key) -> Value {
+        match self.node_index.get(&key) {
+            Some(&i) => self.nodes[i].1,
+            None => Value::Nil,
+        }
+    }
+
+    fn set_node(&mut self, key: Key, value: Value) {
+        if let Some(&i) = self.node_index.get(&key) {
+            let slot = &mut self.nodes[i].1;
+            match (slot.is_nil(), value.is_nil()) {
+                (true, false) => self.dead_nodes -= 1,
+                (false, true) => self.dead_nodes += 1,
+                _ => (),
+            }
+            *slot = value;
+        } else if !value.is_nil() {
+            if self.dead_nodes > self.nodes.len() / 2 {
+                self.compact();
+            }
+            self.node_index.insert(key, self.nodes.len());
+            self.nodes.push((key, value));
+        }
+    }
+
+    /// Moves the run of integer keys that directly follows the array part out of the hash part.
+    fn migrate_to_array(&mut self) {
+        loop {
+            let key = Key(Value::Int(self.array.len() as i64 + 1));
+            let Some(&i) = self.node_index.get(&key) else {
+                break;
+            };
+
+            let value = mem::take(&mut self.nodes[i].1);
+            if value.is_nil() {
+                break;
+            }
+
+            self.dead_nodes += 1;
+            self.array.push(value);
+        }
+    }
+
+    fn compact(&mut self) {
+        self.nodes.retain(|(_, value)| !value.is_nil());
+        self.node_index.clear();
+        self.node_index
+            .extend(self.nodes.iter().enumerate().map(|(i, &(key, _))| (key, i)));
+        self.dead_nodes = 0;
+    }
+
+    /// Unbounded search for a border past `j`, given that `t[j + 1]` is present.
+    fn hash_border(&self, mut j: u64) -> i64 {
+        let mut i;
+
+        if j == 0 {
+            j += 1;
+        }
+
+        loop {
+            i = j;
+            if j <= i64::MAX as u64 / 2 {
+                j *= 2;
+            } else {
+                j = i64::MAX as u64;
+                if self.get_int(j as i64).is_nil() {
+                    break;
+                } else {
+                    return j as i64;
+                }
+            }
+
+            if self.get_int(j as i64).is_nil() {
+                break;
+            }
+        }
+
+        while j - i > 1 {
+            let m = (i + j) / 2;
+            if self.get_int(m as i64).is_nil() {
+                j = m;
+            } else {
+                i = m;
+            }
+        }
+
+        i as i64
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A table key that is neither nil nor NaN, with floats that have an exact integer value
+/// already converted to integers so that `t[1.0]` and `t[1]` name the same field.
+#[derive(Clone, Copy, Debug)]
+struct Key(Value);
+
+impl Key {
+    fn new(value: Value) -> Result<Self> {
+        match value {
+            Value::Nil => Err(Error::NilIndex),
+            Value::Float(f) if f.is_nan() => Err(Error::NanIndex),
+            Value::Float(f) => Ok(Self(float_to_int(f).map_or(value, Value::Int))),
+            _ => Ok(Self(value)),
+        }
+    }
+
+    fn identity(self) -> (u8, u64) {
+        match self.0 {
+            Value::Nil => (0, 0),
+            Value::Bool(b) => (1, b as _),
+            Value::Int(i) => (2, i as _),
+            Value::Float(f) => (3, f.to_bits()),
+            Value::String(s) => (4, s.index() as _),
+            Value::Table(t) => (5, t.index() as _),
+            Value::Function(f) => (6, f.index() as _),
+            Value::Thread(t) => (7, t.index() as _),
+            Value::UserData(u) => (8, u.index() as _),
+        }
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
+    }
+}
+
+impl Eq for Key {}
+
+impl Hash for Key {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(table: &Table) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut key = Value::Nil;
+        while let Some((next, _)) = table.next(key).unwrap() {
+            keys.push(Key(next));
+            key = next;
+        }
+        keys
+    }
+
+    #[test]
+    fn sequences_grow_the_array_part() {
+        let mut table = Table::new();
+        for i in 1..=10 {
+            table.set_int(i, Value::Int(i * i));
+        }
+
+        assert_eq!(table.array.len(), 10);
+        assert!(table.nodes.is_empty());
+        assert_eq!(table.border(), 10);
+        assert!(matches!(table.get_int(4), Value::Int(16)));
+    }
+
+    #[test]
+    fn keys_filling_a_gap_migrate_to_the_array_part() {
+        let mut table = Table::new();
+        for i in [3, 2, 4, 1] {
+            table.set_int(i, Value::Bool(true));
+        }
+
+        assert_eq!(table.array.len(), 4);
+        assert_eq!(table.border(), 4);
+        assert!(matches!(table.get_int(3), Value::Bool(true)));
+    }
+
+    #[test]
+    fn borders() {
+        let mut table = Table::new();
+        assert_eq!(table.border(), 0);
+
+        for i in 1..=8 {
+            table.set_int(i, Value::Int(i));
+        }
+        table.set_int(8, Value::Nil);
+        table.set_int(7, Value::Nil);
+        assert_eq!(table.border(), 6);
+
+        // A border found in the hash part, past an empty array part.
+        let mut table = Table::new();
+        table.set_int(2, Value::Int(2));
+        assert_eq!(table.border(), 0);
+        table.set_int(1, Value::Int(1));
+        assert_eq!(table.border(), 2);
+
+        let mut table = Table::new();
+        table.set_int(i64::MAX, Value::Int(0));
+        assert_eq!(table.border(), 0);
+    }
+
+    #[test]
+    fn float_keys_with_integer_values_are_integers() {
+        let mut table = Table::new();
+        table.set(Value::Float(1.0), Value::Int(1)).unwrap();
+        table.set(Value::Float(1.5), Value::Int(2)).unwrap();
+
+        assert!(matches!(table.get_int(1), Value::Int(1)));
+        assert!(matches!(table.get(Value::Float(1.5)), Value::Int(2)));
+        assert_eq!(table.border(), 1);
+        assert_eq!(keys(&table), [Key(Value::Int(1)), Key(Value::Float(1.5))]);
+    }
+
+    #[test]
+    fn nil_and_nan_are_not_keys() {
+        let mut table = Table::new();
+        assert!(matches!(
+            table.set(Value::Nil, Value::Int(1)),
+            Err(Error::NilIndex)
+        ));
+        let nan = Value::Float(f64::NAN);
+        assert!(matches!(
+            table.set(nan, Value::Int(1)),
+            Err(Error::NanIndex)
+        ));
+        assert!(table.get(Value::Nil).is_nil());
+        assert!(table.get(nan).is_nil());
+    }
+
+    #[test]
+    fn next_visits_the_array_part_then_the_hash_part_in_insertion_order() {
+        let mut table = Table::new();
+        table.set(Value::Bool(true), Value::Int(0)).unwrap();
+        table.set_int(1, Value::Int(1));
+        table.set_int(-1, Value::Int(2));
+        table.set_int(2, Value::Nil);
+        table.set_int(2, Value::Int(3));
+
+        let expected = [
+            Value::Int(1),
+            Value::Int(2),
+            Value::Bool(true),
+            Value::Int(-1),
+        ];
+        assert_eq!(keys(&table), expected.map(Key));
+    }
+
+    #[test]
+    fn next_survives_clearing_fields_during_traversal() {
+        let mut table = Table::new();
+        for i in 0..10 {
+            table
+                .set(Value::Float(i as f64 + 0.5), Value::Int(i))
+                .unwrap();
+        }
+
+        let mut key = Value::Nil;
+        let mut visited = 0;
+        while let Some((next, _)) = table.next(key).unwrap() {
+            table.set(next, Value::Nil).unwrap();
+            visited += 1;
+            key = next;
+        }
+
+        assert_eq!(visited, 10);
+        assert!(keys(&table).is_empty());
+    }
+
+    #[test]
+    fn next_rejects_keys_the_table_does_not_have() {
+        let mut table = Table::new();
+        table.set_int(1, Value::Int(1));
+        assert!(matches!(
+            table.next(Value::Int(5)),
+            Err(Error::InvalidNextKey)
+        ));
+        assert!(matches!(
+            table.next(Value::Float(f64::NAN)),
+            Err(Error::InvalidNextKey)
+        ));
+    }
+
+    #[test]
+    fn dead_nodes_are_compacted_on_insertion() {
+        let mut table = Table::new();
+        for i in 0..8 {
+            table.set(Value::Bool(i % 2 == 0), Value::Int(i)).unwrap();
+            table.set_int(-i - 1, Value::Int(i));
+        }
+        for i in 0..8 {
+            table.set_int(-i - 1, Value::Nil);
+        }
+
+        table.set_int(-100, Value::Int(0));
+        assert_eq!(table.dead_nodes, 0);
+        assert_eq!(table.nodes.len(), 3);
+        assert!(matches!(table.get(Value::Bool(false)), Value::Int(7)));
+    }
+}
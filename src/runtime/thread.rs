@@ -0,0 +1,115 @@
+//! Coroutines, each of which runs on a native stack of its own, so that it can yield from any
+//! depth of calls: through `pcall`, metamethods and iterators as much as through compiled code.
+
+use {
+    super::{state::Frame, Hook, Result, State, UpvalueRef, Value},
+    corosensei::{stack::DefaultStack, Coroutine, Yielder},
+    std::ptr::NonNull,
+};
+
+/// The size of the native stack of a coroutine. Only the pages that are used are committed.
+const STACK_SIZE: usize = 8 << 20;
+
+/// What a coroutine is resumed with: the state, and how many values on top of the coroutine's
+/// stack are passed in.
+pub(super) type Resume = (*mut State, usize);
+
+/// The native side of a coroutine, which yields and returns the number of values on top of its
+/// stack that are passed out.
+pub(super) type Body = Coroutine<Resume, usize, Result<usize>, DefaultStack>;
+
+pub(super) fn new_body() -> Body {
+    let stack = DefaultStack::new(STACK_SIZE).expect("failed to allocate a coroutine stack");
+
+    Coroutine::with_stack(stack, |yielder: &Yielder<Resume, usize>, (state, nargs)| {
+        // SAFETY: the resumer hands over its state for as long as the coroutine runs, and
+        // does not touch it until the coroutine yields or returns.
+        let state = unsafe { &mut *state };
+        let thread = state.current_thread();
+        state.heap[thread].yielder = Some(NonNull::from(yielder));
+        state.call(nargs, None)
+    })
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Status {
+    /// Not yet started, or stopped in a call to `coroutine.yield`.
+    Suspended,
+
+    Running,
+
+    /// Active but not running, because it resumed another coroutine.
+    Normal,
+
+    /// Finished, whether by returning or by an error.
+    Dead,
+}
+
+impl Status {
+    /// The name `coroutine.status` gives the status.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Suspended => "suspended",
+            Self::Running => "running",
+            Self::Normal => "normal",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+/// A thread of execution: the main one, or a coroutine.
+///
+/// The running thread's stack, frames, to-be-closed variables, open upvalues, message handler
+/// and hook live in the [`State`] and its heap, and are moved here whenever another thread
+/// starts running, and back when it stops.
+pub struct Thread {
+    pub(super) status: Status,
+    pub(super) stack: Vec<Value>,
+    pub(super) frames: Vec<Frame>,
+    pub(super) call_depth: usize,
+    pub(super) to_be_closed: Vec<usize>,
+    pub(super) open_upvalues: Vec<UpvalueRef>,
+    pub(super) message_handler: Option<Value>,
+    pub(super) hook: Option<Hook>,
+    pub(super) hook_count: u32,
+    pub(super) in_hook: bool,
+
+    /// The native side of a coroutine that has started and is not running, since a running
+    /// one is owned by the call that resumed it.
+    pub(super) body: Option<Body>,
+
+    /// The means for a running coroutine to yield, which is absent for the main thread and
+    /// while yielding is not allowed.
+    pub(super) yielder: Option<NonNull<Yielder<Resume, usize>>>,
+
+    /// The error that a dead coroutine finished with.
+    pub(super) error: Option<Value>,
+}
+
+impl Thread {
+    /// A thread that will call `function` when first resumed.
+    pub(super) fn new(function: Value) -> Self {
+        Self {
+            status: Status::Suspended,
+            stack: vec![function],
+            frames: Vec::new(),
+            call_depth: 0,
+            to_be_closed: Vec::new(),
+            open_upvalues: Vec::new(),
+            message_handler: None,
+            hook: None,
+            hook_count: 0,
+            in_hook: false,
+            body: None,
+            yielder: None,
+            error: None,
+        }
+    }
+
+    /// Calls `f` with every value the thread refers to.
+    pub(super) fn trace(&self, mut f: impl FnMut(Value)) {
+        self.stack.iter().for_each(|&value| f(value));
+        self.message_handler.into_iter().for_each(&mut f);
+        self.error.into_iter().for_each(&mut f);
+    }
+}
@@ -0,0 +1,82 @@
+//! Full userdata: Rust data that Lua code can hold and pass around, but only reach through
+//! the metatable of the userdata.
+
+use {
+    super::{TableRef, Value},
+    std::{any::Any, mem, rc::Rc},
+};
+
+/// Rust data with a metatable of its own and a fixed number of user values, which are Lua
+/// values that the userdata keeps alive, as `debug.getuservalue` and `debug.setuservalue`
+/// reach them.
+///
+/// The data is shared, so that native functions can keep it while they run even if Lua code
+/// drops the last reference to the userdata meanwhile. It is dropped once the userdata has been
+/// collected and every clone of it is gone.
+pub struct UserData {
+    data: Rc<dyn Any>,
+    metatable: Option<TableRef>,
+    user_values: Box<[Value]>,
+}
+
+impl UserData {
+    /// A userdata with no metatable and `user_values` user values, all nil.
+    pub fn new(data: Rc<dyn Any>, user_values: usize) -> Self {
+        Self {
+            data,
+            metatable: None,
+            user_values: vec![Value::Nil; user_values].into_boxed_slice(),
+        }
+    }
+
+    pub fn data(&self) -> &Rc<dyn Any> {
+        &self.data
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable
+    }
+
+    pub(super) fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+
+    /// The `n`th user value, counting from 1, or `None` if there is no such user value.
+    pub fn user_value(&self, n: usize) -> Option<Value> {
+        n.checked_sub(1)
+            .and_then(|index| self.user_values.get(index))
+            .copied()
+    }
+
+    /// Sets the `n`th user value, counting from 1, returning false if there is no such user
+    /// value.
+    pub fn set_user_value(&mut self, n: usize, value: Value) -> bool {
+        match n.checked_sub(1).and_then(|i| self.user_values.get_mut(i)) {
+            Some(slot) => {
+                *slot = value;
+                true
+            }
+
+            None => false,
+        }
+    }
+
+    pub fn user_value_count(&self) -> usize {
+        self.user_values.len()
+    }
+
+    /// Calls `mark` with every value the userdata refers to.
+    pub(super) fn trace(&self, mut mark: impl FnMut(Value)) {
+        if let Some(metatable) = self.metatable {
+            mark(Value::Table(metatable));
+        }
+
+        self.user_values.iter().for_each(|&value| mark(value));
+    }
+
+    /// The size of the userdata for the collector's accounting, which cannot see the size of
+    /// the data itself.
+    pub(super) fn size(&self) -> usize {
+        mem::size_of::<Self>() + self.user_values.len() * mem::size_of::<Value>()
+    }
+}
@@ -0,0 +1,128 @@
+use {
+    super::{FunctionRef, StrRef, TableRef, ThreadRef, UserDataRef},
+    crate::number::Number,
+};
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(StrRef),
+    Table(TableRef),
+    Function(FunctionRef),
+    Thread(ThreadRef),
+    UserData(UserDataRef),
+}
+
+impl Value {
+    pub fn is_nil(self) -> bool {
+        matches!(self, Self::Nil)
+    }
+
+    pub fn is_truthy(self) -> bool {
+        !matches!(self, Self::Nil | Self::Bool(false))
+    }
+
+    /// Primitive equality, as performed by `rawequal`: integers and floats compare by
+    /// mathematical value, and everything else compares by identity.
+    pub fn raw_eq(self, other: Self) -> bool {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => true,
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::Int(a), Self::Int(b)) => a == b,
+            (Self::Float(a), Self::Float(b)) => a == b,
+            (Self::Int(i), Self::Float(f)) | (Self::Float(f), Self::Int(i)) => {
+                float_to_int(f) == Some(i)
+            }
+            (Self::String(a), Self::String(b)) => a == b,
+            (Self::Table(a), Self::Table(b)) => a == b,
+            (Self::Function(a), Self::Function(b)) => a == b,
+            (Self::Thread(a), Self::Thread(b)) => a == b,
+            (Self::UserData(a), Self::UserData(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn type_name(self) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Bool(_) => "boolean",
+            Self::Int(_) | Self::Float(_) => "number",
+            Self::String(_) => "string",
+            Self::Table(_) => "table",
+            Self::Function(_) => "function",
+            Self::Thread(_) => "thread",
+            Self::UserData(_) => "userdata",
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Self::Int(value)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(value: f64) -> Self {
+        Self::Float(value)
+    }
+}
+
+impl From<Number> for Value {
+    fn from(value: Number) -> Self {
+        match value {
+            Number::Int(value) => Self::Int(value),
+            Number::Float(value) => Self::Float(value),
+        }
+    }
+}
+
+impl From<StrRef> for Value {
+    fn from(value: StrRef) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<TableRef> for Value {
+    fn from(value: TableRef) -> Self {
+        Self::Table(value)
+    }
+}
+
+impl From<FunctionRef> for Value {
+    fn from(value: FunctionRef) -> Self {
+        Self::Function(value)
+    }
+}
+
+impl From<ThreadRef> for Value {
+    fn from(value: ThreadRef) -> Self {
+        Self::Thread(value)
+    }
+}
+
+impl From<UserDataRef> for Value {
+    fn from(value: UserDataRef) -> Self {
+        Self::UserData(value)
+    }
+}
+
+/// Converts a float to an integer if it has an exact integer representation.
+pub fn float_to_int(value: f64) -> Option<i64> {
+    // -2^63 is exactly representable, but 2^63 is the first float past i64::MAX.
+    if (-9223372036854775808.0..9223372036854775808.0).contains(&value) && value.trunc() == value {
+        Some(value as i64)
+    } else {
+        None
+    }
+}
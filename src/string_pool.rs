@@ -35,12 +35,6 @@ impl StringPool {
             }
         }
     }
-
-    pub fn clear(&mut self) {
-        self.dedup_table.get_mut().clear();
-        self.strings.get_mut().clear();
-        self.storage.reset();
-    }
 }
 
 impl Index<StringRef> for StringPool {
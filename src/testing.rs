@@ -0,0 +1,27 @@
+//! Helpers for tests that run Lua source.
+
+use crate::{Lua, Value};
+
+/// Runs a chunk named `test` in a fresh state, returning its first result converted to a
+/// string as `tostring` does, or its error message.
+pub fn run(source: &str) -> Result<String, String> {
+    let mut lua = Lua::new();
+    let result = lua
+        .load(source, "=test")
+        .and_then(|function| function.call::<Value>(&mut lua, ()))
+        .and_then(|value| lua.tostring(value));
+    match result {
+        Ok(string) => Ok(String::from_utf8_lossy(&lua.heap[string]).into_owned()),
+        Err(error) => Err(error.to_string()),
+    }
+}
+
+#[track_caller]
+pub fn assert_runs(source: &str, expected: &str) {
+    assert_eq!(run(source), Ok(expected.to_owned()), "{source}");
+}
+
+#[track_caller]
+pub fn assert_fails(source: &str, expected: &str) {
+    assert_eq!(run(source), Err(expected.to_owned()), "{source}");
+}
@@ -0,0 +1,146 @@
+//! UTF-8 as Lua extends it, to code points up to 2^31 - 1 in sequences of up to six bytes.
+//!
+//! The lexer encodes `\u{XXX}` escapes and the `utf8` library encodes and decodes strings with
+//! the same routines.
+
+/// The largest code point that can be encoded.
+pub const MAX_CODE_POINT: u32 = 0x7fff_ffff;
+
+/// The largest code point that Unicode allows, which is the largest that strict decoding
+/// accepts.
+pub const MAX_UNICODE: u32 = 0x10_ffff;
+
+/// Appends the encoding of `code`, which must be at most [`MAX_CODE_POINT`].
+pub fn encode(buffer: &mut Vec<u8>, mut code: u32) {
+    if code < 0x80 {
+        buffer.push(code as u8);
+        return;
+    }
+
+    // Continuation bytes are produced from the last one back, and each leaves one bit less for
+    // the first byte.
+    let mut continuation = [0; 5];
+    let mut count = 0;
+    let mut first_max = 0x3f;
+    loop {
+        continuation[count] = 0x80 | (code & 0x3f) as u8;
+        count += 1;
+        code >>= 6;
+        first_max >>= 1;
+
+        if code <= first_max {
+            break;
+        }
+    }
+
+    buffer.push((!first_max << 1) as u8 | code as u8);
+    buffer.extend(continuation[..count].iter().rev());
+}
+
+/// Decodes the sequence at the start of `bytes`, returning its code point and length, or
+/// `None` if it is not a valid sequence. Overlong encodings are never valid, and with `strict`,
+/// neither are surrogates nor code points past [`MAX_UNICODE`].
+pub fn decode(bytes: &[u8], strict: bool) -> Option<(u32, usize)> {
+    // The smallest code point that needs each number of continuation bytes. A continuation
+    // byte cannot start a sequence.
+    const LIMITS: [u32; 6] = [u32::MAX, 0x80, 0x800, 0x1_0000, 0x20_0000, 0x400_0000];
+
+    let &first = bytes.first()?;
+    let (code, len) = if first < 0x80 {
+        (first as u32, 1)
+    } else {
+        let mut lead = first as u32;
+        let mut code = 0u32;
+        let mut count = 0;
+
+        while lead & 0x40 != 0 {
+            count += 1;
+            let &byte = bytes.get(count)?;
+            if byte & 0xc0 != 0x80 || count > 5 {
+                return None;
+            }
+
+            code = (code << 6) | (byte & 0x3f) as u32;
+            lead <<= 1;
+        }
+
+        code |= (lead & 0x7f) << (count * 5);
+        if code > MAX_CODE_POINT || code < LIMITS[count] {
+            return None;
+        }
+
+        (code, count + 1)
+    };
+
+    if strict && (code > MAX_UNICODE || (0xd800..=0xdfff).contains(&code)) {
+        return None;
+    }
+
+    Some((code, len))
+}
+
+/// Whether `byte` continues a sequence rather than starting one.
+pub fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(code: u32) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        encode(&mut buffer, code);
+        buffer
+    }
+
+    #[test]
+    fn encodings_of_every_length_round_trip() {
+        for (code, len) in [
+            (0x7f, 1),
+            (0x80, 2),
+            (0x7ff, 2),
+            (0x800, 3),
+            (0xffff, 3),
+            (0x1_0000, 4),
+            (0x1f_ffff, 4),
+            (0x20_0000, 5),
+            (0x3ff_ffff, 5),
+            (0x400_0000, 6),
+            (MAX_CODE_POINT, 6),
+        ] {
+            let bytes = encoded(code);
+            assert_eq!(bytes.len(), len, "{code:#x}");
+            assert_eq!(decode(&bytes, false), Some((code, len)), "{code:#x}");
+        }
+
+        assert_eq!(encoded(0x20ac), "€".as_bytes());
+        assert_eq!(encoded(MAX_CODE_POINT), b"\xfd\xbf\xbf\xbf\xbf\xbf");
+    }
+
+    #[test]
+    fn strict_decoding_rejects_surrogates_and_large_code_points() {
+        for code in [0xd800, 0xdfff, MAX_UNICODE + 1, MAX_CODE_POINT] {
+            assert_eq!(decode(&encoded(code), true), None, "{code:#x}");
+            assert!(decode(&encoded(code), false).is_some(), "{code:#x}");
+        }
+        assert_eq!(decode(&encoded(MAX_UNICODE), true), Some((MAX_UNICODE, 4)));
+    }
+
+    #[test]
+    fn malformed_sequences() {
+        for bytes in [
+            &b""[..],
+            b"\x80",
+            b"\xc3",
+            b"\xc3a",
+            b"\xc0\x80",
+            b"\xe0\x80\x80",
+            b"\xfe\x80\x80\x80\x80\x80\x80",
+            b"\xff",
+        ] {
+            assert_eq!(decode(bytes, false), None, "{bytes:?}");
+        }
+        assert_eq!(decode(b"\xc3\xa4rest", true), Some((0xe4, 2)));
+    }
+}